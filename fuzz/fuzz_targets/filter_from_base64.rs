@@ -0,0 +1,24 @@
+// ABOUTME: Fuzzes Filter::from_base64, which decodes the `filter` query parameter of /query
+// ABOUTME: Anything that parses must also survive the accessors and limit capping the router applies
+
+#![no_main]
+
+use divine_rest_gateway::fuzzing::{Filter, KindTtls};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(encoded) = std::str::from_utf8(data) else {
+        return;
+    };
+    let Ok(filter) = Filter::from_base64(encoded) else {
+        return;
+    };
+    let _ = filter.cache_key();
+    let _ = filter.ttl_seconds(1_700_000_000, &KindTtls::default());
+    let _ = filter.tag_filters();
+    let _ = filter.is_single_event_lookup();
+
+    let capped = filter.with_max_limit(500);
+    assert!(capped.limit().map_or(true, |l| l <= 500));
+    assert!(Filter::from_json(capped.as_json()).is_ok());
+});
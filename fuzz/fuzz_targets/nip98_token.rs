@@ -0,0 +1,21 @@
+// ABOUTME: Fuzzes NIP-98 Authorization header validation
+// ABOUTME: Each input is tried as a raw header and as the event JSON inside a well-formed token
+
+#![no_main]
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use divine_rest_gateway::fuzzing::validate_nip98_at;
+use libfuzzer_sys::fuzz_target;
+
+const URL: &str = "https://gateway.example/publish";
+
+/// Fixed clock matching the corpus seeds' created_at
+const NOW: u64 = 1_700_000_000;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(header) = std::str::from_utf8(data) {
+        let _ = validate_nip98_at(Some(header), "POST", URL, NOW);
+    }
+    let token = format!("Nostr {}", STANDARD.encode(data));
+    let _ = validate_nip98_at(Some(&token), "POST", URL, NOW);
+});
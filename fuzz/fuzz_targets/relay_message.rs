@@ -0,0 +1,13 @@
+// ABOUTME: Fuzzes parsing of frames received from the upstream relay by RelayPool
+// ABOUTME: A relay is outside our control, so no frame may panic the Durable Object
+
+#![no_main]
+
+use divine_rest_gateway::fuzzing::parse_relay_message;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        let _ = parse_relay_message(text);
+    }
+});
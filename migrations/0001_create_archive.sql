@@ -0,0 +1,21 @@
+-- Event archive for relay-independent reads (?source=archive)
+CREATE TABLE IF NOT EXISTS events (
+    id TEXT PRIMARY KEY,
+    pubkey TEXT NOT NULL,
+    kind INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    raw TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_events_pubkey_kind ON events (pubkey, kind, created_at DESC);
+CREATE INDEX IF NOT EXISTS idx_events_kind_created ON events (kind, created_at DESC);
+CREATE INDEX IF NOT EXISTS idx_events_created ON events (created_at DESC);
+
+CREATE TABLE IF NOT EXISTS event_tags (
+    event_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (event_id, name, value)
+);
+
+CREATE INDEX IF NOT EXISTS idx_event_tags_lookup ON event_tags (name, value);
@@ -0,0 +1,765 @@
+// ABOUTME: Operator-only /admin/* API behind the ADMIN_SECRET binding and an optional IP allowlist
+// ABOUTME: Cache inspection, relay management, and publish queue / quarantine review
+
+use crate::api_keys::{self, ApiKey};
+use crate::branding::{self, Branding};
+use crate::cache::{now_seconds, Cache};
+use crate::custom_routes::{self, CustomRoute};
+use crate::decision_log::{self, Decision, Layer, Outcome};
+use crate::degraded::{self, DegradedMode};
+use crate::router::json_response;
+use crate::filter::{is_hex64, Filter, FilterError};
+use crate::metrics::CacheCounts;
+use crate::presets::{self, Preset};
+use crate::prewarm::{self, MAX_PREWARM_FILTERS};
+use crate::publish_acl::{self, AccessLists};
+use crate::publish_ledger::{self, Transition};
+use crate::publish_state::PublishState;
+use crate::signed_url;
+use crate::types::{
+    AdminKeysResponse, ApiKeyRequest, CacheEntryResponse, CachePurgeRequest, CachePurgeResponse, CacheStatsResponse,
+    CacheStatsRow, CachedQuery,
+    CustomRouteRequest, DegradedRequest, ErrorResponse, PresetRequest, PrewarmRequest, PublishStatus,
+    QuarantinedEvent, SignedUrlRequest,
+};
+use std::net::IpAddr;
+use worker::*;
+
+/// Largest page returned by list endpoints
+const MAX_PAGE: u64 = 100;
+
+pub async fn handle_admin(req: Request, env: Env) -> Result<Response> {
+    // Without a configured secret the namespace doesn't exist
+    let secret = match env.secret("ADMIN_SECRET") {
+        Ok(s) => s.to_string(),
+        Err(_) => return json_response(&ErrorResponse::new("not_found").with_detail("endpoint not found"), 404),
+    };
+    let method = req.method();
+    let path = req.path();
+    let ip = req.headers().get("CF-Connecting-IP")?;
+    let from = ip.as_deref().unwrap_or("unknown address");
+
+    let allowlist = env.var("ADMIN_ALLOWED_CIDRS").map(|v| v.to_string()).unwrap_or_default();
+    if !ip_allowed(ip.as_deref(), &allowlist) {
+        console_log!("Admin {:?} {} refused: {} is not allowlisted", method, path, from);
+        let err = ErrorResponse::new("forbidden").with_detail("address not allowed");
+        return json_response(&err, 403);
+    }
+
+    let provided = req.headers().get("Authorization")?.unwrap_or_default();
+    let token = provided.strip_prefix("Bearer ").unwrap_or_default();
+    // Only the secret: an API key is one leaked client config away from the whole admin API
+    if secret.is_empty() || !constant_time_eq(token.as_bytes(), secret.as_bytes()) {
+        console_log!("Admin {:?} {} refused: unauthorized request from {}", method, path, from);
+        let err = ErrorResponse::new("unauthorized").with_detail("admin secret required");
+        return json_response(&err, 401);
+    }
+
+    // Every admin action is logged with who took it and how it ended
+    let result = dispatch(req, env).await;
+    let outcome = match &result {
+        Ok(resp) => resp.status_code().to_string(),
+        Err(e) => format!("error: {}", e),
+    };
+    console_log!("Admin {:?} {} from {}: {}", method, path, from, outcome);
+    result
+}
+
+async fn dispatch(mut req: Request, env: Env) -> Result<Response> {
+    let url = req.url()?;
+    let path = crate::router::unversioned(url.path()).trim_start_matches("/admin").to_string();
+    let params: std::collections::HashMap<_, _> = url.query_pairs().collect();
+    let cache = Cache::from_env(&env)?;
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match (req.method(), segments.as_slice()) {
+        // Cache inspection
+        (Method::Get, ["cache", "keys"]) => {
+            let prefix = params.get("prefix").map(|p| p.to_string()).unwrap_or_default();
+            let (keys, cursor) = cache
+                .list_keys(&prefix, params.get("cursor").map(|c| c.to_string()), page_limit(&params))
+                .await?;
+            json_response(&AdminKeysResponse { keys, cursor }, 200)
+        }
+        (Method::Get, ["cache", "key", key]) => match cache.get_raw(key).await? {
+            Some(raw) => {
+                let value = serde_json::from_str(&raw).unwrap_or(serde_json::Value::String(raw));
+                json_response(&serde_json::json!({ "key": key, "value": value }), 200)
+            }
+            None => json_response(&ErrorResponse::new("not_found").with_detail("key not found"), 404),
+        },
+        (Method::Get, ["cache", "entry"]) => {
+            let filter = match params.get("filter").map(|f| Filter::from_base64(f)).transpose() {
+                Ok(f) => f,
+                Err(e) => {
+                    let err = ErrorResponse::new("invalid_filter").with_detail(&e.to_string());
+                    return json_response(&err, 400);
+                }
+            };
+            let key = match (params.get("key"), &filter) {
+                (Some(key), _) => key.to_string(),
+                (None, Some(filter)) => filter.cache_key(),
+                (None, None) => {
+                    let err = ErrorResponse::new("invalid_request").with_detail("give key or filter");
+                    return json_response(&err, 400);
+                }
+            };
+            let Some((entry, body)) = cache.get_stored(&key).await? else {
+                return match cache.get_raw(&key).await? {
+                    Some(_) => {
+                        let err = ErrorResponse::new("invalid_request").with_detail("key is not a cached query");
+                        json_response(&err, 400)
+                    }
+                    None => json_response(&ErrorResponse::new("not_found").with_detail("key not found"), 404),
+                };
+            };
+            let expiration = cache.expiration(&key).await?;
+            let mut summary = summarize_entry(key, entry, body.len(), now_seconds(), expiration, filter.as_ref());
+            if params.get("body").map(|b| b.as_ref()) != Some("1") {
+                summary.events = None;
+            }
+            json_response(&summary, 200)
+        }
+        (Method::Get, ["cache", "stats"]) => {
+            let (total, rows) = crate::metrics::snapshot(&env).await?.cache_stats();
+            let row = |route: Option<String>, class: Option<String>, counts: CacheCounts| CacheStatsRow {
+                route,
+                class,
+                hits: counts.hits,
+                stale: counts.stale,
+                misses: counts.misses,
+                bypasses: counts.bypasses,
+                hit_ratio: counts.hit_ratio(),
+            };
+            let breakdown = rows
+                .into_iter()
+                .map(|(route, class, counts)| row(Some(route), Some(class), counts))
+                .collect();
+            json_response(&CacheStatsResponse { total: row(None, None, total), breakdown }, 200)
+        }
+        (Method::Post, ["cache", "purge"]) => {
+            let body: CachePurgeRequest = match req.json().await {
+                Ok(b) => b,
+                Err(e) => {
+                    let err = ErrorResponse::new("invalid_request").with_detail(&e.to_string());
+                    return json_response(&err, 400);
+                }
+            };
+            let host = url.host_str().unwrap_or_default().to_string();
+            purge_cache(&env, &cache, &host, body).await
+        }
+
+        // Cache prewarming
+        (Method::Post, ["prewarm"]) => {
+            let body: PrewarmRequest = match req.json().await {
+                Ok(b) => b,
+                Err(e) => {
+                    let err = ErrorResponse::new("invalid_request").with_detail(&e.to_string());
+                    return json_response(&err, 400);
+                }
+            };
+            if body.filters.is_empty() || body.filters.len() > MAX_PREWARM_FILTERS {
+                let detail = format!("give between 1 and {} filters", MAX_PREWARM_FILTERS);
+                return json_response(&ErrorResponse::new("invalid_request").with_detail(&detail), 400);
+            }
+            let mut filters = Vec::with_capacity(body.filters.len());
+            for (i, filter) in body.filters.iter().enumerate() {
+                match parse_filter(filter) {
+                    Ok(f) => filters.push(f),
+                    Err(e) => {
+                        let detail = format!("filter {}: {}", i, e);
+                        return json_response(&ErrorResponse::new("invalid_filter").with_detail(&detail), 400);
+                    }
+                }
+            }
+
+            let job = prewarm::start_job(&env, &filters).await?;
+            let response = serde_json::json!({ "job_id": &job.id, "total": job.total });
+            json_response(&response, 202)
+        }
+        (Method::Get, ["prewarm", job_id]) => match prewarm::get_job(&cache, job_id).await? {
+            Some(job) => json_response(&job, 200),
+            None => json_response(&ErrorResponse::new("not_found").with_detail("job not found"), 404),
+        },
+
+        // Operator-defined feeds
+        (Method::Get, ["routes"]) => {
+            let (keys, cursor) = cache
+                .list_keys(custom_routes::KEY_PREFIX, params.get("cursor").map(|c| c.to_string()), page_limit(&params))
+                .await?;
+            let kv = env.kv("REST_GATEWAY_CACHE")?;
+            let mut routes = Vec::with_capacity(keys.len());
+            for key in keys {
+                if let Some(route) = custom_routes::get(&kv, key.trim_start_matches(custom_routes::KEY_PREFIX)).await? {
+                    routes.push(route);
+                }
+            }
+            json_response(&serde_json::json!({ "routes": routes, "cursor": cursor }), 200)
+        }
+        (Method::Get, ["routes", name]) => match custom_routes::get(&env.kv("REST_GATEWAY_CACHE")?, name).await? {
+            Some(route) => json_response(&route, 200),
+            None => json_response(&ErrorResponse::new("not_found").with_detail("route not found"), 404),
+        },
+        (Method::Put, ["routes", name]) => {
+            if !custom_routes::valid_name(name) {
+                let err = ErrorResponse::new("invalid_request").with_detail("route names are lowercase letters, digits, - and _");
+                return json_response(&err, 400);
+            }
+            let body: CustomRouteRequest = match req.json().await {
+                Ok(b) => b,
+                Err(e) => {
+                    let err = ErrorResponse::new("invalid_request").with_detail(&e.to_string());
+                    return json_response(&err, 400);
+                }
+            };
+            if !body.filter.is_object() {
+                let err = ErrorResponse::new("invalid_filter").with_detail("filter must be a JSON object");
+                return json_response(&err, 400);
+            }
+            if let Err(e) = Filter::from_json(&body.filter.to_string()) {
+                return json_response(&ErrorResponse::new("invalid_filter").with_detail(&e.to_string()), 400);
+            }
+            let route = CustomRoute {
+                name: name.to_string(),
+                filter: body.filter,
+                ttl: body.ttl,
+                hydrate: body.hydrate,
+                updated_at: now_seconds(),
+            };
+            custom_routes::put(&env.kv("REST_GATEWAY_CACHE")?, &route).await?;
+            json_response(&route, 200)
+        }
+        (Method::Delete, ["routes", name]) => {
+            let kv = env.kv("REST_GATEWAY_CACHE")?;
+            if custom_routes::get(&kv, name).await?.is_none() {
+                return json_response(&ErrorResponse::new("not_found").with_detail("route not found"), 404);
+            }
+            custom_routes::delete(&kv, name).await?;
+            json_response(&serde_json::json!({ "name": name, "deleted": true }), 200)
+        }
+
+        // Named filters for /query?preset=
+        (Method::Get, ["presets"]) => {
+            let (keys, cursor) = cache
+                .list_keys(presets::KEY_PREFIX, params.get("cursor").map(|c| c.to_string()), page_limit(&params))
+                .await?;
+            let kv = env.kv("REST_GATEWAY_CACHE")?;
+            let mut list = Vec::with_capacity(keys.len());
+            for key in keys {
+                if let Some(preset) = presets::get(&kv, key.trim_start_matches(presets::KEY_PREFIX)).await? {
+                    list.push(preset);
+                }
+            }
+            json_response(&serde_json::json!({ "presets": list, "cursor": cursor }), 200)
+        }
+        (Method::Get, ["presets", name]) => match presets::get(&env.kv("REST_GATEWAY_CACHE")?, name).await? {
+            Some(preset) => json_response(&preset, 200),
+            None => json_response(&ErrorResponse::new("not_found").with_detail("preset not found"), 404),
+        },
+        (Method::Put, ["presets", name]) => {
+            if !custom_routes::valid_name(name) {
+                let err = ErrorResponse::new("invalid_request")
+                    .with_detail("preset names are lowercase letters, digits, - and _");
+                return json_response(&err, 400);
+            }
+            let body: PresetRequest = match req.json().await {
+                Ok(b) => b,
+                Err(e) => {
+                    let err = ErrorResponse::new("invalid_request").with_detail(&e.to_string());
+                    return json_response(&err, 400);
+                }
+            };
+            if !body.filter.is_object() {
+                let err = ErrorResponse::new("invalid_filter").with_detail("filter must be a JSON object");
+                return json_response(&err, 400);
+            }
+            let preset = Preset {
+                name: name.to_string(),
+                filter: body.filter,
+                updated_at: now_seconds(),
+            };
+            if let Err(e) = preset.to_filter() {
+                return json_response(&ErrorResponse::new("invalid_filter").with_detail(&e.to_string()), 400);
+            }
+            presets::put(&env.kv("REST_GATEWAY_CACHE")?, &preset).await?;
+            json_response(&preset, 200)
+        }
+        (Method::Delete, ["presets", name]) => {
+            let kv = env.kv("REST_GATEWAY_CACHE")?;
+            if presets::get(&kv, name).await?.is_none() {
+                return json_response(&ErrorResponse::new("not_found").with_detail("preset not found"), 404);
+            }
+            presets::delete(&kv, name).await?;
+            json_response(&serde_json::json!({ "name": name, "deleted": true }), 200)
+        }
+
+        // API keys
+        (Method::Get, ["api-keys"]) => {
+            let (keys, cursor) = cache
+                .list_keys(api_keys::KEY_PREFIX, params.get("cursor").map(|c| c.to_string()), page_limit(&params))
+                .await?;
+            let kv = env.kv("REST_GATEWAY_CACHE")?;
+            let mut records = Vec::with_capacity(keys.len());
+            for key in keys {
+                if let Some(record) = api_keys::get(&kv, key.trim_start_matches(api_keys::KEY_PREFIX)).await? {
+                    records.push(record);
+                }
+            }
+            json_response(&serde_json::json!({ "keys": records, "cursor": cursor }), 200)
+        }
+        (Method::Post, ["api-keys"]) => {
+            let body: ApiKeyRequest = match req.json().await {
+                Ok(b) => b,
+                Err(e) => {
+                    let err = ErrorResponse::new("invalid_request").with_detail(&e.to_string());
+                    return json_response(&err, 400);
+                }
+            };
+            let key = api_keys::new_key();
+            let record = ApiKey {
+                id: api_keys::key_id(&key),
+                name: body.name,
+                tier: body.tier,
+                scopes: body.scopes,
+                created_at: now_seconds(),
+            };
+            api_keys::put(&env.kv("REST_GATEWAY_CACHE")?, &record).await?;
+            // The only time the key itself is returned
+            json_response(&serde_json::json!({ "key": key, "api_key": record }), 201)
+        }
+        (Method::Delete, ["api-keys", id]) => {
+            let kv = env.kv("REST_GATEWAY_CACHE")?;
+            if api_keys::get(&kv, id).await?.is_none() {
+                return json_response(&ErrorResponse::new("not_found").with_detail("API key not found"), 404);
+            }
+            api_keys::delete(&kv, id).await?;
+            json_response(&serde_json::json!({ "id": id, "deleted": true }), 200)
+        }
+
+        // Time-limited deep links
+        (Method::Post, ["signed-urls"]) => {
+            let Some(secret) = signed_url::secret(&env) else {
+                let err = ErrorResponse::new("signed_urls_unavailable").with_detail("SIGNED_URL_SECRET is not set");
+                return json_response(&err, 503);
+            };
+            let body: SignedUrlRequest = match req.json().await {
+                Ok(b) => b,
+                Err(e) => {
+                    let err = ErrorResponse::new("invalid_request").with_detail(&e.to_string());
+                    return json_response(&err, 400);
+                }
+            };
+            if !body.path.starts_with('/') || body.path.starts_with("/admin/") {
+                let err = ErrorResponse::new("invalid_request").with_detail("path must be a public route");
+                return json_response(&err, 400);
+            }
+            if body.ttl_seconds == 0 || body.ttl_seconds > signed_url::MAX_TTL_SECONDS {
+                let detail = format!("ttl_seconds must be between 1 and {}", signed_url::MAX_TTL_SECONDS);
+                return json_response(&ErrorResponse::new("invalid_request").with_detail(&detail), 400);
+            }
+            let mut signed = url.join(&body.path)?;
+            let expires = now_seconds() + body.ttl_seconds;
+            signed_url::sign(&secret, &mut signed, expires);
+            json_response(&serde_json::json!({ "url": signed.as_str(), "expires": expires }), 201)
+        }
+
+        // Degraded mode for relay maintenance
+        (Method::Get, ["degraded"]) => {
+            let mode = degraded::get(&env).await;
+            json_response(&serde_json::json!({ "degraded": mode.is_some(), "mode": mode }), 200)
+        }
+        (Method::Put, ["degraded"]) => {
+            let text = req.text().await?;
+            let body: DegradedRequest = if text.trim().is_empty() {
+                DegradedRequest::default()
+            } else {
+                match serde_json::from_str(&text) {
+                    Ok(b) => b,
+                    Err(e) => {
+                        let err = ErrorResponse::new("invalid_request").with_detail(&e.to_string());
+                        return json_response(&err, 400);
+                    }
+                }
+            };
+            let mode = DegradedMode::new(body.reason, body.retry_after, now_seconds());
+            degraded::set(&env.kv("REST_GATEWAY_CACHE")?, &mode).await?;
+            json_response(&serde_json::json!({ "degraded": true, "mode": mode }), 200)
+        }
+        (Method::Delete, ["degraded"]) => {
+            degraded::clear(&env.kv("REST_GATEWAY_CACHE")?).await?;
+            json_response(&serde_json::json!({ "degraded": false }), 200)
+        }
+
+        // Publish allowlist and denylist, on top of the env lists
+        (Method::Get, ["publish-acl"]) => {
+            let stored = publish_acl::get(&env.kv("REST_GATEWAY_CACHE")?, 0).await?;
+            json_response(&stored.unwrap_or_default(), 200)
+        }
+        (Method::Put, ["publish-acl"]) => {
+            let body: AccessLists = match req.json().await {
+                Ok(b) => b,
+                Err(e) => {
+                    let err = ErrorResponse::new("invalid_request").with_detail(&e.to_string());
+                    return json_response(&err, 400);
+                }
+            };
+            if let Some(bad) = body.allow.iter().chain(&body.deny).find(|p| !is_hex64(p)) {
+                let detail = format!("{} is not a 64 character hex pubkey", bad);
+                return json_response(&ErrorResponse::new("invalid_pubkey").with_detail(&detail), 400);
+            }
+            publish_acl::put(&env.kv("REST_GATEWAY_CACHE")?, &body).await?;
+            json_response(&body, 200)
+        }
+        (Method::Delete, ["publish-acl"]) => {
+            publish_acl::clear(&env.kv("REST_GATEWAY_CACHE")?).await?;
+            json_response(&AccessLists::default(), 200)
+        }
+
+        // Branding of the HTML surfaces, for the whole deployment or one `?host=`
+        (Method::Get, ["branding"]) => {
+            let host = params.get("host").map(|h| h.as_ref());
+            let stored = branding::get_stored(&env.kv("REST_GATEWAY_CACHE")?, host).await?;
+            json_response(&serde_json::json!({ "host": host, "branding": stored }), 200)
+        }
+        (Method::Put, ["branding"]) => {
+            let body: Branding = match req.json().await {
+                Ok(b) => b,
+                Err(e) => {
+                    let err = ErrorResponse::new("invalid_request").with_detail(&e.to_string());
+                    return json_response(&err, 400);
+                }
+            };
+            if let Err(e) = body.validate() {
+                return json_response(&ErrorResponse::new("invalid_branding").with_detail(&e), 400);
+            }
+            let host = params.get("host").map(|h| h.as_ref());
+            branding::set(&env.kv("REST_GATEWAY_CACHE")?, host, &body).await?;
+            json_response(&serde_json::json!({ "host": host, "branding": body }), 200)
+        }
+        (Method::Delete, ["branding"]) => {
+            let host = params.get("host").map(|h| h.as_ref());
+            branding::clear(&env.kv("REST_GATEWAY_CACHE")?, host).await?;
+            json_response(&serde_json::json!({ "host": host, "deleted": true }), 200)
+        }
+
+        // Relay management
+        (Method::Get, ["relay"]) => {
+            let relay_url = crate::router::relay_url(&env);
+            let mut latency = relay_pool_fetch(&env, "/latency").await?;
+            let latency: serde_json::Value = latency.json().await?;
+            let info = crate::relay_info::fetch_document(&env, &relay_url)
+                .await
+                .map(|(doc, _)| doc)
+                .ok();
+            json_response(
+                &serde_json::json!({ "relay": relay_url, "info": info, "latency": latency }),
+                200,
+            )
+        }
+        (Method::Post, ["relay", "reset"]) => {
+            let relay_url = crate::router::relay_url(&env);
+            cache.delete_key(&format!("relayinfo:{}", relay_url)).await?;
+            let mut resp = relay_pool_fetch(&env, "/reset").await?;
+            let body: serde_json::Value = resp.json().await?;
+            json_response(&body, 200)
+        }
+
+        // Publish queue inspection
+        (Method::Get, ["publish", event_id]) => {
+            let status = cache.get_publish_status(event_id).await?;
+            let quarantine = cache.get_quarantine(event_id).await?;
+            if status.is_none() && quarantine.is_none() {
+                return json_response(&ErrorResponse::new("not_found").with_detail("publish not found"), 404);
+            }
+            json_response(
+                &serde_json::json!({ "event_id": event_id, "status": status, "quarantine": quarantine }),
+                200,
+            )
+        }
+        (Method::Get, ["quarantine"]) => {
+            let (keys, cursor) = cache
+                .list_keys("quarantine:", params.get("cursor").map(|c| c.to_string()), page_limit(&params))
+                .await?;
+            let mut entries = Vec::with_capacity(keys.len());
+            for key in keys {
+                let event_id = key.trim_start_matches("quarantine:").to_string();
+                if let Some(entry) = cache.get_quarantine(&event_id).await? {
+                    entries.push(QuarantinedEvent { event_id, entry });
+                }
+            }
+            json_response(&serde_json::json!({ "entries": entries, "cursor": cursor }), 200)
+        }
+        (Method::Post, ["quarantine", event_id, action @ ("approve" | "reject")]) => {
+            review_quarantine(&env, &cache, event_id, action).await
+        }
+
+        _ => json_response(&ErrorResponse::new("not_found").with_detail("admin endpoint not found"), 404),
+    }
+}
+
+/// Filters in admin bodies are JSON objects, or base64url exactly as sent to /query
+fn parse_filter(filter: &serde_json::Value) -> std::result::Result<Filter, FilterError> {
+    match filter {
+        serde_json::Value::String(encoded) => Filter::from_base64(encoded),
+        other => Filter::from_json(&other.to_string()),
+    }
+}
+
+async fn purge_cache(env: &Env, cache: &Cache, host: &str, body: CachePurgeRequest) -> Result<Response> {
+    let mut keys = Vec::new();
+    if let Some(key) = body.key {
+        keys.push(key);
+    }
+    if let Some(filter) = body.filter {
+        match parse_filter(&filter) {
+            Ok(f) => keys.push(f.cache_key()),
+            Err(e) => {
+                let err = ErrorResponse::new("invalid_filter").with_detail(&e.to_string());
+                return json_response(&err, 400);
+            }
+        }
+    }
+    if let Some(pubkey) = &body.pubkey {
+        keys.push(Filter::profile(pubkey).cache_key());
+    }
+    if let Some(event_id) = &body.event_id {
+        keys.push(Filter::note(event_id).cache_key());
+    }
+    if keys.is_empty() {
+        let err = ErrorResponse::new("invalid_request").with_detail("give a filter, key, pubkey or event_id");
+        return json_response(&err, 400);
+    }
+
+    for key in &keys {
+        cache.delete_key(key).await?;
+        let decision = Decision::new(Layer::Kv, key.as_str(), Outcome::Invalidated).with_trigger("admin_purge");
+        decision_log::record(env, &decision);
+    }
+    let html_purged = match &body.event_id {
+        Some(event_id) => {
+            crate::html_cache::purge_event(env, host, event_id, "admin_purge").await;
+            true
+        }
+        None => false,
+    };
+    json_response(
+        &CachePurgeResponse {
+            deleted_keys: keys,
+            html_purged,
+        },
+        200,
+    )
+}
+
+/// Approve (forward now) or reject (never forward) a held event
+async fn review_quarantine(env: &Env, cache: &Cache, event_id: &str, action: &str) -> Result<Response> {
+    let entry = match cache.get_quarantine(event_id).await? {
+        Some(e) => e,
+        None => {
+            let err = ErrorResponse::new("not_found").with_detail("event is not quarantined");
+            return json_response(&err, 404);
+        }
+    };
+
+    let pubkey = entry.event.get("pubkey").and_then(|v| v.as_str()).map(String::from);
+    let next = if action == "approve" {
+        PublishState::Queued
+    } else {
+        PublishState::Rejected
+    };
+    // The author may have cancelled, or the delay released it to the queue already
+    let state = match publish_ledger::transition(env, event_id, &PublishState::Quarantined, next).await? {
+        Transition::Moved(state) => state,
+        Transition::Refused(current) => {
+            let detail = format!("publish is already {}", current.as_str());
+            let err = ErrorResponse::new("not_reviewable").with_detail(&detail);
+            return json_response(&err, 409);
+        }
+    };
+    if state == PublishState::Queued {
+        // The delayed copy still in the queue is skipped once this one publishes
+        env.queue("PUBLISH_QUEUE")?.send(entry.event).await?;
+    }
+    let status = state.as_str();
+    cache.delete_quarantine(event_id).await?;
+    cache
+        .set_publish_status(
+            event_id,
+            &PublishStatus {
+                quarantine_reasons: Some(entry.reasons),
+                pubkey,
+                ..PublishStatus::new(state, 0)
+            },
+        )
+        .await?;
+    json_response(&serde_json::json!({ "event_id": event_id, "status": status }), 200)
+}
+
+async fn relay_pool_fetch(env: &Env, path: &str) -> Result<Response> {
+    let stub = env.durable_object("RELAY_POOL")?.id_from_name("default")?.get_stub()?;
+    stub.fetch_with_str(&format!("http://do{}", path)).await
+}
+
+fn page_limit(params: &std::collections::HashMap<std::borrow::Cow<str>, std::borrow::Cow<str>>) -> u64 {
+    params
+        .get("limit")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(50)
+        .min(MAX_PAGE)
+}
+
+/// Compare secrets without leaking the mismatch position through timing
+/// Metadata of a cached query, events included
+fn summarize_entry(
+    key: String,
+    entry: CachedQuery,
+    size_bytes: usize,
+    now: u64,
+    expiration: Option<u64>,
+    filter: Option<&Filter>,
+) -> CacheEntryResponse {
+    let mut kinds = std::collections::BTreeMap::new();
+    for event in &entry.events {
+        if let Some(kind) = event.get("kind").and_then(|k| k.as_u64()) {
+            *kinds.entry(kind).or_insert(0) += 1;
+        }
+    }
+    CacheEntryResponse {
+        key,
+        filter: filter.and_then(|f| serde_json::from_str(f.as_json()).ok()),
+        timestamp: entry.timestamp,
+        age_seconds: now.saturating_sub(entry.timestamp),
+        ttl_remaining_seconds: expiration.map(|e| e.saturating_sub(now)),
+        eose: entry.eose,
+        event_count: entry.events.len(),
+        size_bytes,
+        kinds,
+        tags: filter.map(|f| f.tag_filters()).unwrap_or_default(),
+        events: Some(entry.events),
+    }
+}
+
+/// Whether a request passes the admin gate: the `ADMIN_SECRET` bearer token, from
+/// an address in `ADMIN_ALLOWED_CIDRS`. Used outside `/admin/*` to decide who sees
+/// operator-only details on ordinary responses.
+pub fn is_operator(req: &Request, env: &Env) -> bool {
+    let Ok(secret) = env.secret("ADMIN_SECRET").map(|s| s.to_string()) else {
+        return false;
+    };
+    let header = |name: &str| req.headers().get(name).ok().flatten();
+    let provided = header("Authorization").unwrap_or_default();
+    let token = provided.strip_prefix("Bearer ").unwrap_or_default();
+    let allowlist = env.var("ADMIN_ALLOWED_CIDRS").map(|v| v.to_string()).unwrap_or_default();
+    !secret.is_empty()
+        && constant_time_eq(token.as_bytes(), secret.as_bytes())
+        && ip_allowed(header("CF-Connecting-IP").as_deref(), &allowlist)
+}
+
+/// Whether a client address is in `ADMIN_ALLOWED_CIDRS` (comma-separated CIDRs or bare
+/// addresses). An empty list allows everyone; entries that don't parse match nothing,
+/// so a mistyped list locks the namespace rather than opening it.
+fn ip_allowed(ip: Option<&str>, allowlist: &str) -> bool {
+    let mut entries = allowlist.split(',').map(str::trim).filter(|e| !e.is_empty()).peekable();
+    if entries.peek().is_none() {
+        return true;
+    }
+    let Some(ip) = ip.and_then(|ip| ip.trim().parse::<IpAddr>().ok()) else {
+        return false;
+    };
+    entries.filter_map(Cidr::parse).any(|cidr| cidr.contains(ip))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    fn parse(entry: &str) -> Option<Self> {
+        let (addr, prefix) = match entry.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (entry, None),
+        };
+        let network: IpAddr = addr.parse().ok()?;
+        let width = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u32>().ok().filter(|p| *p <= width)?,
+            None => width,
+        };
+        Some(Self { network, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let (network, ip, width) = match (self.network, ip) {
+            (IpAddr::V4(n), IpAddr::V4(ip)) => (u32::from(n) as u128, u32::from(ip) as u128, 32),
+            (IpAddr::V6(n), IpAddr::V6(ip)) => (u128::from(n), u128::from(ip), 128),
+            _ => return false,
+        };
+        let shift = width - self.prefix;
+        network.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+    }
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(!constant_time_eq(b"", b"x"));
+    }
+
+    #[test]
+    fn test_ip_allowed() {
+        assert!(ip_allowed(Some("203.0.113.9"), ""));
+        assert!(ip_allowed(None, " "));
+
+        let list = "203.0.113.0/24, 2001:db8::/32, 198.51.100.7";
+        assert!(ip_allowed(Some("203.0.113.200"), list));
+        assert!(ip_allowed(Some("2001:db8:1::5"), list));
+        assert!(ip_allowed(Some("198.51.100.7"), list));
+        assert!(!ip_allowed(Some("198.51.100.8"), list));
+        assert!(!ip_allowed(Some("203.0.114.1"), list));
+        assert!(!ip_allowed(None, list));
+        assert!(ip_allowed(Some("192.0.2.1"), "0.0.0.0/0"));
+
+        // A typo fails closed
+        assert!(!ip_allowed(Some("203.0.113.9"), "203.0.113.0/33"));
+    }
+
+    #[test]
+    fn test_summarize_entry() {
+        let entry = CachedQuery {
+            events: vec![
+                serde_json::json!({"id": "a", "kind": 1}),
+                serde_json::json!({"id": "b", "kind": 1}),
+                serde_json::json!({"id": "c", "kind": 6}),
+            ],
+            eose: true,
+            timestamp: 1000,
+            negative: false,
+            filter: None,
+        };
+        let filter = Filter::from_json(r##"{"kinds":[1,6],"#t":["music"]}"##).unwrap();
+        let summary = summarize_entry(filter.cache_key(), entry, 321, 1030, Some(1300), Some(&filter));
+        assert_eq!(summary.age_seconds, 30);
+        assert_eq!(summary.ttl_remaining_seconds, Some(270));
+        assert_eq!(summary.event_count, 3);
+        assert_eq!(summary.size_bytes, 321);
+        assert_eq!(summary.kinds.get(&1), Some(&2));
+        assert_eq!(summary.kinds.get(&6), Some(&1));
+        assert_eq!(summary.tags, vec![("t".to_string(), vec!["music".to_string()])]);
+        assert_eq!(summary.filter.unwrap()["kinds"], serde_json::json!([1, 6]));
+    }
+}
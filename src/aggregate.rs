@@ -0,0 +1,399 @@
+// ABOUTME: Incrementally maintained aggregates (counts, reaction summaries, zap totals)
+// ABOUTME: Keeps the last result plus a `since` watermark so refreshes only fetch newer events
+
+use crate::degraded::DegradedMode;
+use crate::filter::Filter;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use worker::*;
+
+/// Events requested per relay round trip
+const PAGE_LIMIT: u64 = 500;
+
+/// Pages walked back per refresh. More new events than this between two
+/// refreshes are left as a backfill for later refreshes rather than turning
+/// one request into a crawl.
+const MAX_PAGES: usize = 5;
+
+/// Aggregates are rebuilt from scratch if untouched for this long
+const STATE_TTL_SECONDS: u64 = 7 * 86400;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateKind {
+    Count,
+    Reactions,
+    Zaps,
+}
+
+impl AggregateKind {
+    fn name(&self) -> &'static str {
+        match self {
+            AggregateKind::Count => "count",
+            AggregateKind::Reactions => "reactions",
+            AggregateKind::Zaps => "zaps",
+        }
+    }
+}
+
+/// Stored aggregate. `watermark` is the newest `created_at` folded in; events at
+/// exactly that second are remembered by id since `since` is inclusive.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AggregateState {
+    pub count: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reactions: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub zap_msats: u64,
+    pub watermark: Option<u64>,
+    #[serde(default)]
+    pub boundary_ids: Vec<String>,
+    pub refreshed_at: u64,
+    /// Older events a refresh ran out of pages for, counted by later refreshes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backfill: Option<Backfill>,
+}
+
+/// A stretch of `created_at` seconds not yet fully counted, bounds inclusive.
+/// Events sitting on either bound may be counted already, so their ids are kept.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Backfill {
+    /// The watermark when the stretch was left behind; `None` back to the beginning
+    pub since: Option<u64>,
+    pub until: u64,
+    #[serde(default)]
+    pub since_ids: Vec<String>,
+    #[serde(default)]
+    pub until_ids: Vec<String>,
+}
+
+impl AggregateState {
+    /// Fold in events not already counted. Returns how many were new.
+    pub fn fold(&mut self, kind: AggregateKind, events: &[Value]) -> usize {
+        let mut seen: HashSet<String> = self.boundary_ids.iter().cloned().collect();
+        let mut added = 0;
+        for event in events {
+            let Some(id) = event.get("id").and_then(|v| v.as_str()) else { continue };
+            let created_at = event.get("created_at").and_then(|v| v.as_u64()).unwrap_or(0);
+            if self.watermark.is_some_and(|w| created_at < w) || !seen.insert(id.to_string()) {
+                continue;
+            }
+            self.count_event(kind, event);
+            added += 1;
+        }
+
+        // Advance the watermark and keep only the ids sitting on it
+        let newest = events
+            .iter()
+            .filter_map(|e| e.get("created_at").and_then(|v| v.as_u64()))
+            .max();
+        if let Some(newest) = newest {
+            if self.watermark.map_or(true, |w| newest > w) {
+                self.watermark = Some(newest);
+                self.boundary_ids.clear();
+            }
+        }
+        let watermark = self.watermark;
+        for event in events {
+            let created_at = event.get("created_at").and_then(|v| v.as_u64());
+            if created_at.is_some() && created_at == watermark {
+                if let Some(id) = event.get("id").and_then(|v| v.as_str()) {
+                    if !self.boundary_ids.iter().any(|b| b == id) {
+                        self.boundary_ids.push(id.to_string());
+                    }
+                }
+            }
+        }
+        added
+    }
+
+    /// Fold in a page walk that ran out of pages before reaching the watermark it
+    /// started from: everything fetched is counted and the watermark advances,
+    /// and the stretch below the oldest event fetched is left as a backfill
+    pub fn fold_cut_off(&mut self, kind: AggregateKind, events: &[Value]) -> usize {
+        let (since, since_ids) = (self.watermark, self.boundary_ids.clone());
+        let added = self.fold(kind, events);
+        if let Some(until) = oldest(events) {
+            self.backfill = Some(Backfill {
+                since,
+                until,
+                since_ids,
+                until_ids: ids_at(events, until),
+            });
+        }
+        added
+    }
+
+    /// Fold in a page of the backfill, newest first. `finished` means the walk
+    /// reached the backfill's start, so nothing is left of it.
+    pub fn fold_backfill(&mut self, kind: AggregateKind, events: &[Value], finished: bool) -> usize {
+        let Some(mut gap) = self.backfill.take() else { return 0 };
+        let mut seen: HashSet<String> = gap.since_ids.iter().chain(&gap.until_ids).cloned().collect();
+        let mut added = 0;
+        for event in events {
+            let Some(id) = event.get("id").and_then(|v| v.as_str()) else { continue };
+            let created_at = event.get("created_at").and_then(|v| v.as_u64()).unwrap_or(0);
+            let outside = created_at > gap.until || gap.since.is_some_and(|s| created_at < s);
+            if outside || !seen.insert(id.to_string()) {
+                continue;
+            }
+            self.count_event(kind, event);
+            added += 1;
+        }
+        if !finished {
+            // The rest of the stretch, below what this walk reached
+            match oldest(events).filter(|o| *o < gap.until) {
+                Some(until) => {
+                    gap.until = until;
+                    gap.until_ids = ids_at(events, until);
+                }
+                None => gap.until_ids.extend(ids_at(events, gap.until)),
+            }
+            self.backfill = Some(gap);
+        }
+        added
+    }
+
+    fn count_event(&mut self, kind: AggregateKind, event: &Value) {
+        self.count += 1;
+        match kind {
+            AggregateKind::Count => {}
+            AggregateKind::Reactions => *self.reactions.entry(reaction_key(event)).or_insert(0) += 1,
+            AggregateKind::Zaps => self.zap_msats += zap_amount_msats(event),
+        }
+    }
+}
+
+fn oldest(events: &[Value]) -> Option<u64> {
+    events.iter().filter_map(|e| e.get("created_at").and_then(|v| v.as_u64())).min()
+}
+
+/// Ids of the events created at exactly `second`
+fn ids_at(events: &[Value], second: u64) -> Vec<String> {
+    events
+        .iter()
+        .filter(|e| e.get("created_at").and_then(|v| v.as_u64()) == Some(second))
+        .filter_map(|e| e.get("id").and_then(|v| v.as_str()).map(String::from))
+        .collect()
+}
+
+/// `+` and empty content both mean "like" (NIP-25)
+fn reaction_key(event: &Value) -> String {
+    match event.get("content").and_then(|v| v.as_str()).unwrap_or("") {
+        "" => "+".to_string(),
+        content => content.to_string(),
+    }
+}
+
+/// Amount of a zap receipt (kind 9735), read from the `amount` tag of the zap
+/// request embedded in its `description` tag (NIP-57)
+pub fn zap_amount_msats(receipt: &Value) -> u64 {
+    let tag_value = |event: &Value, name: &str| -> Option<String> {
+        event
+            .get("tags")?
+            .as_array()?
+            .iter()
+            .filter_map(|t| t.as_array())
+            .find(|t| t.first().and_then(|n| n.as_str()) == Some(name))
+            .and_then(|t| t.get(1))
+            .and_then(|v| v.as_str())
+            .map(String::from)
+    };
+    tag_value(receipt, "description")
+        .and_then(|d| serde_json::from_str::<Value>(&d).ok())
+        .and_then(|request| tag_value(&request, "amount"))
+        .and_then(|a| a.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Filter for one relay page: events newer than the watermark, older than `until`.
+/// Bounds already in the base filter are only ever narrowed; its `limit` is replaced.
+pub fn page_filter(base: &Value, watermark: Option<u64>, until: Option<u64>) -> Value {
+    let mut filter = base.clone();
+    filter["limit"] = PAGE_LIMIT.into();
+    let since = watermark.max(base.get("since").and_then(|v| v.as_u64()));
+    if let Some(since) = since {
+        filter["since"] = since.into();
+    }
+    let until = match (until, base.get("until").and_then(|v| v.as_u64())) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+    if let Some(until) = until {
+        filter["until"] = until.into();
+    }
+    filter
+}
+
+/// Base filter of an aggregate over reactions or zaps on an event
+pub fn target_filter(kind: AggregateKind, event_id: &str) -> Value {
+    let kinds = match kind {
+        AggregateKind::Zaps => vec![crate::kind::ZAP_RECEIPT],
+        _ => vec![crate::kind::REACTION],
+    };
+    serde_json::json!({ "kinds": kinds, "#e": [event_id] })
+}
+
+fn state_key(kind: AggregateKind, base: &Value) -> Result<String> {
+    let filter = Filter::from_json(&base.to_string()).map_err(|e| Error::from(e.to_string()))?;
+    Ok(format!("agg:{}:{}", kind.name(), filter.cache_key()))
+}
+
+/// Return the aggregate, refreshing it from the relay once it is older than `ttl`.
+/// The bool is true when the stored result was served without a refresh.
+pub async fn load(
+    env: &Env,
+    kind: AggregateKind,
+    base: &Value,
+    ttl: u64,
+    now: u64,
+    degraded: Option<&DegradedMode>,
+) -> Result<(AggregateState, bool)> {
+    let kv = env.kv("REST_GATEWAY_CACHE")?;
+    let key = state_key(kind, base)?;
+    let mut state = kv.get(&key).json::<AggregateState>().await?.unwrap_or_default();
+    if state.watermark.is_some() && now.saturating_sub(state.refreshed_at) < ttl {
+        return Ok((state, true));
+    }
+
+    // A backfill left by an earlier refresh is finished before newer events are
+    // fetched, so there is only ever one stretch still to count
+    let (since, mut until) = match &state.backfill {
+        Some(gap) => (gap.since, Some(gap.until)),
+        None => (state.watermark, None),
+    };
+
+    // Relays return newest first, so walk back with `until` until a page comes back short
+    let mut fetched = Vec::new();
+    let mut finished = false;
+    for _ in 0..MAX_PAGES {
+        let page = page_filter(base, since, until);
+        let filter = Filter::from_json(&page.to_string()).map_err(|e| Error::from(e.to_string()))?;
+        let events = crate::router::query_relay(env, &filter, degraded).await?;
+        let full = events.len() as u64 >= PAGE_LIMIT;
+        let page_oldest = oldest(&events);
+        fetched.extend(events);
+        match page_oldest {
+            Some(oldest) if full => until = Some(oldest),
+            _ => {
+                finished = true;
+                break;
+            }
+        }
+    }
+    // Folded in one go so older pages aren't cut off by the advancing watermark
+    if state.backfill.is_some() {
+        state.fold_backfill(kind, &fetched, finished);
+    } else if finished {
+        state.fold(kind, &fetched);
+    } else {
+        state.fold_cut_off(kind, &fetched);
+    }
+    state.refreshed_at = now;
+
+    kv.put(&key, serde_json::to_string(&state)?)?
+        .expiration_ttl(STATE_TTL_SECONDS)
+        .execute()
+        .await?;
+    Ok((state, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn reaction(id: &str, created_at: u64, content: &str) -> Value {
+        json!({"id": id, "kind": 7, "created_at": created_at, "content": content})
+    }
+
+    #[test]
+    fn test_fold_skips_already_counted_boundary_events() {
+        let mut state = AggregateState::default();
+        state.fold(AggregateKind::Reactions, &[reaction("a", 10, "+"), reaction("b", 20, "")]);
+        assert_eq!(state.count, 2);
+        assert_eq!(state.watermark, Some(20));
+        assert_eq!(state.boundary_ids, vec!["b"]);
+
+        // Refresh with since=20 returns b again plus newer events
+        let added = state.fold(
+            AggregateKind::Reactions,
+            &[reaction("b", 20, ""), reaction("c", 20, "🔥"), reaction("d", 30, "+")],
+        );
+        assert_eq!(added, 2);
+        assert_eq!(state.count, 4);
+        assert_eq!(state.reactions.get("+"), Some(&3));
+        assert_eq!(state.reactions.get("🔥"), Some(&1));
+        assert_eq!(state.watermark, Some(30));
+        assert_eq!(state.boundary_ids, vec!["d"]);
+    }
+
+    #[test]
+    fn test_fold_ignores_events_below_watermark() {
+        let mut state = AggregateState::default();
+        state.fold(AggregateKind::Count, &[reaction("a", 50, "+")]);
+        assert_eq!(state.fold(AggregateKind::Count, &[reaction("old", 40, "+")]), 0);
+        assert_eq!(state.count, 1);
+        assert_eq!(state.watermark, Some(50));
+    }
+
+    #[test]
+    fn test_cut_off_walk_is_backfilled() {
+        let mut state = AggregateState::default();
+        state.fold(AggregateKind::Count, &[reaction("a", 10, "+")]);
+
+        // A refresh that ran out of pages reached back to 50, not to the watermark at 10
+        state.fold_cut_off(AggregateKind::Count, &[reaction("z", 90, "+"), reaction("y", 50, "+")]);
+        assert_eq!(state.count, 3);
+        assert_eq!(state.watermark, Some(90));
+        let gap = state.backfill.clone().unwrap();
+        assert_eq!((gap.since, gap.until), (Some(10), 50));
+        assert_eq!((gap.since_ids, gap.until_ids), (vec!["a".to_string()], vec!["y".to_string()]));
+
+        // The next refresh walks 10..=50, seeing both bounds again, and gets part of it
+        state.fold_backfill(
+            AggregateKind::Count,
+            &[reaction("y", 50, "+"), reaction("x", 40, "+"), reaction("w", 30, "+")],
+            false,
+        );
+        assert_eq!(state.count, 5);
+        assert_eq!(state.backfill.as_ref().map(|g| g.until), Some(30));
+
+        // ...and the one after that the rest, which ends the backfill
+        state.fold_backfill(AggregateKind::Count, &[reaction("w", 30, "+"), reaction("v", 20, "+"), reaction("a", 10, "+")], true);
+        assert_eq!(state.count, 6);
+        assert!(state.backfill.is_none());
+        assert_eq!(state.watermark, Some(90));
+    }
+
+    #[test]
+    fn test_zap_amount_from_embedded_request() {
+        let request = json!({"kind": 9734, "tags": [["amount", "21000"], ["e", "x"]]});
+        let receipt = json!({
+            "id": "z",
+            "kind": 9735,
+            "created_at": 1,
+            "tags": [["bolt11", "lnbc..."], ["description", request.to_string()]]
+        });
+        assert_eq!(zap_amount_msats(&receipt), 21000);
+
+        let mut state = AggregateState::default();
+        state.fold(AggregateKind::Zaps, &[receipt]);
+        assert_eq!(state.zap_msats, 21000);
+        assert_eq!(zap_amount_msats(&json!({"tags": []})), 0);
+    }
+
+    #[test]
+    fn test_page_filter() {
+        let base = target_filter(AggregateKind::Reactions, "abc");
+        let page = page_filter(&base, Some(100), Some(200));
+        assert_eq!(page, json!({"kinds": [7], "#e": ["abc"], "limit": 500, "since": 100, "until": 200}));
+        assert_eq!(page_filter(&base, None, None).get("since"), None);
+
+        let bounded = json!({"kinds": [1], "since": 150, "until": 180, "limit": 10});
+        assert_eq!(
+            page_filter(&bounded, Some(100), Some(200)),
+            json!({"kinds": [1], "limit": 500, "since": 150, "until": 180})
+        );
+    }
+}
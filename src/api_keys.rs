@@ -0,0 +1,325 @@
+// ABOUTME: Tiered, scoped API keys (free/pro; read/publish) and the per-tier limits the router enforces
+// ABOUTME: Keys are stored in KV under their SHA-256 hash; requests without a key get the free tier
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use worker::kv::KvStore;
+use worker::*;
+
+/// KV prefix of stored key records, followed by the key id
+pub const KEY_PREFIX: &str = "apikey:";
+
+/// Request header carrying the key
+pub const HEADER: &str = "X-Api-Key";
+
+/// Prefix of every issued key, which tells them apart from other bearer tokens
+const KEY_TOKEN_PREFIX: &str = "dgk_";
+
+/// KV prefix of per-key daily export usage, followed by `{key id}:{day}`
+const EXPORT_USAGE_PREFIX: &str = "exportusage:";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Tier {
+    #[default]
+    Free,
+    Pro,
+}
+
+/// What a tier is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct TierLimits {
+    /// Largest `limit` a query may ask for; filters without one, or with a larger one, are capped to it
+    pub max_limit: usize,
+    /// Serialized events per response; past this the result is cut short and marked incomplete
+    pub max_response_bytes: usize,
+    /// Whether feeds may attach author profiles
+    pub hydration: bool,
+    /// Live streams open at once
+    pub sse_concurrency: u32,
+    /// Access to bulk export
+    pub export: bool,
+    /// Rows bulk export may return per key per UTC day
+    pub export_rows_per_day: u64,
+}
+
+impl TierLimits {
+    /// These limits with `max_limit` lowered to the operator's cap
+    pub fn capped(self, max_query_limit: usize) -> Self {
+        Self {
+            max_limit: self.max_limit.min(max_query_limit),
+            ..self
+        }
+    }
+}
+
+/// Most events a query may return on any tier, unless `MAX_QUERY_LIMIT` says otherwise
+pub const DEFAULT_MAX_QUERY_LIMIT: usize = 500;
+
+/// Operator's cap on `limit` (`MAX_QUERY_LIMIT`), applied on top of every tier's own
+pub fn max_query_limit(env: &Env) -> usize {
+    env.var("MAX_QUERY_LIMIT")
+        .ok()
+        .and_then(|v| v.to_string().parse().ok())
+        .filter(|&max| max > 0)
+        .unwrap_or(DEFAULT_MAX_QUERY_LIMIT)
+}
+
+/// Ceiling on `Filter::cost()` for client filters; a follow feed over a few hundred
+/// authors stays well under it
+pub const DEFAULT_MAX_QUERY_COST: u64 = 1000;
+
+/// Operator's cap on filter cost (`MAX_QUERY_COST`)
+pub fn max_query_cost(env: &Env) -> u64 {
+    env.var("MAX_QUERY_COST")
+        .ok()
+        .and_then(|v| v.to_string().parse().ok())
+        .filter(|&max| max > 0)
+        .unwrap_or(DEFAULT_MAX_QUERY_COST)
+}
+
+impl Tier {
+    pub fn limits(&self) -> TierLimits {
+        match self {
+            Tier::Free => TierLimits {
+                max_limit: 500,
+                max_response_bytes: 2 * 1024 * 1024,
+                hydration: false,
+                sse_concurrency: 1,
+                export: false,
+                export_rows_per_day: 0,
+            },
+            Tier::Pro => TierLimits {
+                max_limit: 5000,
+                max_response_bytes: 16 * 1024 * 1024,
+                hydration: true,
+                sse_concurrency: 10,
+                export: true,
+                export_rows_per_day: 1_000_000,
+            },
+        }
+    }
+}
+
+/// What a key may be used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Reads, served at the key's tier
+    Read,
+    /// `POST /publish` without a NIP-98 event
+    Publish,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Publish => "publish",
+        }
+    }
+}
+
+/// Keys issued before scopes existed were read keys
+pub fn default_scopes() -> Vec<Scope> {
+    vec![Scope::Read]
+}
+
+/// Stored key record. The key itself is only shown once, when it is created.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub tier: Tier,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<Scope>,
+    pub created_at: u64,
+}
+
+impl ApiKey {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+/// The caller a request is served for
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Caller {
+    /// Key record, if the request presented one
+    pub key: Option<ApiKey>,
+}
+
+impl Caller {
+    pub fn tier(&self) -> Tier {
+        self.key.as_ref().map(|k| k.tier).unwrap_or_default()
+    }
+
+    pub fn limits(&self) -> TierLimits {
+        self.tier().limits()
+    }
+
+    /// Whether the request presented a key with this scope
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.key.as_ref().is_some_and(|k| k.allows(scope))
+    }
+}
+
+pub fn new_key() -> String {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).expect("RNG available in Workers");
+    format!("{}{}", KEY_TOKEN_PREFIX, hex::encode(bytes))
+}
+
+/// Id a key is stored and managed under, so KV never holds the key itself
+pub fn key_id(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+pub async fn get(kv: &KvStore, id: &str) -> Result<Option<ApiKey>> {
+    Ok(kv.get(&format!("{}{}", KEY_PREFIX, id)).json::<ApiKey>().await?)
+}
+
+pub async fn put(kv: &KvStore, key: &ApiKey) -> Result<()> {
+    kv.put(&format!("{}{}", KEY_PREFIX, key.id), serde_json::to_string(key)?)?
+        .execute()
+        .await?;
+    Ok(())
+}
+
+pub async fn delete(kv: &KvStore, id: &str) -> Result<()> {
+    kv.delete(&format!("{}{}", KEY_PREFIX, id)).await?;
+    Ok(())
+}
+
+fn export_usage_key(id: &str, day: u64) -> String {
+    format!("{}{}:{}", EXPORT_USAGE_PREFIX, id, day)
+}
+
+/// Export rows a key has used on a day (days since the epoch)
+pub async fn export_used(kv: &KvStore, id: &str, day: u64) -> Result<u64> {
+    let used = kv.get(&export_usage_key(id, day)).text().await?;
+    Ok(used.and_then(|u| u.parse().ok()).unwrap_or(0))
+}
+
+/// Add to a key's export usage. KV isn't atomic, so concurrent exports can
+/// overshoot the quota by up to a page each.
+pub async fn record_export(kv: &KvStore, id: &str, day: u64, rows: u64) -> Result<()> {
+    let used = export_used(kv, id, day).await?;
+    kv.put(&export_usage_key(id, day), (used + rows).to_string())?
+        .expiration_ttl(2 * 86400)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Whether a bearer token is one of our keys rather than, say, a JWT
+pub fn is_key_token(token: &str) -> bool {
+    token.starts_with(KEY_TOKEN_PREFIX)
+}
+
+/// The key a request presents, in `X-Api-Key` or as an `Authorization: Bearer` token
+pub fn request_key(api_key_header: Option<&str>, authorization: Option<&str>) -> Option<String> {
+    let bearer = authorization
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| is_key_token(token));
+    api_key_header.map(str::trim).or(bearer).map(String::from)
+}
+
+/// The caller behind a request. `Ok(None)` means a key was given but isn't known.
+pub async fn resolve(req: &Request, env: &Env) -> Result<Option<Caller>> {
+    let headers = req.headers();
+    let Some(key) = request_key(headers.get(HEADER)?.as_deref(), headers.get("Authorization")?.as_deref()) else {
+        return Ok(Some(Caller::default()));
+    };
+    let record = get(&env.kv("REST_GATEWAY_CACHE")?, &key_id(&key)).await?;
+    Ok(record.map(|key| Caller { key: Some(key) }))
+}
+
+/// Drop events from the end until the rest serialize within `max_bytes`.
+/// Returns true if any were dropped.
+pub fn fit_to_budget(events: &mut Vec<Value>, max_bytes: usize) -> bool {
+    let mut total = 0;
+    let keep = events
+        .iter()
+        .take_while(|event| {
+            total += event.to_string().len();
+            total <= max_bytes
+        })
+        .count();
+    let trimmed = keep < events.len();
+    events.truncate(keep);
+    trimmed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_tier_limits() {
+        let free = Tier::Free.limits();
+        let pro = Tier::Pro.limits();
+        assert!(free.max_limit < pro.max_limit);
+        assert!(free.max_response_bytes < pro.max_response_bytes);
+        assert!(!free.hydration && pro.hydration);
+        assert!(!free.export && pro.export);
+        assert_eq!(free.export_rows_per_day, 0);
+        assert_eq!(Caller::default().tier(), Tier::Free);
+        assert_eq!(serde_json::to_value(Tier::Pro).unwrap(), json!("pro"));
+        assert_eq!(pro.capped(1000).max_limit, 1000);
+        assert_eq!(free.capped(1000), free);
+    }
+
+    #[test]
+    fn test_key_id() {
+        let key = new_key();
+        assert!(key.starts_with("dgk_"));
+        assert_eq!(key_id(&key), key_id(&key));
+        assert_ne!(key_id(&key), key_id(&new_key()));
+        assert_eq!(key_id(&key).len(), 64);
+    }
+
+    #[test]
+    fn test_request_key() {
+        assert_eq!(request_key(Some(" dgk_a "), None), Some("dgk_a".to_string()));
+        assert_eq!(request_key(None, Some("Bearer dgk_b")), Some("dgk_b".to_string()));
+        assert_eq!(request_key(Some("dgk_a"), Some("Bearer dgk_b")), Some("dgk_a".to_string()));
+        // Other bearer tokens and NIP-98 auth aren't keys
+        assert_eq!(request_key(None, Some("Bearer admin-secret")), None);
+        assert_eq!(request_key(None, Some("Nostr eyJ...")), None);
+        assert_eq!(request_key(None, None), None);
+    }
+
+    #[test]
+    fn test_scopes() {
+        let record: ApiKey =
+            serde_json::from_value(json!({"id": "x", "name": "old", "tier": "pro", "created_at": 1})).unwrap();
+        assert_eq!(record.scopes, vec![Scope::Read]);
+        let caller = Caller {
+            key: Some(ApiKey {
+                scopes: vec![Scope::Publish],
+                ..record
+            }),
+        };
+        assert!(caller.allows(Scope::Publish));
+        assert!(!caller.allows(Scope::Read));
+        assert!(!Caller::default().allows(Scope::Read));
+    }
+
+    #[test]
+    fn test_fit_to_budget() {
+        let event = json!({"id": "a", "content": "x".repeat(100)});
+        let size = event.to_string().len();
+        let mut events = vec![event.clone(), event.clone(), event];
+        assert!(!fit_to_budget(&mut events, size * 3));
+        assert_eq!(events.len(), 3);
+        assert!(fit_to_budget(&mut events, size * 2 + 1));
+        assert_eq!(events.len(), 2);
+        assert!(fit_to_budget(&mut events, size - 1));
+        assert!(events.is_empty());
+    }
+}
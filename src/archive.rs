@@ -0,0 +1,500 @@
+// ABOUTME: Optional D1-backed event archive for relay-independent reads
+// ABOUTME: Translates Nostr filters into SQL and stores events seen from the relay
+
+use crate::filter::{is_hex64, is_hex_prefix, Filter};
+use serde::Deserialize;
+use wasm_bindgen::JsValue;
+use worker::d1::D1Database;
+use worker::*;
+
+/// Maximum rows returned when a filter has no limit
+const DEFAULT_LIMIT: usize = 500;
+
+/// Largest page of a follower export
+pub const MAX_FOLLOWERS_PAGE: usize = 5000;
+
+pub struct Archive {
+    db: D1Database,
+}
+
+/// SQL parameter, converted to a JS value when binding
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SqlValue {
+    Text(String),
+    Int(i64),
+}
+
+impl From<&SqlValue> for JsValue {
+    fn from(value: &SqlValue) -> Self {
+        match value {
+            SqlValue::Text(s) => JsValue::from_str(s),
+            SqlValue::Int(i) => JsValue::from_f64(*i as f64),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ArchiveRow {
+    raw: String,
+}
+
+#[derive(Deserialize)]
+struct PubkeyRow {
+    pubkey: String,
+}
+
+impl Archive {
+    /// Open the archive if the `ARCHIVE` D1 binding is configured
+    pub fn from_env(env: &Env) -> Option<Self> {
+        env.d1("ARCHIVE").ok().map(|db| Self { db })
+    }
+
+    /// Answer a filter from the archive only - no relay round-trip
+    pub async fn query(&self, filter: &Filter) -> Result<Vec<serde_json::Value>> {
+        let (sql, params) = build_select(filter);
+        let binds: Vec<JsValue> = params.iter().map(JsValue::from).collect();
+        let result = self.db.prepare(sql).bind(&binds)?.all().await?;
+        let rows: Vec<ArchiveRow> = result.results()?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| serde_json::from_str(&row.raw).ok())
+            .collect())
+    }
+
+    /// Events whose `e` or `q` tags point at `event_id`, newest first
+    pub async fn referenced_by(&self, event_id: &str, until: Option<u64>, limit: usize) -> Result<Vec<serde_json::Value>> {
+        let (sql, params) = build_referenced_by(event_id, until, limit);
+        let binds: Vec<JsValue> = params.iter().map(JsValue::from).collect();
+        let result = self.db.prepare(sql).bind(&binds)?.all().await?;
+        let rows: Vec<ArchiveRow> = result.results()?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| serde_json::from_str(&row.raw).ok())
+            .collect())
+    }
+
+    /// Pubkeys whose newest archived contact list follows `pubkey`, in pubkey order
+    /// after the `after` cursor
+    pub async fn followers(&self, pubkey: &str, after: Option<&str>, limit: usize) -> Result<Vec<String>> {
+        let (sql, params) = build_followers(pubkey, after, limit);
+        let binds: Vec<JsValue> = params.iter().map(JsValue::from).collect();
+        let result = self.db.prepare(sql).bind(&binds)?.all().await?;
+        let rows: Vec<PubkeyRow> = result.results()?;
+        Ok(rows.into_iter().map(|row| row.pubkey).collect())
+    }
+
+    /// Remove an event its author deleted, with its tags. Rows by anyone else stay.
+    pub async fn delete_event(&self, event_id: &str, pubkey: &str) -> Result<()> {
+        let (id, pubkey) = (JsValue::from_str(event_id), JsValue::from_str(pubkey));
+        let statements = vec![
+            self.db
+                .prepare("DELETE FROM event_tags WHERE event_id IN (SELECT id FROM events WHERE id = ?1 AND pubkey = ?2)")
+                .bind(&[id.clone(), pubkey.clone()])?,
+            self.db
+                .prepare("DELETE FROM events WHERE id = ?1 AND pubkey = ?2")
+                .bind(&[id, pubkey])?,
+        ];
+        self.db.batch(statements).await?;
+        Ok(())
+    }
+
+    /// Upsert events by id, along with their tags for tag queries
+    pub async fn store_events(&self, events: &[serde_json::Value]) -> Result<()> {
+        let mut statements = Vec::new();
+        for event in events {
+            let Some(row) = EventRow::from_event(event) else {
+                continue;
+            };
+            statements.push(
+                self.db
+                    .prepare(
+                        "INSERT OR REPLACE INTO events (id, pubkey, kind, created_at, raw) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    )
+                    .bind(&[
+                        JsValue::from_str(&row.id),
+                        JsValue::from_str(&row.pubkey),
+                        JsValue::from_f64(row.kind as f64),
+                        JsValue::from_f64(row.created_at as f64),
+                        JsValue::from_str(&event.to_string()),
+                    ])?,
+            );
+            for (name, value) in &row.tags {
+                statements.push(
+                    self.db
+                        .prepare("INSERT OR IGNORE INTO event_tags (event_id, name, value) VALUES (?1, ?2, ?3)")
+                        .bind(&[
+                            JsValue::from_str(&row.id),
+                            JsValue::from_str(name),
+                            JsValue::from_str(value),
+                        ])?,
+                );
+            }
+        }
+        if !statements.is_empty() {
+            self.db.batch(statements).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Indexed columns extracted from a raw event
+#[derive(Debug, PartialEq)]
+pub(crate) struct EventRow {
+    pub id: String,
+    pub pubkey: String,
+    pub kind: u64,
+    pub created_at: u64,
+    pub tags: Vec<(String, String)>,
+}
+
+impl EventRow {
+    pub fn from_event(event: &serde_json::Value) -> Option<Self> {
+        let tags = event
+            .get("tags")
+            .and_then(|t| t.as_array())
+            .map(|tags| {
+                tags.iter()
+                    .filter_map(|tag| {
+                        let name = tag.get(0)?.as_str()?;
+                        let value = tag.get(1)?.as_str()?;
+                        // Index every tag, not just single-letter ones - Divine
+                        // clients filter on custom tags like #platform
+                        Some((name.to_string(), value.to_string()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Some(Self {
+            id: event.get("id")?.as_str()?.to_string(),
+            pubkey: event.get("pubkey")?.as_str()?.to_string(),
+            kind: event.get("kind")?.as_u64()?,
+            created_at: event.get("created_at")?.as_u64()?,
+            tags,
+        })
+    }
+}
+
+/// Whether cache misses try the archive before the relay (`ARCHIVE_READ_FIRST`,
+/// on unless set to `false`)
+pub fn reads_first(env: &Env) -> bool {
+    env.var("ARCHIVE_READ_FIRST").map(|v| v.to_string() != "false").unwrap_or(true)
+}
+
+/// How long downstream caches may keep a provisional archive answer: long enough
+/// to absorb a burst, short enough that the relay's answer soon replaces it
+pub const PROVISIONAL_TTL_SECONDS: u64 = 10;
+
+/// How far an archive answer can stand in for the relay's
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadFirst {
+    /// Every event the filter names by id: events never change, so it's final
+    Complete,
+    /// What the archive has for an author/kind lookup. Newer events may be on the
+    /// relay, so it's served while the relay is asked in the background.
+    Provisional,
+    /// Ask the relay
+    Relay,
+}
+
+/// Judge an archive answer to a filter. Only id lookups and author/kind lookups
+/// are tried; NIP-50 searches aren't indexed.
+pub fn read_first(filter: &Filter, events: &[serde_json::Value]) -> ReadFirst {
+    if events.is_empty() || filter.search().is_some() {
+        return ReadFirst::Relay;
+    }
+    if let Some(ids) = filter.ids() {
+        let found = |id: &String| events.iter().any(|e| e.get("id").and_then(|v| v.as_str()) == Some(id.as_str()));
+        return if ids.iter().all(|id| is_hex64(id) && found(&id.to_ascii_lowercase())) {
+            ReadFirst::Complete
+        } else {
+            ReadFirst::Relay
+        };
+    }
+    if filter.authors().is_some() && filter.kinds().is_some() {
+        return ReadFirst::Provisional;
+    }
+    ReadFirst::Relay
+}
+
+/// Whether a filter is worth asking the archive for before the relay
+pub fn is_lookup(filter: &Filter) -> bool {
+    filter.search().is_none() && (filter.ids().is_some() || (filter.authors().is_some() && filter.kinds().is_some()))
+}
+
+/// The bounds of the lowercase hex values starting with `prefix`: the prefix itself,
+/// up to the prefix with its last character bumped (`ab` covers `ab` to `ac`)
+fn prefix_range(prefix: &str) -> (String, String) {
+    let start = prefix.to_ascii_lowercase();
+    let mut end = start.clone();
+    let last = end.pop().map_or(0, |c| c as u32 + 1);
+    end.push(char::from_u32(last).unwrap_or(char::MAX));
+    (start, end)
+}
+
+/// Build the SELECT for a filter. Kept free of D1 types so it can be unit tested.
+pub(crate) fn build_select(filter: &Filter) -> (String, Vec<SqlValue>) {
+    let mut clauses = Vec::new();
+    let mut params = Vec::new();
+
+    fn push_in(clauses: &mut Vec<String>, params: &mut Vec<SqlValue>, column: &str, values: Vec<SqlValue>) {
+        if values.is_empty() {
+            // An empty list matches nothing, same as on the relay
+            clauses.push("0".to_string());
+            return;
+        }
+        let start = params.len() + 1;
+        let placeholders: Vec<String> = (start..start + values.len()).map(|i| format!("?{}", i)).collect();
+        clauses.push(format!("{} IN ({})", column, placeholders.join(", ")));
+        params.extend(values);
+    }
+
+    // Full values are looked up exactly, NIP-01 prefixes as a range of the index
+    fn push_hex(clauses: &mut Vec<String>, params: &mut Vec<SqlValue>, column: &str, values: &[String]) {
+        let (full, prefixes): (Vec<&String>, Vec<&String>) =
+            values.iter().partition(|v| is_hex64(v) || !is_hex_prefix(v));
+        if prefixes.is_empty() {
+            push_in(clauses, params, column, full.into_iter().cloned().map(SqlValue::Text).collect());
+            return;
+        }
+        let mut alternatives = Vec::new();
+        if !full.is_empty() {
+            push_in(&mut alternatives, params, column, full.into_iter().cloned().map(SqlValue::Text).collect());
+        }
+        for prefix in prefixes {
+            let (start, end) = prefix_range(prefix);
+            params.push(SqlValue::Text(start));
+            params.push(SqlValue::Text(end));
+            alternatives.push(format!("({} >= ?{} AND {} < ?{})", column, params.len() - 1, column, params.len()));
+        }
+        clauses.push(format!("({})", alternatives.join(" OR ")));
+    }
+
+    if let Some(ids) = filter.ids() {
+        push_hex(&mut clauses, &mut params, "id", ids);
+    }
+    if let Some(authors) = filter.authors() {
+        push_hex(&mut clauses, &mut params, "pubkey", authors);
+    }
+    if let Some(kinds) = filter.kinds() {
+        push_in(&mut clauses, &mut params, "kind", kinds.iter().map(|k| SqlValue::Int(*k as i64)).collect());
+    }
+    if let Some(since) = filter.since() {
+        params.push(SqlValue::Int(since as i64));
+        clauses.push(format!("created_at >= ?{}", params.len()));
+    }
+    if let Some(until) = filter.until() {
+        params.push(SqlValue::Int(until as i64));
+        clauses.push(format!("created_at <= ?{}", params.len()));
+    }
+
+    let mut tags = filter.tag_filters();
+    tags.sort();
+    for (name, values) in tags {
+        params.push(SqlValue::Text(name));
+        let name_param = params.len();
+        let mut value_clause = Vec::new();
+        push_in(&mut value_clause, &mut params, "value", values.into_iter().map(SqlValue::Text).collect());
+        clauses.push(format!(
+            "id IN (SELECT event_id FROM event_tags WHERE name = ?{} AND {})",
+            name_param, value_clause[0]
+        ));
+    }
+
+    let limit = filter.limit().unwrap_or(DEFAULT_LIMIT).min(DEFAULT_LIMIT);
+    params.push(SqlValue::Int(limit as i64));
+
+    let where_clause = if clauses.is_empty() {
+        String::new()
+    } else {
+        format!(" WHERE {}", clauses.join(" AND "))
+    };
+    let sql = format!(
+        "SELECT raw FROM events{} ORDER BY created_at DESC LIMIT ?{}",
+        where_clause,
+        params.len()
+    );
+    (sql, params)
+}
+
+/// Inverse tag lookup behind `/event/{id}/referenced-by`
+pub(crate) fn build_referenced_by(event_id: &str, until: Option<u64>, limit: usize) -> (String, Vec<SqlValue>) {
+    let mut params = vec![SqlValue::Text(event_id.to_string())];
+    let mut sql = "SELECT raw FROM events WHERE id IN \
+                   (SELECT event_id FROM event_tags WHERE name IN ('e', 'q') AND value = ?1)"
+        .to_string();
+    if let Some(until) = until {
+        params.push(SqlValue::Int(until as i64));
+        sql.push_str(&format!(" AND created_at <= ?{}", params.len()));
+    }
+    params.push(SqlValue::Int(limit.min(DEFAULT_LIMIT) as i64));
+    sql.push_str(&format!(" ORDER BY created_at DESC LIMIT ?{}", params.len()));
+    (sql, params)
+}
+
+/// Follower lookup behind `/profile/{pubkey}/followers/export`. Contact lists are
+/// replaceable but every version stays archived, so only each author's newest counts.
+/// Paging is by pubkey, which stays stable while new lists arrive.
+pub(crate) fn build_followers(pubkey: &str, after: Option<&str>, limit: usize) -> (String, Vec<SqlValue>) {
+    let mut params = vec![SqlValue::Text(pubkey.to_string())];
+    let mut sql = "SELECT DISTINCT e.pubkey FROM event_tags t JOIN events e ON e.id = t.event_id \
+                   WHERE t.name = 'p' AND t.value = ?1 AND e.kind = 3 \
+                   AND e.created_at = (SELECT MAX(created_at) FROM events l WHERE l.pubkey = e.pubkey AND l.kind = 3)"
+        .to_string();
+    if let Some(after) = after {
+        params.push(SqlValue::Text(after.to_string()));
+        sql.push_str(&format!(" AND e.pubkey > ?{}", params.len()));
+    }
+    params.push(SqlValue::Int(limit.min(MAX_FOLLOWERS_PAGE) as i64));
+    sql.push_str(&format!(" ORDER BY e.pubkey LIMIT ?{}", params.len()));
+    (sql, params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_select_empty_filter() {
+        let filter = Filter::from_json("{}").unwrap();
+        let (sql, params) = build_select(&filter);
+        assert_eq!(sql, "SELECT raw FROM events ORDER BY created_at DESC LIMIT ?1");
+        assert_eq!(params, vec![SqlValue::Int(500)]);
+    }
+
+    #[test]
+    fn test_build_select_all_fields() {
+        let filter = Filter::from_json(&format!(
+            r#"{{"ids":["{}","{}"],"authors":["{}"],"kinds":[1],"since":10,"until":20,"limit":5}}"#,
+            "a".repeat(64),
+            "b".repeat(64),
+            "c".repeat(64)
+        ))
+        .unwrap();
+        let (sql, params) = build_select(&filter);
+        assert_eq!(
+            sql,
+            "SELECT raw FROM events WHERE id IN (?1, ?2) AND pubkey IN (?3) AND kind IN (?4) \
+             AND created_at >= ?5 AND created_at <= ?6 ORDER BY created_at DESC LIMIT ?7"
+        );
+        assert_eq!(params.len(), 7);
+        assert_eq!(params[6], SqlValue::Int(5));
+    }
+
+    #[test]
+    fn test_build_select_prefixes() {
+        let full = "a".repeat(64);
+        let filter = Filter::from_json(&format!(r#"{{"ids":["{}","B1"],"authors":["c9"]}}"#, full)).unwrap();
+        let (sql, params) = build_select(&filter);
+        assert_eq!(
+            sql,
+            "SELECT raw FROM events WHERE (id IN (?1) OR (id >= ?2 AND id < ?3)) AND ((pubkey >= ?4 AND pubkey < ?5)) \
+             ORDER BY created_at DESC LIMIT ?6"
+        );
+        assert_eq!(params[0], SqlValue::Text(full));
+        assert_eq!(params[1..3], [SqlValue::Text("b1".to_string()), SqlValue::Text("b2".to_string())]);
+        // Past `9` comes `:`, which sorts before `a`, so `c9` doesn't reach `ca`
+        assert_eq!(params[3..5], [SqlValue::Text("c9".to_string()), SqlValue::Text("c:".to_string())]);
+    }
+
+    #[test]
+    fn test_build_select_tags() {
+        let filter = Filter::from_json(r##"{"kinds":[34236],"#platform":["vine"]}"##).unwrap();
+        let (sql, params) = build_select(&filter);
+        assert!(sql.contains("id IN (SELECT event_id FROM event_tags WHERE name = ?2 AND value IN (?3))"));
+        assert_eq!(params[1], SqlValue::Text("platform".to_string()));
+        assert_eq!(params[2], SqlValue::Text("vine".to_string()));
+    }
+
+    #[test]
+    fn test_build_select_empty_list_matches_nothing() {
+        let filter = Filter::from_json(r#"{"ids":[]}"#).unwrap();
+        let (sql, _) = build_select(&filter);
+        assert!(sql.contains("WHERE 0 ORDER BY"));
+    }
+
+    #[test]
+    fn test_build_select_caps_limit() {
+        let filter = Filter::from_json(r#"{"limit":100000}"#).unwrap();
+        let (_, params) = build_select(&filter);
+        assert_eq!(params.last(), Some(&SqlValue::Int(500)));
+    }
+
+    #[test]
+    fn test_build_referenced_by() {
+        let (sql, params) = build_referenced_by("abc", Some(20), 1000);
+        assert_eq!(
+            sql,
+            "SELECT raw FROM events WHERE id IN (SELECT event_id FROM event_tags WHERE name IN ('e', 'q') \
+             AND value = ?1) AND created_at <= ?2 ORDER BY created_at DESC LIMIT ?3"
+        );
+        assert_eq!(
+            params,
+            vec![SqlValue::Text("abc".to_string()), SqlValue::Int(20), SqlValue::Int(500)]
+        );
+    }
+
+    #[test]
+    fn test_event_row_extracts_tags() {
+        let event = serde_json::json!({
+            "id": "abc",
+            "pubkey": "def",
+            "kind": 1,
+            "created_at": 1700000000,
+            "tags": [["e", "x"], ["platform", "vine"], ["t", "nostr"]],
+            "content": "",
+            "sig": ""
+        });
+        let row = EventRow::from_event(&event).unwrap();
+        assert_eq!(row.id, "abc");
+        assert_eq!(row.kind, 1);
+        assert_eq!(
+            row.tags,
+            vec![
+                ("e".to_string(), "x".to_string()),
+                ("platform".to_string(), "vine".to_string()),
+                ("t".to_string(), "nostr".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_event_row_rejects_incomplete_event() {
+        assert!(EventRow::from_event(&serde_json::json!({"id": "abc"})).is_none());
+    }
+
+    #[test]
+    fn test_read_first() {
+        let id = "ab".repeat(32);
+        let event = serde_json::json!({"id": id, "pubkey": "cd".repeat(32), "kind": 1, "created_at": 1});
+        let by_id = Filter::note(&id);
+        assert!(is_lookup(&by_id));
+        assert_eq!(read_first(&by_id, std::slice::from_ref(&event)), ReadFirst::Complete);
+        assert_eq!(read_first(&by_id, &[]), ReadFirst::Relay);
+
+        // One of two ids missing: the relay may have it
+        let both = Filter::notes(&[id.clone(), "ef".repeat(32)]);
+        assert_eq!(read_first(&both, std::slice::from_ref(&event)), ReadFirst::Relay);
+
+        let by_author = Filter::from_json(&format!(r#"{{"authors":["{}"],"kinds":[1]}}"#, "cd".repeat(32))).unwrap();
+        assert_eq!(read_first(&by_author, std::slice::from_ref(&event)), ReadFirst::Provisional);
+
+        let search = Filter::from_json(r#"{"kinds":[1],"authors":["cd"],"search":"x"}"#).unwrap();
+        assert!(!is_lookup(&search));
+        assert!(!is_lookup(&Filter::from_json(r#"{"kinds":[1]}"#).unwrap()));
+    }
+
+    #[test]
+    fn test_build_followers() {
+        let (sql, params) = build_followers("pk", None, 100);
+        assert!(sql.contains("t.name = 'p' AND t.value = ?1 AND e.kind = 3"));
+        assert!(sql.ends_with("ORDER BY e.pubkey LIMIT ?2"));
+        assert_eq!(params, vec![SqlValue::Text("pk".to_string()), SqlValue::Int(100)]);
+
+        let (sql, params) = build_followers("pk", Some("abc"), 100_000);
+        assert!(sql.contains("AND e.pubkey > ?2 ORDER BY e.pubkey LIMIT ?3"));
+        assert_eq!(params[1], SqlValue::Text("abc".to_string()));
+        assert_eq!(params[2], SqlValue::Int(MAX_FOLLOWERS_PAGE as i64));
+    }
+
+}
@@ -1,10 +1,52 @@
-// ABOUTME: NIP-98 HTTP authentication validation
-// ABOUTME: Validates kind 27235 auth events for authenticated endpoints
+// ABOUTME: Request authentication behind an AuthProvider trait: NIP-98 events, API keys and service JWTs
+// ABOUTME: The router picks providers per route and combines them with AnyOf; the clock is passed in
 
 use base64::{engine::general_purpose::STANDARD, Engine};
-use k256::schnorr::{signature::Verifier, Signature, VerifyingKey};
+use futures_util::future::{self, FutureExt, LocalBoxFuture};
+use k256::schnorr::{Signature, VerifyingKey};
 use serde::Deserialize;
+use crate::api_keys::{self, ApiKey, Scope};
+use crate::jwt::{JwtConfig, JwtError};
+use crate::turnstile::TurnstileError;
 use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use worker::Url;
+
+/// How far an auth event's `created_at` may be from the gateway's clock
+const MAX_SKEW_SECONDS: u64 = 60;
+
+/// Most signatures an isolate remembers; past this, expired ones are dropped and
+/// then the whole set if need be
+const VERIFIED_CAPACITY: usize = 4096;
+
+thread_local! {
+    /// Auth event signatures this isolate has verified. A KV round trip would cost
+    /// more than the Schnorr check it saves, so this only lives in memory.
+    static VERIFIED: RefCell<VerifiedSignatures> = RefCell::new(VerifiedSignatures::default());
+}
+
+/// Verified `{id}:{sig}` pairs, each kept until its event leaves the validity window
+#[derive(Debug, Default)]
+struct VerifiedSignatures {
+    expiry: HashMap<String, u64>,
+}
+
+impl VerifiedSignatures {
+    fn contains(&self, key: &str, now: u64) -> bool {
+        self.expiry.get(key).is_some_and(|expires| *expires >= now)
+    }
+
+    fn insert(&mut self, key: String, expires: u64, now: u64) {
+        if self.expiry.len() >= VERIFIED_CAPACITY {
+            self.expiry.retain(|_, e| *e >= now);
+            if self.expiry.len() >= VERIFIED_CAPACITY {
+                self.expiry.clear();
+            }
+        }
+        self.expiry.insert(key, expires);
+    }
+}
 
 #[derive(Debug)]
 pub struct AuthResult {
@@ -22,6 +64,12 @@ pub enum AuthError {
     InvalidUrl,
     Expired,
     InvalidSignature,
+    Jwt(JwtError),
+    Turnstile(TurnstileError),
+    UnknownApiKey,
+    InsufficientScope(Scope),
+    UnknownSession,
+    SessionScope(Scope),
 }
 
 impl std::fmt::Display for AuthError {
@@ -36,6 +84,12 @@ impl std::fmt::Display for AuthError {
             Self::InvalidUrl => write!(f, "url tag does not match request"),
             Self::Expired => write!(f, "auth event expired"),
             Self::InvalidSignature => write!(f, "invalid event signature"),
+            Self::Jwt(e) => write!(f, "{}", e),
+            Self::Turnstile(e) => write!(f, "{}", e),
+            Self::UnknownApiKey => write!(f, "unknown API key"),
+            Self::InsufficientScope(scope) => write!(f, "API key lacks the {} scope", scope.as_str()),
+            Self::UnknownSession => write!(f, "unknown or expired session token"),
+            Self::SessionScope(scope) => write!(f, "session lacks the {} scope", scope.as_str()),
         }
     }
 }
@@ -52,10 +106,155 @@ pub(crate) struct AuthEvent {
     sig: String,
 }
 
+/// Who an authenticated request acts for
+#[derive(Debug, Clone, PartialEq)]
+pub enum Principal {
+    /// Pubkey of a NIP-98 auth event
+    Nostr(String),
+    /// `sub` of a service JWT
+    Service(String),
+    /// Name of an API key
+    ApiKey(String),
+    /// Anonymous browser that passed a Turnstile challenge
+    Visitor,
+}
+
+impl std::fmt::Display for Principal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Nostr(pubkey) => write!(f, "nostr:{}", pubkey),
+            Self::Service(subject) => write!(f, "service:{}", subject),
+            Self::ApiKey(name) => write!(f, "key:{}", name),
+            Self::Visitor => write!(f, "turnstile visitor"),
+        }
+    }
+}
+
+/// The parts of a request that authentication looks at
+#[derive(Debug, Clone, Copy)]
+pub struct AuthRequest<'a> {
+    pub method: &'a str,
+    pub url: &'a str,
+    pub authorization: Option<&'a str>,
+    /// `X-Api-Key` header
+    pub api_key: Option<&'a str>,
+    /// Unix seconds
+    pub now: u64,
+}
+
+impl<'a> AuthRequest<'a> {
+    pub(crate) fn bearer(&self) -> Option<&'a str> {
+        self.authorization.and_then(|h| h.strip_prefix("Bearer ")).map(str::trim)
+    }
+}
+
+/// One way of proving who a request acts for
+pub trait AuthProvider {
+    /// Whether the request carries this provider's kind of credential
+    fn presented(&self, req: &AuthRequest) -> bool;
+
+    fn authenticate<'a>(&'a self, req: &'a AuthRequest<'a>) -> LocalBoxFuture<'a, Result<Principal, AuthError>>;
+}
+
+/// NIP-98 auth event in `Authorization: Nostr ...`
+pub struct Nip98;
+
+impl AuthProvider for Nip98 {
+    fn presented(&self, req: &AuthRequest) -> bool {
+        req.authorization.is_some_and(|h| h.starts_with("Nostr "))
+    }
+
+    fn authenticate<'a>(&'a self, req: &'a AuthRequest<'a>) -> LocalBoxFuture<'a, Result<Principal, AuthError>> {
+        let result = validate_nip98_at(req.authorization, req.method, req.url, req.now);
+        future::ready(result.map(|auth| Principal::Nostr(auth.pubkey))).boxed_local()
+    }
+}
+
+/// Service JWT in `Authorization: Bearer ...`, checked against the operator's keys
+pub struct Jwt(pub JwtConfig);
+
+impl AuthProvider for Jwt {
+    fn presented(&self, req: &AuthRequest) -> bool {
+        req.bearer()
+            .is_some_and(|token| !api_keys::is_key_token(token) && !crate::session::is_session_token(token))
+    }
+
+    fn authenticate<'a>(&'a self, req: &'a AuthRequest<'a>) -> LocalBoxFuture<'a, Result<Principal, AuthError>> {
+        async move {
+            let token = req.bearer().ok_or(AuthError::MissingHeader)?;
+            let claims = self.0.verify(token, req.now).await.map_err(AuthError::Jwt)?;
+            Ok(Principal::Service(claims.sub))
+        }
+        .boxed_local()
+    }
+}
+
+/// An API key that must hold `scope`. The router has already looked the key up
+/// (`None` when the presented key is unknown), so this never touches KV.
+pub struct ApiKeyAuth {
+    pub key: Option<ApiKey>,
+    pub scope: Scope,
+}
+
+impl AuthProvider for ApiKeyAuth {
+    fn presented(&self, req: &AuthRequest) -> bool {
+        api_keys::request_key(req.api_key, req.authorization).is_some()
+    }
+
+    fn authenticate<'a>(&'a self, _req: &'a AuthRequest<'a>) -> LocalBoxFuture<'a, Result<Principal, AuthError>> {
+        let result = match &self.key {
+            None => Err(AuthError::UnknownApiKey),
+            Some(key) if key.allows(self.scope) => Ok(Principal::ApiKey(key.name.clone())),
+            Some(_) => Err(AuthError::InsufficientScope(self.scope)),
+        };
+        future::ready(result).boxed_local()
+    }
+}
+
+/// Accepts what any of its providers accepts, trying those whose credentials the
+/// request carries in order. When every one fails the last error is returned, and
+/// when none is presented the last provider reports what it expected.
+pub struct AnyOf(pub Vec<Box<dyn AuthProvider>>);
+
+impl AuthProvider for AnyOf {
+    fn presented(&self, req: &AuthRequest) -> bool {
+        self.0.iter().any(|p| p.presented(req))
+    }
+
+    fn authenticate<'a>(&'a self, req: &'a AuthRequest<'a>) -> LocalBoxFuture<'a, Result<Principal, AuthError>> {
+        async move {
+            let mut last_error = None;
+            for provider in self.0.iter().filter(|p| p.presented(req)) {
+                match provider.authenticate(req).await {
+                    Ok(principal) => return Ok(principal),
+                    Err(e) => last_error = Some(e),
+                }
+            }
+            match (last_error, self.0.last()) {
+                (Some(e), _) => Err(e),
+                (None, Some(fallback)) => fallback.authenticate(req).await,
+                (None, None) => Err(AuthError::MissingHeader),
+            }
+        }
+        .boxed_local()
+    }
+}
+
 pub fn validate_nip98(
     auth_header: Option<&str>,
     method: &str,
     url: &str,
+) -> Result<AuthResult, AuthError> {
+    let now = (js_sys::Date::now() / 1000.0) as u64;
+    validate_nip98_at(auth_header, method, url, now)
+}
+
+/// `validate_nip98` against a given clock (unix seconds), so it runs outside Workers
+pub fn validate_nip98_at(
+    auth_header: Option<&str>,
+    method: &str,
+    url: &str,
+    now: u64,
 ) -> Result<AuthResult, AuthError> {
     let header = auth_header.ok_or(AuthError::MissingHeader)?;
 
@@ -77,8 +276,7 @@ pub fn validate_nip98(
     }
 
     // Check created_at within ±60 seconds
-    let now = (js_sys::Date::now() / 1000.0) as u64;
-    if event.created_at > now + 60 || event.created_at < now.saturating_sub(60) {
+    if event.created_at > now + MAX_SKEW_SECONDS || event.created_at < now.saturating_sub(MAX_SKEW_SECONDS) {
         return Err(AuthError::Expired);
     }
 
@@ -102,42 +300,73 @@ pub fn validate_nip98(
         .and_then(|t| t.get(1))
         .ok_or(AuthError::InvalidUrl)?;
 
-    if url_tag != url {
+    if !urls_match(url_tag, url) {
         return Err(AuthError::InvalidUrl);
     }
 
-    // Verify signature
-    if !verify_signature(&event) {
+    // The id is recomputed every time, since it binds the tags just checked. Only the
+    // Schnorr check is skipped for an (id, sig) pair this isolate has already verified.
+    if !id_matches(&event) {
         return Err(AuthError::InvalidSignature);
     }
+    let cache_key = format!("{}:{}", event.id, event.sig);
+    if !VERIFIED.with(|v| v.borrow().contains(&cache_key, now)) {
+        if !schnorr_valid(&event) {
+            return Err(AuthError::InvalidSignature);
+        }
+        VERIFIED.with(|v| v.borrow_mut().insert(cache_key, event.created_at + MAX_SKEW_SECONDS, now));
+    }
 
     Ok(AuthResult {
         pubkey: event.pubkey,
     })
 }
 
-// Made pub(crate) for testing
+/// The `u` tag must name the request URL including its query string (which GET
+/// requests depend on). Both are parsed first, so percent-encoding and the order
+/// of query parameters don't matter; a fragment is never sent and is ignored.
+fn urls_match(tag: &str, request: &str) -> bool {
+    let (Ok(tag), Ok(request)) = (Url::parse(tag), Url::parse(request)) else {
+        return tag == request;
+    };
+    let sorted_query = |url: &Url| {
+        let mut pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        pairs.sort();
+        pairs
+    };
+    tag.scheme() == request.scheme()
+        && tag.host_str() == request.host_str()
+        && tag.port_or_known_default() == request.port_or_known_default()
+        && tag.path() == request.path()
+        && sorted_query(&tag) == sorted_query(&request)
+}
+
+/// Both checks `validate_nip98_at` makes, uncached
 pub(crate) fn verify_signature(event: &AuthEvent) -> bool {
-    // Compute event ID (SHA256 of serialized event)
-    let serialized = serde_json::json!([
-        0,
-        event.pubkey,
-        event.created_at,
-        event.kind,
-        event.tags,
-        event.content
-    ]);
-    let serialized_str = serialized.to_string();
+    id_matches(event) && schnorr_valid(event)
+}
 
-    let mut hasher = Sha256::new();
-    hasher.update(serialized_str.as_bytes());
-    let computed_id = hex::encode(hasher.finalize());
+/// Whether an event from a relay is what it claims: its id recomputed from its
+/// content and its signature the author's. Anything that isn't an event fails.
+pub(crate) fn verify_event(event: &serde_json::Value) -> bool {
+    AuthEvent::deserialize(event).is_ok_and(|event| verify_signature(&event))
+}
 
-    // Verify computed ID matches claimed ID
-    if computed_id != event.id {
-        return false;
-    }
+/// Whether the claimed id is the SHA-256 of the serialized event
+fn id_matches(event: &AuthEvent) -> bool {
+    event_id(&event.pubkey, event.created_at, event.kind, &event.tags, &event.content) == event.id
+}
 
+/// NIP-01 event id: hex SHA-256 of the serialized `[0, pubkey, created_at, kind, tags, content]`
+pub(crate) fn event_id(pubkey: &str, created_at: u64, kind: u32, tags: &[Vec<String>], content: &str) -> String {
+    let serialized = serde_json::json!([0, pubkey, created_at, kind, tags, content]);
+    let mut hasher = Sha256::new();
+    hasher.update(serialized.to_string().as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// Whether `sig` is the pubkey's Schnorr signature over the id
+fn schnorr_valid(event: &AuthEvent) -> bool {
     // Parse public key (32-byte x-only pubkey)
     let pubkey_bytes: [u8; 32] = match hex::decode(&event.pubkey) {
         Ok(bytes) if bytes.len() == 32 => bytes.try_into().unwrap(),
@@ -166,8 +395,8 @@ pub(crate) fn verify_signature(event: &AuthEvent) -> bool {
         _ => return false,
     };
 
-    // Verify schnorr signature over the event ID
-    verifying_key.verify(&id_bytes, &signature).is_ok()
+    // The id itself is what gets signed (BIP-340), not a further hash of it
+    verifying_key.verify_raw(&id_bytes, &signature).is_ok()
 }
 
 #[cfg(test)]
@@ -237,6 +466,22 @@ mod tests {
         assert!(computed_id.chars().all(|c| c.is_ascii_hexdigit()));
     }
 
+    #[test]
+    fn test_urls_match() {
+        let url = "https://gateway.divine.video/query?filter=eyJraW5kcyI6WzFdfQ&nocache=1";
+        assert!(urls_match(url, url));
+        assert!(urls_match(
+            "https://gateway.divine.video/query?nocache=1&filter=eyJraW5kcyI6WzFdfQ",
+            url
+        ));
+        assert!(urls_match("https://gateway.divine.video:443/publish", "https://gateway.divine.video/publish"));
+        assert!(urls_match("https://gateway.divine.video/q?a=%7B", "https://gateway.divine.video/q?a={"));
+        assert!(!urls_match("https://gateway.divine.video/query", url));
+        assert!(!urls_match("https://gateway.divine.video/query?filter=other&nocache=1", url));
+        assert!(!urls_match("http://gateway.divine.video/publish", "https://gateway.divine.video/publish"));
+        assert!(!urls_match("https://evil.example/publish", "https://gateway.divine.video/publish"));
+    }
+
     #[test]
     fn test_verify_signature_invalid_pubkey() {
         let mut event = make_test_event();
@@ -265,6 +510,33 @@ mod tests {
         assert!(!verify_signature(&event));
     }
 
+    #[test]
+    fn test_verify_event() {
+        let key = k256::schnorr::SigningKey::from_bytes(&[7u8; 32]).unwrap();
+        let pubkey = hex::encode(key.verifying_key().to_bytes());
+        let tags = vec![vec!["e".to_string(), "ab".repeat(32)]];
+        let id = event_id(&pubkey, 1700000000, 5, &tags, "");
+        let sig = key.sign_raw(&hex::decode(&id).unwrap(), &[0u8; 32]).unwrap();
+        let event = serde_json::json!({
+            "id": id,
+            "pubkey": pubkey,
+            "created_at": 1700000000,
+            "kind": 5,
+            "tags": tags,
+            "content": "",
+            "sig": hex::encode(sig.to_bytes()),
+        });
+        assert!(verify_event(&event));
+
+        let mut forged = event.clone();
+        forged["content"] = "changed".into();
+        assert!(!verify_event(&forged));
+        let mut other_author = event.clone();
+        other_author["pubkey"] = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798".into();
+        assert!(!verify_event(&other_author));
+        assert!(!verify_event(&serde_json::json!({"id": event["id"]})));
+    }
+
     #[test]
     fn test_verify_signature_id_mismatch() {
         let mut event = make_test_event();
@@ -337,4 +609,105 @@ mod tests {
         let request_method = "POST";
         assert_eq!(method_tag.to_uppercase(), request_method.to_uppercase());
     }
+
+    fn request<'a>(authorization: Option<&'a str>, api_key: Option<&'a str>) -> AuthRequest<'a> {
+        AuthRequest {
+            method: "POST",
+            url: "https://example.com/publish",
+            authorization,
+            api_key,
+            now: 1000,
+        }
+    }
+
+    fn key(scopes: Vec<Scope>) -> ApiKey {
+        ApiKey {
+            id: "id".to_string(),
+            name: "acme".to_string(),
+            tier: api_keys::Tier::Free,
+            scopes,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_any_of() {
+        let publish = |key: Option<ApiKey>| {
+            AnyOf(vec![
+                Box::new(ApiKeyAuth { key, scope: Scope::Publish }),
+                Box::new(Nip98),
+            ])
+        };
+        let run = |auth: &AnyOf, req: &AuthRequest| auth.authenticate(req).now_or_never().unwrap();
+
+        let with_key = request(None, Some("dgk_abc"));
+        assert_eq!(run(&publish(Some(key(vec![Scope::Publish]))), &with_key).unwrap(), Principal::ApiKey("acme".to_string()));
+        assert!(matches!(
+            run(&publish(Some(key(vec![Scope::Read]))), &with_key),
+            Err(AuthError::InsufficientScope(Scope::Publish))
+        ));
+        assert!(matches!(run(&publish(None), &with_key), Err(AuthError::UnknownApiKey)));
+
+        // A read key alongside NIP-98: the key is tried first, and NIP-98's error is the one reported
+        let both = request(Some("Nostr e30="), Some("dgk_abc"));
+        assert!(matches!(run(&publish(Some(key(vec![Scope::Read]))), &both), Err(AuthError::InvalidJson)));
+
+        // Without credentials, the fallback says what it wanted
+        assert!(matches!(run(&publish(None), &request(None, None)), Err(AuthError::MissingHeader)));
+        assert!(matches!(run(&publish(None), &request(Some("Basic x"), None)), Err(AuthError::InvalidFormat)));
+    }
+
+    #[test]
+    fn test_presented() {
+        let nostr = request(Some("Nostr abc"), None);
+        let key_bearer = request(Some("Bearer dgk_abc"), None);
+        assert!(Nip98.presented(&nostr) && !Nip98.presented(&key_bearer));
+        let api_key = ApiKeyAuth { key: None, scope: Scope::Read };
+        assert!(api_key.presented(&key_bearer) && !api_key.presented(&nostr));
+    }
+
+    #[test]
+    fn test_verified_signatures() {
+        let mut verified = VerifiedSignatures::default();
+        verified.insert("id:sig".to_string(), 1060, 1000);
+        assert!(verified.contains("id:sig", 1060));
+        assert!(!verified.contains("id:sig", 1061));
+        assert!(!verified.contains("id:other", 1000));
+
+        // A full set sheds expired entries first
+        for i in 0..VERIFIED_CAPACITY {
+            verified.insert(format!("old{}", i), 1100, 1000);
+        }
+        verified.insert("new".to_string(), 1300, 1200);
+        assert_eq!(verified.expiry.len(), 1);
+    }
+
+    #[test]
+    fn test_cached_signature_still_binds_tags() {
+        // A cached (id, sig) pair must not vouch for an event whose tags were changed
+        let mut event = make_test_event();
+        VERIFIED.with(|v| v.borrow_mut().insert(format!("{}:{}", event.id, event.sig), u64::MAX, 0));
+        event.tags[0][1] = "https://example.com/other".to_string();
+        let json = serde_json::json!({
+            "id": event.id, "pubkey": event.pubkey, "created_at": event.created_at, "kind": event.kind,
+            "tags": event.tags, "content": event.content, "sig": event.sig
+        });
+        let header = format!("Nostr {}", STANDARD.encode(json.to_string()));
+        let result = validate_nip98_at(Some(&header), "POST", "https://example.com/other", event.created_at);
+        assert!(matches!(result, Err(AuthError::InvalidSignature)));
+    }
+
+    #[test]
+    fn test_validate_at_clock() {
+        let event = serde_json::json!({
+            "id": "00", "pubkey": "00", "created_at": 1000, "kind": 27235,
+            "tags": [["u", "https://example.com/publish"], ["method", "POST"]],
+            "content": "", "sig": "00"
+        });
+        let header = format!("Nostr {}", STANDARD.encode(event.to_string()));
+        let validate = |now| validate_nip98_at(Some(&header), "POST", "https://example.com/publish", now);
+        assert!(matches!(validate(2000), Err(AuthError::Expired)));
+        // Within the window the checks reach the (bogus) signature
+        assert!(matches!(validate(1030), Err(AuthError::InvalidSignature)));
+    }
 }
@@ -0,0 +1,87 @@
+// ABOUTME: Batch profile and event lookups: request validation and picking the newest kind 0 per pubkey
+// ABOUTME: The router serves per-item cache hits and fetches the rest in one relay REQ
+
+use crate::types::ProfileMetadata;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Most pubkeys or event ids accepted in one batch
+pub const MAX_BATCH_SIZE: usize = 100;
+
+/// Lowercased, deduplicated hex ids (pubkeys or event ids) in request order.
+/// `noun` names them in error messages.
+pub fn normalize_hex_ids(values: &[String], noun: &str) -> std::result::Result<Vec<String>, String> {
+    if values.is_empty() {
+        return Err(format!("{}s must not be empty", noun));
+    }
+    let mut out: Vec<String> = Vec::with_capacity(values.len());
+    for value in values {
+        if value.len() != 64 || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("invalid {}: {}", noun, value));
+        }
+        let value = value.to_ascii_lowercase();
+        if !out.contains(&value) {
+            out.push(value);
+        }
+    }
+    if out.len() > MAX_BATCH_SIZE {
+        return Err(format!("at most {} {}s per request", MAX_BATCH_SIZE, noun));
+    }
+    Ok(out)
+}
+
+/// The newest event per author, as relays may return several versions
+pub fn newest_by_author(events: &[Value]) -> HashMap<String, &Value> {
+    let mut newest: HashMap<String, &Value> = HashMap::new();
+    for event in events {
+        let Some(pubkey) = event.get("pubkey").and_then(|v| v.as_str()) else { continue };
+        let created_at = |e: &Value| e.get("created_at").and_then(|v| v.as_u64()).unwrap_or(0);
+        match newest.get(pubkey) {
+            Some(current) if created_at(current) >= created_at(event) => {}
+            _ => {
+                newest.insert(pubkey.to_string(), event);
+            }
+        }
+    }
+    newest
+}
+
+/// Parsed metadata of the newest kind 0 per author. Unparseable content counts
+/// as no profile.
+pub fn latest_by_author(events: &[Value]) -> HashMap<String, ProfileMetadata> {
+    newest_by_author(events)
+        .into_iter()
+        .filter_map(|(pubkey, event)| Some((pubkey, ProfileMetadata::from_event(event)?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_normalize_hex_ids() {
+        let a = "a".repeat(64);
+        let upper = "A".repeat(64);
+        assert_eq!(normalize_hex_ids(&[a.clone(), upper, a.clone()], "pubkey"), Ok(vec![a.clone()]));
+        assert_eq!(normalize_hex_ids(&[], "id"), Err("ids must not be empty".to_string()));
+        assert!(normalize_hex_ids(&["npub1xyz".to_string()], "pubkey").is_err());
+
+        let too_many: Vec<String> = (0..=MAX_BATCH_SIZE).map(|i| format!("{:064x}", i)).collect();
+        assert!(normalize_hex_ids(&too_many, "id").is_err());
+        assert_eq!(normalize_hex_ids(&too_many[..MAX_BATCH_SIZE], "id").unwrap().len(), MAX_BATCH_SIZE);
+    }
+
+    #[test]
+    fn test_latest_by_author() {
+        let events = vec![
+            json!({"pubkey": "pk1", "created_at": 10, "content": r#"{"name":"old"}"#}),
+            json!({"pubkey": "pk1", "created_at": 20, "content": r#"{"name":"new"}"#}),
+            json!({"pubkey": "pk2", "created_at": 5, "content": "not json"}),
+        ];
+        let profiles = latest_by_author(&events);
+        assert_eq!(profiles.len(), 1);
+        assert_eq!(profiles["pk1"].name.as_deref(), Some("new"));
+    }
+}
@@ -0,0 +1,18 @@
+// ABOUTME: Emits typed SDK descriptions from the gateway's route registry
+// ABOUTME: Usage: cargo run --features sdk --bin sdkgen -- <openapi|typescript|swift>
+
+use divine_rest_gateway::{openapi, sdk};
+
+fn main() {
+    let doc = openapi::document();
+    let output = match std::env::args().nth(1).as_deref() {
+        Some("openapi") => serde_json::to_string_pretty(&doc).expect("document serializes"),
+        Some("typescript") => sdk::typescript(&doc),
+        Some("swift") => sdk::swift(&doc),
+        _ => {
+            eprintln!("usage: sdkgen <openapi|typescript|swift>");
+            std::process::exit(2);
+        }
+    };
+    print!("{}", output);
+}
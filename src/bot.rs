@@ -0,0 +1,233 @@
+// ABOUTME: Bot/abuse fingerprinting from Cloudflare request signals (bot score, JA3/JA4)
+// ABOUTME: Maps signals to a per-route action so scrapers are throttled harder than real clients
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use worker::{Env, Request};
+
+/// Signals Cloudflare attaches to `request.cf.botManagement`.
+/// All fields are absent on plans without Bot Management.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BotSignals {
+    /// 1 (automated) to 99 (human)
+    pub score: Option<u8>,
+    /// Known good crawler (search engines, link unfurlers)
+    pub verified_bot: bool,
+    pub ja3_hash: Option<String>,
+    pub ja4: Option<String>,
+}
+
+impl BotSignals {
+    pub fn from_request(req: &Request) -> Self {
+        Self::from_cf(&cf_properties(req))
+    }
+
+    pub fn from_cf(cf: &serde_json::Value) -> Self {
+        let bm = &cf["botManagement"];
+        Self {
+            score: bm["score"].as_u64().map(|s| s.min(99) as u8),
+            verified_bot: bm["verifiedBot"].as_bool().unwrap_or(false),
+            ja3_hash: bm["ja3Hash"].as_str().map(String::from),
+            ja4: bm["ja4"].as_str().map(String::from),
+        }
+    }
+
+    fn fingerprints(&self) -> impl Iterator<Item = &str> {
+        self.ja3_hash.iter().chain(self.ja4.iter()).map(|s| s.as_str())
+    }
+}
+
+/// The request's `cf` properties, or `Null` outside Cloudflare
+pub fn cf_properties(req: &Request) -> serde_json::Value {
+    // The cf object isn't fully typed in workers-rs; round-trip it through JSON
+    js_sys::Reflect::get(req.inner(), &"cf".into())
+        .ok()
+        .filter(|v| v.is_object())
+        .and_then(|v| js_sys::JSON::stringify(&v).ok())
+        .and_then(|s| s.as_string())
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or(serde_json::Value::Null)
+}
+
+/// What to do with a request given its bot signals
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BotAction {
+    Allow,
+    /// Serve from cache only: cache bypasses are ignored
+    Throttle,
+    /// Require a human check before serving
+    Challenge,
+    Block,
+}
+
+/// Thresholds for one route. Scores at or below a threshold trigger its action.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BotPolicy {
+    pub throttle_below: Option<u8>,
+    pub challenge_below: Option<u8>,
+    pub block_below: Option<u8>,
+    pub allow_verified_bots: bool,
+    /// JA3 hashes or JA4 fingerprints that are always blocked
+    pub blocked_fingerprints: Vec<String>,
+}
+
+impl Default for BotPolicy {
+    fn default() -> Self {
+        Self {
+            // Cloudflare treats scores under 30 as likely automated
+            throttle_below: Some(29),
+            challenge_below: None,
+            block_below: None,
+            allow_verified_bots: true,
+            blocked_fingerprints: Vec::new(),
+        }
+    }
+}
+
+impl BotPolicy {
+    pub fn decide(&self, signals: &BotSignals) -> BotAction {
+        if signals
+            .fingerprints()
+            .any(|fp| self.blocked_fingerprints.iter().any(|b| b == fp))
+        {
+            return BotAction::Block;
+        }
+        if signals.verified_bot && self.allow_verified_bots {
+            return BotAction::Allow;
+        }
+        // No score means Bot Management isn't available; don't penalize anyone
+        let score = match signals.score {
+            Some(s) => s,
+            None => return BotAction::Allow,
+        };
+        let at_or_below = |threshold: Option<u8>| threshold.map(|t| score <= t).unwrap_or(false);
+        if at_or_below(self.block_below) {
+            BotAction::Block
+        } else if at_or_below(self.challenge_below) {
+            BotAction::Challenge
+        } else if at_or_below(self.throttle_below) {
+            BotAction::Throttle
+        } else {
+            BotAction::Allow
+        }
+    }
+}
+
+/// Per-route policies keyed by path prefix, with `*` as the fallback.
+/// Configured as JSON in the `BOT_POLICY` var, e.g.
+/// `{"*": {"throttle_below": 29}, "/publish": {"challenge_below": 29, "block_below": 1}}`
+#[derive(Debug, Clone, Default)]
+pub struct BotPolicies {
+    routes: HashMap<String, BotPolicy>,
+}
+
+impl BotPolicies {
+    pub fn from_env(env: &Env) -> Self {
+        env.var("BOT_POLICY")
+            .ok()
+            .and_then(|v| Self::from_json(&v.to_string()))
+            .unwrap_or_default()
+    }
+
+    pub fn from_json(json: &str) -> Option<Self> {
+        serde_json::from_str(json).ok().map(|routes| Self { routes })
+    }
+
+    /// Longest matching path prefix wins
+    pub fn for_path(&self, path: &str) -> BotPolicy {
+        self.routes
+            .iter()
+            .filter(|(prefix, _)| prefix.as_str() != "*" && path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .or_else(|| self.routes.get_key_value("*"))
+            .map(|(_, policy)| policy.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn signals(score: u8) -> BotSignals {
+        BotSignals {
+            score: Some(score),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_from_cf() {
+        let cf = json!({"botManagement": {"score": 12, "verifiedBot": false, "ja3Hash": "abc", "ja4": "t13d"}});
+        let s = BotSignals::from_cf(&cf);
+        assert_eq!(s.score, Some(12));
+        assert_eq!(s.ja3_hash.as_deref(), Some("abc"));
+        assert_eq!(s.ja4.as_deref(), Some("t13d"));
+        assert_eq!(BotSignals::from_cf(&json!({"colo": "SJC"})), BotSignals::default());
+    }
+
+    #[test]
+    fn test_default_policy_throttles_likely_bots() {
+        let policy = BotPolicy::default();
+        assert_eq!(policy.decide(&signals(5)), BotAction::Throttle);
+        assert_eq!(policy.decide(&signals(29)), BotAction::Throttle);
+        assert_eq!(policy.decide(&signals(30)), BotAction::Allow);
+        assert_eq!(policy.decide(&BotSignals::default()), BotAction::Allow);
+    }
+
+    #[test]
+    fn test_verified_bots_allowed() {
+        let s = BotSignals {
+            score: Some(1),
+            verified_bot: true,
+            ..Default::default()
+        };
+        assert_eq!(BotPolicy::default().decide(&s), BotAction::Allow);
+        let strict = BotPolicy {
+            allow_verified_bots: false,
+            ..Default::default()
+        };
+        assert_eq!(strict.decide(&s), BotAction::Throttle);
+    }
+
+    #[test]
+    fn test_escalation_order() {
+        let policy = BotPolicy {
+            block_below: Some(1),
+            challenge_below: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(policy.decide(&signals(1)), BotAction::Block);
+        assert_eq!(policy.decide(&signals(8)), BotAction::Challenge);
+        assert_eq!(policy.decide(&signals(20)), BotAction::Throttle);
+    }
+
+    #[test]
+    fn test_blocked_fingerprint() {
+        let policy = BotPolicy {
+            blocked_fingerprints: vec!["deadbeef".to_string()],
+            ..Default::default()
+        };
+        let s = BotSignals {
+            ja3_hash: Some("deadbeef".to_string()),
+            verified_bot: true,
+            ..Default::default()
+        };
+        assert_eq!(policy.decide(&s), BotAction::Block);
+    }
+
+    #[test]
+    fn test_route_policies() {
+        let policies = BotPolicies::from_json(
+            r#"{"*": {"throttle_below": 10}, "/publish": {"challenge_below": 29}, "/publish/status": {}}"#,
+        )
+        .unwrap();
+        assert_eq!(policies.for_path("/query").throttle_below, Some(10));
+        assert_eq!(policies.for_path("/publish").challenge_below, Some(29));
+        assert_eq!(policies.for_path("/publish/status/abc").challenge_below, None);
+        assert_eq!(BotPolicies::default().for_path("/query").throttle_below, Some(29));
+        assert!(BotPolicies::from_json("not json").is_none());
+    }
+}
@@ -0,0 +1,223 @@
+// ABOUTME: Per-deployment branding (name, logo, colors, support links) for the HTML surfaces
+// ABOUTME: Stored in KV, optionally per host, so white-label domains don't render Divine's identity
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use worker::kv::KvStore;
+use worker::*;
+
+/// KV key of the deployment-wide document; per-host documents append `:{host}`
+const KV_KEY: &str = "branding";
+
+/// Edge-cached reads; changes take up to this long to reach every colo
+const CACHE_SECONDS: u64 = 60;
+
+const MAX_NAME_CHARS: usize = 80;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Branding {
+    /// Page titles and headings
+    pub name: String,
+    /// Short name for `og:site_name` on embeds
+    pub site_name: String,
+    pub logo_url: Option<String>,
+    pub colors: Colors,
+    pub support_url: Option<String>,
+    pub support_email: Option<String>,
+}
+
+/// CSS hex colors
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Colors {
+    pub background: String,
+    /// Cards and code blocks
+    pub surface: String,
+    pub text: String,
+    pub accent: String,
+    pub border: String,
+}
+
+impl Default for Branding {
+    fn default() -> Self {
+        Self {
+            name: "Divine REST Gateway".to_string(),
+            site_name: "Divine".to_string(),
+            logo_url: None,
+            colors: Colors::default(),
+            support_url: None,
+            support_email: None,
+        }
+    }
+}
+
+impl Default for Colors {
+    fn default() -> Self {
+        Self {
+            background: "#0d1117".to_string(),
+            surface: "#161b22".to_string(),
+            text: "#c9d1d9".to_string(),
+            accent: "#58a6ff".to_string(),
+            border: "#30363d".to_string(),
+        }
+    }
+}
+
+impl Branding {
+    /// Values end up in HTML and inline CSS, so anything free-form is refused
+    /// here rather than trusted to escaping alone
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        for (field, value) in [("name", &self.name), ("site_name", &self.site_name)] {
+            if value.trim().is_empty() || value.chars().count() > MAX_NAME_CHARS {
+                return Err(format!("{} must be 1-{} characters", field, MAX_NAME_CHARS));
+            }
+        }
+        let colors = &self.colors;
+        for (field, value) in [
+            ("background", &colors.background),
+            ("surface", &colors.surface),
+            ("text", &colors.text),
+            ("accent", &colors.accent),
+            ("border", &colors.border),
+        ] {
+            if !is_hex_color(value) {
+                return Err(format!("colors.{} must be a hex color like #0d1117", field));
+            }
+        }
+        for (field, value) in [("logo_url", &self.logo_url), ("support_url", &self.support_url)] {
+            if value.as_deref().is_some_and(|url| !is_https_url(url)) {
+                return Err(format!("{} must be an https URL", field));
+            }
+        }
+        if let Some(email) = &self.support_email {
+            let valid = email.split_once('@').is_some_and(|(user, domain)| !user.is_empty() && domain.contains('.'))
+                && !email.contains(|c: char| c.is_whitespace() || "<>\"'".contains(c));
+            if !valid {
+                return Err("support_email must be an email address".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Short digest of the document, so cached renders change when the branding does
+    pub fn revision(&self) -> String {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        hex::encode(&Sha256::digest(json.as_bytes())[..4])
+    }
+}
+
+fn is_hex_color(value: &str) -> bool {
+    value
+        .strip_prefix('#')
+        .is_some_and(|hex| matches!(hex.len(), 3 | 6) && hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn is_https_url(value: &str) -> bool {
+    Url::parse(value).is_ok_and(|url| url.scheme() == "https" && url.host_str().is_some())
+}
+
+fn kv_key(host: Option<&str>) -> String {
+    match host {
+        Some(host) => format!("{}:{}", KV_KEY, host.to_ascii_lowercase()),
+        None => KV_KEY.to_string(),
+    }
+}
+
+/// Branding for a host: its own document, else the deployment's, else the defaults.
+/// A failed lookup falls back the same way.
+pub async fn get(env: &Env, host: &str) -> Branding {
+    let Ok(kv) = env.kv("REST_GATEWAY_CACHE") else {
+        return Branding::default();
+    };
+    for key in [kv_key(Some(host)), kv_key(None)] {
+        match kv.get(&key).cache_ttl(CACHE_SECONDS).json::<Branding>().await {
+            Ok(Some(branding)) => return branding,
+            Ok(None) => {}
+            Err(e) => console_log!("Branding lookup failed for {}: {}", key, e),
+        }
+    }
+    Branding::default()
+}
+
+/// The stored document for a host, or the deployment's when `host` is None
+pub async fn get_stored(kv: &KvStore, host: Option<&str>) -> Result<Option<Branding>> {
+    Ok(kv.get(&kv_key(host)).json().await?)
+}
+
+pub async fn set(kv: &KvStore, host: Option<&str>, branding: &Branding) -> Result<()> {
+    kv.put(&kv_key(host), serde_json::to_string(branding)?)?.execute().await?;
+    Ok(())
+}
+
+pub async fn clear(kv: &KvStore, host: Option<&str>) -> Result<()> {
+    kv.delete(&kv_key(host)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_document_keeps_defaults() {
+        let json = r##"{"name": "Acme Nostr", "colors": {"accent": "#ff6600"}}"##;
+        let branding: Branding = serde_json::from_str(json).unwrap();
+        assert_eq!(branding.name, "Acme Nostr");
+        assert_eq!(branding.site_name, "Divine");
+        assert_eq!(branding.colors.accent, "#ff6600");
+        assert_eq!(branding.colors.background, Colors::default().background);
+        assert_eq!(branding.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(Branding::default().validate(), Ok(()));
+
+        let css = Branding {
+            colors: Colors {
+                text: "red;}body{display:none".to_string(),
+                ..Colors::default()
+            },
+            ..Branding::default()
+        };
+        assert!(css.validate().unwrap_err().contains("colors.text"));
+
+        let logo = Branding {
+            logo_url: Some("javascript:alert(1)".to_string()),
+            ..Branding::default()
+        };
+        assert!(logo.validate().unwrap_err().contains("logo_url"));
+
+        let email = |address: &str| Branding {
+            support_email: Some(address.to_string()),
+            ..Branding::default()
+        };
+        assert_eq!(email("help@acme.example").validate(), Ok(()));
+        assert!(email("\"><script>@x.y").validate().is_err());
+
+        let blank = Branding {
+            name: " ".to_string(),
+            ..Branding::default()
+        };
+        assert!(blank.validate().is_err());
+    }
+
+    #[test]
+    fn test_revision_follows_content() {
+        let before = Branding::default().revision();
+        assert_eq!(before.len(), 8);
+        assert_eq!(Branding::default().revision(), before);
+        let renamed = Branding {
+            name: "Acme Nostr".to_string(),
+            ..Branding::default()
+        };
+        assert_ne!(renamed.revision(), before);
+    }
+
+    #[test]
+    fn test_kv_key() {
+        assert_eq!(kv_key(None), "branding");
+        assert_eq!(kv_key(Some("Nostr.Acme.Example")), "branding:nostr.acme.example");
+    }
+}
@@ -1,46 +1,288 @@
 // ABOUTME: Workers KV cache operations for storing and retrieving query results
-// ABOUTME: Handles TTL management and cache key generation
+// ABOUTME: Query results also get a colo-local Cache API copy, read before KV; oversized ones live in R2 or KV chunks
 
+use crate::expiration;
+use crate::filter::Filter;
+use crate::quarantine::QuarantineEntry;
 use crate::types::{CachedQuery, PublishStatus};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
 use worker::kv::KvStore;
 use worker::*;
 
+/// Longest a query result is kept past its TTL, to be served stale while it's refreshed
+const MAX_STALE_SECONDS: u64 = 3600;
+
+/// How long a colo keeps its Cache API copy of a query result before reading KV
+/// again. Purges only reach the purging colo's copy, so this bounds how long
+/// other colos can go on serving a purged entry.
+const EDGE_TTL_SECONDS: u64 = 60;
+
+/// How long KV's own edge cache may answer a read of a query result before going
+/// back to the central store: the edge copy's lifetime, so a purge or rewrite
+/// reaches other colos no later than it does through the edge copy
+const QUERY_READ_CACHE_SECONDS: u64 = EDGE_TTL_SECONDS;
+
+/// Chunk keys carry their write's timestamp and are never rewritten, so reads
+/// of them can be cached for as long as any of them lives
+const CHUNK_READ_CACHE_SECONDS: u64 = MAX_STALE_SECONDS;
+
+/// Synthetic host of edge copies; Cache API keys have to be URLs
+const EDGE_KEY_PREFIX: &str = "https://kv-edge.invalid/";
+
+/// Longest an empty result is trusted. The events may simply not have reached the
+/// relay yet, and a miss costs a full empty-result timeout on the relay.
+const NEGATIVE_TTL_SECONDS: u64 = 60;
+
+/// Size above which a query result goes to R2 unless `CACHE_R2_THRESHOLD_BYTES`
+/// says otherwise. Well under KV's limit, as big values are slow to read from KV too.
+const DEFAULT_OVERFLOW_THRESHOLD_BYTES: usize = 1024 * 1024;
+
+/// Without R2, a result larger than this is split into chunks of this size,
+/// which are read in parallel
+const CHUNK_BYTES: usize = 1024 * 1024;
+
+/// Most chunks one result is split into; anything bigger isn't cached, as
+/// every hit would buffer the whole result in the Worker
+const MAX_CHUNKS: usize = 4;
+
+/// TTL of an empty result for a filter whose results otherwise get `ttl`
+pub(crate) fn negative_ttl(ttl: u64) -> u64 {
+    ttl.min(NEGATIVE_TTL_SECONDS)
+}
+
+/// How long past `ttl` a query result stays in KV: another TTL, up to an hour
+pub(crate) fn stale_window(ttl: u64) -> u64 {
+    ttl.min(MAX_STALE_SECONDS)
+}
+
+/// What KV holds under a query key: the result itself, or where R2 keeps it
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Stored {
+    Inline(CachedQuery),
+    Overflow(OverflowPointer),
+    Chunked(ChunkManifest),
+}
+
+/// A result split across KV keys `chunk:{cache key}:{timestamp}:{n}`. The write's
+/// timestamp keeps a rewrite from mixing its chunks with an earlier one's, and the
+/// prefix keeps chunks out of listings of `query:` keys such as cache sync's.
+#[derive(Serialize, Deserialize)]
+struct ChunkManifest {
+    chunks: usize,
+    bytes: usize,
+    timestamp: u64,
+}
+
+impl ChunkManifest {
+    fn chunk_keys(&self, cache_key: &str) -> Vec<String> {
+        (0..self.chunks)
+            .map(|n| format!("chunk:{}:{}:{}", cache_key, self.timestamp, n))
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct OverflowPointer {
+    /// Object key in the `CACHE_OVERFLOW` bucket, `{cache key}/{timestamp}`, so a
+    /// rewrite never changes the object an older pointer names
+    r2_key: String,
+    bytes: usize,
+    timestamp: u64,
+}
+
+/// Where oversized query results go: the optional `CACHE_OVERFLOW` R2 bucket
+struct Overflow {
+    bucket: Bucket,
+    threshold: usize,
+}
+
 pub struct Cache {
     kv: KvStore,
+    overflow: Option<Overflow>,
 }
 
 impl Cache {
-    pub fn new(kv: KvStore) -> Self {
-        Self { kv }
+    fn new(kv: KvStore) -> Self {
+        Self { kv, overflow: None }
+    }
+
+    /// The query cache: KV, plus the `CACHE_OVERFLOW` bucket when it is bound. The
+    /// only way to get one, so every reader can follow pointers into R2.
+    pub fn from_env(env: &Env) -> Result<Self> {
+        let mut cache = Self::new(env.kv("REST_GATEWAY_CACHE")?);
+        if let Ok(bucket) = env.bucket("CACHE_OVERFLOW") {
+            let threshold = env
+                .var("CACHE_R2_THRESHOLD_BYTES")
+                .ok()
+                .and_then(|v| v.to_string().trim().parse().ok())
+                .unwrap_or(DEFAULT_OVERFLOW_THRESHOLD_BYTES);
+            cache.overflow = Some(Overflow { bucket, threshold });
+        }
+        Ok(cache)
     }
 
-    /// Get cached query result
+    /// Get cached query result: the colo's edge copy when it has one, otherwise
+    /// KV, whose answer is then copied to the edge. Age comes from the entry's own
+    /// timestamp, so both tiers judge freshness alike. An entry that doesn't
+    /// decode (say, one written in an older format) is a miss.
     pub async fn get_query(&self, cache_key: &str) -> Result<Option<(CachedQuery, u64)>> {
-        match self.kv.get(cache_key).json::<CachedQuery>().await? {
-            Some(cached) => {
-                let now = now_seconds();
-                let age = now.saturating_sub(cached.timestamp);
-                Ok(Some((cached, age)))
+        let cached = match edge_get(cache_key).await {
+            Some(cached) => Some(cached),
+            None => {
+                let stored = self.get_stored(cache_key).await?;
+                match stored {
+                    Some((cached, body)) => {
+                        edge_put(cache_key, body).await;
+                        Some(cached)
+                    }
+                    None => None,
+                }
+            }
+        };
+        Ok(cached.map(|cached| {
+            let age = now_seconds().saturating_sub(cached.timestamp);
+            (cached, age)
+        }))
+    }
+
+    /// A query result as stored, following a pointer into R2, with its serialized
+    /// form. Neither the edge copy nor freshness come into it.
+    pub async fn get_stored(&self, cache_key: &str) -> Result<Option<(CachedQuery, String)>> {
+        let Some(raw) = self.kv.get(cache_key).cache_ttl(QUERY_READ_CACHE_SECONDS).text().await? else {
+            return Ok(None);
+        };
+        match serde_json::from_str::<Stored>(&raw) {
+            Ok(Stored::Inline(cached)) => Ok(Some((cached, raw))),
+            Ok(Stored::Overflow(pointer)) => {
+                let Some(overflow) = &self.overflow else {
+                    return Ok(None);
+                };
+                // The object can be gone (say, to a lifecycle rule) while the pointer lives on
+                let Some(body) = overflow.bucket.get(&pointer.r2_key).execute().await?.and_then(|o| o.body()) else {
+                    return Ok(None);
+                };
+                let mut stream = body.stream()?;
+                let mut bytes = Vec::with_capacity(pointer.bytes);
+                while let Some(chunk) = stream.next().await {
+                    bytes.extend_from_slice(&chunk?);
+                    // Stop reading an object bigger than the pointer says; it isn't this entry
+                    if bytes.len() > pointer.bytes {
+                        return Ok(None);
+                    }
+                }
+                let Ok(body) = String::from_utf8(bytes) else {
+                    return Ok(None);
+                };
+                Ok(serde_json::from_str::<CachedQuery>(&body).ok().map(|cached| (cached, body)))
             }
-            None => Ok(None),
+            Ok(Stored::Chunked(manifest)) => {
+                let keys = manifest.chunk_keys(cache_key);
+                let reads = futures_util::future::join_all(keys.iter().map(|key| self.kv.get(key).cache_ttl(CHUNK_READ_CACHE_SECONDS).bytes())).await;
+                let mut bytes = Vec::with_capacity(manifest.bytes);
+                for chunk in reads {
+                    // A chunk that expired or was evicted first leaves nothing usable
+                    let Some(chunk) = chunk? else {
+                        return Ok(None);
+                    };
+                    bytes.extend_from_slice(&chunk);
+                }
+                if bytes.len() != manifest.bytes {
+                    return Ok(None);
+                }
+                let Ok(body) = String::from_utf8(bytes) else {
+                    return Ok(None);
+                };
+                Ok(serde_json::from_str::<CachedQuery>(&body).ok().map(|cached| (cached, body)))
+            }
+            Err(_) => Ok(None),
         }
     }
 
-    /// Store query result with TTL
-    pub async fn put_query(&self, cache_key: &str, events: Vec<serde_json::Value>, eose: bool, ttl_seconds: u64) -> Result<()> {
+    /// Store a filter's result with TTL, or the negative TTL when it is empty. The
+    /// entry outlives the TTL by its stale window; `timestamp` is what tells fresh
+    /// from stale. Expired events are dropped, and no copy outlives the first
+    /// NIP-40 expiry among the rest. A result over the R2 threshold is written to the bucket with
+    /// only a pointer in KV, and the object an earlier pointer named is deleted once
+    /// the new value is in. Without a bucket, a large result is split into KV
+    /// chunks behind a manifest, and one too large even for that isn't cached.
+    pub async fn put_query(&self, filter: &Filter, mut events: Vec<serde_json::Value>, eose: bool, ttl_seconds: u64) -> Result<()> {
+        let cache_key = filter.cache_key();
+        let cache_key = cache_key.as_str();
+        let now = now_seconds();
+        expiration::strip(&mut events, now);
+        crate::kind::dedupe_replaceable(&mut events);
         let cached = CachedQuery {
+            negative: events.is_empty(),
             events,
             eose,
-            timestamp: now_seconds(),
+            timestamp: now,
+            filter: Some(filter.as_json().to_string()),
+        };
+        let ttl_seconds = cached.ttl(ttl_seconds);
+        let lifetime = expiration::cap_ttl(&cached.events, now, ttl_seconds + stale_window(ttl_seconds)).max(60);
+        let body = serde_json::to_string(&cached)?;
+        let replaced = match &self.overflow {
+            Some(_) => self.overflow_key(cache_key).await,
+            None => None,
+        };
+        let mut written = None;
+        let value = match &self.overflow {
+            Some(overflow) if body.len() > overflow.threshold => {
+                let pointer = OverflowPointer {
+                    r2_key: format!("{}/{}", cache_key, cached.timestamp),
+                    bytes: body.len(),
+                    timestamp: cached.timestamp,
+                };
+                overflow.bucket.put(&pointer.r2_key, body.clone().into_bytes()).execute().await?;
+                written = Some(pointer.r2_key.clone());
+                serde_json::to_string(&Stored::Overflow(pointer))?
+            }
+            _ if body.len() > CHUNK_BYTES * MAX_CHUNKS => {
+                console_log!("Not caching {}: {} bytes is too large to chunk and no R2 bucket is bound", cache_key, body.len());
+                return Ok(());
+            }
+            _ if body.len() > CHUNK_BYTES => {
+                let manifest = ChunkManifest {
+                    chunks: body.len().div_ceil(CHUNK_BYTES),
+                    bytes: body.len(),
+                    timestamp: cached.timestamp,
+                };
+                let keys = manifest.chunk_keys(cache_key);
+                for (key, chunk) in keys.iter().zip(body.as_bytes().chunks(CHUNK_BYTES)) {
+                    self.kv.put_bytes(key, chunk)?.expiration_ttl(lifetime).execute().await?;
+                }
+                serde_json::to_string(&Stored::Chunked(manifest))?
+            }
+            _ => body.clone(),
         };
         self.kv
-            .put(cache_key, serde_json::to_string(&cached)?)?
-            .expiration_ttl(ttl_seconds)
+            .put(cache_key, value)?
+            .expiration_ttl(lifetime)
             .execute()
             .await?;
+        if let (Some(overflow), Some(old)) = (&self.overflow, replaced) {
+            if written.as_deref() != Some(old.as_str()) {
+                if let Err(e) = overflow.bucket.delete(&old).await {
+                    console_log!("R2 delete of {} failed: {}", old, e);
+                }
+            }
+        }
+        // The edge copy always holds the entry itself, never a pointer
+        edge_put(cache_key, body).await;
         Ok(())
     }
 
+    /// The R2 object a query key's KV value points at, if it is a pointer
+    async fn overflow_key(&self, cache_key: &str) -> Option<String> {
+        let raw = self.kv.get(cache_key).text().await.ok()??;
+        match serde_json::from_str::<Stored>(&raw).ok()? {
+            Stored::Overflow(pointer) => Some(pointer.r2_key),
+            _ => None,
+        }
+    }
+
     /// Get publish status
     pub async fn get_publish_status(&self, event_id: &str) -> Result<Option<PublishStatus>> {
         let key = format!("publish:{}", event_id);
@@ -57,9 +299,179 @@ impl Cache {
             .await?;
         Ok(())
     }
+
+    /// Check whether the gateway has successfully published for this pubkey before
+    pub async fn is_known_publisher(&self, pubkey: &str) -> Result<bool> {
+        let key = format!("publisher:{}", pubkey);
+        Ok(self.kv.get(&key).text().await?.is_some())
+    }
+
+    /// Remember a pubkey as an established publisher
+    pub async fn mark_known_publisher(&self, pubkey: &str) -> Result<()> {
+        let key = format!("publisher:{}", pubkey);
+        self.kv
+            .put(&key, "1")?
+            .expiration_ttl(90 * 86400) // 90 days
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    /// Increment and return this hour's publish count for a pubkey.
+    /// Approximate - KV has no atomic increment - which is fine for a policy signal.
+    pub async fn incr_hourly_publishes(&self, pubkey: &str) -> Result<u32> {
+        let key = format!("pubrate:{}:{}", pubkey, now_seconds() / 3600);
+        let count = self
+            .kv
+            .get(&key)
+            .text()
+            .await?
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(0)
+            + 1;
+        self.kv
+            .put(&key, count.to_string())?
+            .expiration_ttl(7200) // 2 hours
+            .execute()
+            .await?;
+        Ok(count)
+    }
+
+    /// Operator-supplied web-of-trust score (written to `wot:{pubkey}` by an external job)
+    pub async fn wot_score(&self, pubkey: &str) -> Result<Option<f64>> {
+        let key = format!("wot:{}", pubkey);
+        Ok(self.kv.get(&key).text().await?.and_then(|v| v.parse().ok()))
+    }
+
+    /// Hold an event in the quarantine review queue
+    pub async fn put_quarantine(&self, event_id: &str, entry: &QuarantineEntry) -> Result<()> {
+        let key = format!("quarantine:{}", event_id);
+        self.kv
+            .put(&key, serde_json::to_string(entry)?)?
+            .expiration_ttl(86400 * 2) // outlives the max 12h release delay
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    /// Get a quarantined event
+    pub async fn get_quarantine(&self, event_id: &str) -> Result<Option<QuarantineEntry>> {
+        let key = format!("quarantine:{}", event_id);
+        Ok(self.kv.get(&key).json::<QuarantineEntry>().await?)
+    }
+
+    /// Remove an event from the quarantine review queue
+    pub async fn delete_quarantine(&self, event_id: &str) -> Result<()> {
+        let key = format!("quarantine:{}", event_id);
+        self.kv.delete(&key).await?;
+        Ok(())
+    }
+
+    /// List keys under a prefix, one page at a time
+    pub async fn list_keys(&self, prefix: &str, cursor: Option<String>, limit: u64) -> Result<(Vec<String>, Option<String>)> {
+        let mut list = self.kv.list().prefix(prefix.to_string()).limit(limit);
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        let keys = page.keys.into_iter().map(|k| k.name).collect();
+        Ok((keys, if page.list_complete { None } else { page.cursor }))
+    }
+
+    /// Raw value of any key, for inspection
+    pub async fn get_raw(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.kv.get(key).text().await?)
+    }
+
+    /// Unix time KV will expire a key at, if it exists and has an expiration
+    pub async fn expiration(&self, key: &str) -> Result<Option<u64>> {
+        // A key sorts before every longer key sharing it as a prefix
+        let page = self.kv.list().prefix(key.to_string()).limit(1).execute().await?;
+        Ok(page.keys.into_iter().find(|k| k.name == key).and_then(|k| k.expiration))
+    }
+
+    /// Delete a key from KV, and this colo's edge copy, R2 object and chunks if it is a query result
+    pub async fn delete_key(&self, key: &str) -> Result<()> {
+        let stored = self.kv.get(key).text().await?.and_then(|raw| serde_json::from_str(&raw).ok());
+        if let Some(Stored::Chunked(manifest)) = &stored {
+            for chunk in manifest.chunk_keys(key) {
+                self.kv.delete(&chunk).await?;
+            }
+        }
+        self.kv.delete(key).await?;
+        if let (Some(overflow), Some(Stored::Overflow(pointer))) = (&self.overflow, &stored) {
+            if let Err(e) = overflow.bucket.delete(&pointer.r2_key).await {
+                console_log!("R2 delete of {} failed: {}", pointer.r2_key, e);
+            }
+        }
+        if let Err(e) = worker::Cache::default().delete(edge_key(key), false).await {
+            console_log!("Edge cache delete failed: {}", e);
+        }
+        Ok(())
+    }
+}
+
+fn edge_key(cache_key: &str) -> String {
+    format!("{}{}", EDGE_KEY_PREFIX, cache_key)
+}
+
+async fn edge_get(cache_key: &str) -> Option<CachedQuery> {
+    let mut resp = worker::Cache::default().get(edge_key(cache_key), false).await.ok()??;
+    resp.json::<CachedQuery>().await.ok()
+}
+
+/// Store a colo-local copy of a serialized entry. Failures are logged; KV still has the entry.
+async fn edge_put(cache_key: &str, body: String) {
+    let stored = (|| {
+        let mut headers = Headers::new();
+        headers.set("Content-Type", "application/json")?;
+        headers.set("Cache-Control", &format!("public, max-age={}", EDGE_TTL_SECONDS))?;
+        Ok(Response::ok(body)?.with_headers(headers))
+    })();
+    let result = match stored {
+        Ok(resp) => worker::Cache::default().put(edge_key(cache_key), resp).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        console_log!("Edge cache put failed: {}", e);
+    }
 }
 
 /// Get current Unix timestamp in seconds
-fn now_seconds() -> u64 {
+pub(crate) fn now_seconds() -> u64 {
     (js_sys::Date::now() / 1000.0) as u64
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stored_forms() {
+        let inline = r#"{"events":[{"id":"a"}],"eose":true,"timestamp":1}"#;
+        assert!(matches!(serde_json::from_str::<Stored>(inline), Ok(Stored::Inline(q)) if q.events.len() == 1));
+
+        let pointer = OverflowPointer {
+            r2_key: "query:abc".to_string(),
+            bytes: 2_000_000,
+            timestamp: 1,
+        };
+        let raw = serde_json::to_string(&Stored::Overflow(pointer)).unwrap();
+        assert!(matches!(serde_json::from_str::<Stored>(&raw), Ok(Stored::Overflow(p)) if p.r2_key == "query:abc"));
+        assert!(serde_json::from_str::<Stored>(r#"{"something":"else"}"#).is_err());
+
+        let manifest = ChunkManifest {
+            chunks: 3,
+            bytes: 3 * CHUNK_BYTES - 10,
+            timestamp: 1700000000,
+        };
+        let raw = serde_json::to_string(&Stored::Chunked(manifest)).unwrap();
+        let Ok(Stored::Chunked(manifest)) = serde_json::from_str::<Stored>(&raw) else {
+            panic!("manifest didn't round-trip");
+        };
+        assert_eq!(
+            manifest.chunk_keys("query:abc"),
+            vec!["chunk:query:abc:1700000000:0", "chunk:query:abc:1700000000:1", "chunk:query:abc:1700000000:2"]
+        );
+    }
+}
@@ -0,0 +1,456 @@
+// ABOUTME: Cache sync for read mirrors: the primary serves signed manifests and entries of its hot cache,
+// ABOUTME: and a mirror deployment copies what changed on each cron run instead of asking the relay itself
+
+use crate::admin::constant_time_eq;
+use crate::cache::{now_seconds, Cache};
+use crate::outbound::{self, Policy};
+use crate::router::{json_response, unversioned};
+use crate::types::ErrorResponse;
+use crate::webhooks;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use worker::kv::KvStore;
+use worker::*;
+
+/// KV prefixes worth mirroring: query results and parsed profiles
+pub const PREFIXES: [&str; 2] = ["query:", "profile:"];
+
+/// Largest manifest page, and most keys per entries request
+pub const MAX_PAGE: u64 = 100;
+
+/// Signed documents older than this are refused, so a captured one can't be replayed
+const MAX_SIGNATURE_AGE_SECONDS: u64 = 300;
+
+/// Tolerated clock skew between primary and mirror
+const MAX_CLOCK_SKEW_SECONDS: u64 = 60;
+
+/// Manifest pages a mirror walks per cron run
+const MAX_PAGES_PER_RUN: usize = 10;
+
+/// KV reads and writes a mirror spends per cron run. A manifest page costs up to
+/// one read and one write per entry.
+const MAX_OPS_PER_RUN: usize = 1000;
+
+/// KV key holding where the mirror's walk of the primary's keys stopped
+const WALK_KEY: &str = "cachesync:walk";
+
+/// Entries expiring sooner than this aren't worth copying
+const MIN_REMAINING_SECONDS: u64 = 60;
+
+/// A payload and the primary's signature over it. The payload stays a string so
+/// the mirror checks exactly the bytes that were signed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Signed {
+    pub timestamp: u64,
+    /// `sha256=<hex>` HMAC of `{timestamp}.{payload}` with `CACHE_SYNC_SECRET`
+    pub signature: String,
+    pub payload: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub key: String,
+    /// Hex SHA-256 of the stored value
+    pub hash: String,
+    /// Unix time the primary's copy expires
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiration: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+    /// Next page of the same prefix, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncedEntry {
+    pub key: String,
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiration: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entries {
+    pub entries: Vec<SyncedEntry>,
+}
+
+/// Where a mirror's walk of the primary's keys carries on next run
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Walk {
+    /// Index into `PREFIXES`
+    pub prefix: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+}
+
+impl Walk {
+    pub fn prefix(&self) -> &'static str {
+        PREFIXES[self.prefix % PREFIXES.len()]
+    }
+
+    /// Past a manifest page: on to its next page, or to the start of the next
+    /// prefix, going round again after the last
+    pub fn advance(self, next: Option<String>) -> Self {
+        match next {
+            Some(cursor) => Self {
+                cursor: Some(cursor),
+                ..self
+            },
+            None => Self {
+                prefix: (self.prefix + 1) % PREFIXES.len(),
+                cursor: None,
+            },
+        }
+    }
+}
+
+pub fn is_syncable(key: &str) -> bool {
+    PREFIXES.iter().any(|p| key.starts_with(p))
+}
+
+pub fn content_hash(value: &str) -> String {
+    hex::encode(Sha256::digest(value.as_bytes()))
+}
+
+pub fn sign<T: Serialize>(secret: &str, data: &T, now: u64) -> serde_json::Result<Signed> {
+    let payload = serde_json::to_string(data)?;
+    Ok(Signed {
+        timestamp: now,
+        signature: webhooks::sign(secret, now, &payload),
+        payload,
+    })
+}
+
+/// The payload of a document signed with `secret` within the last few minutes
+pub fn verify<T>(secret: &str, signed: &Signed, now: u64) -> std::result::Result<T, String>
+where
+    T: serde::de::DeserializeOwned,
+{
+    if now.saturating_sub(signed.timestamp) > MAX_SIGNATURE_AGE_SECONDS
+        || signed.timestamp > now + MAX_CLOCK_SKEW_SECONDS
+    {
+        return Err("signature expired".to_string());
+    }
+    let expected = webhooks::sign(secret, signed.timestamp, &signed.payload);
+    if !constant_time_eq(expected.as_bytes(), signed.signature.as_bytes()) {
+        return Err("bad signature".to_string());
+    }
+    serde_json::from_str(&signed.payload).map_err(|e| e.to_string())
+}
+
+/// Keys whose local copy is missing or differs and that still have time to live.
+/// `local` holds the hash of each entry's local value, in manifest order.
+pub fn stale_keys(entries: &[ManifestEntry], local: &[Option<String>], now: u64) -> Vec<String> {
+    entries
+        .iter()
+        .zip(local)
+        .filter(|(entry, local)| local.as_deref() != Some(entry.hash.as_str()))
+        .filter(|(entry, _)| entry.expiration.map_or(true, |exp| exp >= now + MIN_REMAINING_SECONDS))
+        .filter(|(entry, _)| is_syncable(&entry.key))
+        .map(|(entry, _)| entry.key.clone())
+        .collect()
+}
+
+/// Shared secret of a primary and its mirrors; without it there is no sync API
+fn secret(env: &Env) -> Option<String> {
+    env.secret("CACHE_SYNC_SECRET")
+        .ok()
+        .map(|s| s.to_string())
+        .filter(|s| !s.is_empty())
+}
+
+/// `GET /sync/manifest`, `GET /sync/hot` and `GET /sync/entries` on the primary, for mirrors
+/// holding the shared secret as a bearer token
+pub async fn handle(req: Request, env: Env) -> Result<Response> {
+    let Some(secret) = secret(&env) else {
+        return json_response(&ErrorResponse::new("not_found").with_detail("endpoint not found"), 404);
+    };
+    let provided = req.headers().get("Authorization")?.unwrap_or_default();
+    let token = provided.strip_prefix("Bearer ").unwrap_or_default();
+    if !constant_time_eq(token.as_bytes(), secret.as_bytes()) {
+        let err = ErrorResponse::new("unauthorized").with_detail("invalid sync token");
+        return json_response(&err, 401);
+    }
+
+    let url = req.url()?;
+    let params: std::collections::HashMap<_, _> = url.query_pairs().collect();
+    let kv = env.kv("REST_GATEWAY_CACHE")?;
+    let cache = Cache::from_env(&env)?;
+    let now = now_seconds();
+    let signed = match (req.method(), unversioned(url.path())) {
+        (Method::Get, "/sync/manifest") => {
+            let prefix = params.get("prefix").map(|p| p.as_ref()).unwrap_or(PREFIXES[0]);
+            if !PREFIXES.contains(&prefix) {
+                let err = ErrorResponse::new("invalid_request").with_detail("prefix must be query: or profile:");
+                return json_response(&err, 400);
+            }
+            let cursor = params.get("cursor").map(|c| c.to_string());
+            sign(&secret, &manifest_page(&kv, &cache, prefix, cursor).await?, now)?
+        }
+        (Method::Get, "/sync/hot") => sign(&secret, &hot_manifest(&env, &kv, &cache).await?, now)?,
+        (Method::Get, "/sync/entries") => {
+            let keys: Vec<&str> = params
+                .get("keys")
+                .map(|k| k.split(',').filter(|k| is_syncable(k)).take(MAX_PAGE as usize).collect())
+                .unwrap_or_default();
+            sign(&secret, &read_entries(&kv, &cache, &keys).await?, now)?
+        }
+        _ => {
+            let err = ErrorResponse::new("not_found").with_detail("endpoint not found");
+            return json_response(&err, 404);
+        }
+    };
+    json_response(&signed, 200)
+}
+
+/// The value a key stands for, following a pointer into R2 or a chunk manifest,
+/// so mirrors are sent and compare the result itself
+async fn resolved(cache: &Cache, key: &str) -> Option<String> {
+    cache.get_stored(key).await.ok().flatten().map(|(_, body)| body)
+}
+
+async fn manifest_page(kv: &KvStore, cache: &Cache, prefix: &str, cursor: Option<String>) -> Result<Manifest> {
+    let mut list = kv.list().prefix(prefix.to_string()).limit(MAX_PAGE);
+    if let Some(cursor) = cursor {
+        list = list.cursor(cursor);
+    }
+    let page = list.execute().await?;
+    let values = futures_util::future::join_all(page.keys.iter().map(|k| resolved(cache, &k.name))).await;
+    let entries = page
+        .keys
+        .iter()
+        .zip(values)
+        .filter_map(|(key, value)| {
+            Some(ManifestEntry {
+                key: key.name.clone(),
+                hash: content_hash(&value?),
+                expiration: key.expiration,
+            })
+        })
+        .collect();
+    Ok(Manifest {
+        entries,
+        cursor: if page.list_complete { None } else { page.cursor },
+    })
+}
+
+/// The most requested syncable entries, hottest first, as a single manifest page
+async fn hot_manifest(env: &Env, kv: &KvStore, cache: &Cache) -> Result<Manifest> {
+    let keys = crate::metrics::peek_hot_keys(env, MAX_PAGE as usize).await?;
+    let keys: Vec<&str> = keys.iter().map(String::as_str).filter(|k| is_syncable(k)).collect();
+    let entries = read_entries(kv, cache, &keys)
+        .await?
+        .entries
+        .into_iter()
+        .map(|entry| ManifestEntry {
+            hash: content_hash(&entry.value),
+            key: entry.key,
+            expiration: entry.expiration,
+        })
+        .collect();
+    Ok(Manifest { entries, cursor: None })
+}
+
+async fn read_entries(kv: &KvStore, cache: &Cache, keys: &[&str]) -> Result<Entries> {
+    use futures_util::future::{join, join_all};
+    let expirations = join_all(keys.iter().map(|k| kv.list().prefix(k.to_string()).limit(1).execute()));
+    let values = join_all(keys.iter().map(|k| resolved(cache, k)));
+    let (expirations, values) = join(expirations, values).await;
+    let entries = keys
+        .iter()
+        .zip(expirations)
+        .zip(values)
+        .filter_map(|((key, listed), value)| {
+            // A key sorts before every longer key sharing it as a prefix
+            let expiration = listed.ok()?.keys.into_iter().find(|k| k.name == *key)?.expiration;
+            Some(SyncedEntry {
+                key: key.to_string(),
+                value: value?,
+                expiration,
+            })
+        })
+        .collect();
+    Ok(Entries { entries })
+}
+
+/// A mirror deployment: `CACHE_SYNC_PRIMARY` names the primary's base URL
+#[derive(Debug, Clone, PartialEq)]
+pub struct MirrorSync {
+    pub primary: Url,
+    pub secret: String,
+}
+
+impl MirrorSync {
+    pub fn from_env(env: &Env) -> Option<Self> {
+        let primary = env.var("CACHE_SYNC_PRIMARY").ok().and_then(|v| Url::parse(&v.to_string()).ok())?;
+        Some(Self {
+            primary,
+            secret: secret(env)?,
+        })
+    }
+
+    async fn fetch<T: serde::de::DeserializeOwned>(&self, path: &str, query: &[(&str, &str)]) -> Result<T> {
+        let mut url = self.primary.join(path)?;
+        url.query_pairs_mut().extend_pairs(query);
+        let mut headers = Headers::new();
+        headers.set("Authorization", &format!("Bearer {}", self.secret))?;
+        let policy = Policy {
+            max_bytes: 16 * 1024 * 1024,
+            timeout: Duration::from_secs(15),
+            ..Policy::default()
+        };
+        let fetched = outbound::get(url, &headers, &policy).await?;
+        if fetched.status != 200 {
+            return Err(Error::RustError(format!("primary answered {} for {}", fetched.status, path)));
+        }
+        let signed: Signed = fetched.json()?;
+        verify(&self.secret, &signed, now_seconds()).map_err(Error::RustError)
+    }
+}
+
+/// Copy new and changed entries from the primary, the most requested first, then
+/// carrying on through the rest of its keys where the last run stopped. Called
+/// from the cron trigger on mirrors; returns how many entries were written.
+pub async fn pull(env: &Env, sync: &MirrorSync) -> Result<usize> {
+    let kv = env.kv("REST_GATEWAY_CACHE")?;
+    let cache = Cache::from_env(env)?;
+    let mut budget = MAX_OPS_PER_RUN;
+
+    let hot: Manifest = sync.fetch("/sync/hot", &[]).await?;
+    let mut written = sync_page(&kv, &cache, sync, &hot.entries, &mut budget).await?;
+
+    let mut walk = kv.get(WALK_KEY).json::<Walk>().await.ok().flatten().unwrap_or_default();
+    // A page is only started with enough budget left to finish it, so the walk never skips keys
+    for _ in 0..MAX_PAGES_PER_RUN {
+        if budget < 2 * MAX_PAGE as usize {
+            break;
+        }
+        let mut query = vec![("prefix", walk.prefix())];
+        if let Some(cursor) = &walk.cursor {
+            query.push(("cursor", cursor.as_str()));
+        }
+        let manifest: Manifest = sync.fetch("/sync/manifest", &query).await?;
+        written += sync_page(&kv, &cache, sync, &manifest.entries, &mut budget).await?;
+        walk = walk.advance(manifest.cursor);
+    }
+    kv.put(WALK_KEY, serde_json::to_string(&walk)?)?.execute().await?;
+    Ok(written)
+}
+
+/// Fetch and store the entries of a manifest page that are missing or differ
+/// locally, taking the KV operations spent from `budget`
+async fn sync_page(
+    kv: &KvStore,
+    cache: &Cache,
+    sync: &MirrorSync,
+    manifest: &[ManifestEntry],
+    budget: &mut usize,
+) -> Result<usize> {
+    let manifest = &manifest[..manifest.len().min(*budget)];
+    let local = futures_util::future::join_all(manifest.iter().map(|e| resolved(cache, &e.key))).await;
+    *budget -= manifest.len();
+    let local: Vec<Option<String>> = local.into_iter().map(|v| v.map(|v| content_hash(&v))).collect();
+    let mut stale = stale_keys(manifest, &local, now_seconds());
+    stale.truncate(*budget);
+    if stale.is_empty() {
+        return Ok(0);
+    }
+    *budget -= stale.len();
+    let keys = stale.join(",");
+    let entries: Entries = sync.fetch("/sync/entries", &[("keys", keys.as_str())]).await?;
+    Ok(store(kv, manifest, entries).await)
+}
+
+/// Write fetched entries whose content matches the manifest, keeping the primary's expiry
+async fn store(kv: &KvStore, manifest: &[ManifestEntry], entries: Entries) -> usize {
+    let now = now_seconds();
+    let mut written = 0;
+    for entry in entries.entries {
+        let listed = manifest.iter().find(|m| m.key == entry.key);
+        if !listed.is_some_and(|m| m.hash == content_hash(&entry.value)) || !is_syncable(&entry.key) {
+            continue;
+        }
+        let put = match kv.put(&entry.key, entry.value) {
+            Ok(put) => put,
+            Err(e) => {
+                console_log!("Cache sync write of {} failed: {}", entry.key, e);
+                continue;
+            }
+        };
+        let put = match entry.expiration {
+            Some(exp) if exp >= now + MIN_REMAINING_SECONDS => put.expiration(exp),
+            Some(_) => continue,
+            None => put,
+        };
+        match put.execute().await {
+            Ok(()) => written += 1,
+            Err(e) => console_log!("Cache sync write of {} failed: {}", entry.key, e),
+        }
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn entry(key: &str, hash: &str, expiration: Option<u64>) -> ManifestEntry {
+        ManifestEntry {
+            key: key.to_string(),
+            hash: hash.to_string(),
+            expiration,
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let manifest = Manifest {
+            entries: vec![entry("query:abc", &content_hash("{}"), Some(NOW + 300))],
+            cursor: None,
+        };
+        let signed = sign("secret", &manifest, NOW).unwrap();
+        let verified: Manifest = verify("secret", &signed, NOW + 10).unwrap();
+        assert_eq!(verified.entries, manifest.entries);
+
+        assert!(verify::<Manifest>("other", &signed, NOW).is_err());
+        assert!(verify::<Manifest>("secret", &signed, NOW + MAX_SIGNATURE_AGE_SECONDS + 1).is_err());
+        let tampered = Signed {
+            payload: signed.payload.replace("query:abc", "query:abd"),
+            ..signed.clone()
+        };
+        assert!(verify::<Manifest>("secret", &tampered, NOW).is_err());
+    }
+
+    #[test]
+    fn test_stale_keys() {
+        let entries = vec![
+            entry("query:same", "h1", None),
+            entry("query:changed", "h2", Some(NOW + 600)),
+            entry("profile:missing", "h3", Some(NOW + 600)),
+            entry("query:expiring", "h4", Some(NOW + 10)),
+            entry("apikey:secret", "h5", None),
+            entry("chunk:query:big:1700000000:0", "h6", None),
+        ];
+        let local = vec![Some("h1".to_string()), Some("old".to_string()), None, None, None, None];
+        assert_eq!(stale_keys(&entries, &local, NOW), vec!["query:changed", "profile:missing"]);
+    }
+
+    #[test]
+    fn test_walk_goes_round_every_prefix() {
+        let walk = Walk::default();
+        assert_eq!(walk.prefix(), "query:");
+        let walk = walk.advance(Some("page2".to_string()));
+        assert_eq!((walk.prefix(), walk.cursor.as_deref()), ("query:", Some("page2")));
+        let walk = walk.advance(None);
+        assert_eq!((walk.prefix(), walk.cursor.as_deref()), ("profile:", None));
+        // After the last prefix the walk starts over
+        assert_eq!(walk.advance(None), Walk::default());
+    }
+}
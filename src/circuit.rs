@@ -0,0 +1,145 @@
+// ABOUTME: Circuit breaker the RelayPool keeps in front of its upstream relay, and its reconnect backoff
+// ABOUTME: Consecutive failed queries open it; while open, queries fail fast instead of waiting out timeouts
+
+use serde::Serialize;
+use std::time::Duration;
+
+/// Consecutive failed queries that open the circuit
+pub const FAILURE_THRESHOLD: u32 = 5;
+
+/// How long the circuit stays open before one query is let through to probe the relay
+pub const COOLDOWN_MS: f64 = 30_000.0;
+
+/// Error message `query_relay` fails with while the circuit is open
+pub const CIRCUIT_OPEN: &str = "relay circuit open";
+
+/// Header the RelayPool marks its fast-fail responses with
+pub const HEADER: &str = "X-Relay-Circuit";
+
+/// Longest the keepalive alarm waits between attempts to reconnect a dead relay connection
+const MAX_RECONNECT_SECONDS: u64 = 60;
+
+/// When the keepalive alarm tries again after `failures` failed reconnects in a
+/// row: one second, doubling each time, up to a minute
+pub fn reconnect_delay(failures: u32) -> Duration {
+    let seconds = 1u64.checked_shl(failures.saturating_sub(1)).unwrap_or(u64::MAX);
+    Duration::from_secs(seconds.min(MAX_RECONNECT_SECONDS))
+}
+
+pub fn is_circuit_open(e: &worker::Error) -> bool {
+    e.to_string().contains(CIRCUIT_OPEN)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    /// Cooldown over; the next query decides whether the circuit closes again
+    HalfOpen,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CircuitBreaker {
+    failures: u32,
+    opened_at: Option<f64>,
+}
+
+impl CircuitBreaker {
+    pub fn state(&self, now_ms: f64) -> CircuitState {
+        match self.opened_at {
+            None => CircuitState::Closed,
+            Some(at) if now_ms - at < COOLDOWN_MS => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Whether a query may go to the relay. A half-open circuit lets one through
+    /// and re-arms the cooldown, so concurrent callers don't all probe at once.
+    pub fn allow(&mut self, now_ms: f64) -> bool {
+        match self.state(now_ms) {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => {
+                self.opened_at = Some(now_ms);
+                true
+            }
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.failures = 0;
+        self.opened_at = None;
+    }
+
+    pub fn record_failure(&mut self, now_ms: f64) {
+        self.failures = self.failures.saturating_add(1);
+        if self.failures >= FAILURE_THRESHOLD || self.opened_at.is_some() {
+            self.opened_at = Some(now_ms);
+        }
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let mut breaker = CircuitBreaker::default();
+        for _ in 1..FAILURE_THRESHOLD {
+            breaker.record_failure(0.0);
+        }
+        assert_eq!(breaker.state(0.0), CircuitState::Closed);
+        breaker.record_success();
+        for _ in 1..FAILURE_THRESHOLD {
+            breaker.record_failure(0.0);
+        }
+        assert!(breaker.allow(0.0));
+
+        breaker.record_failure(1_000.0);
+        assert_eq!(breaker.state(1_000.0), CircuitState::Open);
+        assert!(!breaker.allow(2_000.0));
+    }
+
+    #[test]
+    fn test_half_open_probe() {
+        let mut breaker = CircuitBreaker::default();
+        for _ in 0..FAILURE_THRESHOLD {
+            breaker.record_failure(0.0);
+        }
+        let later = COOLDOWN_MS + 1.0;
+        assert_eq!(breaker.state(later), CircuitState::HalfOpen);
+        assert!(breaker.allow(later));
+        // Only one probe per cooldown
+        assert!(!breaker.allow(later + 1.0));
+
+        // A failed probe keeps it open for another cooldown
+        breaker.record_failure(later + 10.0);
+        assert_eq!(breaker.state(later + COOLDOWN_MS), CircuitState::Open);
+
+        let much_later = later + 10.0 + COOLDOWN_MS;
+        assert!(breaker.allow(much_later));
+        breaker.record_success();
+        assert_eq!(breaker.state(much_later), CircuitState::Closed);
+        assert_eq!(breaker.failures(), 0);
+    }
+
+    #[test]
+    fn test_reconnect_delay() {
+        assert_eq!(reconnect_delay(1), Duration::from_secs(1));
+        assert_eq!(reconnect_delay(3), Duration::from_secs(4));
+        assert_eq!(reconnect_delay(7), Duration::from_secs(MAX_RECONNECT_SECONDS));
+        assert_eq!(reconnect_delay(200), Duration::from_secs(MAX_RECONNECT_SECONDS));
+    }
+
+    #[test]
+    fn test_is_circuit_open() {
+        assert!(is_circuit_open(&worker::Error::RustError(CIRCUIT_OPEN.to_string())));
+        assert!(!is_circuit_open(&worker::Error::RustError("timeout".to_string())));
+    }
+}
@@ -0,0 +1,126 @@
+// ABOUTME: Operator-defined feeds stored in KV and served at /feeds/{name}
+// ABOUTME: Each route is a saved filter plus cache TTL and hydration options
+
+use crate::filter::{Filter, FilterError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use worker::kv::KvStore;
+use worker::*;
+
+/// KV prefix of stored route definitions
+pub const KEY_PREFIX: &str = "customroute:";
+
+/// Upper bound on `?limit=`, and on a route without its own limit
+const MAX_LIMIT: u64 = 100;
+
+/// Extra data attached to a feed response
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Hydration {
+    /// Include parsed profiles of the events' authors
+    #[serde(default)]
+    pub profiles: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CustomRoute {
+    pub name: String,
+    pub filter: Value,
+    /// Cache TTL in seconds; defaults to the kind-based TTL of the filter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    #[serde(default)]
+    pub hydrate: Hydration,
+    pub updated_at: u64,
+}
+
+impl CustomRoute {
+    /// The saved filter, narrowed by a client's `limit` and `until` parameters.
+    /// Clients can page and shrink a feed but never widen it.
+    pub fn request_filter(&self, params: &HashMap<String, String>) -> std::result::Result<Filter, FilterError> {
+        let mut filter = self.filter.clone();
+        let saved_limit = filter.get("limit").and_then(|v| v.as_u64()).unwrap_or(MAX_LIMIT);
+        let limit = params
+            .get("limit")
+            .and_then(|l| l.parse::<u64>().ok())
+            .map_or(saved_limit, |l| l.min(saved_limit))
+            .min(MAX_LIMIT);
+        filter["limit"] = limit.into();
+        if let Some(until) = params.get("until").and_then(|u| u.parse::<u64>().ok()) {
+            let saved_until = filter.get("until").and_then(|v| v.as_u64());
+            filter["until"] = saved_until.map_or(until, |s| s.min(until)).into();
+        }
+        Filter::from_json(&filter.to_string())
+    }
+}
+
+/// Route names are single lowercase path segments
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+pub async fn get(kv: &KvStore, name: &str) -> Result<Option<CustomRoute>> {
+    Ok(kv.get(&format!("{}{}", KEY_PREFIX, name)).json::<CustomRoute>().await?)
+}
+
+pub async fn put(kv: &KvStore, route: &CustomRoute) -> Result<()> {
+    kv.put(&format!("{}{}", KEY_PREFIX, route.name), serde_json::to_string(route)?)?
+        .execute()
+        .await?;
+    Ok(())
+}
+
+pub async fn delete(kv: &KvStore, name: &str) -> Result<()> {
+    kv.delete(&format!("{}{}", KEY_PREFIX, name)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn route(filter: Value) -> CustomRoute {
+        CustomRoute {
+            name: "official".to_string(),
+            filter,
+            ttl: None,
+            hydrate: Hydration::default(),
+            updated_at: 0,
+        }
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_request_filter_narrows_saved_filter() {
+        let feed = route(json!({"kinds": [34236], "authors": ["pk"], "limit": 30, "until": 1000}));
+        let filter = feed.request_filter(&params(&[("limit", "500"), ("until", "2000")])).unwrap();
+        let parsed: Value = serde_json::from_str(filter.as_json()).unwrap();
+        assert_eq!(parsed["limit"], 30);
+        assert_eq!(parsed["until"], 1000);
+
+        let filter = feed.request_filter(&params(&[("limit", "10"), ("until", "500")])).unwrap();
+        let parsed: Value = serde_json::from_str(filter.as_json()).unwrap();
+        assert_eq!(parsed["limit"], 10);
+        assert_eq!(parsed["until"], 500);
+
+        let unbounded = route(json!({"kinds": [1]}));
+        let parsed: Value = serde_json::from_str(unbounded.request_filter(&params(&[])).unwrap().as_json()).unwrap();
+        assert_eq!(parsed["limit"], MAX_LIMIT);
+    }
+
+    #[test]
+    fn test_valid_name() {
+        assert!(valid_name("official"));
+        assert!(valid_name("top-videos_2"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("Official"));
+        assert!(!valid_name("a/b"));
+        assert!(!valid_name(&"a".repeat(65)));
+    }
+}
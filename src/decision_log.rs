@@ -0,0 +1,152 @@
+// ABOUTME: Sampled, structured records of cache decisions written to the Workers log
+// ABOUTME: One compact JSON line per decision, so Logpush exports can be analysed offline
+
+use serde::Serialize;
+use worker::*;
+
+/// The cache a decision was made against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layer {
+    /// Query results in KV
+    Kv,
+    /// Rendered HTML in the per-colo Cache API
+    Edge,
+    /// The D1 event archive
+    Archive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Hit,
+    /// Served from cache although older than the TTL chosen now, while it is
+    /// refreshed in the background
+    Stale,
+    Miss,
+    /// The caller asked to skip the cache
+    Bypass,
+    /// Removed by a purge or deletion
+    Invalidated,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Decision {
+    pub layer: Layer,
+    pub key: String,
+    pub outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age: Option<u64>,
+    /// What fired an invalidation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trigger: Option<&'static str>,
+}
+
+impl Decision {
+    pub fn new(layer: Layer, key: impl Into<String>, outcome: Outcome) -> Self {
+        Self {
+            layer,
+            key: key.into(),
+            outcome,
+            ttl: None,
+            age: None,
+            trigger: None,
+        }
+    }
+
+    /// A lookup that found an entry `age` seconds old, judged against `ttl`
+    pub fn lookup_hit(layer: Layer, key: impl Into<String>, age: u64, ttl: u64) -> Self {
+        let outcome = if age > ttl { Outcome::Stale } else { Outcome::Hit };
+        Self::new(layer, key, outcome).with_ttl(ttl).with_age(age)
+    }
+
+    pub fn with_ttl(mut self, ttl: u64) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn with_age(mut self, age: u64) -> Self {
+        self.age = Some(age);
+        self
+    }
+
+    pub fn with_trigger(mut self, trigger: &'static str) -> Self {
+        self.trigger = Some(trigger);
+        self
+    }
+
+    /// The log line: a `type` marker and timestamp ahead of the decision fields
+    pub fn to_line(&self, ts_ms: u64) -> String {
+        #[derive(Serialize)]
+        struct Line<'a> {
+            #[serde(rename = "type")]
+            kind: &'static str,
+            ts: u64,
+            #[serde(flatten)]
+            decision: &'a Decision,
+        }
+        let line = Line {
+            kind: "cache_decision",
+            ts: ts_ms,
+            decision: self,
+        };
+        serde_json::to_string(&line).unwrap_or_default()
+    }
+}
+
+/// Fraction of decisions logged, from `CACHE_LOG_SAMPLE_RATE` (default 0, off)
+pub fn sample_rate(env: &Env) -> f64 {
+    env.var("CACHE_LOG_SAMPLE_RATE")
+        .ok()
+        .and_then(|v| v.to_string().parse::<f64>().ok())
+        .filter(|r| r.is_finite())
+        .unwrap_or(0.0)
+        .clamp(0.0, 1.0)
+}
+
+/// Log the decision if it falls in the sample
+pub fn record(env: &Env, decision: &Decision) {
+    let rate = sample_rate(env);
+    if rate > 0.0 && js_sys::Math::random() < rate {
+        console_log!("{}", decision.to_line(js_sys::Date::now() as u64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_line_format() {
+        let decision = Decision::lookup_hit(Layer::Kv, "query:abc", 30, 60);
+        let line: Value = serde_json::from_str(&decision.to_line(1_700_000_000_000)).unwrap();
+        assert_eq!(
+            line,
+            json!({
+                "type": "cache_decision",
+                "ts": 1_700_000_000_000u64,
+                "layer": "kv",
+                "key": "query:abc",
+                "outcome": "hit",
+                "ttl": 60,
+                "age": 30
+            })
+        );
+
+        let purge = Decision::new(Layer::Edge, "https://h/__html/embed/v1/x", Outcome::Invalidated);
+        let purge = purge.with_trigger("deletion");
+        let line: Value = serde_json::from_str(&purge.to_line(1)).unwrap();
+        assert_eq!(line["outcome"], "invalidated");
+        assert_eq!(line["trigger"], "deletion");
+        assert!(line.get("ttl").is_none());
+    }
+
+    #[test]
+    fn test_stale_hit() {
+        assert_eq!(Decision::lookup_hit(Layer::Kv, "k", 61, 60).outcome, Outcome::Stale);
+        assert_eq!(Decision::lookup_hit(Layer::Kv, "k", 60, 60).outcome, Outcome::Hit);
+    }
+}
@@ -0,0 +1,89 @@
+// ABOUTME: Operator-set degraded mode for relay maintenance windows
+// ABOUTME: While on, reads come only from the caches and archive, and publishes are turned away
+
+use serde::{Deserialize, Serialize};
+use worker::kv::KvStore;
+use worker::*;
+
+const KV_KEY: &str = "degraded";
+
+/// Edge-cached reads of the flag; toggles take up to this long to reach every colo
+const FLAG_CACHE_SECONDS: u64 = 60;
+
+/// Suggested retry delay when the operator doesn't give one
+const DEFAULT_RETRY_AFTER_SECONDS: u32 = 300;
+
+/// Error message `query_relay` fails with while degraded. The router turns it into a 503.
+pub const RELAY_UNAVAILABLE: &str = "relay unavailable in degraded mode";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DegradedMode {
+    pub reason: String,
+    pub since: u64,
+    /// Seconds clients are told to wait before retrying
+    #[serde(default = "default_retry_after")]
+    pub retry_after: u32,
+}
+
+fn default_retry_after() -> u32 {
+    DEFAULT_RETRY_AFTER_SECONDS
+}
+
+impl DegradedMode {
+    pub fn new(reason: Option<String>, retry_after: Option<u32>, since: u64) -> Self {
+        Self {
+            reason: reason.filter(|r| !r.is_empty()).unwrap_or_else(|| "relay maintenance".to_string()),
+            since,
+            retry_after: retry_after.unwrap_or(DEFAULT_RETRY_AFTER_SECONDS),
+        }
+    }
+}
+
+/// The current mode, or None when operating normally. A failed lookup counts as normal.
+pub async fn get(env: &Env) -> Option<DegradedMode> {
+    let kv = env.kv("REST_GATEWAY_CACHE").ok()?;
+    match kv.get(KV_KEY).cache_ttl(FLAG_CACHE_SECONDS).json::<DegradedMode>().await {
+        Ok(mode) => mode,
+        Err(e) => {
+            console_log!("Degraded mode lookup failed: {}", e);
+            None
+        }
+    }
+}
+
+pub async fn set(kv: &KvStore, mode: &DegradedMode) -> Result<()> {
+    kv.put(KV_KEY, serde_json::to_string(mode)?)?.execute().await?;
+    Ok(())
+}
+
+pub async fn clear(kv: &KvStore) -> Result<()> {
+    kv.delete(KV_KEY).await?;
+    Ok(())
+}
+
+/// Whether an error is a relay read refused because of degraded mode
+pub fn is_relay_unavailable(error: &Error) -> bool {
+    matches!(error, Error::RustError(message) if message == RELAY_UNAVAILABLE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_defaults() {
+        let mode = DegradedMode::new(None, None, 100);
+        assert_eq!(mode.reason, "relay maintenance");
+        assert_eq!(mode.retry_after, DEFAULT_RETRY_AFTER_SECONDS);
+
+        let mode = DegradedMode::new(Some("upgrading relay".to_string()), Some(60), 100);
+        assert_eq!(mode.reason, "upgrading relay");
+        assert_eq!(mode.retry_after, 60);
+    }
+
+    #[test]
+    fn test_is_relay_unavailable() {
+        assert!(is_relay_unavailable(&Error::RustError(RELAY_UNAVAILABLE.to_string())));
+        assert!(!is_relay_unavailable(&Error::RustError("boom".to_string())));
+    }
+}
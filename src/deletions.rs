@@ -0,0 +1,382 @@
+// ABOUTME: NIP-09 deletions (kind 5) seen by the gateway, so it stops serving what authors deleted
+// ABOUTME: A Durable Object owns the recent deletions index and mirrors it to KV, which every request reads once
+
+use crate::archive::Archive;
+use crate::cache::{now_seconds, Cache};
+use crate::decision_log::{self, Decision, Layer, Outcome};
+use crate::filter::Filter;
+use crate::html_cache::deletion_targets;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+use worker::kv::KvStore;
+use worker::*;
+
+/// KV key of the recent deletions index, also its key in the Durable Object's storage
+const RECENT_KEY: &str = "deletions:recent";
+
+/// Name of the one `DeletionIndex` Durable Object
+const INDEX_NAME: &str = "global";
+
+/// How long the index waits after a change before copying itself to KV, so a
+/// burst of deletions is one KV write
+const MIRROR_DELAY: Duration = Duration::from_secs(1);
+
+/// KV prefix of per-event deletion records, followed by the deleted event's id
+const RECORD_PREFIX: &str = "deleted:";
+
+/// How long a deletion stays in the index: longer than a cached entry lives
+/// with the default TTLs, after which no cached copy of the event is left
+const RECENT_WINDOW_SECONDS: u64 = 2 * 86400;
+
+/// Most deletions the index holds; past this the oldest go first
+const MAX_RECENT: usize = 2000;
+
+/// How long `/event/{id}` keeps answering 410 for a deleted event
+const RECORD_TTL_SECONDS: u64 = 90 * 86400;
+
+/// Most targets taken from one deletion event, as each costs several KV and
+/// archive operations; the rest of a longer list is ignored
+const MAX_TARGETS_PER_DELETION: usize = 20;
+
+/// The index is read on every query, so reads are edge-cached; a deletion takes
+/// up to this long to reach every colo
+const READ_CACHE_SECONDS: u64 = 60;
+
+/// Recently deleted events, as `{event id}:{author}` to when the deletion was seen.
+/// Keyed by the pair, so only the event's own author can delete it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Deletions {
+    entries: HashMap<String, u64>,
+}
+
+impl Deletions {
+    pub fn insert(&mut self, event_id: &str, pubkey: &str, now: u64) {
+        self.entries.insert(entry_key(event_id, pubkey), now);
+    }
+
+    /// Drop deletions older than the window, then the oldest past the cap
+    pub fn prune(&mut self, now: u64) {
+        self.entries.retain(|_, seen| now.saturating_sub(*seen) <= RECENT_WINDOW_SECONDS);
+        if self.entries.len() > MAX_RECENT {
+            let mut seen: Vec<u64> = self.entries.values().copied().collect();
+            seen.sort_unstable_by(|a, b| b.cmp(a));
+            let cutoff = seen[MAX_RECENT - 1];
+            self.entries.retain(|_, seen| *seen >= cutoff);
+        }
+    }
+
+    pub fn is_deleted(&self, event: &Value) -> bool {
+        let field = |name: &str| event.get(name).and_then(Value::as_str);
+        match (field("id"), field("pubkey")) {
+            (Some(id), Some(pubkey)) => self.entries.contains_key(&entry_key(id, pubkey)),
+            _ => false,
+        }
+    }
+
+    /// Remove deleted events, returning how many there were
+    pub fn strip(&self, events: &mut Vec<Value>) -> usize {
+        if self.entries.is_empty() {
+            return 0;
+        }
+        let before = events.len();
+        events.retain(|event| !self.is_deleted(event));
+        before - events.len()
+    }
+}
+
+fn entry_key(event_id: &str, pubkey: &str) -> String {
+    format!("{}:{}", event_id.to_ascii_lowercase(), pubkey.to_ascii_lowercase())
+}
+
+/// A deletion of one event, kept under `deleted:{id}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeletionRecord {
+    /// Who asked for the deletion, which only counts if they wrote the event
+    pub pubkey: String,
+    pub deletion_id: String,
+    pub deleted_at: u64,
+    /// The gateway has seen the event and it is theirs
+    pub confirmed: bool,
+}
+
+impl DeletionRecord {
+    /// Whether this deletion applies to `event`
+    pub fn covers(&self, event: &Value) -> bool {
+        event
+            .get("pubkey")
+            .and_then(Value::as_str)
+            .is_some_and(|author| author.eq_ignore_ascii_case(&self.pubkey))
+    }
+}
+
+/// The recent deletions index. A failed read is logged and treated as empty:
+/// deleted events then go on being served until it can be read again.
+async fn recent(env: &Env) -> Deletions {
+    let read = async {
+        let kv = env.kv("REST_GATEWAY_CACHE")?;
+        Ok::<_, Error>(kv.get(RECENT_KEY).cache_ttl(READ_CACHE_SECONDS).json::<Deletions>().await?)
+    };
+    match read.await {
+        Ok(deletions) => deletions.unwrap_or_default(),
+        Err(e) => {
+            console_log!("Reading recent deletions failed: {}", e);
+            Deletions::default()
+        }
+    }
+}
+
+/// The recent deletions index as one request sees it: read on first use, then shared
+/// by every lookup the request makes
+#[derive(Debug, Default)]
+pub struct RequestDeletions(OnceCell<Rc<Deletions>>);
+
+impl RequestDeletions {
+    pub async fn get(&self, env: &Env) -> Rc<Deletions> {
+        if let Some(deletions) = self.0.get() {
+            return deletions.clone();
+        }
+        let deletions = Rc::new(recent(env).await);
+        self.0.get_or_init(|| deletions).clone()
+    }
+}
+
+/// A deleted event as added to the index
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct IndexEntry {
+    event_id: String,
+    pubkey: String,
+}
+
+/// Durable Object owning the recent deletions index. Additions are applied one
+/// request at a time, so concurrent deletions can't overwrite each other, and the
+/// result is copied to KV for readers.
+#[durable_object]
+pub struct DeletionIndex {
+    state: State,
+    env: Env,
+}
+
+impl DurableObject for DeletionIndex {
+    fn new(state: State, env: Env) -> Self {
+        Self { state, env }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        match (req.method(), req.path().as_str()) {
+            (Method::Post, "/add") => {
+                let added: Vec<IndexEntry> = req.json().await?;
+                let storage = self.state.storage();
+                let mut index = match storage.get::<Deletions>(RECENT_KEY).await.ok().flatten() {
+                    Some(index) => index,
+                    // The KV copy seeds an index that has nothing stored yet
+                    None => {
+                        let kv = self.env.kv("REST_GATEWAY_CACHE")?;
+                        kv.get(RECENT_KEY).json::<Deletions>().await?.unwrap_or_default()
+                    }
+                };
+                let now = now_seconds();
+                for entry in &added {
+                    index.insert(&entry.event_id, &entry.pubkey, now);
+                }
+                index.prune(now);
+                storage.put(RECENT_KEY, &index).await?;
+                // A pending alarm is left as it is, so a steady stream of deletions
+                // can't keep pushing the copy back
+                if storage.get_alarm().await?.is_none() {
+                    storage.set_alarm(MIRROR_DELAY).await?;
+                }
+                Response::empty()
+            }
+            _ => Response::error("not found", 404),
+        }
+    }
+
+    /// Copy the index to KV. Alarms don't overlap, and a change made meanwhile sets
+    /// another, so the copy always ends up matching storage.
+    async fn alarm(&self) -> Result<Response> {
+        let index = self.state.storage().get::<Deletions>(RECENT_KEY).await?.unwrap_or_default();
+        self.env
+            .kv("REST_GATEWAY_CACHE")?
+            .put(RECENT_KEY, serde_json::to_string(&index)?)?
+            .expiration_ttl(RECENT_WINDOW_SECONDS)
+            .execute()
+            .await?;
+        Response::empty()
+    }
+}
+
+async fn add_to_index(env: &Env, added: &[IndexEntry]) -> Result<()> {
+    let req = Request::new_with_init(
+        "http://do/add",
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_body(Some(serde_json::to_string(added)?.into())),
+    )?;
+    let stub = env.durable_object("DELETIONS")?.id_from_name(INDEX_NAME)?.get_stub()?;
+    let resp = stub.fetch_with_request(req).await?;
+    if resp.status_code() != 200 {
+        return Err(Error::from(format!("deletion index answered {}", resp.status_code())));
+    }
+    Ok(())
+}
+
+/// The deletion of an event, if one is known
+pub async fn record_for(kv: &KvStore, event_id: &str) -> Result<Option<DeletionRecord>> {
+    let key = format!("{}{}", RECORD_PREFIX, event_id.to_ascii_lowercase());
+    Ok(kv.get(&key).json::<DeletionRecord>().await?)
+}
+
+/// Take note of kind 5 events: index their targets, record them for `/event/{id}`,
+/// and purge their cached lookups and archive rows. A target the gateway knows
+/// was written by someone else is left alone, as is a deletion whose id or
+/// signature doesn't check out. Each target is handled on its own, a failure
+/// logged without stopping the rest, and the index is updated once for all.
+pub async fn apply(env: &Env, cache: &Cache, deletions: &[Value]) -> Result<()> {
+    let kv = env.kv("REST_GATEWAY_CACHE")?;
+    let archive = Archive::from_env(env);
+    let now = now_seconds();
+    let index = recent(env).await;
+    let mut added: Vec<IndexEntry> = Vec::new();
+
+    for deletion in deletions {
+        let field = |name: &str| deletion.get(name).and_then(Value::as_str).unwrap_or_default().to_ascii_lowercase();
+        let (pubkey, deletion_id) = (field("pubkey"), field("id"));
+        if pubkey.is_empty() {
+            continue;
+        }
+        // Whoever handed it over, a deletion only counts if its author signed it
+        if !crate::auth::verify_event(deletion) {
+            console_log!("Ignoring deletion {} with an invalid id or signature", deletion_id);
+            continue;
+        }
+        let targets = deletion_targets(deletion);
+        for target in targets.iter().take(MAX_TARGETS_PER_DELETION).map(|t| t.to_ascii_lowercase()) {
+            // Relays hand back the same deletions query after query
+            let entry = IndexEntry {
+                event_id: target,
+                pubkey: pubkey.clone(),
+            };
+            if index.entries.contains_key(&entry_key(&entry.event_id, &pubkey)) || added.contains(&entry) {
+                continue;
+            }
+            let record = DeletionRecord {
+                pubkey: pubkey.clone(),
+                deletion_id: deletion_id.clone(),
+                deleted_at: now,
+                confirmed: false,
+            };
+            match apply_target(env, cache, &kv, archive.as_ref(), &entry.event_id, record).await {
+                Ok(true) => added.push(entry),
+                Ok(false) => {}
+                Err(e) => console_log!("Applying deletion of {} failed: {}", entry.event_id, e),
+            }
+        }
+    }
+
+    if added.is_empty() {
+        return Ok(());
+    }
+    add_to_index(env, &added).await
+}
+
+/// Apply one target of a deletion, returning whether it belongs in the index. Once
+/// the target is known to be deletable, the record, purge and archive delete are
+/// each attempted regardless of the others.
+async fn apply_target(
+    env: &Env,
+    cache: &Cache,
+    kv: &KvStore,
+    archive: Option<&Archive>,
+    target: &str,
+    mut record: DeletionRecord,
+) -> Result<bool> {
+    let lookup = Filter::note(target);
+    let cache_key = lookup.cache_key();
+    let mut known = cache.get_stored(&cache_key).await?.and_then(|(cached, _)| cached.events.into_iter().next());
+    if known.is_none() {
+        if let Some(archive) = archive {
+            known = archive.query(&lookup).await?.into_iter().next();
+        }
+    }
+    record.confirmed = known.is_some();
+    if known.as_ref().is_some_and(|event| !record.covers(event)) {
+        return Ok(false);
+    }
+
+    let recorded = async {
+        // A confirmed record isn't replaced by one that may not hold
+        if !record_for(kv, target).await?.is_some_and(|existing| existing.confirmed) {
+            kv.put(&format!("{}{}", RECORD_PREFIX, target), serde_json::to_string(&record)?)?
+                .expiration_ttl(RECORD_TTL_SECONDS)
+                .execute()
+                .await?;
+        }
+        Ok::<_, Error>(())
+    };
+    if let Err(e) = recorded.await {
+        console_log!("Recording deletion of {} failed: {}", target, e);
+    }
+    match cache.delete_key(&cache_key).await {
+        Ok(()) => {
+            let decision = Decision::new(Layer::Kv, cache_key, Outcome::Invalidated).with_trigger("deletion");
+            decision_log::record(env, &decision);
+        }
+        Err(e) => console_log!("Purging {} after deletion failed: {}", cache_key, e),
+    }
+    if let Some(archive) = archive {
+        if let Err(e) = archive.delete_event(target, &record.pubkey).await {
+            console_log!("Archive delete of {} failed: {}", target, e);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_strip() {
+        let mut deletions = Deletions::default();
+        deletions.insert("AA", "pk1", 100);
+        let mut events = vec![
+            json!({"id": "aa", "pubkey": "pk1"}),
+            json!({"id": "aa", "pubkey": "pk2"}),
+            json!({"id": "bb", "pubkey": "pk1"}),
+        ];
+        // Only the author's own deletion counts
+        assert_eq!(deletions.strip(&mut events), 1);
+        assert_eq!(events.len(), 2);
+        assert!(!deletions.is_deleted(&json!({"id": "aa"})));
+    }
+
+    #[test]
+    fn test_prune() {
+        let mut deletions = Deletions::default();
+        deletions.insert("old", "pk", 0);
+        for i in 0..MAX_RECENT + 5 {
+            deletions.insert(&format!("{:x}", i), "pk", RECENT_WINDOW_SECONDS + i as u64);
+        }
+        deletions.prune(RECENT_WINDOW_SECONDS + MAX_RECENT as u64 + 5);
+        assert_eq!(deletions.entries.len(), MAX_RECENT);
+        assert!(!deletions.is_deleted(&json!({"id": "old", "pubkey": "pk"})));
+        assert!(!deletions.is_deleted(&json!({"id": "0", "pubkey": "pk"})));
+    }
+
+    #[test]
+    fn test_record_covers() {
+        let record = DeletionRecord {
+            pubkey: "abc".to_string(),
+            deletion_id: "dd".to_string(),
+            deleted_at: 1,
+            confirmed: false,
+        };
+        assert!(record.covers(&json!({"pubkey": "ABC"})));
+        assert!(!record.covers(&json!({"pubkey": "def"})));
+    }
+}
@@ -0,0 +1,304 @@
+// ABOUTME: HTML embed/preview rendering for shared event links
+// ABOUTME: Emits Open Graph and Twitter Card meta tags so chat apps can unfurl events
+
+use crate::branding::Branding;
+use crate::media::{media_entries, video_from_event};
+use crate::types::ProfileMetadata;
+use serde_json::Value;
+
+/// Longest description placed in meta tags; unfurlers truncate anyway
+const MAX_DESCRIPTION_CHARS: usize = 280;
+
+/// Bump whenever the rendered markup changes, to invalidate cached renders
+pub const TEMPLATE_VERSION: u32 = 2;
+
+/// Everything the embed template needs, extracted from the event and its author's profile
+#[derive(Debug, Default, PartialEq)]
+pub struct EmbedCard {
+    pub title: String,
+    pub description: String,
+    pub author_name: String,
+    pub author_picture: Option<String>,
+    pub image: Option<String>,
+    pub video: Option<String>,
+}
+
+impl EmbedCard {
+    pub fn from_event(event: &Value, profile: Option<&ProfileMetadata>) -> Self {
+        let pubkey = event.get("pubkey").and_then(|v| v.as_str()).unwrap_or_default();
+        let author_name = profile
+            .and_then(|p| p.best_name())
+            .map(String::from)
+            .unwrap_or_else(|| short_pubkey(pubkey));
+        let author_picture = profile.and_then(|p| p.picture.clone());
+        let content = event.get("content").and_then(|v| v.as_str()).unwrap_or_default();
+
+        if let Some(video) = video_from_event(event) {
+            let media = video.media.first();
+            return Self {
+                title: video.title.clone().unwrap_or_else(|| format!("Video by {}", author_name)),
+                description: truncate(video.summary.as_deref().unwrap_or_default()),
+                author_name,
+                author_picture,
+                image: media.and_then(|m| m.thumbnails.first().cloned()),
+                video: media.map(|m| m.url.clone()),
+            };
+        }
+
+        let image = media_entries(event)
+            .into_iter()
+            .find(|m| m.mime_type.as_deref().map(|t| t.starts_with("image/")).unwrap_or(true))
+            .map(|m| m.url)
+            .or_else(|| author_picture.clone());
+
+        Self {
+            title: format!("{} on Nostr", author_name),
+            description: truncate(content),
+            author_name,
+            author_picture,
+            image,
+            video: None,
+        }
+    }
+}
+
+/// Render the embed page. `canonical_url` is the gateway URL being shared.
+pub fn render_embed(card: &EmbedCard, canonical_url: &str, branding: &Branding) -> String {
+    let mut meta = vec![
+        meta_property("og:type", if card.video.is_some() { "video.other" } else { "article" }),
+        meta_property("og:title", &card.title),
+        meta_property("og:description", &card.description),
+        meta_property("og:url", canonical_url),
+        meta_property("og:site_name", &branding.site_name),
+        meta_name(
+            "twitter:card",
+            if card.video.is_some() {
+                "player"
+            } else if card.image.is_some() {
+                "summary_large_image"
+            } else {
+                "summary"
+            },
+        ),
+        meta_name("twitter:title", &card.title),
+        meta_name("twitter:description", &card.description),
+    ];
+    if let Some(image) = &card.image {
+        meta.push(meta_property("og:image", image));
+        meta.push(meta_name("twitter:image", image));
+    }
+    if let Some(video) = &card.video {
+        meta.push(meta_property("og:video", video));
+        meta.push(meta_name("twitter:player:stream", video));
+    }
+
+    let avatar = card
+        .author_picture
+        .as_ref()
+        .map(|p| format!(r#"<img class="avatar" src="{}" alt="">"#, escape_html(p)))
+        .unwrap_or_default();
+    let media = match (&card.video, &card.image) {
+        (Some(video), poster) => format!(
+            r#"<video controls playsinline src="{}"{}></video>"#,
+            escape_html(video),
+            poster
+                .as_ref()
+                .map(|p| format!(r#" poster="{}""#, escape_html(p)))
+                .unwrap_or_default()
+        ),
+        (None, Some(image)) if card.author_picture.as_ref() != Some(image) => {
+            format!(r#"<img class="media" src="{}" alt="">"#, escape_html(image))
+        }
+        _ => String::new(),
+    };
+    let logo = branding
+        .logo_url
+        .as_ref()
+        .map(|l| format!(r#"<img class="logo" src="{}" alt="">"#, escape_html(l)))
+        .unwrap_or_default();
+    let colors = &branding.colors;
+
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>{title}</title>
+    {meta}
+    <style>
+        body {{ font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', sans-serif; background: {background}; color: {text}; margin: 0; padding: 1rem; }}
+        .card {{ max-width: 560px; margin: 0 auto; background: {surface}; border: 1px solid {border}; border-radius: 8px; padding: 1rem; }}
+        .author {{ display: flex; align-items: center; gap: 0.5rem; font-weight: bold; color: #fff; }}
+        .avatar {{ width: 40px; height: 40px; border-radius: 50%; object-fit: cover; }}
+        .content {{ white-space: pre-wrap; word-wrap: break-word; line-height: 1.5; }}
+        .media, video {{ width: 100%; border-radius: 8px; margin-top: 0.5rem; }}
+        .brand {{ display: flex; align-items: center; gap: 0.4rem; margin-top: 0.75rem; font-size: 0.85em; color: {accent}; }}
+        .logo {{ height: 18px; }}
+    </style>
+</head>
+<body>
+    <div class="card">
+        <div class="author">{avatar}<span>{author}</span></div>
+        <p class="content">{description}</p>
+        {media}
+        <div class="brand">{logo}<span>{site_name}</span></div>
+    </div>
+</body>
+</html>"#,
+        title = escape_html(&card.title),
+        meta = meta.join("\n    "),
+        avatar = avatar,
+        author = escape_html(&card.author_name),
+        description = escape_html(&card.description),
+        media = media,
+        logo = logo,
+        site_name = escape_html(&branding.site_name),
+        background = escape_html(&colors.background),
+        surface = escape_html(&colors.surface),
+        text = escape_html(&colors.text),
+        accent = escape_html(&colors.accent),
+        border = escape_html(&colors.border),
+    )
+}
+
+fn meta_property(property: &str, content: &str) -> String {
+    format!(r#"<meta property="{}" content="{}">"#, property, escape_html(content))
+}
+
+fn meta_name(name: &str, content: &str) -> String {
+    format!(r#"<meta name="{}" content="{}">"#, name, escape_html(content))
+}
+
+/// Escape text for safe inclusion in HTML bodies and attribute values
+pub fn escape_html(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn truncate(text: &str) -> String {
+    if text.chars().count() <= MAX_DESCRIPTION_CHARS {
+        return text.to_string();
+    }
+    let mut out: String = text.chars().take(MAX_DESCRIPTION_CHARS - 1).collect();
+    out.push('…');
+    out
+}
+
+fn short_pubkey(pubkey: &str) -> String {
+    match pubkey.get(..8) {
+        Some(prefix) => format!("{}…", prefix),
+        None => pubkey.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn profile() -> ProfileMetadata {
+        ProfileMetadata {
+            name: Some("alice".to_string()),
+            picture: Some("https://example.com/alice.jpg".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html(r#"<script>alert("x")</script> & 'y'"#),
+            "&lt;script&gt;alert(&quot;x&quot;)&lt;/script&gt; &amp; &#39;y&#39;"
+        );
+    }
+
+    #[test]
+    fn test_note_card() {
+        let event = json!({"id": "a", "pubkey": "0123456789abcdef", "kind": 1, "content": "hello world", "tags": []});
+        let card = EmbedCard::from_event(&event, Some(&profile()));
+        assert_eq!(card.title, "alice on Nostr");
+        assert_eq!(card.description, "hello world");
+        assert_eq!(card.image.as_deref(), Some("https://example.com/alice.jpg"));
+        assert!(card.video.is_none());
+    }
+
+    #[test]
+    fn test_note_card_without_profile() {
+        let event = json!({"id": "a", "pubkey": "0123456789abcdef", "kind": 1, "content": "hi", "tags": []});
+        let card = EmbedCard::from_event(&event, None);
+        assert_eq!(card.author_name, "01234567…");
+        assert!(card.image.is_none());
+    }
+
+    #[test]
+    fn test_video_card_uses_poster() {
+        let event = json!({
+            "id": "a", "pubkey": "p", "kind": 34235, "created_at": 1, "content": "my clip",
+            "tags": [["title", "Clip"], ["imeta", "url https://cdn/v.mp4", "image https://cdn/poster.jpg"]]
+        });
+        let card = EmbedCard::from_event(&event, Some(&profile()));
+        assert_eq!(card.title, "Clip");
+        assert_eq!(card.video.as_deref(), Some("https://cdn/v.mp4"));
+        assert_eq!(card.image.as_deref(), Some("https://cdn/poster.jpg"));
+    }
+
+    #[test]
+    fn test_render_escapes_content() {
+        let card = EmbedCard {
+            title: "t".to_string(),
+            description: r#""><script>x</script>"#.to_string(),
+            author_name: "a".to_string(),
+            ..Default::default()
+        };
+        let html = render_embed(&card, "https://gateway.divine.video/embed/a", &Branding::default());
+        assert!(!html.contains("<script>x"));
+        assert!(html.contains(r#"<meta property="og:description" content="&quot;&gt;&lt;script&gt;x&lt;/script&gt;">"#));
+        assert!(html.contains(r#"<meta name="twitter:card" content="summary">"#));
+        assert!(html.contains(r#"<meta property="og:url" content="https://gateway.divine.video/embed/a">"#));
+    }
+
+    #[test]
+    fn test_render_video_meta() {
+        let card = EmbedCard {
+            title: "Clip".to_string(),
+            video: Some("https://cdn/v.mp4".to_string()),
+            image: Some("https://cdn/poster.jpg".to_string()),
+            ..Default::default()
+        };
+        let html = render_embed(&card, "https://gateway.divine.video/embed/a", &Branding::default());
+        assert!(html.contains(r#"<meta property="og:video" content="https://cdn/v.mp4">"#));
+        assert!(html.contains(r#"<meta name="twitter:card" content="player">"#));
+        assert!(html.contains(r#"poster="https://cdn/poster.jpg""#));
+    }
+
+    #[test]
+    fn test_render_uses_branding() {
+        let branding = Branding {
+            site_name: "Acme".to_string(),
+            logo_url: Some("https://acme.example/logo.png".to_string()),
+            ..Default::default()
+        };
+        let html = render_embed(&EmbedCard::default(), "https://nostr.acme.example/embed/a", &branding);
+        assert!(html.contains(r#"<meta property="og:site_name" content="Acme">"#));
+        assert!(html.contains(r#"<img class="logo" src="https://acme.example/logo.png" alt="">"#));
+        assert!(!html.contains("Divine"));
+    }
+
+    #[test]
+    fn test_truncate_long_description() {
+        let long = "x".repeat(500);
+        assert_eq!(truncate(&long).chars().count(), MAX_DESCRIPTION_CHARS);
+        assert_eq!(truncate("short"), "short");
+    }
+}
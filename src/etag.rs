@@ -0,0 +1,73 @@
+// ABOUTME: Entity tags for cacheable JSON responses and If-None-Match revalidation
+// ABOUTME: Tags follow the events a response holds, not where it was served from, so re-polls get 304s
+
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+/// Top-level fields that describe how an answer was served rather than what it says
+const VOLATILE_FIELDS: [&str; 4] = ["cached", "stale", "cache_age_seconds", "source"];
+
+/// A strong ETag for a response body: its event ids in sorted order, plus every
+/// other field but the volatile ones, so the same events from cache or relay
+/// share a tag
+pub fn compute(body: &Value) -> String {
+    let mut hasher = Sha256::new();
+    match body.as_object() {
+        Some(object) => {
+            let mut ids: Vec<&str> = object
+                .get("events")
+                .and_then(Value::as_array)
+                .map(|events| events.iter().filter_map(|e| e.get("id").and_then(Value::as_str)).collect())
+                .unwrap_or_default();
+            ids.sort_unstable();
+            for id in ids {
+                hasher.update(id.as_bytes());
+                hasher.update(b",");
+            }
+            // serde_json's map keeps keys sorted, so this is stable
+            for (key, value) in object {
+                if key != "events" && !VOLATILE_FIELDS.contains(&key.as_str()) {
+                    hasher.update(key.as_bytes());
+                    hasher.update(value.to_string().as_bytes());
+                }
+            }
+        }
+        None => hasher.update(body.to_string().as_bytes()),
+    }
+    format!("\"{}\"", hex::encode(&hasher.finalize()[..16]))
+}
+
+/// Whether an `If-None-Match` header names `etag`. The comparison is weak, as
+/// RFC 9110 has it for this header, and `*` matches anything.
+pub fn matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match
+        .split(',')
+        .any(|candidate| candidate.trim() == "*" || opaque(candidate) == opaque(etag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_compute() {
+        let relay = json!({"events": [{"id": "a"}, {"id": "b"}], "eose": true, "cached": false, "source": "relay"});
+        let cache = json!({"events": [{"id": "b"}, {"id": "a"}], "eose": true, "cached": true, "cache_age_seconds": 30, "source": "cache"});
+        assert_eq!(compute(&relay), compute(&cache));
+        let changed = json!({"events": [{"id": "a"}, {"id": "c"}], "eose": true});
+        assert_ne!(compute(&relay), compute(&changed));
+        let incomplete = json!({"events": [{"id": "a"}, {"id": "b"}], "eose": false});
+        assert_ne!(compute(&relay), compute(&incomplete));
+        assert!(compute(&json!({"count": 3})).starts_with('"'));
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("\"abc\"", "\"abc\""));
+        assert!(matches("\"x\", W/\"abc\"", "\"abc\""));
+        assert!(matches("*", "\"abc\""));
+        assert!(!matches("\"abd\"", "\"abc\""));
+    }
+}
@@ -0,0 +1,60 @@
+// ABOUTME: NIP-40 expiration tags: expired events are never served and nothing is cached past an expiry
+// ABOUTME: Responses and cache entries holding expiring events get their TTL capped at the soonest expiry
+
+use serde_json::Value;
+
+/// Unix time an event expires at, from its first well-formed `expiration` tag
+pub fn expires_at(event: &Value) -> Option<u64> {
+    event
+        .get("tags")?
+        .as_array()?
+        .iter()
+        .filter_map(|tag| tag.as_array())
+        .filter(|tag| tag.first().and_then(Value::as_str) == Some("expiration"))
+        .find_map(|tag| tag.get(1)?.as_str()?.trim().parse().ok())
+}
+
+pub fn is_expired(event: &Value, now: u64) -> bool {
+    expires_at(event).is_some_and(|at| at <= now)
+}
+
+/// Remove expired events, returning how many there were
+pub fn strip(events: &mut Vec<Value>, now: u64) -> usize {
+    let before = events.len();
+    events.retain(|event| !is_expired(event, now));
+    before - events.len()
+}
+
+/// `ttl`, shortened so it runs out when the first of `events` expires
+pub fn cap_ttl(events: &[Value], now: u64, ttl: u64) -> u64 {
+    events
+        .iter()
+        .filter_map(expires_at)
+        .map(|at| at.saturating_sub(now))
+        .fold(ttl, u64::min)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_expires_at() {
+        assert_eq!(expires_at(&json!({"tags": [["t", "x"], ["expiration", "1700000000"]]})), Some(1700000000));
+        assert_eq!(expires_at(&json!({"tags": [["expiration", "soon"], ["expiration", "5"]]})), Some(5));
+        assert_eq!(expires_at(&json!({"tags": [["expiration"]]})), None);
+        assert_eq!(expires_at(&json!({"kind": 1})), None);
+    }
+
+    #[test]
+    fn test_strip_and_cap() {
+        let expiring = |at: u64| json!({"tags": [["expiration", at.to_string()]]});
+        let mut events = vec![expiring(100), expiring(1000), json!({"tags": []})];
+        assert_eq!(strip(&mut events, 100), 1);
+        assert_eq!(events.len(), 2);
+        assert_eq!(cap_ttl(&events, 400, 3600), 600);
+        assert_eq!(cap_ttl(&events, 400, 300), 300);
+        assert_eq!(cap_ttl(&[json!({"tags": []})], 400, 300), 300);
+    }
+}
@@ -0,0 +1,121 @@
+// ABOUTME: Fan-out of relay queries to the extra relays in FANOUT_RELAY_URLS, merged and deduplicated by event id
+// ABOUTME: Tracks how much each relay contributes, so operators can see which ones are worth querying
+
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+
+/// Most extra relays one query fans out to
+const MAX_FANOUT_RELAYS: usize = 8;
+
+/// The extra relays from `FANOUT_RELAY_URLS`: comma-separated `wss://` or `ws://`
+/// URLs, duplicates and the primary relay left out
+pub fn parse_relays(value: &str, primary: &str) -> Vec<String> {
+    let mut relays: Vec<String> = Vec::new();
+    for relay in value.split(',').map(|r| r.trim().trim_end_matches('/')) {
+        let valid = relay.starts_with("wss://") || relay.starts_with("ws://");
+        if valid && relay != primary.trim_end_matches('/') && !relays.iter().any(|r| r == relay) {
+            relays.push(relay.to_string());
+        }
+    }
+    relays.truncate(MAX_FANOUT_RELAYS);
+    relays
+}
+
+/// One relay's answer to a fanned-out query
+pub struct RelayAnswer {
+    pub relay: String,
+    pub events: Vec<Value>,
+    /// Sent EOSE or at least one event, rather than failing or timing out
+    pub answered: bool,
+}
+
+/// What one relay has added to fanned-out queries since the instance started
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Contribution {
+    pub queries: u64,
+    /// Queries it didn't answer in time, or failed outright
+    pub failures: u64,
+    pub events: u64,
+    /// Events no relay listed before it returned
+    pub unique: u64,
+}
+
+/// Merge answers in order, the primary relay's first: each event is kept once,
+/// newest first with ties broken by id, as `Filter::apply_limit` sorts. Each
+/// relay's contribution is added to `totals`.
+pub fn merge(answers: Vec<RelayAnswer>, totals: &mut BTreeMap<String, Contribution>) -> Vec<Value> {
+    let mut seen = HashSet::new();
+    let mut merged = Vec::new();
+    for answer in answers {
+        let totals = totals.entry(answer.relay).or_default();
+        totals.queries += 1;
+        if !answer.answered {
+            totals.failures += 1;
+        }
+        totals.events += answer.events.len() as u64;
+        for event in answer.events {
+            let Some(id) = event.get("id").and_then(Value::as_str).map(str::to_ascii_lowercase) else {
+                continue;
+            };
+            if seen.insert(id) {
+                totals.unique += 1;
+                merged.push(event);
+            }
+        }
+    }
+    let created_at = |e: &Value| e.get("created_at").and_then(Value::as_u64).unwrap_or(0);
+    let id = |e: &Value| e.get("id").and_then(Value::as_str).unwrap_or("").to_string();
+    merged.sort_by(|a, b| created_at(b).cmp(&created_at(a)).then_with(|| id(a).cmp(&id(b))));
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_relays() {
+        let relays = parse_relays(" wss://a.example/, https://b.example, wss://primary.example,wss://a.example,ws://c.example", "wss://primary.example/");
+        assert_eq!(relays, vec!["wss://a.example", "ws://c.example"]);
+        assert!(parse_relays("", "wss://primary.example").is_empty());
+    }
+
+    #[test]
+    fn test_merge() {
+        let event = |id: &str, at: u64| json!({"id": id, "created_at": at});
+        let answers = vec![
+            RelayAnswer {
+                relay: "wss://primary".to_string(),
+                events: vec![event("a", 1), event("b", 3)],
+                answered: true,
+            },
+            RelayAnswer {
+                relay: "wss://extra".to_string(),
+                events: vec![event("B", 3), event("c", 2)],
+                answered: true,
+            },
+            RelayAnswer {
+                relay: "wss://down".to_string(),
+                events: vec![],
+                answered: false,
+            },
+        ];
+        let mut totals = BTreeMap::new();
+        let merged = merge(answers, &mut totals);
+        let ids: Vec<&str> = merged.iter().filter_map(|e| e["id"].as_str()).collect();
+        assert_eq!(ids, vec!["b", "c", "a"]);
+        assert_eq!(totals["wss://primary"].unique, 2);
+        assert_eq!(
+            totals["wss://extra"],
+            Contribution {
+                queries: 1,
+                failures: 0,
+                events: 2,
+                unique: 1
+            }
+        );
+        assert_eq!(totals["wss://down"].failures, 1);
+    }
+}
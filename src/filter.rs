@@ -1,6 +1,8 @@
 // ABOUTME: Nostr filter parsing, validation, and base64url encoding
 // ABOUTME: Handles conversion between HTTP query params and Nostr filter objects
 
+use crate::kind;
+use crate::ttl::KindTtls;
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
@@ -30,6 +32,9 @@ struct ParsedFilter {
     until: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     limit: Option<usize>,
+    /// NIP-50 full-text query, answered by relays that support it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    search: Option<String>,
 }
 
 impl Filter {
@@ -45,6 +50,60 @@ impl Filter {
         Ok(Self { raw_json: raw_json.to_string(), parsed })
     }
 
+    /// Filter built by the gateway itself; the raw JSON is serialized from the
+    /// parsed fields so the two always agree
+    fn from_parsed(parsed: ParsedFilter) -> Self {
+        Self {
+            raw_json: serde_json::to_string(&parsed).unwrap_or_default(),
+            parsed,
+        }
+    }
+
+    /// Single-event lookup (of any kind) used by `/event/{id}` and `/embed/{id}`.
+    /// Shared so every caller (and cache purges) agree on the cache key.
+    pub fn note(event_id: &str) -> Self {
+        Self::notes(&[event_id.to_string()])
+    }
+
+    /// Events by id, all of them in one filter
+    pub fn notes(event_ids: &[String]) -> Self {
+        Self::from_parsed(ParsedFilter {
+            ids: Some(event_ids.to_vec()),
+            limit: Some(event_ids.len()),
+            ..Default::default()
+        })
+    }
+
+    /// Kind 0 lookup used by `/profile/{pubkey}` and embed author cards
+    pub fn profile(pubkey: &str) -> Self {
+        Self::profiles(&[pubkey.to_string()])
+    }
+
+    /// Kind 0 events of several pubkeys. Relays keep only the latest per author,
+    /// so one each is enough.
+    pub fn profiles(pubkeys: &[String]) -> Self {
+        Self::latest_by_authors(pubkeys, kind::PROFILE)
+    }
+
+    /// A pubkey's contact list, in the shape most clients ask for it
+    pub fn contacts(pubkey: &str) -> Self {
+        Self::latest_by_authors(&[pubkey.to_string()], kind::CONTACTS)
+    }
+
+    /// A pubkey's NIP-51 mute list
+    pub fn mute_list(pubkey: &str) -> Self {
+        Self::latest_by_authors(&[pubkey.to_string()], kind::MUTE_LIST)
+    }
+
+    fn latest_by_authors(pubkeys: &[String], kind: u16) -> Self {
+        Self::from_parsed(ParsedFilter {
+            authors: Some(pubkeys.to_vec()),
+            kinds: Some(vec![kind]),
+            limit: Some(pubkeys.len()),
+            ..Default::default()
+        })
+    }
+
     /// Decode a base64url-encoded filter from query string.
     /// Preserves the raw JSON for passing to relays unchanged.
     pub fn from_base64(encoded: &str) -> Result<Self, FilterError> {
@@ -55,53 +114,512 @@ impl Filter {
         Self::from_json(&raw_json)
     }
 
-    /// Encode filter to base64url for use in URLs
+    /// Build a filter from plain query parameters (`kinds=1,6&authors=<hex>&limit=20`).
+    /// List fields take comma-separated values and may repeat; tag filters use `#t=music`
+    /// (sent as `%23t`). Returns None when no filter parameter is present.
+    pub fn from_query_params<'a>(
+        params: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<Option<Self>, FilterError> {
+        let mut object = serde_json::Map::new();
+        let mut push = |key: &str, values: Vec<serde_json::Value>| {
+            let list = object.entry(key).or_insert_with(|| serde_json::Value::Array(Vec::new()));
+            if let Some(list) = list.as_array_mut() {
+                list.extend(values);
+            }
+        };
+        let split = |value: &str| -> Vec<String> {
+            value.split(',').map(str::trim).filter(|v| !v.is_empty()).map(String::from).collect()
+        };
+        let number = |key: &str, value: &str| {
+            value.trim().parse::<u64>().map_err(|_| FilterError::InvalidParam(format!("{} must be an integer", key)))
+        };
+
+        let mut scalars: Vec<(&str, serde_json::Value)> = Vec::new();
+        for (key, value) in params {
+            match key {
+                "ids" | "authors" => push(key, split(value).into_iter().map(Into::into).collect()),
+                "kinds" => {
+                    let kinds = split(value)
+                        .iter()
+                        .map(|k| k.parse::<u16>().map(Into::into))
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|_| FilterError::InvalidParam("kinds must be integers".to_string()))?;
+                    push(key, kinds);
+                }
+                "since" | "until" | "limit" => scalars.push((key, number(key, value)?.into())),
+                "search" => scalars.push((key, value.into())),
+                tag if tag.len() == 2 && tag.starts_with('#') => {
+                    push(tag, split(value).into_iter().map(Into::into).collect())
+                }
+                _ => {}
+            }
+        }
+        for (key, value) in scalars {
+            object.insert(key.to_string(), value);
+        }
+        if object.is_empty() {
+            return Ok(None);
+        }
+
+        // Sorted, deduplicated lists so equivalent URLs share a cache key
+        for list in object.values_mut().filter_map(|v| v.as_array_mut()) {
+            list.sort_by_key(|v| v.to_string());
+            list.dedup();
+        }
+        Self::from_json(&serde_json::Value::Object(object).to_string()).map(Some)
+    }
+
+    /// Encode filter to base64url for use in URLs. Only clients build such URLs;
+    /// the gateway itself passes filters around directly.
+    #[cfg(test)]
     pub fn to_base64(&self) -> String {
         URL_SAFE_NO_PAD.encode(self.raw_json.as_bytes())
     }
 
-    /// Generate cache key hash from the RAW JSON - includes ALL fields
+    /// Generate cache key hash from the canonical JSON - includes ALL fields.
+    /// A plain profile lookup is keyed by its pubkey instead, so `/profile/{pk}`,
+    /// batch lookups and equivalent `/query` filters share one entry.
     pub fn cache_key(&self) -> String {
+        if let Some(pubkey) = self.profile_pubkey() {
+            return format!("profile:{}", pubkey);
+        }
         let mut hasher = Sha256::new();
-        hasher.update(self.raw_json.as_bytes());
+        hasher.update(self.canonical_json().as_bytes());
         let hash = hasher.finalize();
         format!("query:{}", hex::encode(&hash[..16])) // 128-bit truncated
     }
 
+    /// The raw JSON with object keys in order, every list sorted and deduplicated,
+    /// and hex ids and pubkeys lowercased, so equivalent filters from different
+    /// clients share a cache entry. Empty lists stay: they match nothing, which
+    /// is not the same as leaving the field out.
+    pub fn canonical_json(&self) -> String {
+        let Ok(serde_json::Value::Object(mut object)) = serde_json::from_str(&self.raw_json) else {
+            return self.raw_json.clone();
+        };
+        for (key, value) in object.iter_mut() {
+            let Some(list) = value.as_array_mut() else {
+                continue;
+            };
+            if matches!(key.as_str(), "ids" | "authors" | "#e" | "#p") {
+                for item in list.iter_mut() {
+                    if let Some(hex) = item.as_str().filter(|s| s.chars().all(|c| c.is_ascii_hexdigit())) {
+                        *item = hex.to_ascii_lowercase().into();
+                    }
+                }
+            }
+            list.sort_by(|a, b| match (a.as_f64(), b.as_f64()) {
+                (Some(a), Some(b)) => a.total_cmp(&b),
+                _ => a.to_string().cmp(&b.to_string()),
+            });
+            list.dedup();
+        }
+        // serde_json's map keeps keys sorted
+        serde_json::Value::Object(object).to_string()
+    }
+
     /// Get the raw JSON for passing to relays
     pub fn as_json(&self) -> &str {
         &self.raw_json
     }
 
+    /// Same filter with `limit` lowered to `max`, or set to it when absent.
+    /// A filter already within the cap keeps its raw JSON, and so its cache key.
+    pub fn with_max_limit(&self, max: usize) -> Self {
+        if self.parsed.limit.is_some_and(|l| l <= max) {
+            return self.clone();
+        }
+        let mut value: serde_json::Value = serde_json::from_str(&self.raw_json).unwrap_or_default();
+        let Some(object) = value.as_object_mut() else {
+            return self.clone();
+        };
+        object.insert("limit".to_string(), max.into());
+        Self {
+            raw_json: value.to_string(),
+            parsed: ParsedFilter {
+                limit: Some(max),
+                ..self.parsed.clone()
+            },
+        }
+    }
+
     /// Get limit if specified
     pub fn limit(&self) -> Option<usize> {
         self.parsed.limit
     }
 
-    /// Determine TTL in seconds based on filter content
-    pub fn ttl_seconds(&self) -> u64 {
-        match self.parsed.kinds.as_ref().and_then(|k| k.first()) {
-            Some(0) => 900,   // profiles: 15 min
-            Some(3) => 600,   // contacts: 10 min
-            Some(1) => 300,   // notes: 5 min
-            Some(7) => 120,   // reactions: 2 min
-            _ => 300,         // default: 5 min
+    /// Order events as a relay would (newest first, ties by lowest id) and drop
+    /// any past `limit`. Relays don't all honor `limit`, and cached results may
+    /// hold more than a later request asks for.
+    pub fn apply_limit(&self, events: &mut Vec<serde_json::Value>) {
+        let created_at = |e: &serde_json::Value| e.get("created_at").and_then(|v| v.as_u64()).unwrap_or(0);
+        let id = |e: &serde_json::Value| e.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
+        events.sort_by(|a, b| created_at(b).cmp(&created_at(a)).then_with(|| id(a).cmp(&id(b))));
+        if let Some(limit) = self.parsed.limit {
+            events.truncate(limit);
+        }
+    }
+
+    /// Get event ids if specified
+    pub fn ids(&self) -> Option<&[String]> {
+        self.parsed.ids.as_deref()
+    }
+
+    /// Get authors if specified
+    pub fn authors(&self) -> Option<&[String]> {
+        self.parsed.authors.as_deref()
+    }
+
+    /// Get kinds if specified
+    pub fn kinds(&self) -> Option<&[u16]> {
+        self.parsed.kinds.as_deref()
+    }
+
+    /// Get since timestamp if specified
+    pub fn since(&self) -> Option<u64> {
+        self.parsed.since
+    }
+
+    /// Get until timestamp if specified
+    pub fn until(&self) -> Option<u64> {
+        self.parsed.until
+    }
+
+    /// Refuse filters that could match anything on the relay: at least one of ids,
+    /// authors, kinds, a tag filter or a search must narrow it, and `ids` and
+    /// `authors` prefixes need `MIN_PREFIX_LEN` characters. (Limits are capped
+    /// separately, by `with_max_limit`.)
+    pub fn check_breadth(&self) -> Result<(), FilterError> {
+        let short = |list: &Option<Vec<String>>| {
+            list.iter().flatten().any(|value| is_hex_prefix(value) && value.len() < MIN_PREFIX_LEN)
+        };
+        if short(&self.parsed.ids) || short(&self.parsed.authors) {
+            return Err(FilterError::PrefixTooShort { min: MIN_PREFIX_LEN });
+        }
+        let narrowed = |list: &Option<Vec<String>>| list.as_ref().is_some_and(|l| !l.is_empty());
+        let constrained = narrowed(&self.parsed.ids)
+            || narrowed(&self.parsed.authors)
+            || self.parsed.kinds.as_ref().is_some_and(|k| !k.is_empty())
+            || self.search().is_some()
+            || self.tag_filters().iter().any(|(_, values)| !values.is_empty());
+        if constrained {
+            Ok(())
+        } else {
+            Err(FilterError::TooBroad)
+        }
+    }
+
+    /// Get the NIP-50 search query if specified (and not blank)
+    pub fn search(&self) -> Option<&str> {
+        self.parsed.search.as_deref().filter(|s| !s.trim().is_empty())
+    }
+
+    /// Get tag filters (`#e`, `#p`, `#platform`, ...) as (name, values) pairs.
+    /// Read from the raw JSON since tag names are open-ended.
+    pub fn tag_filters(&self) -> Vec<(String, Vec<String>)> {
+        let value: serde_json::Value = serde_json::from_str(&self.raw_json).unwrap_or_default();
+        let Some(obj) = value.as_object() else {
+            return Vec::new();
+        };
+        obj.iter()
+            .filter_map(|(key, values)| {
+                let name = key.strip_prefix('#')?;
+                let values = values
+                    .as_array()?
+                    .iter()
+                    .filter_map(|v| v.as_str().map(String::from))
+                    .collect();
+                Some((name.to_string(), values))
+            })
+            .collect()
+    }
+
+    /// Determine TTL in seconds based on filter content. Events named by id never
+    /// change, and a window that closed an hour before `now` rarely gains events;
+    /// anything else is a "latest" query and gets the shortest TTL among its kinds.
+    pub fn ttl_seconds(&self, now: u64, ttls: &KindTtls) -> u64 {
+        self.ttl_class(now, ttls).seconds(ttls)
+    }
+
+    /// The rule `ttl_seconds` applies
+    pub fn ttl_class(&self, now: u64, ttls: &KindTtls) -> TtlClass {
+        if self.is_immutable_lookup() {
+            return TtlClass::Event;
+        }
+        // Full ids name fixed events; a prefix can still gain matches
+        if self.parsed.ids.as_ref().is_some_and(|ids| !ids.is_empty() && ids.iter().all(|id| is_hex64(id))) {
+            return TtlClass::IdLookup;
+        }
+        if self.parsed.until.is_some_and(|until| until.saturating_add(SETTLED_AFTER_SECONDS) <= now) {
+            return TtlClass::Historical;
+        }
+        self.parsed
+            .kinds
+            .as_ref()
+            .and_then(|kinds| kinds.iter().copied().min_by_key(|&kind| ttls.seconds(kind)))
+            .map_or(TtlClass::Default, TtlClass::Kind)
+    }
+
+    /// Estimated expense of the filter for the relay, in the units query budgets
+    /// are counted in: one per listed id and author plus one per 8 characters a
+    /// prefix leaves out, two per tag value (tag indexes fan out), one per 100
+    /// events of `limit`, doubled when no `since` or `ids` bound the scan, and a
+    /// flat 10 for a full-text search. A single event lookup costs 2.
+    pub fn cost(&self) -> u64 {
+        let width = |value: &String| match is_hex_prefix(value) {
+            true => 1 + (64 - value.len()) / 8,
+            false => 1,
+        };
+        let listed = |list: &Option<Vec<String>>| list.iter().flatten().map(width).sum::<usize>();
+        let lookups = listed(&self.parsed.ids) + listed(&self.parsed.authors);
+        let tags = 2 * self.tag_filters().iter().map(|(_, values)| values.len()).sum::<usize>();
+        let pages = self.parsed.limit.unwrap_or(0).div_ceil(100);
+        let open = self.parsed.since.is_none() && self.parsed.ids.is_none();
+        let pages = if open { pages * 2 } else { pages };
+        let search = if self.search().is_some() { 10 } else { 0 };
+        ((lookups + tags + pages + search) as u64).max(1)
+    }
+
+    /// Refuse filters costing more than `max`
+    pub fn check_cost(&self, max: u64) -> Result<(), FilterError> {
+        match self.cost() {
+            cost if cost > max => Err(FilterError::TooExpensive { cost, max }),
+            _ => Ok(()),
         }
     }
 
+    /// Whether an event's id and pubkey fit the filter's `ids` and `authors`, which
+    /// NIP-01 lets be hex prefixes. Relays that ignore prefixes return extra events.
+    pub fn matches_ids_and_authors(&self, event: &serde_json::Value) -> bool {
+        let fits = |list: &Option<Vec<String>>, field: &str| {
+            let value = event.get(field).and_then(|v| v.as_str()).unwrap_or_default();
+            list.as_ref().map_or(true, |list| {
+                list.iter().any(|prefix| value.get(..prefix.len()).is_some_and(|head| head.eq_ignore_ascii_case(prefix)))
+            })
+        };
+        fits(&self.parsed.ids, "id") && fits(&self.parsed.authors, "pubkey")
+    }
+
+    /// Whether `kinds` names a DM or gift wrap kind
+    pub fn requests_private_kinds(&self) -> bool {
+        self.parsed.kinds.as_ref().is_some_and(|kinds| kinds.iter().any(|k| kind::PRIVATE.contains(k)))
+    }
+
+    /// Whether `authors` and `#p` name `pubkey` and nobody else, so it is a party to
+    /// everything the filter matches. One other pubkey, or a prefix, and it isn't.
+    pub fn names_only_pubkey(&self, pubkey: &str) -> bool {
+        let authors = self.parsed.authors.iter().flatten().cloned();
+        let tagged = self.tag_filters().into_iter().filter(|(name, _)| name == "p").flat_map(|(_, values)| values);
+        let named: Vec<String> = authors.chain(tagged).collect();
+        !named.is_empty() && named.iter().all(|p| p.eq_ignore_ascii_case(pubkey))
+    }
+
+    /// Whether any `ids` or `authors` entry is shorter than a full id or pubkey
+    pub fn has_prefixes(&self) -> bool {
+        let short = |list: &Option<Vec<String>>| list.iter().flatten().any(|value| value.len() < 64);
+        short(&self.parsed.ids) || short(&self.parsed.authors)
+    }
+
+    /// An empty `ids`, `authors` or `kinds` list: no event can match, so there is
+    /// nothing to ask the relay
+    pub fn matches_nothing(&self) -> bool {
+        self.parsed.ids.as_ref().is_some_and(|l| l.is_empty())
+            || self.parsed.authors.as_ref().is_some_and(|l| l.is_empty())
+            || self.parsed.kinds.as_ref().is_some_and(|l| l.is_empty())
+    }
+
     /// Check if this is a single-event lookup by ID
     pub fn is_single_event_lookup(&self) -> bool {
         matches!(&self.parsed.ids, Some(ids) if ids.len() == 1)
             && self.parsed.authors.is_none()
             && self.parsed.kinds.is_none()
     }
+
+    /// The lowercased pubkey of a filter asking for nothing but one author's kind 0.
+    /// Any nonzero `limit` is the same question, as relays keep only the latest.
+    pub fn profile_pubkey(&self) -> Option<String> {
+        let author = match (&self.parsed.authors, &self.parsed.kinds) {
+            (Some(authors), Some(kinds)) if authors.len() == 1 && kinds.as_slice() == [kind::PROFILE] => &authors[0],
+            _ => return None,
+        };
+        if !is_hex64(author) || self.parsed.limit == Some(0) {
+            return None;
+        }
+        // Tag filters and fields the gateway doesn't read can narrow the answer
+        let object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&self.raw_json).ok()?;
+        if object.keys().any(|key| !matches!(key.as_str(), "authors" | "kinds" | "limit")) {
+            return None;
+        }
+        Some(author.to_ascii_lowercase())
+    }
+
+    /// A single-event lookup by full id, whose answer, once found, can't change
+    pub fn is_immutable_lookup(&self) -> bool {
+        self.is_single_event_lookup() && self.parsed.ids.as_ref().is_some_and(|ids| is_hex64(&ids[0]))
+    }
+}
+
+/// Which fields of a filter object the gateway reads, and what is wrong with them.
+/// The relay receives every field as sent; this is for clients debugging filters.
+#[derive(Debug, Default, PartialEq)]
+pub struct FieldReport {
+    pub recognized: Vec<String>,
+    /// Passed to the relay untouched, but not understood by the gateway
+    pub ignored: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+/// Check each field's shape. A field of the wrong type is otherwise silently
+/// dropped when the gateway parses the filter, so caching and limits misjudge it.
+pub fn check_fields(object: &serde_json::Map<String, serde_json::Value>) -> FieldReport {
+    use serde_json::Value;
+    let mut report = FieldReport::default();
+    for (key, value) in object {
+        let problem = match key.as_str() {
+            "ids" | "authors" => match value.as_array() {
+                Some(list) if list.iter().all(|v| v.as_str().is_some_and(is_hex_prefix)) => None,
+                Some(_) => Some(format!("{} must hold hex strings of up to 64 characters", key)),
+                None => Some(format!("{} must be an array", key)),
+            },
+            "kinds" => match value.as_array() {
+                Some(list) if list.iter().all(|v| v.as_u64().is_some_and(|k| k <= u16::MAX as u64)) => None,
+                _ => Some("kinds must be an array of integers from 0 to 65535".to_string()),
+            },
+            "since" | "until" | "limit" => (!value.is_u64()).then(|| format!("{} must be a non-negative integer", key)),
+            "search" => (!value.is_string()).then(|| "search must be a string".to_string()),
+            tag if tag.len() > 1 && tag.starts_with('#') => match value {
+                Value::Array(list) if list.iter().all(Value::is_string) => None,
+                _ => Some(format!("{} must be an array of strings", tag)),
+            },
+            _ => {
+                report.ignored.push(key.clone());
+                continue;
+            }
+        };
+        report.recognized.push(key.clone());
+        report.errors.extend(problem);
+    }
+    let bound = |key: &str| object.get(key).and_then(Value::as_u64);
+    if let (Some(since), Some(until)) = (bound("since"), bound("until")) {
+        if since > until {
+            report.errors.push("since is after until, so nothing can match".to_string());
+        }
+    }
+    report
+}
+
+/// Whether `pubkey` wrote an event or is named in one of its `p` tags
+pub fn event_involves(event: &serde_json::Value, pubkey: &str) -> bool {
+    let is = |value: Option<&serde_json::Value>| {
+        value.and_then(|v| v.as_str()).is_some_and(|p| p.eq_ignore_ascii_case(pubkey))
+    };
+    is(event.get("pubkey"))
+        || event["tags"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .any(|tag| tag.get(0).and_then(|v| v.as_str()) == Some("p") && is(tag.get(1)))
+}
+
+/// Check for a 32-byte lowercase or uppercase hex string (event ids, pubkeys)
+pub fn is_hex64(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Check for a hex id or pubkey, or a NIP-01 prefix of one
+pub fn is_hex_prefix(value: &str) -> bool {
+    (1..=64).contains(&value.len()) && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Filters answered together, like the several filters of one NIP-01 REQ.
+/// Used by handlers that compose a response from more than one lookup.
+#[derive(Debug, Clone, Default)]
+pub struct FilterSet {
+    filters: Vec<Filter>,
+}
+
+impl FilterSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, filter: Filter) {
+        self.filters.push(filter);
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Filter> {
+        self.filters.iter()
+    }
 }
 
+/// Why a filter gets the cache TTL it does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtlClass {
+    /// Names exactly one event by its full id
+    Event,
+    /// Names events by id
+    IdLookup,
+    /// Its `until` closed long enough ago
+    Historical,
+    /// Latest events, cached as long as its shortest-lived kind
+    Kind(u16),
+    Default,
+}
+
+impl TtlClass {
+    pub fn seconds(self, ttls: &KindTtls) -> u64 {
+        match self {
+            Self::Event => EVENT_TTL_SECONDS,
+            Self::IdLookup => ID_LOOKUP_TTL_SECONDS,
+            Self::Historical => HISTORICAL_TTL_SECONDS,
+            Self::Kind(kind) => ttls.seconds(kind),
+            Self::Default => ttls.default_seconds(),
+        }
+    }
+
+    /// `event`, `id_lookup`, `historical`, `kind:{n}` or `default`
+    pub fn label(self) -> String {
+        match self {
+            Self::Event => "event".to_string(),
+            Self::IdLookup => "id_lookup".to_string(),
+            Self::Historical => "historical".to_string(),
+            Self::Kind(kind) => format!("kind:{}", kind),
+            Self::Default => "default".to_string(),
+        }
+    }
+}
+
+/// For `/event/{id}`-style lookups of one event. Events are content-addressed, so
+/// the only change a found event can see is a deletion, which can wait a day.
+const EVENT_TTL_SECONDS: u64 = 86400;
+
+/// Deletions only purge the single-event lookup of their target, so other filters
+/// naming ids aren't kept forever even though their events are immutable
+const ID_LOOKUP_TTL_SECONDS: u64 = 3600;
+
+/// For filters whose `until` is at least `SETTLED_AFTER_SECONDS` in the past
+const HISTORICAL_TTL_SECONDS: u64 = 6 * 3600;
+
+/// Shortest `ids` or `authors` prefix a client filter may use. Shorter ones match
+/// a large share of all events, which the relay and the archive have to scan.
+pub const MIN_PREFIX_LEN: usize = 8;
+
+/// How far back `until` must be before late-arriving events are unlikely
+const SETTLED_AFTER_SECONDS: u64 = 3600;
+
 #[derive(Debug)]
 pub enum FilterError {
     InvalidBase64,
     InvalidUtf8,
     InvalidJson,
+    /// A query-parameter filter field with the wrong shape
+    InvalidParam(String),
+    /// Nothing narrows the filter down
+    TooBroad,
+    /// An `ids` or `authors` prefix shorter than `min`
+    PrefixTooShort { min: usize },
+    /// `cost()` over the operator's maximum
+    TooExpensive { cost: u64, max: u64 },
 }
 
 impl std::fmt::Display for FilterError {
@@ -110,6 +628,10 @@ impl std::fmt::Display for FilterError {
             Self::InvalidBase64 => write!(f, "invalid base64 encoding"),
             Self::InvalidUtf8 => write!(f, "invalid UTF-8"),
             Self::InvalidJson => write!(f, "invalid JSON filter"),
+            Self::InvalidParam(detail) => write!(f, "{}", detail),
+            Self::TooBroad => write!(f, "filter must include ids, authors, kinds, a tag filter or a search"),
+            Self::PrefixTooShort { min } => write!(f, "ids and authors prefixes need at least {} hex characters", min),
+            Self::TooExpensive { cost, max } => write!(f, "filter cost {} exceeds the maximum of {}", cost, max),
         }
     }
 }
@@ -153,6 +675,94 @@ mod tests {
         assert_ne!(filter1.cache_key(), filter2.cache_key());
     }
 
+    #[test]
+    fn test_cache_key_canonical() {
+        let upper = "AB".repeat(32);
+        let lower = upper.to_ascii_lowercase();
+        let a = Filter::from_json(&format!(r#"{{"limit":20,"kinds":[7,1,1],"authors":["{}","cd"]}}"#, upper)).unwrap();
+        let b = Filter::from_json(&format!(r#"{{ "authors": ["cd", "{}"], "kinds": [1, 7], "limit": 20 }}"#, lower));
+        let b = b.unwrap();
+        assert_eq!(a.cache_key(), b.cache_key());
+
+        // Numbers sort numerically, tag values keep their case unless they are ids or pubkeys
+        let c = Filter::from_json(r##"{"kinds":[10,9],"#t":["Music"]}"##).unwrap();
+        let d = Filter::from_json(r##"{"kinds":[9,10],"#t":["music"]}"##).unwrap();
+        assert_eq!(c.canonical_json(), r##"{"#t":["Music"],"kinds":[9,10]}"##);
+        assert_ne!(c.cache_key(), d.cache_key());
+
+        // An empty list matches nothing, so it can't share a key with no list at all
+        let empty = Filter::from_json(r#"{"kinds":[1],"ids":[]}"#).unwrap();
+        let absent = Filter::from_json(r#"{"kinds":[1]}"#).unwrap();
+        assert_ne!(empty.cache_key(), absent.cache_key());
+    }
+
+    #[test]
+    fn test_search_field() {
+        let plain = Filter::from_json(r#"{"kinds":[1],"limit":20}"#).unwrap();
+        let search = Filter::from_json(r#"{"kinds":[1],"limit":20,"search":"divine loops"}"#).unwrap();
+        assert_eq!(plain.search(), None);
+        assert_eq!(search.search(), Some("divine loops"));
+        assert_ne!(plain.cache_key(), search.cache_key());
+
+        // Capping the limit must not drop the search
+        let capped = search.with_max_limit(10);
+        assert_eq!(capped.search(), Some("divine loops"));
+        assert!(capped.as_json().contains("\"search\":\"divine loops\""));
+
+        let blank = Filter::from_json(r#"{"kinds":[1],"search":"  "}"#).unwrap();
+        assert_eq!(blank.search(), None);
+    }
+
+    #[test]
+    fn test_from_query_params() {
+        let filter = Filter::from_query_params([
+            ("kinds", "6,1"),
+            ("authors", "abc"),
+            ("limit", "20"),
+            ("since", "1700000000"),
+            ("#t", "music,art"),
+            ("kinds", "1"),
+            ("source", "archive"),
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(filter.kinds(), Some(&[1, 6][..]));
+        assert_eq!(filter.authors(), Some(&["abc".to_string()][..]));
+        assert_eq!(filter.limit(), Some(20));
+        assert_eq!(filter.since(), Some(1700000000));
+        assert_eq!(filter.tag_filters(), vec![("t".to_string(), vec!["art".to_string(), "music".to_string()])]);
+
+        // Parameter order doesn't change the cache key
+        let a = Filter::from_query_params([("kinds", "1"), ("limit", "5")]).unwrap().unwrap();
+        let b = Filter::from_query_params([("limit", "5"), ("kinds", "1")]).unwrap().unwrap();
+        assert_eq!(a.cache_key(), b.cache_key());
+    }
+
+    #[test]
+    fn test_from_query_params_errors() {
+        assert!(Filter::from_query_params([("source", "relay")]).unwrap().is_none());
+        assert!(Filter::from_query_params([("kinds", "note")]).is_err());
+        assert!(Filter::from_query_params([("limit", "-1")]).is_err());
+        assert!(Filter::from_query_params([("kinds", "70000")]).is_err());
+    }
+
+    #[test]
+    fn test_check_breadth() {
+        let broad = [r#"{}"#, r#"{"limit":10}"#, r#"{"since":1700000000}"#, r#"{"ids":[],"kinds":[]}"#, r##"{"#t":[]}"##];
+        for broad in broad {
+            let filter = Filter::from_json(broad).unwrap();
+            assert!(matches!(filter.check_breadth(), Err(FilterError::TooBroad)), "{}", broad);
+        }
+        let narrow = [r#"{"kinds":[1]}"#, r#"{"authors":["abcd1234"]}"#, r##"{"#t":["music"]}"##, r#"{"search":"loops"}"#];
+        for narrow in narrow {
+            assert!(Filter::from_json(narrow).unwrap().check_breadth().is_ok(), "{}", narrow);
+        }
+        for short in [r#"{"authors":["abc"]}"#, r#"{"ids":["abcd123"],"kinds":[1]}"#] {
+            let filter = Filter::from_json(short).unwrap();
+            assert!(matches!(filter.check_breadth(), Err(FilterError::PrefixTooShort { min: 8 })), "{}", short);
+        }
+    }
+
     #[test]
     fn test_cache_key_deterministic() {
         let json = r#"{"authors":["abc"],"kinds":[1]}"#;
@@ -164,6 +774,26 @@ mod tests {
         assert!(key1.starts_with("query:"));
     }
 
+    #[test]
+    fn test_profile_cache_key() {
+        let pk = "ab".repeat(32);
+        let key = format!("profile:{}", pk);
+        assert_eq!(Filter::profile(&pk).cache_key(), key);
+        let query = Filter::from_json(&format!(r#"{{"kinds":[0],"authors":["{}"],"limit":5}}"#, pk.to_uppercase())).unwrap();
+        assert_eq!(query.cache_key(), key);
+        assert_eq!(Filter::from_json(&format!(r#"{{"kinds":[0],"authors":["{}"]}}"#, pk)).unwrap().cache_key(), key);
+
+        for other in [
+            format!(r#"{{"kinds":[0],"authors":["{}"],"limit":0}}"#, pk),
+            format!(r#"{{"kinds":[0],"authors":["{}"],"#t":["x"]}}"#, pk),
+            format!(r#"{{"kinds":[0,1],"authors":["{}"]}}"#, pk),
+            format!(r#"{{"kinds":[0],"authors":["{}","{}"]}}"#, pk, "cd".repeat(32)),
+            r#"{"kinds":[0],"authors":["abc"]}"#.to_string(),
+        ] {
+            assert!(Filter::from_json(&other).unwrap().cache_key().starts_with("query:"), "{}", other);
+        }
+    }
+
     #[test]
     fn test_cache_key_length() {
         let filter = Filter::from_json("{}").unwrap();
@@ -172,28 +802,167 @@ mod tests {
         assert_eq!(key.len(), 38);
     }
 
+    const NOW: u64 = 1_700_000_000;
+
     #[test]
     fn test_ttl_by_kind() {
         let profile = Filter::from_json(r#"{"kinds":[0]}"#).unwrap();
-        assert_eq!(profile.ttl_seconds(), 900); // 15 min
+        assert_eq!(profile.ttl_seconds(NOW, &KindTtls::default()), 900); // 15 min
 
         let note = Filter::from_json(r#"{"kinds":[1]}"#).unwrap();
-        assert_eq!(note.ttl_seconds(), 300); // 5 min
+        assert_eq!(note.ttl_seconds(NOW, &KindTtls::default()), 300); // 5 min
 
         let contacts = Filter::from_json(r#"{"kinds":[3]}"#).unwrap();
-        assert_eq!(contacts.ttl_seconds(), 600); // 10 min
+        assert_eq!(contacts.ttl_seconds(NOW, &KindTtls::default()), 600); // 10 min
 
         let reactions = Filter::from_json(r#"{"kinds":[7]}"#).unwrap();
-        assert_eq!(reactions.ttl_seconds(), 120); // 2 min
+        assert_eq!(reactions.ttl_seconds(NOW, &KindTtls::default()), 120); // 2 min
+    }
+
+    #[test]
+    fn test_ttl_uses_shortest_kind() {
+        let mixed = Filter::from_json(r#"{"kinds":[0,7]}"#).unwrap();
+        assert_eq!(mixed.ttl_seconds(NOW, &KindTtls::default()), 120);
+        let reordered = Filter::from_json(r#"{"kinds":[7,0]}"#).unwrap();
+        assert_eq!(reordered.ttl_seconds(NOW, &KindTtls::default()), 120);
     }
 
     #[test]
     fn test_ttl_default() {
         let filter = Filter::from_json(r#"{"kinds":[30023]}"#).unwrap();
-        assert_eq!(filter.ttl_seconds(), 300); // 5 min default
+        assert_eq!(filter.ttl_seconds(NOW, &KindTtls::default()), 300); // 5 min default
 
         let empty = Filter::from_json("{}").unwrap();
-        assert_eq!(empty.ttl_seconds(), 300); // 5 min default
+        assert_eq!(empty.ttl_seconds(NOW, &KindTtls::default()), 300); // 5 min default
+    }
+
+    #[test]
+    fn test_ttl_for_id_lookups() {
+        let lookup = Filter::from_json(&format!(r#"{{"ids":["{}"],"kinds":[7]}}"#, "ab".repeat(32))).unwrap();
+        assert_eq!(lookup.ttl_seconds(NOW, &KindTtls::default()), ID_LOOKUP_TTL_SECONDS);
+        // A prefix can match events published later
+        let prefix = Filter::from_json(r#"{"ids":["abc"],"kinds":[7]}"#).unwrap();
+        assert_eq!(prefix.ttl_seconds(NOW, &KindTtls::default()), 120);
+        let no_ids = Filter::from_json(r#"{"ids":[],"kinds":[7]}"#).unwrap();
+        assert_eq!(no_ids.ttl_seconds(NOW, &KindTtls::default()), 120);
+
+        let event = Filter::from_json(&format!(r#"{{"ids":["{}"]}}"#, "ab".repeat(32))).unwrap();
+        assert!(event.is_immutable_lookup());
+        assert_eq!(event.ttl_class(NOW, &KindTtls::default()), TtlClass::Event);
+        assert_eq!(event.ttl_seconds(NOW, &KindTtls::default()), EVENT_TTL_SECONDS);
+        assert!(!Filter::from_json(r#"{"ids":["abc"]}"#).unwrap().is_immutable_lookup());
+    }
+
+    #[test]
+    fn test_ttl_by_time_bounds() {
+        let settled = Filter::from_json(&format!(r#"{{"kinds":[7],"until":{}}}"#, NOW - 86400)).unwrap();
+        assert_eq!(settled.ttl_seconds(NOW, &KindTtls::default()), HISTORICAL_TTL_SECONDS);
+
+        // A window closing just now, or in the future, can still gain events
+        let recent = Filter::from_json(&format!(r#"{{"kinds":[7],"until":{}}}"#, NOW - 60)).unwrap();
+        assert_eq!(recent.ttl_seconds(NOW, &KindTtls::default()), 120);
+        let future = Filter::from_json(&format!(r#"{{"kinds":[7],"until":{}}}"#, NOW + 60)).unwrap();
+        assert_eq!(future.ttl_seconds(NOW, &KindTtls::default()), 120);
+        let open = Filter::from_json(&format!(r#"{{"kinds":[7],"since":{}}}"#, NOW - 86400)).unwrap();
+        assert_eq!(open.ttl_seconds(NOW, &KindTtls::default()), 120);
+    }
+
+    #[test]
+    fn test_ttl_class() {
+        let mixed = Filter::from_json(r#"{"kinds":[0,7]}"#).unwrap();
+        assert_eq!(mixed.ttl_class(NOW, &KindTtls::default()), TtlClass::Kind(7));
+        assert_eq!(mixed.ttl_class(NOW, &KindTtls::default()).label(), "kind:7");
+        assert_eq!(Filter::note(&"ab".repeat(32)).ttl_class(NOW, &KindTtls::default()).label(), "event");
+        let ids = ["ab".repeat(32), "cd".repeat(32)];
+        assert_eq!(Filter::notes(&ids).ttl_class(NOW, &KindTtls::default()).label(), "id_lookup");
+        assert_eq!(Filter::from_json("{}").unwrap().ttl_class(NOW, &KindTtls::default()), TtlClass::Default);
+    }
+
+    #[test]
+    fn test_cost() {
+        let (a, b) = ("a".repeat(64), "b".repeat(64));
+        assert_eq!(Filter::note(&a).cost(), 2);
+        assert_eq!(Filter::profile(&a).cost(), 3);
+        // Open-ended feed vs the same window bounded by `since`
+        let feed = Filter::from_json(&format!(r#"{{"kinds":[1],"authors":["{}","{}"],"limit":200}}"#, a, b)).unwrap();
+        assert_eq!(feed.cost(), 2 + 4);
+        let recent =
+            Filter::from_json(&format!(r#"{{"kinds":[1],"authors":["{}","{}"],"limit":200,"since":1}}"#, a, b)).unwrap();
+        assert_eq!(recent.cost(), 2 + 2);
+        // The shorter a prefix, the more the relay scans for it
+        assert_eq!(Filter::note("abcd1234").cost(), 1 + 7 + 1);
+        assert_eq!(Filter::note(&"a".repeat(32)).cost(), 1 + 4 + 1);
+        let tagged = Filter::from_json(r##"{"kinds":[1],"#t":["a","b"],"limit":500,"search":"x"}"##).unwrap();
+        assert_eq!(tagged.cost(), 4 + 10 + 10);
+        assert_eq!(Filter::from_json(r#"{"kinds":[1]}"#).unwrap().cost(), 1);
+
+        assert!(tagged.check_cost(24).is_ok());
+        let err = tagged.check_cost(20).unwrap_err();
+        assert_eq!(err.to_string(), "filter cost 24 exceeds the maximum of 20");
+    }
+
+    #[test]
+    fn test_private_kinds() {
+        let dms = Filter::from_json(r##"{"kinds":[1,4],"#p":["Viewer"]}"##).unwrap();
+        assert!(dms.requests_private_kinds());
+        assert!(dms.names_only_pubkey("viewer"));
+        assert!(!dms.names_only_pubkey("someone"));
+        let sent = Filter::from_json(r#"{"kinds":[1059],"authors":["viewer"]}"#).unwrap();
+        assert!(sent.requests_private_kinds() && sent.names_only_pubkey("viewer"));
+        // Naming the viewer doesn't open up the other pubkeys listed beside it
+        let mixed = Filter::from_json(r#"{"kinds":[4],"authors":["viewer","someone"]}"#).unwrap();
+        assert!(!mixed.names_only_pubkey("viewer"));
+        let tagged = Filter::from_json(r##"{"kinds":[4],"authors":["viewer"],"#p":["someone"]}"##).unwrap();
+        assert!(!tagged.names_only_pubkey("viewer"));
+        assert!(!Filter::from_json(r#"{"kinds":[4]}"#).unwrap().names_only_pubkey("viewer"));
+        assert!(!Filter::from_json(r#"{"authors":["viewer"]}"#).unwrap().requests_private_kinds());
+
+        let to_viewer = serde_json::json!({"pubkey": "someone", "tags": [["p", "Viewer"]]});
+        assert!(event_involves(&to_viewer, "viewer"));
+        assert!(event_involves(&serde_json::json!({"pubkey": "viewer", "tags": []}), "viewer"));
+        let elsewhere = serde_json::json!({"pubkey": "someone", "tags": [["e", "viewer"], ["p", "other"]]});
+        assert!(!event_involves(&elsewhere, "viewer"));
+    }
+
+    #[test]
+    fn test_prefix_matching() {
+        let full = "ab".repeat(32);
+        let filter = Filter::from_json(r#"{"ids":["ABC","ff"],"authors":["12"]}"#).unwrap();
+        assert!(filter.has_prefixes());
+        assert!(filter.matches_ids_and_authors(&serde_json::json!({"id": "abc0", "pubkey": "1234"})));
+        assert!(filter.matches_ids_and_authors(&serde_json::json!({"id": "ff", "pubkey": "12"})));
+        assert!(!filter.matches_ids_and_authors(&serde_json::json!({"id": "abd0", "pubkey": "1234"})));
+        assert!(!filter.matches_ids_and_authors(&serde_json::json!({"id": "abc0", "pubkey": "2234"})));
+        assert!(!filter.matches_ids_and_authors(&serde_json::json!({"id": "a", "pubkey": "12"})));
+
+        let exact = Filter::note(&full);
+        assert!(!exact.has_prefixes());
+        assert!(exact.matches_ids_and_authors(&serde_json::json!({"id": full, "pubkey": "x"})));
+        assert!(Filter::from_json(r#"{"kinds":[1]}"#).unwrap().matches_ids_and_authors(&serde_json::json!({})));
+
+        assert!(is_hex_prefix("a") && is_hex_prefix(&full));
+        assert!(!is_hex_prefix("") && !is_hex_prefix("xyz") && !is_hex_prefix(&format!("{}0", full)));
+    }
+
+    #[test]
+    fn test_check_fields() {
+        let id = "a".repeat(64);
+        let value = serde_json::json!({"ids": [id], "kinds": [1], "#t": ["music"], "limit": 20, "foo": 1});
+        let report = check_fields(value.as_object().unwrap());
+        assert_eq!(report.recognized, vec!["#t", "ids", "kinds", "limit"]);
+        assert_eq!(report.ignored, vec!["foo"]);
+        assert!(report.errors.is_empty());
+
+        let value = serde_json::json!({"kinds": "1", "authors": ["npub1xyz"], "since": 200, "until": 100});
+        let report = check_fields(value.as_object().unwrap());
+        assert_eq!(
+            report.errors,
+            vec![
+                "authors must hold hex strings of up to 64 characters",
+                "kinds must be an array of integers from 0 to 65535",
+                "since is after until, so nothing can match",
+            ]
+        );
     }
 
     #[test]
@@ -241,6 +1010,42 @@ mod tests {
         assert_eq!(FilterError::InvalidJson.to_string(), "invalid JSON filter");
     }
 
+    #[test]
+    fn test_with_max_limit() {
+        let within = Filter::from_json(r#"{"kinds":[1],"limit":50}"#).unwrap();
+        assert_eq!(within.with_max_limit(100).as_json(), within.as_json());
+
+        let over = Filter::from_json(r#"{"kinds":[1],"limit":5000}"#).unwrap().with_max_limit(100);
+        assert_eq!(over.limit(), Some(100));
+        assert_eq!(over.kinds(), Some(&[1u16][..]));
+        let value: serde_json::Value = serde_json::from_str(over.as_json()).unwrap();
+        assert_eq!(value["limit"], 100);
+
+        let unbounded = Filter::from_json(r#"{"kinds":[1]}"#).unwrap().with_max_limit(100);
+        assert_eq!(unbounded.limit(), Some(100));
+    }
+
+    #[test]
+    fn test_apply_limit() {
+        let events = || {
+            vec![
+                serde_json::json!({"id": "b", "created_at": 10}),
+                serde_json::json!({"id": "c", "created_at": 30}),
+                serde_json::json!({"id": "a", "created_at": 10}),
+                serde_json::json!({"id": "d", "created_at": 20}),
+            ]
+        };
+        let ids = |events: &[serde_json::Value]| events.iter().map(|e| e["id"].as_str().unwrap()).collect::<Vec<_>>();
+
+        let mut limited = events();
+        Filter::from_json(r#"{"kinds":[1],"limit":3}"#).unwrap().apply_limit(&mut limited);
+        assert_eq!(ids(&limited), ["c", "d", "a"]);
+
+        let mut all = events();
+        Filter::from_json(r#"{"kinds":[1]}"#).unwrap().apply_limit(&mut all);
+        assert_eq!(ids(&all), ["c", "d", "a", "b"]);
+    }
+
     #[test]
     fn test_limit_extraction() {
         let filter = Filter::from_json(r#"{"limit":50}"#).unwrap();
@@ -249,4 +1054,59 @@ mod tests {
         let no_limit = Filter::from_json(r#"{"kinds":[1]}"#).unwrap();
         assert_eq!(no_limit.limit(), None);
     }
+
+    #[test]
+    fn test_field_accessors() {
+        let filter = Filter::from_json(
+            r#"{"ids":["a"],"authors":["b"],"kinds":[1,7],"since":100,"until":200}"#,
+        )
+        .unwrap();
+        assert_eq!(filter.ids(), Some(&["a".to_string()][..]));
+        assert_eq!(filter.authors(), Some(&["b".to_string()][..]));
+        assert_eq!(filter.kinds(), Some(&[1u16, 7][..]));
+        assert_eq!(filter.since(), Some(100));
+        assert_eq!(filter.until(), Some(200));
+
+        let empty = Filter::from_json("{}").unwrap();
+        assert!(empty.ids().is_none());
+        assert!(empty.since().is_none());
+    }
+
+    #[test]
+    fn test_tag_filters() {
+        let filter = Filter::from_json(r##"{"kinds":[1],"#t":["nostr","video"],"#p":["abc"]}"##).unwrap();
+        let mut tags = filter.tag_filters();
+        tags.sort();
+        assert_eq!(
+            tags,
+            vec![
+                ("p".to_string(), vec!["abc".to_string()]),
+                ("t".to_string(), vec!["nostr".to_string(), "video".to_string()]),
+            ]
+        );
+
+        let no_tags = Filter::from_json(r#"{"kinds":[1]}"#).unwrap();
+        assert!(no_tags.tag_filters().is_empty());
+    }
+
+    #[test]
+    fn test_lookup_filters_match_hand_written_json() {
+        let id = "a".repeat(64);
+        let event = Filter::note(&id);
+        let manual = Filter::from_json(&format!(r#"{{"ids":["{}"],"limit":1}}"#, id)).unwrap();
+        assert_eq!(event.cache_key(), manual.cache_key());
+        assert!(event.is_single_event_lookup());
+
+        let profile = Filter::profile("pk");
+        assert_eq!(profile.raw_json, r#"{"authors":["pk"],"kinds":[0],"limit":1}"#);
+        assert_eq!(profile.ttl_seconds(NOW, &KindTtls::default()), 900);
+
+        let mutes = Filter::mute_list("pk");
+        assert_eq!(mutes.raw_json, r#"{"authors":["pk"],"kinds":[10000],"limit":1}"#);
+
+        let batch = Filter::profiles(&["a".to_string(), "b".to_string()]);
+        assert_eq!(batch.raw_json, r#"{"authors":["a","b"],"kinds":[0],"limit":2}"#);
+        assert!(Filter::notes(&[]).matches_nothing());
+        assert!(!batch.matches_nothing());
+    }
 }
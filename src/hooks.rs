@@ -0,0 +1,275 @@
+// ABOUTME: Compiled-in extension hooks for request gating, event filtering and response rewriting
+// ABOUTME: Forks register deployment-specific policies here and enable them with GATEWAY_HOOKS
+
+use serde_json::Value;
+use worker::{console_log, Env, Method, Request, Response, Result};
+
+/// What a hook sees of the request it runs for
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestContext {
+    pub method: Method,
+    /// Path without the `/v1` prefix
+    pub path: String,
+    /// ISO 3166-1 alpha-2 country Cloudflare geolocated the client to
+    pub country: Option<String>,
+}
+
+impl RequestContext {
+    pub fn from_request(req: &Request, path: &str) -> Self {
+        Self {
+            method: req.method(),
+            path: path.to_string(),
+            country: crate::bot::cf_properties(req)["country"].as_str().map(String::from),
+        }
+    }
+}
+
+impl std::fmt::Display for RequestContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} {}", self.method, self.path)?;
+        match &self.country {
+            Some(country) => write!(f, " from {}", country),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Why a hook turned a request away, sent back as an `ErrorResponse`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    /// Filled in with the rejecting hook's name
+    pub hook: &'static str,
+    pub status: u16,
+    pub error: &'static str,
+    pub detail: String,
+}
+
+/// A deployment-specific policy. Every method defaults to letting things through,
+/// so a hook implements only the stages it cares about.
+pub trait Hook {
+    fn name(&self) -> &'static str;
+
+    /// Runs before routing; a rejection is answered without touching the cache or relay
+    fn check_request(&self, _ctx: &RequestContext) -> Option<Rejection> {
+        None
+    }
+
+    /// Whether an event may be served. Runs after the cache, which always holds
+    /// the unfiltered result.
+    fn allow_event(&self, _ctx: &RequestContext, _event: &Value) -> bool {
+        true
+    }
+
+    /// Last look at a response before CORS headers are added
+    fn finish_response(&self, _ctx: &RequestContext, _response: &mut Response) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The hooks a deployment compiles in, by the name `GATEWAY_HOOKS` uses.
+/// Forks add their own policies here.
+fn build(name: &str, env: &Env) -> Option<Box<dyn Hook>> {
+    match name {
+        GeoBlock::NAME => Some(Box::new(GeoBlock::from_env(env))),
+        PubkeyDenylist::NAME => Some(Box::new(PubkeyDenylist::from_env(env))),
+        _ => None,
+    }
+}
+
+/// Hooks enabled for this deployment, run in the order `GATEWAY_HOOKS` lists them
+pub struct Hooks {
+    ctx: RequestContext,
+    hooks: Vec<Box<dyn Hook>>,
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.hooks.iter().map(|h| h.name())).finish()
+    }
+}
+
+impl Hooks {
+    pub fn from_env(env: &Env, ctx: RequestContext) -> Self {
+        let names = env.var("GATEWAY_HOOKS").map(|v| v.to_string()).unwrap_or_default();
+        let hooks = names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .filter_map(|name| {
+                let hook = build(name, env);
+                if hook.is_none() {
+                    console_log!("Unknown gateway hook {}", name);
+                }
+                hook
+            })
+            .collect();
+        Self::new(ctx, hooks)
+    }
+
+    pub fn new(ctx: RequestContext, hooks: Vec<Box<dyn Hook>>) -> Self {
+        Self { ctx, hooks }
+    }
+
+    pub fn context(&self) -> &RequestContext {
+        &self.ctx
+    }
+
+    pub fn check_request(&self) -> Option<Rejection> {
+        self.hooks.iter().find_map(|h| {
+            h.check_request(&self.ctx).map(|rejection| Rejection {
+                hook: h.name(),
+                ..rejection
+            })
+        })
+    }
+
+    /// Whether every hook lets an event be served
+    pub fn allow_event(&self, event: &Value) -> bool {
+        self.hooks.iter().all(|h| h.allow_event(&self.ctx, event))
+    }
+
+    /// Drop the events any hook disallows; returns how many were dropped
+    pub fn filter_events(&self, events: &mut Vec<Value>) -> usize {
+        let before = events.len();
+        events.retain(|event| self.allow_event(event));
+        before - events.len()
+    }
+
+    pub fn finish_response(&self, response: &mut Response) -> Result<()> {
+        for hook in &self.hooks {
+            hook.finish_response(&self.ctx, response)?;
+        }
+        Ok(())
+    }
+}
+
+/// Comma-separated list from a var, trimmed, with empty entries dropped
+fn list_var(env: &Env, name: &str) -> Vec<String> {
+    env.var(name)
+        .map(|v| v.to_string())
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Refuses requests from the countries in `BLOCKED_COUNTRIES` with a 451
+pub struct GeoBlock {
+    countries: Vec<String>,
+}
+
+impl GeoBlock {
+    const NAME: &'static str = "geo-block";
+
+    pub fn new(countries: Vec<String>) -> Self {
+        Self {
+            countries: countries.into_iter().map(|c| c.to_ascii_uppercase()).collect(),
+        }
+    }
+
+    fn from_env(env: &Env) -> Self {
+        Self::new(list_var(env, "BLOCKED_COUNTRIES"))
+    }
+}
+
+impl Hook for GeoBlock {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn check_request(&self, ctx: &RequestContext) -> Option<Rejection> {
+        let country = ctx.country.as_deref()?;
+        self.countries.iter().any(|c| c == country).then(|| Rejection {
+            hook: Self::NAME,
+            status: 451,
+            error: "unavailable_for_legal_reasons",
+            detail: format!("this gateway is not available in {}", country),
+        })
+    }
+}
+
+/// Withholds events authored by the pubkeys in `BLOCKED_PUBKEYS`
+pub struct PubkeyDenylist {
+    pubkeys: Vec<String>,
+}
+
+impl PubkeyDenylist {
+    const NAME: &'static str = "pubkey-denylist";
+
+    pub fn new(pubkeys: Vec<String>) -> Self {
+        Self {
+            pubkeys: pubkeys.into_iter().map(|p| p.to_ascii_lowercase()).collect(),
+        }
+    }
+
+    fn from_env(env: &Env) -> Self {
+        Self::new(list_var(env, "BLOCKED_PUBKEYS"))
+    }
+}
+
+impl Hook for PubkeyDenylist {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn allow_event(&self, _ctx: &RequestContext, event: &Value) -> bool {
+        let author = event.get("pubkey").and_then(|v| v.as_str()).unwrap_or_default();
+        !self.pubkeys.iter().any(|p| p == author)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn context(country: Option<&str>) -> RequestContext {
+        RequestContext {
+            method: Method::Get,
+            path: "/query".to_string(),
+            country: country.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_geo_block() {
+        let hooks = Hooks::new(context(Some("KP")), vec![Box::new(GeoBlock::new(vec!["kp".to_string()]))]);
+        let rejection = hooks.check_request().unwrap();
+        assert_eq!(rejection.status, 451);
+        assert_eq!(rejection.error, "unavailable_for_legal_reasons");
+        assert_eq!(rejection.hook, "geo-block");
+        assert!(hooks.context().to_string().ends_with(" /query from KP"));
+
+        let hooks = Hooks::new(context(Some("DE")), vec![Box::new(GeoBlock::new(vec!["KP".to_string()]))]);
+        assert!(hooks.check_request().is_none());
+        // Requests Cloudflare couldn't place are let through
+        let hooks = Hooks::new(context(None), vec![Box::new(GeoBlock::new(vec!["KP".to_string()]))]);
+        assert!(hooks.check_request().is_none());
+    }
+
+    #[test]
+    fn test_pubkey_denylist() {
+        let blocked = "AB".repeat(32);
+        let hooks = Hooks::new(context(None), vec![Box::new(PubkeyDenylist::new(vec![blocked.clone()]))]);
+        let mut events = vec![
+            json!({"id": "1", "pubkey": blocked.to_lowercase()}),
+            json!({"id": "2", "pubkey": "cd".repeat(32)}),
+            json!({"id": "3"}),
+        ];
+        assert_eq!(hooks.filter_events(&mut events), 1);
+        let ids: Vec<_> = events.iter().map(|e| e["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, vec!["2", "3"]);
+    }
+
+    #[test]
+    fn test_no_hooks() {
+        let hooks = Hooks::new(context(Some("KP")), vec![]);
+        let mut events = vec![json!({"id": "1"})];
+        assert!(hooks.check_request().is_none());
+        assert_eq!(hooks.filter_events(&mut events), 0);
+
+        let hooks = Hooks::new(context(None), vec![Box::new(GeoBlock::new(vec![]))]);
+        assert_eq!(format!("{:?}", hooks), "[\"geo-block\"]");
+    }
+}
@@ -0,0 +1,136 @@
+// ABOUTME: Edge caching of rendered HTML surfaces (embeds) in the Cache API
+// ABOUTME: Keyed by event id and template version so each event is rendered once per colo
+
+use crate::decision_log::{self, Decision, Layer, Outcome};
+use crate::ttl::TtlBounds;
+use worker::*;
+
+/// Rendered pages are reused this long; events are immutable, only author profiles drift
+const HTML_TTL_SECONDS: u64 = 86400;
+
+/// An HTML page rendered from a single event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HtmlSurface {
+    Embed,
+}
+
+impl HtmlSurface {
+    const ALL: [HtmlSurface; 1] = [HtmlSurface::Embed];
+
+    fn name(&self) -> &'static str {
+        match self {
+            HtmlSurface::Embed => "embed",
+        }
+    }
+
+    /// Bump when a surface's template changes so stale renders are never served
+    fn template_version(&self) -> u32 {
+        match self {
+            HtmlSurface::Embed => crate::embed::TEMPLATE_VERSION,
+        }
+    }
+}
+
+/// Synthetic Cache API key. `host` keeps deployments on different domains apart,
+/// since the canonical URL is baked into the page, and `branding` is the revision
+/// of the host's branding, so edits to it aren't hidden behind old renders.
+pub fn cache_key(surface: HtmlSurface, host: &str, branding: &str, event_id: &str) -> String {
+    format!(
+        "https://{}/__html/{}/v{}/{}/{}",
+        host,
+        surface.name(),
+        surface.template_version(),
+        branding,
+        event_id.to_ascii_lowercase()
+    )
+}
+
+pub async fn get(surface: HtmlSurface, host: &str, branding: &str, event_id: &str) -> Option<Response> {
+    Cache::default()
+        .get(cache_key(surface, host, branding, event_id), false)
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Build the HTML response and store a copy. Cache failures are logged, not returned.
+pub async fn put(
+    surface: HtmlSurface,
+    host: &str,
+    branding: &str,
+    event_id: &str,
+    html: String,
+    max_age: u64,
+    bounds: &TtlBounds,
+) -> Result<Response> {
+    let max_age = bounds.apply(max_age);
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "text/html; charset=utf-8")?;
+    headers.set("Cache-Control", &format!("public, max-age={}, s-maxage={}", max_age, max_age))?;
+    let response = Response::from_body(ResponseBody::Body(html.into_bytes()))?.with_headers(headers);
+
+    let mut stored = response.cloned()?;
+    stored
+        .headers_mut()
+        .set("Cache-Control", &format!("public, max-age={}", bounds.apply(HTML_TTL_SECONDS)))?;
+    if let Err(e) = Cache::default().put(cache_key(surface, host, branding, event_id), stored).await {
+        console_log!("HTML cache put failed: {}", e);
+    }
+    Ok(response)
+}
+
+/// Drop every rendered surface for an event. The Cache API is per-colo, so
+/// this only clears the colo handling the request. `trigger` names the cause
+/// in the decision log.
+pub async fn purge_event(env: &Env, host: &str, event_id: &str, trigger: &'static str) {
+    let cache = Cache::default();
+    let branding = crate::branding::get(env, host).await.revision();
+    for surface in HtmlSurface::ALL {
+        let key = cache_key(surface, host, &branding, event_id);
+        if let Err(e) = cache.delete(key.clone(), false).await {
+            console_log!("HTML cache purge failed: {}", e);
+        }
+        decision_log::record(env, &Decision::new(Layer::Edge, key, Outcome::Invalidated).with_trigger(trigger));
+    }
+}
+
+/// Event ids referenced by a NIP-09 deletion (kind 5) event's `e` tags
+pub fn deletion_targets(event: &serde_json::Value) -> Vec<String> {
+    if event.get("kind").and_then(|k| k.as_u64()) != Some(5) {
+        return Vec::new();
+    }
+    event
+        .get("tags")
+        .and_then(|t| t.as_array())
+        .map(|tags| {
+            tags.iter()
+                .filter_map(|tag| tag.as_array())
+                .filter(|tag| tag.first().and_then(|n| n.as_str()) == Some("e"))
+                .filter_map(|tag| tag.get(1).and_then(|v| v.as_str()).map(String::from))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_cache_key_includes_template_version() {
+        let key = cache_key(HtmlSurface::Embed, "gateway.divine.video", "1a2b3c4d", "ABCD");
+        assert_eq!(
+            key,
+            format!("https://gateway.divine.video/__html/embed/v{}/1a2b3c4d/abcd", crate::embed::TEMPLATE_VERSION)
+        );
+    }
+
+    #[test]
+    fn test_deletion_targets() {
+        let deletion = json!({"kind": 5, "tags": [["e", "aa"], ["p", "pk"], ["e", "bb"], ["e"]]});
+        assert_eq!(deletion_targets(&deletion), vec!["aa", "bb"]);
+        let note = json!({"kind": 1, "tags": [["e", "aa"]]});
+        assert!(deletion_targets(&note).is_empty());
+    }
+}
@@ -0,0 +1,155 @@
+// ABOUTME: Cache entries refreshed or dropped once the queue consumer confirms a publish
+// ABOUTME: A new profile or contact list replaces its cached entry, deletions are applied, and presets and feeds naming the author are purged
+
+use crate::cache::Cache;
+use crate::custom_routes::{self, CustomRoute};
+use crate::decision_log::{self, Decision, Layer, Outcome};
+use crate::deletions;
+use crate::filter::Filter;
+use crate::kind;
+use crate::presets::{self, Preset};
+use crate::router::cache_ttl;
+use serde_json::Value;
+use std::collections::HashMap;
+use worker::*;
+
+/// Presets and feeds read per queue batch; past this, the rest wait out their TTL
+const MAX_SAVED_FILTERS: u64 = 100;
+
+/// The lookup whose cached entry a replaceable event supersedes outright
+pub fn replaced_lookup(pubkey: &str, kind: u16) -> Option<Filter> {
+    match kind {
+        kind::PROFILE => Some(Filter::profile(pubkey)),
+        kind::CONTACTS => Some(Filter::contacts(pubkey)),
+        _ => None,
+    }
+}
+
+/// Whether a saved filter asks for events by `pubkey` of `kind`
+pub fn names_author(filter: &Filter, pubkey: &str, kind: u16) -> bool {
+    filter
+        .authors()
+        .is_some_and(|authors| authors.iter().any(|a| a.eq_ignore_ascii_case(pubkey)))
+        && filter.kinds().map_or(true, |kinds| kinds.contains(&kind))
+}
+
+/// The filters of presets and feeds (as requested without parameters), read
+/// once per queue batch and shared by every publish in it
+#[derive(Default)]
+pub struct SavedFilters(Vec<Filter>);
+
+impl SavedFilters {
+    pub fn new(presets: &[Preset], routes: &[CustomRoute]) -> Self {
+        let preset_filters = presets.iter().filter_map(|p| p.to_filter().ok());
+        let route_filters = routes.iter().filter_map(|r| r.request_filter(&HashMap::new()).ok());
+        Self(preset_filters.chain(route_filters).collect())
+    }
+
+    /// Read them from KV; the presets and feeds themselves are fetched in parallel
+    pub async fn load(env: &Env, cache: &Cache) -> Result<Self> {
+        use futures_util::future::join_all;
+        let kv = env.kv("REST_GATEWAY_CACHE")?;
+        let (preset_keys, _) = cache.list_keys(presets::KEY_PREFIX, None, MAX_SAVED_FILTERS).await?;
+        let (route_keys, _) = cache.list_keys(custom_routes::KEY_PREFIX, None, MAX_SAVED_FILTERS).await?;
+        let presets = join_all(
+            preset_keys.iter().map(|k| presets::get(&kv, k.trim_start_matches(presets::KEY_PREFIX))),
+        );
+        let routes = join_all(
+            route_keys.iter().map(|k| custom_routes::get(&kv, k.trim_start_matches(custom_routes::KEY_PREFIX))),
+        );
+        let (presets, routes) = futures_util::future::join(presets, routes).await;
+        let presets: Vec<Preset> = presets.into_iter().collect::<Result<Vec<_>>>()?.into_iter().flatten().collect();
+        let routes: Vec<CustomRoute> = routes.into_iter().collect::<Result<Vec<_>>>()?.into_iter().flatten().collect();
+        Ok(Self::new(&presets, &routes))
+    }
+
+    /// Cache keys of those a new event by `pubkey` of `kind` would show up in
+    pub fn keys(&self, pubkey: &str, kind: u16) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .0
+            .iter()
+            .filter(|f| names_author(f, pubkey, kind))
+            .map(|f| f.cache_key())
+            .collect();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+}
+
+/// Bring the cache up to date with a published event. Failures are logged: the
+/// event is on the relay either way, and the entries still expire on their own.
+pub async fn on_publish(env: &Env, cache: &Cache, saved: &SavedFilters, event: &Value) {
+    let pubkey = event.get("pubkey").and_then(Value::as_str).unwrap_or_default();
+    let Some(kind) = event.get("kind").and_then(Value::as_u64).and_then(|k| u16::try_from(k).ok()) else {
+        return;
+    };
+    if pubkey.is_empty() || kind::is_private_event(event) {
+        return;
+    }
+    if kind == kind::DELETION {
+        if let Err(e) = deletions::apply(env, cache, std::slice::from_ref(event)).await {
+            console_log!("Applying deletion after publish failed: {}", e);
+        }
+    }
+    if let Some(filter) = replaced_lookup(pubkey, kind) {
+        if let Err(e) = replace(env, cache, &filter, event).await {
+            console_log!("Refreshing {} after publish failed: {}", filter.cache_key(), e);
+        }
+    }
+    for key in saved.keys(pubkey, kind) {
+        match cache.delete_key(&key).await {
+            Ok(()) => {
+                let decision = Decision::new(Layer::Kv, key.as_str(), Outcome::Invalidated).with_trigger("publish");
+                decision_log::record(env, &decision);
+            }
+            Err(e) => console_log!("Purging {} after publish failed: {}", key, e),
+        }
+    }
+}
+
+/// Cache the event as the whole answer to `filter`, unless the entry already
+/// holds something newer (an older event can be published after a newer one)
+async fn replace(env: &Env, cache: &Cache, filter: &Filter, event: &Value) -> Result<()> {
+    let created_at = |e: &Value| e.get("created_at").and_then(Value::as_u64).unwrap_or(0);
+    if let Some((cached, _)) = cache.get_stored(&filter.cache_key()).await? {
+        if cached.events.iter().any(|e| created_at(e) > created_at(event)) {
+            return Ok(());
+        }
+    }
+    cache.put_query(filter, vec![event.clone()], true, cache_ttl(env, filter)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PK: &str = "82341f882b6eabcd2ba7f1ef90aad961cf074af15b9ef44a09f9d2a8fbfbe6a2";
+
+    #[test]
+    fn test_replaced_lookup() {
+        assert_eq!(replaced_lookup(PK, 0).map(|f| f.cache_key()), Some(format!("profile:{}", PK)));
+        assert_eq!(replaced_lookup(PK, 3).map(|f| f.cache_key()), Some(Filter::contacts(PK).cache_key()));
+        assert!(replaced_lookup(PK, 1).is_none());
+    }
+
+    #[test]
+    fn test_saved_filters_naming_the_author() {
+        let preset = |name: &str, filter: Value| Preset {
+            name: name.to_string(),
+            filter,
+            updated_at: 0,
+        };
+        let presets = vec![
+            preset("mine", serde_json::json!({"authors": [PK], "kinds": [34236]})),
+            preset("any-kind", serde_json::json!({"authors": [PK.to_uppercase()]})),
+            preset("notes", serde_json::json!({"authors": [PK], "kinds": [1]})),
+            preset("everyone", serde_json::json!({"kinds": [34236]})),
+        ];
+        let saved = SavedFilters::new(&presets, &[]);
+        let keys = saved.keys(PK, 34236);
+        assert_eq!(keys.len(), 2);
+        assert!(keys.contains(&presets[0].to_filter().unwrap().cache_key()));
+        assert!(saved.keys(&"ff".repeat(32), 34236).is_empty());
+    }
+}
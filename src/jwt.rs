@@ -0,0 +1,331 @@
+// ABOUTME: Service-to-service JWT verification (ES256, RS256) against operator-configured public keys
+// ABOUTME: Lets a backend that already issues JWTs call authenticated endpoints without minting NIP-98 events
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsCast;
+use worker::wasm_bindgen_futures::JsFuture;
+use worker::Env;
+
+/// Clock skew tolerated on `exp` and `nbf`
+const LEEWAY_SECONDS: u64 = 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JwtError {
+    Malformed,
+    UnsupportedAlgorithm,
+    UnknownKey,
+    InvalidSignature,
+    Expired,
+    NotYetValid,
+    WrongIssuer,
+    WrongAudience,
+}
+
+impl std::fmt::Display for JwtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "malformed JWT"),
+            Self::UnsupportedAlgorithm => write!(f, "JWT algorithm must be ES256 or RS256"),
+            Self::UnknownKey => write!(f, "JWT signed with an unknown key"),
+            Self::InvalidSignature => write!(f, "invalid JWT signature"),
+            Self::Expired => write!(f, "JWT expired"),
+            Self::NotYetValid => write!(f, "JWT not yet valid"),
+            Self::WrongIssuer => write!(f, "JWT issuer not accepted"),
+            Self::WrongAudience => write!(f, "JWT audience does not include this gateway"),
+        }
+    }
+}
+
+/// A public key in JWK form: `EC` on P-256 for ES256, `RSA` for RS256
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Jwk {
+    pub kty: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Header {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Claims {
+    pub iss: String,
+    /// The calling service
+    #[serde(default)]
+    pub sub: String,
+    /// One audience or a list of them
+    pub aud: serde_json::Value,
+    pub exp: u64,
+    #[serde(default)]
+    pub nbf: Option<u64>,
+}
+
+/// Accepted issuer, audience and signing keys
+#[derive(Debug, Clone)]
+pub struct JwtConfig {
+    pub issuer: String,
+    pub audience: String,
+    pub keys: Vec<Jwk>,
+}
+
+impl JwtConfig {
+    /// From `JWT_ISSUER`, `JWT_AUDIENCE` and a JWK set in `JWT_PUBLIC_KEYS`.
+    /// JWTs aren't accepted unless all three are set.
+    pub fn from_env(env: &Env) -> Option<Self> {
+        let var = |name: &str| env.var(name).ok().map(|v| v.to_string()).filter(|v| !v.is_empty());
+        let keys: JwkSet = serde_json::from_str(&var("JWT_PUBLIC_KEYS")?).ok()?;
+        Some(Self {
+            issuer: var("JWT_ISSUER")?,
+            audience: var("JWT_AUDIENCE")?,
+            keys: keys.keys,
+        })
+    }
+
+    /// Check the signature and claims of a compact JWT, at `now` (unix seconds)
+    pub async fn verify(&self, token: &str, now: u64) -> Result<Claims, JwtError> {
+        let token = decode(token)?;
+        let key = self.key_for(&token.header)?;
+        let valid = match token.header.alg.as_str() {
+            "ES256" => verify_es256(key, token.signing_input.as_bytes(), &token.signature)?,
+            "RS256" => verify_rs256(key, token.signing_input.as_bytes(), &token.signature).await?,
+            _ => return Err(JwtError::UnsupportedAlgorithm),
+        };
+        if !valid {
+            return Err(JwtError::InvalidSignature);
+        }
+        self.check_claims(&token.claims, now)?;
+        Ok(token.claims)
+    }
+
+    /// The configured key a token names by `kid`, or the only one of its type
+    fn key_for(&self, header: &Header) -> Result<&Jwk, JwtError> {
+        let kty = match header.alg.as_str() {
+            "ES256" => "EC",
+            "RS256" => "RSA",
+            _ => return Err(JwtError::UnsupportedAlgorithm),
+        };
+        let mut candidates = self
+            .keys
+            .iter()
+            .filter(|key| key.kty == kty)
+            .filter(|key| header.kid.is_none() || key.kid == header.kid);
+        match (candidates.next(), candidates.next()) {
+            (Some(key), None) => Ok(key),
+            _ => Err(JwtError::UnknownKey),
+        }
+    }
+
+    fn check_claims(&self, claims: &Claims, now: u64) -> Result<(), JwtError> {
+        if claims.iss != self.issuer {
+            return Err(JwtError::WrongIssuer);
+        }
+        let audience = match &claims.aud {
+            serde_json::Value::String(aud) => aud == &self.audience,
+            serde_json::Value::Array(list) => list.iter().any(|aud| aud.as_str() == Some(self.audience.as_str())),
+            _ => false,
+        };
+        if !audience {
+            return Err(JwtError::WrongAudience);
+        }
+        if claims.exp.saturating_add(LEEWAY_SECONDS) < now {
+            return Err(JwtError::Expired);
+        }
+        if claims.nbf.is_some_and(|nbf| nbf > now + LEEWAY_SECONDS) {
+            return Err(JwtError::NotYetValid);
+        }
+        Ok(())
+    }
+}
+
+struct Token {
+    header: Header,
+    claims: Claims,
+    /// `{header}.{payload}` as sent, which the signature covers
+    signing_input: String,
+    signature: Vec<u8>,
+}
+
+fn decode(token: &str) -> Result<Token, JwtError> {
+    let mut parts = token.split('.');
+    let (Some(header), Some(payload), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(JwtError::Malformed);
+    };
+    let part = |encoded: &str| URL_SAFE_NO_PAD.decode(encoded).map_err(|_| JwtError::Malformed);
+    Ok(Token {
+        header: serde_json::from_slice(&part(header)?).map_err(|_| JwtError::Malformed)?,
+        claims: serde_json::from_slice(&part(payload)?).map_err(|_| JwtError::Malformed)?,
+        signing_input: format!("{}.{}", header, payload),
+        signature: part(signature)?,
+    })
+}
+
+fn verify_es256(key: &Jwk, input: &[u8], signature: &[u8]) -> Result<bool, JwtError> {
+    if key.crv.as_deref() != Some("P-256") {
+        return Err(JwtError::UnknownKey);
+    }
+    let coordinate = |c: &Option<String>| {
+        c.as_deref()
+            .and_then(|c| URL_SAFE_NO_PAD.decode(c).ok())
+            .filter(|bytes| bytes.len() == 32)
+            .ok_or(JwtError::UnknownKey)
+    };
+    let mut sec1 = vec![0x04];
+    sec1.extend(coordinate(&key.x)?);
+    sec1.extend(coordinate(&key.y)?);
+    let verifying_key = VerifyingKey::from_sec1_bytes(&sec1).map_err(|_| JwtError::UnknownKey)?;
+    // JWS carries the raw 64-byte r || s, not DER
+    let Ok(signature) = Signature::from_slice(signature) else {
+        return Ok(false);
+    };
+    Ok(verifying_key.verify(input, &signature).is_ok())
+}
+
+/// RS256 goes through WebCrypto, since none of the pure-Rust crates in the
+/// build do RSA
+async fn verify_rs256(key: &Jwk, input: &[u8], signature: &[u8]) -> Result<bool, JwtError> {
+    let subtle = js_sys::Reflect::get(&js_sys::global(), &"crypto".into())
+        .and_then(|crypto| js_sys::Reflect::get(&crypto, &"subtle".into()))
+        .map_err(|_| JwtError::UnsupportedAlgorithm)?;
+    let call = |method: &str, args: js_sys::Array| {
+        js_sys::Reflect::get(&subtle, &method.into())
+            .ok()
+            .and_then(|f| f.dyn_into::<js_sys::Function>().ok())
+            .and_then(|f| f.apply(&subtle, &args).ok())
+            .and_then(|p| p.dyn_into::<js_sys::Promise>().ok())
+            .ok_or(JwtError::UnsupportedAlgorithm)
+    };
+    let parse = |json: &str| js_sys::JSON::parse(json).map_err(|_| JwtError::UnknownKey);
+
+    let jwk = parse(&serde_json::to_string(key).map_err(|_| JwtError::UnknownKey)?)?;
+    let algorithm = parse(r#"{"name":"RSASSA-PKCS1-v1_5","hash":"SHA-256"}"#)?;
+    let usages = js_sys::Array::of1(&"verify".into());
+    let import = call("importKey", js_sys::Array::of5(&"jwk".into(), &jwk, &algorithm, &false.into(), &usages))?;
+    let crypto_key = JsFuture::from(import).await.map_err(|_| JwtError::UnknownKey)?;
+
+    let signature = js_sys::Uint8Array::from(signature);
+    let data = js_sys::Uint8Array::from(input);
+    let verify = call("verify", js_sys::Array::of4(&algorithm, &crypto_key, &signature, &data))?;
+    let verified = JsFuture::from(verify).await.map_err(|_| JwtError::InvalidSignature)?;
+    Ok(verified.as_bool() == Some(true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::{signature::Signer, SigningKey};
+    use serde_json::json;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_slice(&[7u8; 32]).unwrap()
+    }
+
+    fn es256_jwk(kid: &str) -> Jwk {
+        let point = signing_key().verifying_key().to_encoded_point(false);
+        Jwk {
+            kty: "EC".to_string(),
+            kid: Some(kid.to_string()),
+            crv: Some("P-256".to_string()),
+            x: Some(URL_SAFE_NO_PAD.encode(point.x().unwrap())),
+            y: Some(URL_SAFE_NO_PAD.encode(point.y().unwrap())),
+            n: None,
+            e: None,
+        }
+    }
+
+    fn config() -> JwtConfig {
+        JwtConfig {
+            issuer: "https://auth.divine.video".to_string(),
+            audience: "rest-gateway".to_string(),
+            keys: vec![es256_jwk("k1")],
+        }
+    }
+
+    fn sign(header: serde_json::Value, claims: serde_json::Value) -> String {
+        let input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature: Signature = signing_key().sign(input.as_bytes());
+        format!("{}.{}", input, URL_SAFE_NO_PAD.encode(signature.to_bytes()))
+    }
+
+    fn claims() -> serde_json::Value {
+        json!({"iss": "https://auth.divine.video", "sub": "uploader", "aud": ["rest-gateway"], "exp": NOW + 300})
+    }
+
+    fn check(token: &str) -> Result<Claims, JwtError> {
+        let config = config();
+        let token = decode(token)?;
+        let key = config.key_for(&token.header)?;
+        if !verify_es256(key, token.signing_input.as_bytes(), &token.signature)? {
+            return Err(JwtError::InvalidSignature);
+        }
+        config.check_claims(&token.claims, NOW)?;
+        Ok(token.claims)
+    }
+
+    #[test]
+    fn test_es256_roundtrip() {
+        let token = sign(json!({"alg": "ES256", "kid": "k1"}), claims());
+        assert_eq!(check(&token).unwrap().sub, "uploader");
+
+        // A single key of the right type needs no kid
+        let token = sign(json!({"alg": "ES256"}), claims());
+        assert!(check(&token).is_ok());
+
+        let mut tampered = token.clone();
+        tampered.insert(tampered.find('.').unwrap() + 2, 'x');
+        assert!(check(&tampered).is_err());
+    }
+
+    #[test]
+    fn test_key_selection() {
+        assert_eq!(check(&sign(json!({"alg": "ES256", "kid": "k2"}), claims())).unwrap_err(), JwtError::UnknownKey);
+        assert_eq!(check(&sign(json!({"alg": "RS256"}), claims())).unwrap_err(), JwtError::UnknownKey);
+        assert_eq!(
+            check(&sign(json!({"alg": "none"}), claims())).unwrap_err(),
+            JwtError::UnsupportedAlgorithm
+        );
+        assert_eq!(check("a.b").unwrap_err(), JwtError::Malformed);
+    }
+
+    #[test]
+    fn test_claims() {
+        let with = |key: &str, value: serde_json::Value| {
+            let mut claims = claims();
+            claims[key] = value;
+            check(&sign(json!({"alg": "ES256"}), claims))
+        };
+        assert!(with("aud", json!("rest-gateway")).is_ok());
+        assert_eq!(with("aud", json!(["other"])).unwrap_err(), JwtError::WrongAudience);
+        assert_eq!(with("iss", json!("https://evil.example")).unwrap_err(), JwtError::WrongIssuer);
+        assert!(with("exp", json!(NOW - 30)).is_ok());
+        assert_eq!(with("exp", json!(NOW - 120)).unwrap_err(), JwtError::Expired);
+        assert_eq!(with("nbf", json!(NOW + 600)).unwrap_err(), JwtError::NotYetValid);
+    }
+}
@@ -0,0 +1,138 @@
+// ABOUTME: Named Nostr event kinds the gateway builds filters for or treats specially
+// ABOUTME: Plain u16 constants so they drop straight into filters and kind matches, plus replaceable-kind dedup
+
+/// NIP-01 profile metadata
+pub const PROFILE: u16 = 0;
+
+/// NIP-01 short text note
+pub const NOTE: u16 = 1;
+
+/// NIP-02 follow list
+pub const CONTACTS: u16 = 3;
+
+/// NIP-04 encrypted direct message
+pub const ENCRYPTED_DM: u16 = 4;
+
+/// NIP-09 deletion request
+pub const DELETION: u16 = 5;
+
+/// NIP-25 reaction
+pub const REACTION: u16 = 7;
+
+/// NIP-17 private direct message (the rumor inside a gift wrap)
+pub const PRIVATE_DM: u16 = 14;
+
+/// NIP-68 picture-first post
+pub const PICTURE: u16 = 20;
+
+/// NIP-59 gift wrap
+pub const GIFT_WRAP: u16 = 1059;
+
+/// NIP-57 zap receipt
+pub const ZAP_RECEIPT: u16 = 9735;
+
+/// NIP-51 mute list
+pub const MUTE_LIST: u16 = 10000;
+
+/// NIP-71 addressable normal video
+pub const VIDEO: u16 = 34235;
+
+/// NIP-71 addressable short-form video, what Divine publishes
+pub const SHORT_VIDEO: u16 = 34236;
+
+/// Direct messages and their envelopes: only ever served to their parties, never cached
+pub const PRIVATE: [u16; 3] = [ENCRYPTED_DM, PRIVATE_DM, GIFT_WRAP];
+
+/// Whether an event's `kind` is one of the private kinds
+pub fn is_private_event(event: &serde_json::Value) -> bool {
+    event["kind"].as_u64().is_some_and(|k| PRIVATE.iter().any(|&p| p as u64 == k))
+}
+
+/// NIP-01 replaceable kinds: only the newest event per author and kind counts
+pub fn is_replaceable(kind: u64) -> bool {
+    kind == PROFILE as u64 || kind == CONTACTS as u64 || (10000..20000).contains(&kind)
+}
+
+/// NIP-01 addressable kinds: only the newest per author, kind and `d` tag counts
+pub fn is_addressable(kind: u64) -> bool {
+    (30000..40000).contains(&kind)
+}
+
+/// What a replaceable or addressable event replaces earlier versions of
+fn replaces(event: &serde_json::Value) -> Option<(String, u64, String)> {
+    let kind = event["kind"].as_u64()?;
+    let pubkey = event["pubkey"].as_str()?.to_ascii_lowercase();
+    if is_replaceable(kind) {
+        return Some((pubkey, kind, String::new()));
+    }
+    if !is_addressable(kind) {
+        return None;
+    }
+    let d = event["tags"]
+        .as_array()
+        .and_then(|tags| tags.iter().find(|t| t[0].as_str() == Some("d")))
+        .and_then(|t| t[1].as_str())
+        .unwrap_or_default();
+    Some((pubkey, kind, d.to_string()))
+}
+
+/// Keep only the newest version of each replaceable or addressable event, as relays
+/// sometimes return several. Ties go to the lowest id, as in NIP-01. Other events,
+/// and the order of what's kept, are untouched.
+pub fn dedupe_replaceable(events: &mut Vec<serde_json::Value>) {
+    let rank = |e: &serde_json::Value| {
+        let id = e["id"].as_str().unwrap_or_default().to_string();
+        (e["created_at"].as_u64().unwrap_or(0), std::cmp::Reverse(id))
+    };
+    let mut newest = std::collections::HashMap::new();
+    for (i, event) in events.iter().enumerate() {
+        let Some(address) = replaces(event) else { continue };
+        match newest.get(&address) {
+            Some(&kept) if rank(&events[kept]) >= rank(event) => {}
+            _ => {
+                newest.insert(address, i);
+            }
+        }
+    }
+    if newest.is_empty() {
+        return;
+    }
+    let mut i = 0;
+    events.retain(|event| {
+        let keep = replaces(event).map_or(true, |address| newest.get(&address) == Some(&i));
+        i += 1;
+        keep
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_dedupe_replaceable() {
+        let mut events = vec![
+            json!({"id": "a", "pubkey": "pk", "kind": 0, "created_at": 10}),
+            json!({"id": "b", "pubkey": "pk", "kind": 1, "created_at": 5}),
+            json!({"id": "c", "pubkey": "pk", "kind": 0, "created_at": 20}),
+            json!({"id": "d", "pubkey": "pk", "kind": 1, "created_at": 5}),
+            json!({"id": "f", "pubkey": "pk", "kind": 34236, "created_at": 7, "tags": [["d", "x"]]}),
+            json!({"id": "e", "pubkey": "pk", "kind": 34236, "created_at": 7, "tags": [["d", "x"]]}),
+            json!({"id": "g", "pubkey": "pk", "kind": 34236, "created_at": 1, "tags": [["d", "y"]]}),
+            json!({"id": "h", "pubkey": "other", "kind": 0, "created_at": 1}),
+        ];
+        dedupe_replaceable(&mut events);
+        let ids: Vec<&str> = events.iter().map(|e| e["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, ["b", "c", "d", "e", "g", "h"]);
+        assert!(is_replaceable(10002) && !is_replaceable(20000) && is_addressable(30023));
+    }
+
+    #[test]
+    fn test_is_private_event() {
+        assert!(is_private_event(&serde_json::json!({"kind": 1059})));
+        assert!(is_private_event(&serde_json::json!({"kind": 4})));
+        assert!(!is_private_event(&serde_json::json!({"kind": 1})));
+        assert!(!is_private_event(&serde_json::json!({})));
+    }
+}
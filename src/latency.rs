@@ -0,0 +1,243 @@
+// ABOUTME: Per-relay query latency tracking and timeout autotuning
+// ABOUTME: Derives idle/empty timeouts for query_relay_raw from observed EOSE latency percentiles
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use worker::Env;
+
+/// Samples kept per relay; older samples roll off
+const MAX_SAMPLES: usize = 200;
+/// Below this many samples the defaults are used
+const MIN_SAMPLES: usize = 20;
+/// Headroom applied on top of the p95 so typical queries aren't truncated
+const HEADROOM: f64 = 1.5;
+
+/// Query timeouts in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Timeouts {
+    /// Stop waiting once no event has arrived for this long after the first one
+    pub idle_ms: f64,
+    /// Give up on queries with no events after this long
+    pub empty_ms: f64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            idle_ms: 300.0,
+            empty_ms: 1000.0,
+        }
+    }
+}
+
+/// Operator-set bounds that autotuned timeouts must stay within
+#[derive(Debug, Clone, Copy)]
+pub struct TimeoutBounds {
+    pub idle_min_ms: f64,
+    pub idle_max_ms: f64,
+    pub empty_min_ms: f64,
+    pub empty_max_ms: f64,
+}
+
+impl Default for TimeoutBounds {
+    fn default() -> Self {
+        Self {
+            idle_min_ms: 100.0,
+            idle_max_ms: 1500.0,
+            empty_min_ms: 300.0,
+            empty_max_ms: 4000.0,
+        }
+    }
+}
+
+impl TimeoutBounds {
+    /// Load from `RELAY_{IDLE,EMPTY}_TIMEOUT_{MIN,MAX}_MS` vars
+    pub fn from_env(env: &Env) -> Self {
+        let defaults = Self::default();
+        let var = |name: &str, default: f64| {
+            env.var(name)
+                .ok()
+                .and_then(|v| v.to_string().parse().ok())
+                .unwrap_or(default)
+        };
+        Self {
+            idle_min_ms: var("RELAY_IDLE_TIMEOUT_MIN_MS", defaults.idle_min_ms),
+            idle_max_ms: var("RELAY_IDLE_TIMEOUT_MAX_MS", defaults.idle_max_ms),
+            empty_min_ms: var("RELAY_EMPTY_TIMEOUT_MIN_MS", defaults.empty_min_ms),
+            empty_max_ms: var("RELAY_EMPTY_TIMEOUT_MAX_MS", defaults.empty_max_ms),
+        }
+    }
+}
+
+/// Rolling latency samples for one relay
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LatencyTracker {
+    /// Time from REQ to EOSE
+    eose_ms: VecDeque<u32>,
+    /// Largest gap between consecutive messages within a query
+    gap_ms: VecDeque<u32>,
+    /// Queries recorded over the tracker's lifetime
+    #[serde(default)]
+    total: u64,
+    /// Of those, queries that gave up without EOSE or any event
+    #[serde(default)]
+    timeouts: u64,
+}
+
+impl LatencyTracker {
+    /// Record one query. `max_gap_ms` is None when fewer than two messages arrived.
+    pub fn record(&mut self, eose_ms: f64, max_gap_ms: Option<f64>) {
+        push_capped(&mut self.eose_ms, eose_ms);
+        if let Some(gap) = max_gap_ms {
+            push_capped(&mut self.gap_ms, gap);
+        }
+        self.total += 1;
+    }
+
+    /// Record a query that gave up empty. How long it would have taken is unknown,
+    /// so it is counted but doesn't become a sample: the time waited is just the
+    /// current budget, and feeding it back would only ever raise the next one.
+    pub fn record_timeout(&mut self) {
+        self.total += 1;
+        self.timeouts += 1;
+    }
+
+    pub fn samples(&self) -> usize {
+        self.eose_ms.len()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn timeouts(&self) -> u64 {
+        self.timeouts
+    }
+
+    pub fn eose_percentile(&self, p: f64) -> Option<f64> {
+        percentile(&self.eose_ms, p)
+    }
+
+    pub fn gap_percentile(&self, p: f64) -> Option<f64> {
+        percentile(&self.gap_ms, p)
+    }
+
+    /// Timeouts tuned to this relay's p95 latency, clamped to operator bounds
+    pub fn tuned(&self, bounds: &TimeoutBounds) -> Timeouts {
+        let defaults = Timeouts::default();
+        let empty_ms = match self.eose_percentile(95.0) {
+            Some(p95) if self.eose_ms.len() >= MIN_SAMPLES => p95 * HEADROOM,
+            _ => defaults.empty_ms,
+        };
+        let idle_ms = match self.gap_percentile(95.0) {
+            Some(p95) if self.gap_ms.len() >= MIN_SAMPLES => p95 * HEADROOM,
+            _ => defaults.idle_ms,
+        };
+        Timeouts {
+            idle_ms: idle_ms.clamp(bounds.idle_min_ms, bounds.idle_max_ms.max(bounds.idle_min_ms)),
+            empty_ms: empty_ms.clamp(bounds.empty_min_ms, bounds.empty_max_ms.max(bounds.empty_min_ms)),
+        }
+    }
+}
+
+fn push_capped(samples: &mut VecDeque<u32>, value: f64) {
+    if samples.len() >= MAX_SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back(value.max(0.0) as u32);
+}
+
+/// Nearest-rank percentile
+fn percentile(samples: &VecDeque<u32>, p: f64) -> Option<f64> {
+    if samples.is_empty() {
+        return None;
+    }
+    let mut sorted: Vec<u32> = samples.iter().copied().collect();
+    sorted.sort_unstable();
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1] as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let mut tracker = LatencyTracker::default();
+        for ms in 1..=100 {
+            tracker.record(ms as f64, Some(ms as f64));
+        }
+        assert_eq!(tracker.eose_percentile(50.0), Some(50.0));
+        assert_eq!(tracker.eose_percentile(95.0), Some(95.0));
+        assert_eq!(tracker.eose_percentile(100.0), Some(100.0));
+        assert_eq!(LatencyTracker::default().eose_percentile(95.0), None);
+    }
+
+    #[test]
+    fn test_samples_are_capped() {
+        let mut tracker = LatencyTracker::default();
+        for _ in 0..(MAX_SAMPLES + 50) {
+            tracker.record(10.0, None);
+        }
+        assert_eq!(tracker.samples(), MAX_SAMPLES);
+        assert_eq!(tracker.total(), (MAX_SAMPLES + 50) as u64);
+    }
+
+    #[test]
+    fn test_defaults_until_enough_samples() {
+        let mut tracker = LatencyTracker::default();
+        for _ in 0..(MIN_SAMPLES - 1) {
+            tracker.record(3000.0, Some(1000.0));
+        }
+        assert_eq!(tracker.tuned(&TimeoutBounds::default()), Timeouts::default());
+    }
+
+    #[test]
+    fn test_fast_relay_gets_shorter_timeouts() {
+        let mut tracker = LatencyTracker::default();
+        for _ in 0..50 {
+            tracker.record(200.0, Some(40.0));
+        }
+        let timeouts = tracker.tuned(&TimeoutBounds::default());
+        assert_eq!(timeouts.empty_ms, 300.0); // 200 * 1.5
+        assert_eq!(timeouts.idle_ms, 100.0); // 60 clamped to the 100 floor
+    }
+
+    #[test]
+    fn test_slow_relay_clamped_to_max() {
+        let mut tracker = LatencyTracker::default();
+        for _ in 0..50 {
+            tracker.record(3500.0, Some(1200.0));
+        }
+        let timeouts = tracker.tuned(&TimeoutBounds::default());
+        assert_eq!(timeouts.empty_ms, 4000.0);
+        assert_eq!(timeouts.idle_ms, 1500.0);
+    }
+
+    #[test]
+    fn test_timeouts_dont_raise_the_budget() {
+        let mut tracker = LatencyTracker::default();
+        for _ in 0..50 {
+            tracker.record(200.0, Some(40.0));
+        }
+        let before = tracker.tuned(&TimeoutBounds::default());
+        for _ in 0..100 {
+            tracker.record_timeout();
+        }
+        assert_eq!(tracker.tuned(&TimeoutBounds::default()), before);
+        assert_eq!(tracker.timeouts(), 100);
+        assert_eq!(tracker.total(), 150);
+        assert_eq!(tracker.samples(), 50);
+    }
+
+    #[test]
+    fn test_tracker_roundtrip() {
+        let mut tracker = LatencyTracker::default();
+        tracker.record(120.0, Some(30.0));
+        let json = serde_json::to_string(&tracker).unwrap();
+        let restored: LatencyTracker = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.samples(), 1);
+        assert_eq!(restored.gap_percentile(50.0), Some(30.0));
+    }
+}
@@ -3,20 +3,133 @@
 
 use worker::*;
 
+mod admin;
+mod aggregate;
+mod api_keys;
+mod archive;
 mod auth;
+mod batch;
+mod bot;
+mod branding;
 mod cache;
+mod cache_sync;
+mod circuit;
+mod custom_routes;
+mod decision_log;
+mod deletions;
+mod degraded;
+mod embed;
+mod etag;
+mod expiration;
+mod fanout;
 mod filter;
+mod hooks;
+mod html_cache;
+mod http_date;
+mod invalidation;
+mod jwt;
+mod kind;
+mod latency;
+mod media;
+mod metrics;
+mod mirror;
+mod nip19;
+mod nwc;
+mod outbound;
+pub mod openapi;
+mod passthrough;
+mod presets;
+mod prewarm;
+mod publish_acl;
+mod publish_ledger;
+mod publish_state;
+mod push;
+mod quarantine;
 mod queue_consumer;
+mod quota;
+mod rate_limit;
+mod references;
+mod relay_auth;
+mod relay_info;
+mod relay_message;
+mod relay_mux;
 mod relay_pool;
 mod router;
+#[cfg(feature = "sdk")]
+pub mod sdk;
+mod session;
+mod signed_url;
+mod stats;
+mod ttl;
+mod turnstile;
 mod types;
+mod viewer;
+mod warming;
+mod webhooks;
+mod write_behind;
 
+/// Parsers of untrusted network input, for the native fuzz targets
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing {
+    pub use crate::auth::validate_nip98_at;
+    pub use crate::filter::Filter;
+    pub use crate::relay_message::parse as parse_relay_message;
+    pub use crate::ttl::KindTtls;
+}
+
+pub use deletions::DeletionIndex;
+pub use metrics::MetricsCollector;
+pub use publish_ledger::PublishLedger;
+pub use quota::QuotaCounter;
+pub use rate_limit::RateLimiter;
 pub use relay_pool::RelayPool;
+pub use webhooks::WebhookHub;
 
 #[event(fetch)]
-async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
+async fn fetch(req: Request, env: Env, ctx: Context) -> Result<Response> {
     console_error_panic_hook::set_once();
-    router::handle_request(req, env).await
+    let route = metrics::route_label(&req.path()).to_string();
+    let metrics_env = env.clone();
+    let operator = admin::is_operator(&req, &env);
+    let mut result = router::handle_request(req, env, &ctx).await;
+
+    // Count the request after responding so metrics never add latency
+    let observation = match &result {
+        Ok(resp) => {
+            let header = |name: &str| resp.headers().get(name).ok().flatten();
+            let cache = header("X-Cache");
+            let cache_class = header("X-Cache-Class");
+            metrics::Observation::Request {
+                route,
+                status: resp.status_code(),
+                cache_hit: cache.as_deref().map(|v| v == "HIT" || v == "STALE"),
+                events_served: header("X-Event-Count").and_then(|v| v.parse().ok()),
+                cache_key: header("X-Cache-Key"),
+                // Only lookups of a cacheable entry say anything about TTLs
+                cache_result: cache.filter(|_| cache_class.is_some()).map(|v| v.to_ascii_lowercase()),
+                cache_class,
+            }
+        }
+        Err(_) => metrics::Observation::Request {
+            route,
+            status: 500,
+            cache_hit: None,
+            events_served: None,
+            cache_key: None,
+            cache_result: None,
+            cache_class: None,
+        },
+    };
+    ctx.wait_until(async move {
+        metrics::record(&metrics_env, &observation).await;
+    });
+    // Which entry a response read is for the operator's debugging, not every client
+    if let Ok(resp) = &mut result {
+        if !operator {
+            resp.headers_mut().delete("X-Cache-Key")?;
+        }
+    }
+    result
 }
 
 #[event(queue)]
@@ -24,3 +137,22 @@ async fn queue(batch: MessageBatch<serde_json::Value>, env: Env, _ctx: Context)
     console_error_panic_hook::set_once();
     queue_consumer::handle_queue(batch, env).await
 }
+
+#[event(scheduled)]
+async fn scheduled(_event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    console_error_panic_hook::set_once();
+    relay_pool::warm_shards(&env).await;
+    if let Some(sync) = cache_sync::MirrorSync::from_env(&env) {
+        match cache_sync::pull(&env, &sync).await {
+            Ok(written) => console_log!("Cache sync copied {} entries from {}", written, sync.primary),
+            Err(e) => console_log!("Cache sync failed: {}", e),
+        }
+    }
+    if let Err(e) = stats::aggregate(&env).await {
+        console_log!("Stats aggregation failed: {}", e);
+    }
+    match warming::run(&env).await {
+        Ok(warmed) => console_log!("Cache warming refreshed {} entries", warmed),
+        Err(e) => console_log!("Cache warming failed: {}", e),
+    }
+}
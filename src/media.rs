@@ -0,0 +1,266 @@
+// ABOUTME: Media metadata parsing for video (NIP-71) and picture (NIP-68) events and imeta tags (NIP-92)
+// ABOUTME: Turns raw tag arrays into structured media entries for REST clients
+
+use crate::kind;
+use crate::types::{MediaEntry, PictureEvent, VideoEvent};
+use serde_json::Value;
+
+/// Addressable video kinds: normal (34235) and short-form (34236)
+pub const VIDEO_KINDS: [u16; 2] = [kind::VIDEO, kind::SHORT_VIDEO];
+
+/// Picture-first posts (NIP-68)
+pub const PICTURE_KIND: u16 = kind::PICTURE;
+
+/// Parse a single `["imeta", "url ...", "m ...", ...]` tag
+pub fn parse_imeta(tag: &[Value]) -> Option<MediaEntry> {
+    if tag.first()?.as_str()? != "imeta" {
+        return None;
+    }
+
+    let mut entry = MediaEntry::default();
+    for field in tag.iter().skip(1).filter_map(|v| v.as_str()) {
+        let Some((key, value)) = field.split_once(' ') else {
+            continue;
+        };
+        let value = value.trim().to_string();
+        match key {
+            "url" => entry.url = value,
+            "m" => entry.mime_type = Some(value),
+            "dim" => entry.dim = Some(value),
+            "duration" => entry.duration = value.parse().ok(),
+            "image" | "thumb" => entry.thumbnails.push(value),
+            "fallback" => entry.fallbacks.push(value),
+            "blurhash" => entry.blurhash = Some(value),
+            "alt" => entry.alt = Some(value),
+            "x" => entry.sha256 = Some(value),
+            _ => {}
+        }
+    }
+
+    (!entry.url.is_empty()).then_some(entry)
+}
+
+/// All media entries on an event, from imeta tags
+pub fn media_entries(event: &Value) -> Vec<MediaEntry> {
+    tags(event)
+        .filter_map(|tag| parse_imeta(tag))
+        .collect()
+}
+
+/// Build a structured video from a kind 34235/34236 event
+pub fn video_from_event(event: &Value) -> Option<VideoEvent> {
+    let kind = event.get("kind")?.as_u64()?;
+    if !VIDEO_KINDS.iter().any(|k| *k as u64 == kind) {
+        return None;
+    }
+
+    let mut media = media_entries(event);
+    if media.is_empty() {
+        // Older clients put url/m/thumb as top-level tags instead of imeta
+        if let Some(url) = tag_value(event, "url") {
+            media.push(MediaEntry {
+                url,
+                mime_type: tag_value(event, "m"),
+                dim: tag_value(event, "dim"),
+                thumbnails: tag_value(event, "thumb").or_else(|| tag_value(event, "image")).into_iter().collect(),
+                ..Default::default()
+            });
+        }
+    }
+
+    let duration = tag_value(event, "duration")
+        .and_then(|d| d.parse().ok())
+        .or_else(|| media.iter().find_map(|m| m.duration));
+
+    let content = event.get("content").and_then(|c| c.as_str()).unwrap_or_default();
+    let summary = if content.is_empty() {
+        tag_value(event, "summary")
+    } else {
+        Some(content.to_string())
+    };
+
+    Some(VideoEvent {
+        id: event.get("id")?.as_str()?.to_string(),
+        pubkey: event.get("pubkey")?.as_str()?.to_string(),
+        kind,
+        created_at: event.get("created_at")?.as_u64()?,
+        identifier: tag_value(event, "d"),
+        title: tag_value(event, "title"),
+        summary,
+        duration,
+        media,
+        event: event.clone(),
+    })
+}
+
+/// Build a structured picture post from a kind 20 event. Without an imeta
+/// tag there's nothing to show, so such events are skipped.
+pub fn picture_from_event(event: &Value) -> Option<PictureEvent> {
+    let kind = event.get("kind")?.as_u64()?;
+    if kind != PICTURE_KIND as u64 {
+        return None;
+    }
+    let media = media_entries(event);
+    if media.is_empty() {
+        return None;
+    }
+
+    let content = event.get("content").and_then(|c| c.as_str()).unwrap_or_default();
+    Some(PictureEvent {
+        id: event.get("id")?.as_str()?.to_string(),
+        pubkey: event.get("pubkey")?.as_str()?.to_string(),
+        created_at: event.get("created_at")?.as_u64()?,
+        title: tag_value(event, "title"),
+        description: (!content.is_empty()).then(|| content.to_string()),
+        content_warning: tag_value(event, "content-warning"),
+        hashtags: tag_values(event, "t"),
+        media,
+        event: event.clone(),
+    })
+}
+
+fn tags(event: &Value) -> impl Iterator<Item = &Vec<Value>> {
+    event
+        .get("tags")
+        .and_then(|t| t.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|t| t.as_array())
+}
+
+/// First value of the first tag with the given name
+pub fn tag_value(event: &Value, name: &str) -> Option<String> {
+    tags(event)
+        .find(|t| t.first().and_then(|v| v.as_str()) == Some(name))
+        .and_then(|t| t.get(1))
+        .and_then(|v| v.as_str())
+        .map(String::from)
+}
+
+/// Values of every tag with the given name
+fn tag_values(event: &Value, name: &str) -> Vec<String> {
+    tags(event)
+        .filter(|t| t.first().and_then(|v| v.as_str()) == Some(name))
+        .filter_map(|t| t.get(1).and_then(|v| v.as_str()).map(String::from))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn video_event() -> Value {
+        json!({
+            "id": "abc",
+            "pubkey": "def",
+            "kind": 34236,
+            "created_at": 1700000000,
+            "content": "A short loop",
+            "tags": [
+                ["d", "loop-1"],
+                ["title", "My Loop"],
+                ["duration", "6.5"],
+                ["imeta",
+                    "url https://cdn.divine.video/v.mp4",
+                    "m video/mp4",
+                    "dim 1080x1920",
+                    "image https://cdn.divine.video/poster.jpg",
+                    "fallback https://backup.example/v.mp4",
+                    "blurhash eVF$^OI:${M{%LRj",
+                    "x 1234"]
+            ],
+            "sig": "00"
+        })
+    }
+
+    #[test]
+    fn test_parse_imeta() {
+        let tag = vec![
+            json!("imeta"),
+            json!("url https://example.com/a.mp4"),
+            json!("dim 640x480"),
+            json!("duration 12.0"),
+            json!("image https://example.com/1.jpg"),
+            json!("image https://example.com/2.jpg"),
+            json!("alt a cat video"),
+        ];
+        let entry = parse_imeta(&tag).unwrap();
+        assert_eq!(entry.url, "https://example.com/a.mp4");
+        assert_eq!(entry.dim.as_deref(), Some("640x480"));
+        assert_eq!(entry.duration, Some(12.0));
+        assert_eq!(entry.thumbnails.len(), 2);
+        assert_eq!(entry.alt.as_deref(), Some("a cat video"));
+    }
+
+    #[test]
+    fn test_parse_imeta_requires_url() {
+        let tag = vec![json!("imeta"), json!("m video/mp4")];
+        assert!(parse_imeta(&tag).is_none());
+
+        let not_imeta = vec![json!("t"), json!("url https://example.com")];
+        assert!(parse_imeta(&not_imeta).is_none());
+    }
+
+    #[test]
+    fn test_video_from_event() {
+        let video = video_from_event(&video_event()).unwrap();
+        assert_eq!(video.identifier.as_deref(), Some("loop-1"));
+        assert_eq!(video.title.as_deref(), Some("My Loop"));
+        assert_eq!(video.summary.as_deref(), Some("A short loop"));
+        assert_eq!(video.duration, Some(6.5));
+        assert_eq!(video.media.len(), 1);
+        assert_eq!(video.media[0].mime_type.as_deref(), Some("video/mp4"));
+        assert_eq!(video.media[0].thumbnails, vec!["https://cdn.divine.video/poster.jpg"]);
+        assert_eq!(video.media[0].fallbacks, vec!["https://backup.example/v.mp4"]);
+        assert_eq!(video.media[0].sha256.as_deref(), Some("1234"));
+    }
+
+    #[test]
+    fn test_video_from_event_legacy_tags() {
+        let event = json!({
+            "id": "abc", "pubkey": "def", "kind": 34235, "created_at": 1, "content": "",
+            "tags": [["url", "https://example.com/v.mp4"], ["thumb", "https://example.com/t.jpg"], ["summary", "desc"]]
+        });
+        let video = video_from_event(&event).unwrap();
+        assert_eq!(video.media[0].url, "https://example.com/v.mp4");
+        assert_eq!(video.media[0].thumbnails, vec!["https://example.com/t.jpg"]);
+        assert_eq!(video.summary.as_deref(), Some("desc"));
+    }
+
+    #[test]
+    fn test_picture_from_event() {
+        let event = json!({
+            "id": "abc", "pubkey": "def", "kind": 20, "created_at": 1700000000,
+            "content": "Sunset over the bay",
+            "tags": [
+                ["title", "Sunset"],
+                ["imeta", "url https://cdn.example/1.jpg", "m image/jpeg", "dim 3024x4032",
+                    "blurhash eVF$^OI:${M{%LRj", "alt Orange sky over water"],
+                ["imeta", "url https://cdn.example/2.jpg", "m image/jpeg"],
+                ["t", "sunset"],
+                ["t", "photography"]
+            ]
+        });
+        let picture = picture_from_event(&event).unwrap();
+        assert_eq!(picture.title.as_deref(), Some("Sunset"));
+        assert_eq!(picture.description.as_deref(), Some("Sunset over the bay"));
+        assert_eq!(picture.hashtags, vec!["sunset", "photography"]);
+        assert_eq!(picture.media.len(), 2);
+        assert_eq!(picture.media[0].dim.as_deref(), Some("3024x4032"));
+        assert_eq!(picture.media[0].alt.as_deref(), Some("Orange sky over water"));
+        assert!(picture.media[0].blurhash.is_some());
+
+        let mut bare = event.clone();
+        bare["tags"] = json!([["title", "No media"]]);
+        assert!(picture_from_event(&bare).is_none());
+        assert!(picture_from_event(&video_event()).is_none());
+    }
+
+    #[test]
+    fn test_video_from_event_rejects_other_kinds() {
+        let mut event = video_event();
+        event["kind"] = json!(1);
+        assert!(video_from_event(&event).is_none());
+    }
+}
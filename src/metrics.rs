@@ -0,0 +1,632 @@
+// ABOUTME: Prometheus metrics kept in a Durable Object
+// ABOUTME: Counts requests, cache hits, relay query latency and publish outcomes, rendered as text format
+
+use crate::warming::HotKeys;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use worker::*;
+
+/// Upper bounds (seconds) of the relay query latency histogram buckets
+const LATENCY_BUCKETS: [f64; 8] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.0, 3.0, 5.0];
+
+const STORAGE_KEY: &str = "metrics";
+
+/// How long counters build up in memory before the collector writes them out
+const FLUSH_DELAY: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Route and TTL class pairs given their own cache counters; the rest are
+/// counted under class "other", as kinds in filters are up to clients
+const MAX_CACHE_CLASSES: usize = 500;
+
+/// One thing that happened, sent to the collector
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Observation {
+    Request {
+        route: String,
+        status: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_hit: Option<bool>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        events_served: Option<u64>,
+        /// Query cache entry the request read, counted for cache warming
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_key: Option<String>,
+        /// `hit`, `stale`, `miss` or `bypass`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_result: Option<String>,
+        /// The filter's TTL class (`kind:{n}`, `event`, ...)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_class: Option<String>,
+    },
+    RelayQuery {
+        seconds: f64,
+    },
+    Publish {
+        outcome: String,
+    },
+    /// A RelayPool instance handled its first call; `trigger` is "request" or "warmup"
+    ColdStart {
+        trigger: String,
+    },
+}
+
+/// Accumulated counters
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MetricsState {
+    /// route -> status -> count
+    requests: BTreeMap<String, BTreeMap<u16, u64>>,
+    cache_hits: u64,
+    cache_misses: u64,
+    #[serde(default)]
+    events_served: u64,
+    /// Non-cumulative counts per bucket; anything slower only shows in +Inf
+    relay_query_buckets: [u64; LATENCY_BUCKETS.len()],
+    relay_query_sum: f64,
+    relay_query_count: u64,
+    publishes: BTreeMap<String, u64>,
+    #[serde(default)]
+    cold_starts: BTreeMap<String, u64>,
+    /// route -> TTL class -> lookups
+    #[serde(default)]
+    cache_lookups: BTreeMap<String, BTreeMap<String, CacheCounts>>,
+}
+
+/// Query cache lookups by how they went
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct CacheCounts {
+    pub hits: u64,
+    /// Served past their TTL while being refreshed
+    pub stale: u64,
+    pub misses: u64,
+    /// Skipped on request (`cache=bypass` or `source=relay`)
+    pub bypasses: u64,
+}
+
+impl CacheCounts {
+    fn count(&mut self, result: &str) {
+        match result {
+            "hit" => self.hits += 1,
+            "stale" => self.stale += 1,
+            "miss" => self.misses += 1,
+            "bypass" => self.bypasses += 1,
+            _ => {}
+        }
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.hits += other.hits;
+        self.stale += other.stale;
+        self.misses += other.misses;
+        self.bypasses += other.bypasses;
+    }
+
+    /// Share of lookups answered from cache, stale ones included. Bypasses don't
+    /// count: they say nothing about the TTL.
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let served = self.hits + self.stale;
+        let lookups = served + self.misses;
+        (lookups > 0).then(|| served as f64 / lookups as f64)
+    }
+}
+
+impl MetricsState {
+    pub fn apply(&mut self, observation: &Observation) {
+        match observation {
+            Observation::Request {
+                route,
+                status,
+                cache_hit,
+                events_served,
+                cache_result,
+                cache_class,
+                ..
+            } => {
+                *self
+                    .requests
+                    .entry(route.clone())
+                    .or_default()
+                    .entry(*status)
+                    .or_default() += 1;
+                match cache_hit {
+                    Some(true) => self.cache_hits += 1,
+                    Some(false) => self.cache_misses += 1,
+                    None => {}
+                }
+                self.events_served += events_served.unwrap_or(0);
+                if let Some(result) = cache_result {
+                    let class = cache_class.as_deref().unwrap_or("other");
+                    self.cache_counts(route, class).count(result);
+                }
+            }
+            Observation::RelayQuery { seconds } => {
+                if let Some(i) = LATENCY_BUCKETS.iter().position(|le| seconds <= le) {
+                    self.relay_query_buckets[i] += 1;
+                }
+                self.relay_query_sum += seconds;
+                self.relay_query_count += 1;
+            }
+            Observation::Publish { outcome } => {
+                *self.publishes.entry(outcome.clone()).or_default() += 1;
+            }
+            Observation::ColdStart { trigger } => {
+                *self.cold_starts.entry(trigger.clone()).or_default() += 1;
+            }
+        }
+    }
+
+    pub fn cache_hits(&self) -> u64 {
+        self.cache_hits
+    }
+
+    pub fn cache_misses(&self) -> u64 {
+        self.cache_misses
+    }
+
+    pub fn events_served(&self) -> u64 {
+        self.events_served
+    }
+
+    pub fn publishes(&self, outcome: &str) -> u64 {
+        self.publishes.get(outcome).copied().unwrap_or(0)
+    }
+
+    fn cache_counts(&mut self, route: &str, class: &str) -> &mut CacheCounts {
+        let tracked: usize = self.cache_lookups.values().map(BTreeMap::len).sum();
+        let known = self.cache_lookups.get(route).is_some_and(|classes| classes.contains_key(class));
+        let class = if known || tracked < MAX_CACHE_CLASSES { class } else { "other" };
+        self.cache_lookups
+            .entry(route.to_string())
+            .or_default()
+            .entry(class.to_string())
+            .or_default()
+    }
+
+    /// Cache lookups per route and TTL class, busiest first, and their total
+    pub fn cache_stats(&self) -> (CacheCounts, Vec<(String, String, CacheCounts)>) {
+        let mut total = CacheCounts::default();
+        let mut rows = Vec::new();
+        for (route, classes) in &self.cache_lookups {
+            for (class, counts) in classes {
+                total.add(counts);
+                rows.push((route.clone(), class.clone(), *counts));
+            }
+        }
+        let lookups = |c: &CacheCounts| c.hits + c.stale + c.misses + c.bypasses;
+        rows.sort_by(|a, b| lookups(&b.2).cmp(&lookups(&a.2)));
+        (total, rows)
+    }
+
+    /// Prometheus text exposition format (0.0.4)
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "gateway_requests_total", "counter", "HTTP requests by route and status");
+        for (route, statuses) in &self.requests {
+            for (status, count) in statuses {
+                let _ = writeln!(
+                    out,
+                    "gateway_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                    escape_label(route),
+                    status,
+                    count
+                );
+            }
+        }
+
+        header(&mut out, "gateway_cache_requests_total", "counter", "Query cache lookups by result");
+        let _ = writeln!(out, "gateway_cache_requests_total{{result=\"hit\"}} {}", self.cache_hits);
+        let _ = writeln!(out, "gateway_cache_requests_total{{result=\"miss\"}} {}", self.cache_misses);
+
+        header(&mut out, "gateway_events_served_total", "counter", "Events returned by /query");
+        let _ = writeln!(out, "gateway_events_served_total {}", self.events_served);
+
+        header(
+            &mut out,
+            "gateway_relay_query_duration_seconds",
+            "histogram",
+            "Time to answer a relay query",
+        );
+        let mut cumulative = 0;
+        for (le, count) in LATENCY_BUCKETS.iter().zip(self.relay_query_buckets.iter()) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "gateway_relay_query_duration_seconds_bucket{{le=\"{}\"}} {}",
+                le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "gateway_relay_query_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            self.relay_query_count
+        );
+        let _ = writeln!(out, "gateway_relay_query_duration_seconds_sum {}", self.relay_query_sum);
+        let _ = writeln!(out, "gateway_relay_query_duration_seconds_count {}", self.relay_query_count);
+
+        header(&mut out, "gateway_publishes_total", "counter", "Publish outcomes");
+        for (outcome, count) in &self.publishes {
+            let _ = writeln!(
+                out,
+                "gateway_publishes_total{{outcome=\"{}\"}} {}",
+                escape_label(outcome),
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "gateway_relay_pool_cold_starts_total",
+            "counter",
+            "RelayPool instances started by a request or by warmup",
+        );
+        for (trigger, count) in &self.cold_starts {
+            let _ = writeln!(
+                out,
+                "gateway_relay_pool_cold_starts_total{{trigger=\"{}\"}} {}",
+                escape_label(trigger),
+                count
+            );
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Collapse request paths into a bounded set of route labels
+pub fn route_label(path: &str) -> &'static str {
+    let path = crate::router::unversioned(path);
+    const EXACT: [&str; 23] = [
+        "/",
+        "/health",
+        "/query",
+        "/query/explain",
+        "/filter/validate",
+        "/relay-info",
+        "/metrics",
+        "/stats",
+        "/publish",
+        "/ws",
+        "/openapi.json",
+        "/count",
+        "/webhooks",
+        "/push/vapid-public-key",
+        "/push/subscriptions",
+        "/profiles",
+        "/events",
+        "/info",
+        "/nwc",
+        "/status",
+        "/pictures",
+        "/quota",
+        "/auth/session",
+    ];
+    const PREFIXES: [&str; 13] = [
+        "/publish/status/",
+        "/publish/",
+        "/profile/",
+        "/event/",
+        "/videos/",
+        "/video/",
+        "/embed/",
+        "/reactions/",
+        "/zaps/",
+        "/webhooks/",
+        "/push/subscriptions/",
+        "/feeds/",
+        "/mutes/",
+    ];
+    if let Some(route) = EXACT.iter().find(|r| **r == path) {
+        return route;
+    }
+    if path.starts_with("/profile/") && path.ends_with("/followers/export") {
+        return "/profile/followers/export";
+    }
+    if path.starts_with("/event/") {
+        if path.ends_with("/references") {
+            return "/event/references";
+        }
+        if path.ends_with("/referenced-by") {
+            return "/event/referenced-by";
+        }
+    }
+    PREFIXES
+        .iter()
+        .find(|p| path.starts_with(*p))
+        .map(|p| p.trim_end_matches('/'))
+        .unwrap_or("other")
+}
+
+/// Send an observation to the collector. Failures are logged, never surfaced.
+pub async fn record(env: &Env, observation: &Observation) {
+    if let Err(e) = send(env, observation).await {
+        console_log!("Metrics record failed: {}", e);
+    }
+}
+
+async fn send(env: &Env, observation: &Observation) -> Result<()> {
+    let stub = env.durable_object("METRICS")?.id_from_name("global")?.get_stub()?;
+    let req = Request::new_with_init(
+        "http://do/record",
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_body(Some(serde_json::to_string(observation)?.into())),
+    )?;
+    stub.fetch_with_request(req).await?;
+    Ok(())
+}
+
+/// Fetch the rendered metrics from the collector
+pub async fn render(env: &Env) -> Result<String> {
+    let stub = env.durable_object("METRICS")?.id_from_name("global")?.get_stub()?;
+    let mut resp = stub.fetch_with_str("http://do/metrics").await?;
+    resp.text().await
+}
+
+/// The most-requested query cache keys since the last call, hottest first
+pub async fn hot_keys(env: &Env, limit: usize) -> Result<Vec<String>> {
+    let stub = env.durable_object("METRICS")?.id_from_name("global")?.get_stub()?;
+    let mut resp = stub.fetch_with_str(&format!("http://do/hot-keys?limit={}", limit)).await?;
+    resp.json().await
+}
+
+/// The most requested cache keys, hottest first, leaving the counts for the
+/// warming run to decay
+pub async fn peek_hot_keys(env: &Env, limit: usize) -> Result<Vec<String>> {
+    let stub = env.durable_object("METRICS")?.id_from_name("global")?.get_stub()?;
+    let mut resp = stub.fetch_with_str(&format!("http://do/hot-keys?limit={}&peek=1", limit)).await?;
+    resp.json().await
+}
+
+/// Fetch the raw counters from the collector
+pub async fn snapshot(env: &Env) -> Result<MetricsState> {
+    let stub = env.durable_object("METRICS")?.id_from_name("global")?.get_stub()?;
+    let mut resp = stub.fetch_with_str("http://do/snapshot").await?;
+    resp.json().await
+}
+
+#[durable_object]
+pub struct MetricsCollector {
+    state: State,
+    /// Loaded from storage on first use
+    metrics: RefCell<Option<MetricsState>>,
+    /// Counted since the last flush; an eviction before the flush alarm loses at
+    /// most `FLUSH_DELAY` worth of counts
+    dirty: Cell<bool>,
+    /// Kept in memory only: losing them just means one run warms nothing
+    hot_keys: RefCell<HotKeys>,
+}
+
+impl DurableObject for MetricsCollector {
+    fn new(state: State, _env: Env) -> Self {
+        Self {
+            state,
+            metrics: RefCell::new(None),
+            dirty: Cell::new(false),
+            hot_keys: RefCell::new(HotKeys::default()),
+        }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        self.load().await;
+        match req.path().as_str() {
+            "/record" => {
+                let observation: Observation = req.json().await?;
+                if let Observation::Request { cache_key: Some(key), .. } = &observation {
+                    self.hot_keys.borrow_mut().hit(key);
+                }
+                self.metrics
+                    .borrow_mut()
+                    .get_or_insert_with(MetricsState::default)
+                    .apply(&observation);
+                // Writes are batched: the first count after a flush schedules the next one
+                if !self.dirty.replace(true) {
+                    self.state.storage().set_alarm(FLUSH_DELAY).await?;
+                }
+                Response::empty()
+            }
+            "/metrics" => {
+                let body = self.metrics.borrow().clone().unwrap_or_default().render();
+                Response::ok(body)
+            }
+            "/hot-keys" => {
+                let url = req.url()?;
+                let limit = url
+                    .query_pairs()
+                    .find(|(k, _)| k == "limit")
+                    .and_then(|(_, v)| v.parse().ok())
+                    .unwrap_or(0);
+                let keys = if url.query_pairs().any(|(k, v)| k == "peek" && v == "1") {
+                    self.hot_keys.borrow().hottest(limit)
+                } else {
+                    self.hot_keys.borrow_mut().take_hottest(limit)
+                };
+                Response::from_json(&keys)
+            }
+            "/snapshot" => {
+                let metrics = self.metrics.borrow().clone().unwrap_or_default();
+                Response::from_json(&metrics)
+            }
+            _ => Response::error("not found", 404),
+        }
+    }
+
+    async fn alarm(&self) -> Result<Response> {
+        if self.dirty.replace(false) {
+            let snapshot = self.metrics.borrow().clone().unwrap_or_default();
+            if let Err(e) = self.state.storage().put(STORAGE_KEY, snapshot).await {
+                // Try again with the next batch
+                self.dirty.set(true);
+                self.state.storage().set_alarm(FLUSH_DELAY).await?;
+                return Err(e);
+            }
+        }
+        Response::empty()
+    }
+}
+
+impl MetricsCollector {
+    async fn load(&self) {
+        if self.metrics.borrow().is_some() {
+            return;
+        }
+        let stored = self
+            .state
+            .storage()
+            .get::<MetricsState>(STORAGE_KEY)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        self.metrics.borrow_mut().get_or_insert(stored);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(route: &str, status: u16, cache_hit: Option<bool>) -> Observation {
+        Observation::Request {
+            route: route.to_string(),
+            status,
+            cache_hit,
+            events_served: cache_hit.map(|_| 3),
+            cache_key: None,
+            cache_result: None,
+            cache_class: None,
+        }
+    }
+
+    #[test]
+    fn test_route_label() {
+        assert_eq!(route_label("/query"), "/query");
+        assert_eq!(route_label("/v1/query"), "/query");
+        assert_eq!(route_label("/query/explain"), "/query/explain");
+        assert_eq!(route_label("/v1"), "/");
+        assert_eq!(route_label("/v1query"), "other");
+        assert_eq!(route_label("/profile/abc"), "/profile");
+        assert_eq!(route_label("/event/abc"), "/event");
+        assert_eq!(route_label("/event/abc/references"), "/event/references");
+        assert_eq!(route_label("/event/abc/referenced-by"), "/event/referenced-by");
+        assert_eq!(route_label("/profile/abc/followers/export"), "/profile/followers/export");
+        assert_eq!(route_label("/publish/status/abc"), "/publish/status");
+        assert_eq!(route_label("/publish/abc"), "/publish");
+        assert_eq!(route_label("/push/subscriptions/abc"), "/push/subscriptions");
+        assert_eq!(route_label("/videos/abc"), "/videos");
+        assert_eq!(route_label("/video/naddr1"), "/video");
+        assert_eq!(route_label("/wp-admin.php"), "other");
+    }
+
+    #[test]
+    fn test_observation_serialization() {
+        let json = serde_json::to_string(&request("/query", 200, Some(true))).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"request","route":"/query","status":200,"cache_hit":true,"events_served":3}"#
+        );
+        let parsed: Observation = serde_json::from_str(r#"{"type":"relay_query","seconds":0.3}"#).unwrap();
+        assert_eq!(parsed, Observation::RelayQuery { seconds: 0.3 });
+    }
+
+    #[test]
+    fn test_render_counters() {
+        let mut state = MetricsState::default();
+        state.apply(&request("/query", 200, Some(true)));
+        state.apply(&request("/query", 200, Some(false)));
+        state.apply(&request("/event", 404, None));
+        state.apply(&Observation::Publish {
+            outcome: "published".to_string(),
+        });
+        state.apply(&Observation::ColdStart {
+            trigger: "warmup".to_string(),
+        });
+        let text = state.render();
+        assert!(text.contains("# TYPE gateway_requests_total counter"));
+        assert!(text.contains(r#"gateway_requests_total{route="/query",status="200"} 2"#));
+        assert!(text.contains(r#"gateway_requests_total{route="/event",status="404"} 1"#));
+        assert!(text.contains(r#"gateway_cache_requests_total{result="hit"} 1"#));
+        assert!(text.contains(r#"gateway_cache_requests_total{result="miss"} 1"#));
+        assert!(text.contains(r#"gateway_publishes_total{outcome="published"} 1"#));
+        assert!(text.contains(r#"gateway_relay_pool_cold_starts_total{trigger="warmup"} 1"#));
+        assert!(text.contains("gateway_events_served_total 6"));
+        assert_eq!(state.events_served(), 6);
+        assert_eq!(state.publishes("published"), 1);
+        assert_eq!(state.publishes("not_found"), 0);
+    }
+
+    #[test]
+    fn test_cache_stats() {
+        let lookup = |route: &str, class: &str, result: &str| Observation::Request {
+            route: route.to_string(),
+            status: 200,
+            cache_hit: Some(result == "hit" || result == "stale"),
+            events_served: Some(1),
+            cache_key: Some("query:abc".to_string()),
+            cache_result: Some(result.to_string()),
+            cache_class: Some(class.to_string()),
+        };
+        let mut state = MetricsState::default();
+        for result in ["hit", "hit", "stale", "miss", "bypass"] {
+            state.apply(&lookup("/query", "kind:1", result));
+        }
+        state.apply(&lookup("/profile", "kind:0", "miss"));
+        state.apply(&request("/query", 200, Some(true)));
+
+        let (total, rows) = state.cache_stats();
+        assert_eq!(total, CacheCounts { hits: 2, stale: 1, misses: 2, bypasses: 1 });
+        assert_eq!(rows[0].0, "/query");
+        assert_eq!(rows[0].1, "kind:1");
+        assert_eq!(rows[0].2.hit_ratio(), Some(0.75));
+        assert_eq!(rows[1].2.hit_ratio(), Some(0.0));
+        assert_eq!(CacheCounts::default().hit_ratio(), None);
+
+        // Past the cap, new classes share one bucket per route
+        for kind in 0..MAX_CACHE_CLASSES {
+            state.apply(&lookup("/query", &format!("kind:{}", kind + 100), "miss"));
+        }
+        let (_, rows) = state.cache_stats();
+        assert_eq!(rows.len(), MAX_CACHE_CLASSES + 1);
+        assert!(rows.iter().any(|(route, class, _)| route == "/query" && class == "other"));
+    }
+
+    #[test]
+    fn test_histogram_is_cumulative() {
+        let mut state = MetricsState::default();
+        for seconds in [0.01, 0.3, 0.3, 7.0] {
+            state.apply(&Observation::RelayQuery { seconds });
+        }
+        let text = state.render();
+        assert!(text.contains(r#"gateway_relay_query_duration_seconds_bucket{le="0.05"} 1"#));
+        assert!(text.contains(r#"gateway_relay_query_duration_seconds_bucket{le="0.25"} 1"#));
+        assert!(text.contains(r#"gateway_relay_query_duration_seconds_bucket{le="0.5"} 3"#));
+        assert!(text.contains(r#"gateway_relay_query_duration_seconds_bucket{le="5"} 3"#));
+        assert!(text.contains(r#"gateway_relay_query_duration_seconds_bucket{le="+Inf"} 4"#));
+        assert!(text.contains("gateway_relay_query_duration_seconds_count 4"));
+    }
+
+    #[test]
+    fn test_state_roundtrip() {
+        let mut state = MetricsState::default();
+        state.apply(&request("/query", 200, Some(true)));
+        let json = serde_json::to_string(&state).unwrap();
+        let restored: MetricsState = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.render(), state.render());
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label(r#"a"b\c"#), r#"a\"b\\c"#);
+    }
+}
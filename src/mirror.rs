@@ -0,0 +1,224 @@
+// ABOUTME: Shadow traffic for migration testing: replays a sample of public reads against a second deployment
+// ABOUTME: and logs where its answers diverge from the ones actually served
+
+use serde::Serialize;
+use serde_json::Value;
+use worker::*;
+
+/// Marks mirrored requests, so a deployment never mirrors what it was sent as a mirror
+pub const HEADER: &str = "X-Gateway-Mirror";
+
+/// Response fields that legitimately differ between deployments with separate caches
+const VOLATILE_FIELDS: [&str; 4] = ["cached", "stale", "cache_age_seconds", "source"];
+
+/// Read routes worth comparing
+const PREFIXES: [&str; 10] = [
+    "/profile/",
+    "/event/",
+    "/videos/",
+    "/video/",
+    "/feeds/",
+    "/reactions/",
+    "/zaps/",
+    "/mutes/",
+    "/relay-info",
+    "/count",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MirrorConfig {
+    /// Base URL of the deployment under test
+    pub target: Url,
+    /// Fraction of eligible requests mirrored
+    pub sample_rate: f64,
+}
+
+impl MirrorConfig {
+    /// Enabled by `MIRROR_URL`; `MIRROR_SAMPLE_RATE` defaults to 0.01
+    pub fn from_env(env: &Env) -> Option<Self> {
+        let var = |name: &str| env.var(name).ok().map(|v| v.to_string());
+        let target = var("MIRROR_URL").and_then(|u| Url::parse(&u).ok())?;
+        if !matches!(target.scheme(), "https" | "http") {
+            return None;
+        }
+        Some(Self {
+            target,
+            sample_rate: var("MIRROR_SAMPLE_RATE")
+                .and_then(|v| v.parse::<f64>().ok())
+                .filter(|r| r.is_finite())
+                .unwrap_or(0.01)
+                .clamp(0.0, 1.0),
+        })
+    }
+
+    /// Where to send a mirrored copy of `url`
+    pub fn mirror_url(&self, url: &Url) -> Url {
+        let mut mirrored = self.target.clone();
+        mirrored.set_path(url.path());
+        mirrored.set_query(url.query());
+        mirrored
+    }
+}
+
+/// Anonymous GETs of read routes. Authenticated reads are skipped: NIP-98 tokens are
+/// bound to this deployment's URL and wouldn't validate on the other one.
+pub fn is_eligible(method: &Method, path: &str, authenticated: bool) -> bool {
+    *method == Method::Get
+        && !authenticated
+        && (path == "/query" || path == "/events" || PREFIXES.iter().any(|p| path.starts_with(p)))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Divergence {
+    pub path: String,
+    pub primary_status: u16,
+    /// Absent when the mirror couldn't be reached
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirror_status: Option<u16>,
+    /// `status`, `body` or `mirror_error`
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Divergence {
+    pub fn to_line(&self) -> String {
+        #[derive(Serialize)]
+        struct Line<'a> {
+            #[serde(rename = "type")]
+            kind: &'static str,
+            #[serde(flatten)]
+            divergence: &'a Divergence,
+        }
+        let line = Line {
+            kind: "mirror_divergence",
+            divergence: self,
+        };
+        serde_json::to_string(&line).unwrap_or_default()
+    }
+}
+
+/// JSON bodies with the cache bookkeeping removed; other bodies as they are
+fn normalize(body: &str) -> Value {
+    match serde_json::from_str::<Value>(body) {
+        Ok(Value::Object(mut fields)) => {
+            for field in VOLATILE_FIELDS {
+                fields.remove(field);
+            }
+            Value::Object(fields)
+        }
+        Ok(other) => other,
+        Err(_) => Value::String(body.to_string()),
+    }
+}
+
+fn event_ids(body: &Value) -> Option<Vec<&str>> {
+    let ids = body.get("events")?.as_array()?.iter();
+    Some(ids.filter_map(|e| e.get("id").and_then(|v| v.as_str())).collect())
+}
+
+/// How the mirror's answer differs from the one served, if it does
+pub fn compare(path: &str, primary: (u16, &str), mirror: (u16, &str)) -> Option<Divergence> {
+    let divergence = |kind, detail| Divergence {
+        path: path.to_string(),
+        primary_status: primary.0,
+        mirror_status: Some(mirror.0),
+        kind,
+        detail,
+    };
+    if primary.0 != mirror.0 {
+        return Some(divergence("status", None));
+    }
+    let (ours, theirs) = (normalize(primary.1), normalize(mirror.1));
+    if ours == theirs {
+        return None;
+    }
+    let detail = match (event_ids(&ours), event_ids(&theirs)) {
+        (Some(a), Some(b)) => {
+            let missing = a.iter().filter(|id| !b.contains(id)).count();
+            let extra = b.iter().filter(|id| !a.contains(id)).count();
+            Some(format!("{} events missing, {} extra, of {}", missing, extra, a.len()))
+        }
+        _ => None,
+    };
+    Some(divergence("body", detail))
+}
+
+/// Replay the request against the mirror and log any divergence from `primary`
+pub async fn run(mirror_url: Url, path: String, mut primary: Response) {
+    let primary_status = primary.status_code();
+    let primary_body = match primary.text().await {
+        Ok(body) => body,
+        Err(e) => {
+            console_log!("Mirror skipped, primary body unreadable: {}", e);
+            return;
+        }
+    };
+
+    let divergence = match fetch(mirror_url).await {
+        Ok((status, body)) => compare(&path, (primary_status, &primary_body), (status, &body)),
+        Err(e) => Some(Divergence {
+            path,
+            primary_status,
+            mirror_status: None,
+            kind: "mirror_error",
+            detail: Some(e.to_string()),
+        }),
+    };
+    if let Some(divergence) = divergence {
+        console_log!("{}", divergence.to_line());
+    }
+}
+
+async fn fetch(url: Url) -> Result<(u16, String)> {
+    let mut headers = Headers::new();
+    headers.set(HEADER, "1")?;
+    let req = Request::new_with_init(url.as_str(), RequestInit::new().with_headers(headers))?;
+    let mut resp = Fetch::Request(req).send().await?;
+    Ok((resp.status_code(), resp.text().await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eligible() {
+        assert!(is_eligible(&Method::Get, "/query", false));
+        assert!(is_eligible(&Method::Get, "/profile/abc", false));
+        assert!(!is_eligible(&Method::Get, "/query", true));
+        assert!(!is_eligible(&Method::Post, "/events", false));
+        assert!(!is_eligible(&Method::Get, "/admin/relay", false));
+        assert!(!is_eligible(&Method::Get, "/ws", false));
+    }
+
+    #[test]
+    fn test_mirror_url() {
+        let config = MirrorConfig {
+            target: Url::parse("https://next.gateway.example").unwrap(),
+            sample_rate: 1.0,
+        };
+        let url = Url::parse("https://gateway.example/query?filter=abc").unwrap();
+        assert_eq!(config.mirror_url(&url).as_str(), "https://next.gateway.example/query?filter=abc");
+    }
+
+    #[test]
+    fn test_compare_ignores_cache_fields() {
+        let ours = r#"{"events":[{"id":"a"}],"cached":true,"stale":true,"cache_age_seconds":12,"source":"cache"}"#;
+        let theirs = r#"{"events":[{"id":"a"}],"cached":false,"source":"relay"}"#;
+        assert_eq!(compare("/query", (200, ours), (200, theirs)), None);
+    }
+
+    #[test]
+    fn test_compare_reports_divergence() {
+        let ours = r#"{"events":[{"id":"a"},{"id":"b"}]}"#;
+        let theirs = r#"{"events":[{"id":"a"},{"id":"c"},{"id":"d"}]}"#;
+        let divergence = compare("/query", (200, ours), (200, theirs)).unwrap();
+        assert_eq!(divergence.kind, "body");
+        assert_eq!(divergence.detail.as_deref(), Some("1 events missing, 2 extra, of 2"));
+
+        let divergence = compare("/event/a", (200, "{}"), (404, "{}")).unwrap();
+        assert_eq!(divergence.kind, "status");
+        assert!(divergence.to_line().contains("\"type\":\"mirror_divergence\""));
+    }
+}
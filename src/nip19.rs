@@ -0,0 +1,183 @@
+// ABOUTME: NIP-19 bech32 entity decoding (naddr)
+// ABOUTME: Minimal bech32 decoder so URLs can carry shareable Nostr identifiers
+
+const CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// Decoded `naddr` pointing at an addressable event
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddressPointer {
+    pub identifier: String,
+    pub pubkey: String,
+    pub kind: u32,
+    pub relays: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Nip19Error {
+    InvalidBech32,
+    InvalidChecksum,
+    WrongPrefix,
+    MissingField,
+}
+
+impl std::fmt::Display for Nip19Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidBech32 => write!(f, "invalid bech32 encoding"),
+            Self::InvalidChecksum => write!(f, "invalid bech32 checksum"),
+            Self::WrongPrefix => write!(f, "unexpected NIP-19 prefix"),
+            Self::MissingField => write!(f, "missing required TLV field"),
+        }
+    }
+}
+
+/// Decode an `naddr1...` string into its address pointer
+pub fn decode_naddr(encoded: &str) -> Result<AddressPointer, Nip19Error> {
+    let (hrp, data) = bech32_decode(encoded)?;
+    if hrp != "naddr" {
+        return Err(Nip19Error::WrongPrefix);
+    }
+
+    let mut identifier = None;
+    let mut pubkey = None;
+    let mut kind = None;
+    let mut relays = Vec::new();
+
+    let mut pos = 0;
+    while pos + 2 <= data.len() {
+        let t = data[pos];
+        let len = data[pos + 1] as usize;
+        let value = data.get(pos + 2..pos + 2 + len).ok_or(Nip19Error::InvalidBech32)?;
+        match t {
+            0 => identifier = Some(String::from_utf8(value.to_vec()).map_err(|_| Nip19Error::InvalidBech32)?),
+            1 => {
+                if let Ok(relay) = String::from_utf8(value.to_vec()) {
+                    relays.push(relay);
+                }
+            }
+            2 if len == 32 => pubkey = Some(hex::encode(value)),
+            3 if len == 4 => kind = Some(u32::from_be_bytes([value[0], value[1], value[2], value[3]])),
+            _ => {} // Unknown TLVs are ignored per NIP-19
+        }
+        pos += 2 + len;
+    }
+
+    Ok(AddressPointer {
+        identifier: identifier.ok_or(Nip19Error::MissingField)?,
+        pubkey: pubkey.ok_or(Nip19Error::MissingField)?,
+        kind: kind.ok_or(Nip19Error::MissingField)?,
+        relays,
+    })
+}
+
+/// Decode a bech32 string into (hrp, 8-bit data). NIP-19 strings may exceed
+/// the BIP-173 90 character limit, so no length check is applied.
+fn bech32_decode(encoded: &str) -> Result<(String, Vec<u8>), Nip19Error> {
+    let lower = encoded.to_lowercase();
+    if lower != encoded && encoded.to_uppercase() != encoded {
+        return Err(Nip19Error::InvalidBech32); // mixed case
+    }
+    let sep = lower.rfind('1').ok_or(Nip19Error::InvalidBech32)?;
+    let (hrp, rest) = lower.split_at(sep);
+    let rest = &rest[1..];
+    if hrp.is_empty() || rest.len() < 6 {
+        return Err(Nip19Error::InvalidBech32);
+    }
+
+    let values: Vec<u8> = rest
+        .chars()
+        .map(|c| CHARSET.find(c).map(|i| i as u8))
+        .collect::<Option<_>>()
+        .ok_or(Nip19Error::InvalidBech32)?;
+
+    if polymod(&[hrp_expand(hrp), values.clone()].concat()) != 1 {
+        return Err(Nip19Error::InvalidChecksum);
+    }
+
+    let data = convert_bits(&values[..values.len() - 6], 5, 8)?;
+    Ok((hrp.to_string(), data))
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    const GEN: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut chk: u32 = 1;
+    for v in values {
+        let top = chk >> 25;
+        chk = ((chk & 0x1ffffff) << 5) ^ (*v as u32);
+        for (i, g) in GEN.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= g;
+            }
+        }
+    }
+    chk
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let bytes = hrp.as_bytes();
+    let mut out: Vec<u8> = bytes.iter().map(|b| b >> 5).collect();
+    out.push(0);
+    out.extend(bytes.iter().map(|b| b & 31));
+    out
+}
+
+fn convert_bits(data: &[u8], from: u32, to: u32) -> Result<Vec<u8>, Nip19Error> {
+    let mut acc: u32 = 0;
+    let mut bits: u32 = 0;
+    let mut out = Vec::new();
+    let maxv = (1 << to) - 1;
+    for value in data {
+        acc = (acc << from) | (*value as u32);
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            out.push(((acc >> bits) & maxv) as u8);
+        }
+    }
+    // Leftover bits must be zero padding
+    if bits >= from || ((acc << (to - bits)) & maxv) != 0 {
+        return Err(Nip19Error::InvalidBech32);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // naddr for kind 34236, pubkey 79be66...1798, identifier "my-video", relay wss://relay.divine.video
+    const NADDR: &str = "naddr1qqyx67fdwe5kget0qyv8wumn8ghj7un9d3shjtnyd9mxjmn99emxjer9dupzq7d7vel0nh9m4326qc54e6rskpczn07dktww9rv4nu5ptvt0s9ucqvzqqqy9hs28qxya";
+
+    #[test]
+    fn test_decode_naddr() {
+        let pointer = decode_naddr(NADDR).unwrap();
+        assert_eq!(pointer.kind, 34236);
+        assert_eq!(pointer.identifier, "my-video");
+        assert_eq!(
+            pointer.pubkey,
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+        );
+        assert_eq!(pointer.relays, vec!["wss://relay.divine.video".to_string()]);
+    }
+
+    #[test]
+    fn test_decode_naddr_bad_checksum() {
+        let mut corrupted = NADDR.to_string();
+        let last = corrupted.pop().unwrap();
+        corrupted.push(if last == 'q' { 'p' } else { 'q' });
+        assert_eq!(decode_naddr(&corrupted), Err(Nip19Error::InvalidChecksum));
+    }
+
+    #[test]
+    fn test_decode_naddr_wrong_prefix() {
+        // npub for the same key
+        let npub = "npub10xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqpkge6d";
+        assert_eq!(decode_naddr(npub), Err(Nip19Error::WrongPrefix));
+    }
+
+    #[test]
+    fn test_decode_naddr_garbage() {
+        assert_eq!(decode_naddr("not-bech32"), Err(Nip19Error::InvalidBech32));
+        assert_eq!(decode_naddr("naddr1bbbbbb!"), Err(Nip19Error::InvalidBech32));
+    }
+}
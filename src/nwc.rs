@@ -0,0 +1,177 @@
+// ABOUTME: NIP-47 wallet-connect proxy: forwards a client-signed request to the wallet service's relay
+// ABOUTME: and waits for its reply. Payloads are encrypted end to end; the gateway never holds keys.
+
+use crate::outbound::{check_public_host, HostError};
+use futures_util::future::{select, Either};
+use futures_util::StreamExt;
+use serde_json::{json, Value};
+use std::time::Duration;
+use worker::*;
+
+pub const REQUEST_KIND: u64 = 23194;
+pub const RESPONSE_KIND: u64 = 23195;
+
+/// How long the wallet service gets to answer. Paying an invoice can take a while.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const SUBSCRIPTION_ID: &str = "nwc";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// The wallet service's kind 23195 reply
+    Response(Value),
+    /// The relay refused the request event, with its reason
+    Rejected(String),
+    Timeout,
+}
+
+/// Wallet relays are public `wss` hosts
+pub fn validate_relay_url(raw: &str) -> std::result::Result<Url, &'static str> {
+    let url = Url::parse(raw).map_err(|_| "relay is not a valid URL")?;
+    if url.scheme() != "wss" {
+        return Err("relay must use wss");
+    }
+    let host = url.host_str().ok_or("relay has no host")?;
+    check_public_host(host).map_err(|e| match e {
+        HostError::IpAddress => "relay must use a hostname, not an IP address",
+        HostError::Private => "relay must be publicly reachable",
+    })?;
+    Ok(url)
+}
+
+/// Check that a request is a signed kind 23194 event and return the wallet
+/// service pubkey from its `p` tag
+pub fn wallet_pubkey(request: &Value) -> std::result::Result<String, &'static str> {
+    if request.get("kind").and_then(|v| v.as_u64()) != Some(REQUEST_KIND) {
+        return Err("event must be kind 23194");
+    }
+    for field in ["id", "pubkey", "sig", "content"] {
+        if request.get(field).and_then(|v| v.as_str()).map_or(true, str::is_empty) {
+            return Err("event must be signed, with encrypted content");
+        }
+    }
+    tag_value(request, "p").ok_or("event has no p tag for the wallet service")
+}
+
+fn tag_value(event: &Value, name: &str) -> Option<String> {
+    event
+        .get("tags")?
+        .as_array()?
+        .iter()
+        .filter_map(|t| t.as_array())
+        .find(|t| t.first().and_then(|n| n.as_str()) == Some(name))
+        .and_then(|t| t.get(1))
+        .and_then(|v| v.as_str())
+        .filter(|v| !v.is_empty())
+        .map(String::from)
+}
+
+/// Subscription for the wallet service's reply to one request
+pub fn response_filter(request_id: &str, wallet: &str) -> Value {
+    json!({ "kinds": [RESPONSE_KIND], "authors": [wallet], "#e": [request_id] })
+}
+
+/// What a relay message means for the exchange, if anything
+fn read_message(message: &Value, request_id: &str) -> Option<Outcome> {
+    let parts = message.as_array()?;
+    match parts.first()?.as_str()? {
+        "EVENT" if parts.get(1)?.as_str()? == SUBSCRIPTION_ID => {
+            let event = parts.get(2)?;
+            let is_reply = event.get("kind").and_then(|v| v.as_u64()) == Some(RESPONSE_KIND)
+                && tag_value(event, "e").as_deref() == Some(request_id);
+            is_reply.then(|| Outcome::Response(event.clone()))
+        }
+        "OK" if parts.get(1)?.as_str()? == request_id && parts.get(2)?.as_bool() == Some(false) => {
+            let reason = parts.get(3).and_then(|v| v.as_str()).unwrap_or_default();
+            Some(Outcome::Rejected(reason.to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// Subscribe for the reply, publish the request, and wait for one or the other to settle
+pub async fn exchange(relay: Url, request: &Value, wallet: &str) -> Result<Outcome> {
+    let request_id = request.get("id").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+    let ws = WebSocket::connect(relay).await?;
+    ws.accept()?;
+    let mut events = ws.events()?;
+
+    // Subscribing first means a fast wallet's reply can't slip past
+    let subscribe = json!(["REQ", SUBSCRIPTION_ID, response_filter(&request_id, wallet)]);
+    ws.send_with_str(&subscribe.to_string())?;
+    ws.send_with_str(&json!(["EVENT", request]).to_string())?;
+
+    let mut timeout = Box::pin(Delay::from(RESPONSE_TIMEOUT));
+    let outcome = loop {
+        match select(Box::pin(events.next()), timeout.as_mut()).await {
+            Either::Left((Some(Ok(WebsocketEvent::Message(msg))), _)) => {
+                let parsed = msg.text().and_then(|t| serde_json::from_str::<Value>(&t).ok());
+                if let Some(outcome) = parsed.and_then(|m| read_message(&m, &request_id)) {
+                    break outcome;
+                }
+            }
+            Either::Left(_) => break Outcome::Rejected("wallet relay closed the connection".to_string()),
+            Either::Right(_) => break Outcome::Timeout,
+        }
+    };
+    let _ = ws.close(Some(1000), Some("done"));
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> Value {
+        json!({
+            "id": "req1",
+            "pubkey": "client",
+            "sig": "sig",
+            "kind": 23194,
+            "content": "encrypted",
+            "tags": [["p", "wallet"]]
+        })
+    }
+
+    #[test]
+    fn test_validate_relay_url() {
+        assert!(validate_relay_url("wss://relay.getalby.com/v1").is_ok());
+        assert!(validate_relay_url("https://relay.getalby.com").is_err());
+        assert!(validate_relay_url("wss://127.0.0.1").is_err());
+        assert!(validate_relay_url("wss://relay.internal").is_err());
+    }
+
+    #[test]
+    fn test_wallet_pubkey() {
+        assert_eq!(wallet_pubkey(&request()), Ok("wallet".to_string()));
+
+        let mut wrong_kind = request();
+        wrong_kind["kind"] = 1.into();
+        assert!(wallet_pubkey(&wrong_kind).is_err());
+
+        let mut unsigned = request();
+        unsigned["sig"] = "".into();
+        assert!(wallet_pubkey(&unsigned).is_err());
+
+        let mut no_wallet = request();
+        no_wallet["tags"] = json!([]);
+        assert!(wallet_pubkey(&no_wallet).is_err());
+    }
+
+    #[test]
+    fn test_read_message() {
+        let reply = json!({"kind": 23195, "pubkey": "wallet", "tags": [["e", "req1"], ["p", "client"]]});
+        assert_eq!(
+            read_message(&json!(["EVENT", "nwc", reply]), "req1"),
+            Some(Outcome::Response(reply.clone()))
+        );
+        let other = json!({"kind": 23195, "tags": [["e", "req2"]]});
+        assert_eq!(read_message(&json!(["EVENT", "nwc", other]), "req1"), None);
+        assert_eq!(read_message(&json!(["OK", "req1", true, ""]), "req1"), None);
+        assert_eq!(
+            read_message(&json!(["OK", "req1", false, "blocked: rate limited"]), "req1"),
+            Some(Outcome::Rejected("blocked: rate limited".to_string()))
+        );
+        assert_eq!(read_message(&json!(["EOSE", "nwc"]), "req1"), None);
+    }
+}
@@ -0,0 +1,641 @@
+// ABOUTME: Registry of public routes and the OpenAPI document built from it
+// ABOUTME: Schemas are derived from the structs in types.rs so the spec can't drift from responses
+
+use crate::types::{
+    AggregateResponse, ErrorResponse, EventsRequest, EventsResponse, FeedResponse, FilterValidateRequest, FilterValidateResponse, FollowerExportLine, InfoResponse, MuteListResponse, NwcRequest, NwcResponse, PublishRequest, PublishResponse, PublishStatus, ProfilesRequest, ProfilesResponse, PushRegistrationRequest,
+    PictureEvent, PicturesResponse, PushRegistrationResponse, QueryExplainResponse, QueryResponse, QuotaResponse, ReferencedByResponse, ReferencesResponse,
+    RelayInfoResponse, SessionRequest, SessionResponse, StatsResponse, StatusResponse, VapidKeyResponse, VideoEvent, VideosResponse, WebhookRequest,
+    WebhookResponse, WebhooksResponse,
+};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use serde_json::{json, Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamIn {
+    Path,
+    Query,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Param {
+    pub name: &'static str,
+    pub location: ParamIn,
+    pub required: bool,
+    pub description: &'static str,
+}
+
+/// Body of a request or a successful response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Body {
+    /// A schema in `components.schemas`, named after its type in types.rs
+    Json(&'static str),
+    /// Newline-delimited JSON, each line matching the named schema
+    Jsonl(&'static str),
+    Html,
+    Text,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Route {
+    pub method: &'static str,
+    pub path: &'static str,
+    /// Stable name used for SDK method names
+    pub operation_id: &'static str,
+    pub summary: &'static str,
+    pub params: &'static [Param],
+    pub request: Option<&'static str>,
+    pub status: u16,
+    pub response: Body,
+}
+
+const fn path(name: &'static str, description: &'static str) -> Param {
+    Param {
+        name,
+        location: ParamIn::Path,
+        required: true,
+        description,
+    }
+}
+
+const fn query(name: &'static str, required: bool, description: &'static str) -> Param {
+    Param {
+        name,
+        location: ParamIn::Query,
+        required,
+        description,
+    }
+}
+
+/// Client-facing routes. Operator (/admin, /metrics) and WebSocket routes are
+/// not part of the public contract and are left out.
+pub const ROUTES: &[Route] = &[
+    Route {
+        method: "get",
+        path: "/health",
+        operation_id: "health",
+        summary: "Liveness check; the body is `degraded` during relay maintenance",
+        params: &[],
+        request: None,
+        status: 200,
+        response: Body::Text,
+    },
+    Route {
+        method: "get",
+        path: "/status",
+        operation_id: "getStatus",
+        summary: "Operating mode, and the reason when degraded for relay maintenance",
+        params: &[],
+        request: None,
+        status: 200,
+        response: Body::Json("StatusResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/query",
+        operation_id: "query",
+        summary: "Run a Nostr filter, served from cache when possible",
+        params: &[
+            query("filter", false, "Base64url-encoded NIP-01 filter JSON; without it the fields below are used"),
+            query("preset", false, "Name of an operator-defined filter, used instead of the filter fields"),
+            query("ids", false, "Comma-separated event ids"),
+            query("authors", false, "Comma-separated hex pubkeys"),
+            query("kinds", false, "Comma-separated kinds"),
+            query("since", false, "Unix timestamp"),
+            query("until", false, "Unix timestamp"),
+            query("limit", false, "Maximum events"),
+            query("search", false, "NIP-50 search query"),
+            query("cache", false, "Set to 'bypass' to skip the cache (authenticated callers)"),
+            query("nocache", false, "Set to 1 to bypass the cache; same as cache=bypass"),
+            query("ttl", false, "Cache lifetime in seconds, at most the filter's own TTL"),
+            query("source", false, "Force 'relay' or 'archive'"),
+            query("apply_mutes", false, "Hex pubkey whose mute list filters the results"),
+        ],
+        request: None,
+        status: 200,
+        response: Body::Json("QueryResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/query/explain",
+        operation_id: "explainQuery",
+        summary: "How /query would handle a filter: cache key, TTL, relay and cost, without running it",
+        params: &[
+            query("filter", false, "Base64url-encoded NIP-01 filter JSON; the /query filter fields also work"),
+            query("preset", false, "Name of an operator-defined filter"),
+        ],
+        request: None,
+        status: 200,
+        response: Body::Json("QueryExplainResponse"),
+    },
+    Route {
+        method: "post",
+        path: "/filter/validate",
+        operation_id: "validateFilter",
+        summary: "Decode a filter and report recognized fields, errors, cache key, TTL and cost",
+        params: &[],
+        request: Some("FilterValidateRequest"),
+        status: 200,
+        response: Body::Json("FilterValidateResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/profile/{pubkey}",
+        operation_id: "getProfile",
+        summary: "Latest kind-0 profile for a pubkey",
+        params: &[path("pubkey", "Hex pubkey")],
+        request: None,
+        status: 200,
+        response: Body::Json("QueryResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/profile/{pubkey}/followers/export",
+        operation_id: "exportFollowers",
+        summary: "Pubkeys following a pubkey, from the archive, as JSONL; needs an API key with export",
+        params: &[
+            path("pubkey", "Hex pubkey"),
+            query("cursor", false, "X-Next-Cursor of the previous page"),
+            query("limit", false, "Maximum pubkeys, default and max 5000"),
+        ],
+        request: None,
+        status: 200,
+        response: Body::Jsonl("FollowerExportLine"),
+    },
+    Route {
+        method: "get",
+        path: "/events",
+        operation_id: "getEvents",
+        summary: "Up to 100 events by id",
+        params: &[query("ids", true, "Comma-separated hex event ids")],
+        request: None,
+        status: 200,
+        response: Body::Json("EventsResponse"),
+    },
+    Route {
+        method: "post",
+        path: "/events",
+        operation_id: "postEvents",
+        summary: "Up to 100 events by id, for id lists too long for a URL",
+        params: &[],
+        request: Some("EventsRequest"),
+        status: 200,
+        response: Body::Json("EventsResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/feeds/{name}",
+        operation_id: "getFeed",
+        summary: "Operator-defined feed backed by a saved filter",
+        params: &[
+            path("name", "Feed name"),
+            query("limit", false, "Page size, at most the feed's own limit"),
+            query("until", false, "Only events created at or before this unix time"),
+            query("apply_mutes", false, "Hex pubkey whose mute list filters the results"),
+        ],
+        request: None,
+        status: 200,
+        response: Body::Json("FeedResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/mutes/{pubkey}",
+        operation_id: "getMuteList",
+        summary: "Public entries of a pubkey's kind 10000 mute list",
+        params: &[path("pubkey", "Hex pubkey")],
+        request: None,
+        status: 200,
+        response: Body::Json("MuteListResponse"),
+    },
+    Route {
+        method: "post",
+        path: "/profiles",
+        operation_id: "getProfiles",
+        summary: "Parsed profiles for up to 100 pubkeys",
+        params: &[],
+        request: Some("ProfilesRequest"),
+        status: 200,
+        response: Body::Json("ProfilesResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/event/{id}",
+        operation_id: "getEvent",
+        summary: "Single event by id; 410 once its author has deleted it",
+        params: &[path("id", "Hex event id")],
+        request: None,
+        status: 200,
+        response: Body::Json("QueryResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/event/{id}/references",
+        operation_id: "getReferences",
+        summary: "Events, pubkeys, addresses and URLs an event points at, with previews",
+        params: &[path("id", "Hex event id")],
+        request: None,
+        status: 200,
+        response: Body::Json("ReferencesResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/event/{id}/referenced-by",
+        operation_id: "getReferencedBy",
+        summary: "Events referencing an event, from the archive",
+        params: &[
+            path("id", "Hex event id"),
+            query("limit", false, "Maximum events, default 50, max 500"),
+            query("until", false, "Only events created before this unix timestamp"),
+        ],
+        request: None,
+        status: 200,
+        response: Body::Json("ReferencedByResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/video/{naddr}",
+        operation_id: "getVideo",
+        summary: "Structured NIP-71 video by address",
+        params: &[path("naddr", "NIP-19 naddr of the video")],
+        request: None,
+        status: 200,
+        response: Body::Json("VideoEvent"),
+    },
+    Route {
+        method: "get",
+        path: "/videos/{pubkey}",
+        operation_id: "listVideos",
+        summary: "Videos published by a pubkey, newest first",
+        params: &[
+            path("pubkey", "Hex pubkey"),
+            query("limit", false, "Maximum videos, default 20, max 100"),
+            query("until", false, "Only videos created before this unix timestamp"),
+        ],
+        request: None,
+        status: 200,
+        response: Body::Json("VideosResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/pictures",
+        operation_id: "listPictures",
+        summary: "Picture posts (NIP-68, kind 20), newest first",
+        params: &[
+            query("author", false, "Only pictures by this hex pubkey"),
+            query("limit", false, "Maximum pictures, default 20, max 100"),
+            query("until", false, "Only pictures created before this unix timestamp"),
+        ],
+        request: None,
+        status: 200,
+        response: Body::Json("PicturesResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/embed/{id}",
+        operation_id: "getEmbed",
+        summary: "Embeddable HTML preview of an event",
+        params: &[path("id", "Hex event id")],
+        request: None,
+        status: 200,
+        response: Body::Html,
+    },
+    Route {
+        method: "get",
+        path: "/relay-info",
+        operation_id: "getRelayInfo",
+        summary: "NIP-11 document of the upstream relay",
+        params: &[query("url", false, "Relay to describe instead of the default")],
+        request: None,
+        status: 200,
+        response: Body::Json("RelayInfoResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/stats",
+        operation_id: "getStats",
+        summary: "Public gateway stats",
+        params: &[],
+        request: None,
+        status: 200,
+        response: Body::Json("StatsResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/info",
+        operation_id: "getInfo",
+        summary: "Tier, scopes and limits of the caller's API key (X-Api-Key or Authorization: Bearer)",
+        params: &[],
+        request: None,
+        status: 200,
+        response: Body::Json("InfoResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/quota",
+        operation_id: "getQuota",
+        summary: "Reads and publishes the NIP-98 signer has left today (Authorization: Nostr, signed for GET)",
+        params: &[],
+        request: None,
+        status: 200,
+        response: Body::Json("QuotaResponse"),
+    },
+    Route {
+        method: "post",
+        path: "/auth/session",
+        operation_id: "createSession",
+        summary: "Exchange a NIP-98 event (signed for POST) for a short-lived Bearer session token",
+        params: &[],
+        request: Some("SessionRequest"),
+        status: 201,
+        response: Body::Json("SessionResponse"),
+    },
+    Route {
+        method: "delete",
+        path: "/auth/session",
+        operation_id: "revokeSession",
+        summary: "Revoke the session token in Authorization: Bearer",
+        params: &[],
+        request: None,
+        status: 200,
+        response: Body::Json("SessionResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/count",
+        operation_id: "count",
+        summary: "Number of events matching a filter",
+        params: &[query("filter", true, "Base64url-encoded NIP-01 filter JSON")],
+        request: None,
+        status: 200,
+        response: Body::Json("AggregateResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/reactions/{id}",
+        operation_id: "getReactions",
+        summary: "Reaction summary for an event",
+        params: &[path("id", "Hex event id")],
+        request: None,
+        status: 200,
+        response: Body::Json("AggregateResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/zaps/{id}",
+        operation_id: "getZaps",
+        summary: "Zap total for an event",
+        params: &[path("id", "Hex event id")],
+        request: None,
+        status: 200,
+        response: Body::Json("AggregateResponse"),
+    },
+    Route {
+        method: "post",
+        path: "/publish",
+        operation_id: "publish",
+        summary: "Queue a signed event for publishing (NIP-98 auth)",
+        params: &[],
+        request: Some("PublishRequest"),
+        status: 202,
+        response: Body::Json("PublishResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/publish/status/{id}",
+        operation_id: "getPublishStatus",
+        summary: "Delivery status of a queued publish",
+        params: &[path("id", "Hex event id")],
+        request: None,
+        status: 200,
+        response: Body::Json("PublishStatus"),
+    },
+    Route {
+        method: "delete",
+        path: "/publish/{id}",
+        operation_id: "cancelPublish",
+        summary: "Cancel a queued, quarantined or retrying publish (NIP-98 auth as the author)",
+        params: &[path("id", "Hex event id")],
+        request: None,
+        status: 200,
+        response: Body::Json("PublishStatus"),
+    },
+    Route {
+        method: "post",
+        path: "/webhooks",
+        operation_id: "createWebhook",
+        summary: "Register a filter and callback for push delivery (NIP-98 auth)",
+        params: &[],
+        request: Some("WebhookRequest"),
+        status: 201,
+        response: Body::Json("WebhookResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/webhooks",
+        operation_id: "listWebhooks",
+        summary: "Webhooks registered by the authenticated pubkey (NIP-98 auth)",
+        params: &[],
+        request: None,
+        status: 200,
+        response: Body::Json("WebhooksResponse"),
+    },
+    Route {
+        method: "delete",
+        path: "/webhooks/{id}",
+        operation_id: "deleteWebhook",
+        summary: "Remove a webhook (NIP-98 auth)",
+        params: &[path("id", "Webhook id")],
+        request: None,
+        status: 200,
+        response: Body::Json("WebhookResponse"),
+    },
+    Route {
+        method: "post",
+        path: "/nwc",
+        operation_id: "relayWalletRequest",
+        summary: "Relay a NIP-47 wallet-connect request and return the wallet's reply (NIP-98 auth)",
+        params: &[],
+        request: Some("NwcRequest"),
+        status: 200,
+        response: Body::Json("NwcResponse"),
+    },
+    Route {
+        method: "get",
+        path: "/push/vapid-public-key",
+        operation_id: "getVapidPublicKey",
+        summary: "VAPID application server key for PushManager.subscribe",
+        params: &[],
+        request: None,
+        status: 200,
+        response: Body::Json("VapidKeyResponse"),
+    },
+    Route {
+        method: "post",
+        path: "/push/subscriptions",
+        operation_id: "createPushSubscription",
+        summary: "Register a Web Push subscription and filter (NIP-98 auth)",
+        params: &[],
+        request: Some("PushRegistrationRequest"),
+        status: 201,
+        response: Body::Json("PushRegistrationResponse"),
+    },
+    Route {
+        method: "delete",
+        path: "/push/subscriptions/{id}",
+        operation_id: "deletePushSubscription",
+        summary: "Remove a Web Push subscription (NIP-98 auth)",
+        params: &[path("id", "Push subscription id")],
+        request: None,
+        status: 200,
+        response: Body::Json("PushRegistrationResponse"),
+    },
+];
+
+/// Schemas for every type referenced by ROUTES, plus the error body
+pub fn component_schemas() -> Map<String, Value> {
+    let mut gen = SchemaGenerator::new(SchemaSettings::openapi3());
+    gen.subschema_for::<QueryResponse>();
+    gen.subschema_for::<QueryExplainResponse>();
+    gen.subschema_for::<FilterValidateRequest>();
+    gen.subschema_for::<FilterValidateResponse>();
+    gen.subschema_for::<VideoEvent>();
+    gen.subschema_for::<VideosResponse>();
+    gen.subschema_for::<PictureEvent>();
+    gen.subschema_for::<PicturesResponse>();
+    gen.subschema_for::<RelayInfoResponse>();
+    gen.subschema_for::<StatsResponse>();
+    gen.subschema_for::<AggregateResponse>();
+    gen.subschema_for::<ReferencesResponse>();
+    gen.subschema_for::<ReferencedByResponse>();
+    gen.subschema_for::<PublishRequest>();
+    gen.subschema_for::<PublishResponse>();
+    gen.subschema_for::<PublishStatus>();
+    gen.subschema_for::<WebhookRequest>();
+    gen.subschema_for::<WebhookResponse>();
+    gen.subschema_for::<WebhooksResponse>();
+    gen.subschema_for::<EventsRequest>();
+    gen.subschema_for::<EventsResponse>();
+    gen.subschema_for::<FeedResponse>();
+    gen.subschema_for::<ProfilesRequest>();
+    gen.subschema_for::<ProfilesResponse>();
+    gen.subschema_for::<VapidKeyResponse>();
+    gen.subschema_for::<PushRegistrationRequest>();
+    gen.subschema_for::<PushRegistrationResponse>();
+    gen.subschema_for::<InfoResponse>();
+    gen.subschema_for::<QuotaResponse>();
+    gen.subschema_for::<SessionRequest>();
+    gen.subschema_for::<SessionResponse>();
+    gen.subschema_for::<StatusResponse>();
+    gen.subschema_for::<MuteListResponse>();
+    gen.subschema_for::<NwcRequest>();
+    gen.subschema_for::<NwcResponse>();
+    gen.subschema_for::<FollowerExportLine>();
+    gen.subschema_for::<ErrorResponse>();
+    gen.take_definitions()
+        .into_iter()
+        .map(|(name, schema)| (name, serde_json::to_value(schema).unwrap_or(Value::Null)))
+        .collect()
+}
+
+fn body_content(body: Body) -> Value {
+    match body {
+        Body::Json(name) => json!({ "application/json": { "schema": { "$ref": schema_ref(name) } } }),
+        Body::Jsonl(name) => json!({ "application/x-ndjson": { "schema": { "$ref": schema_ref(name) } } }),
+        Body::Html => json!({ "text/html": { "schema": { "type": "string" } } }),
+        Body::Text => json!({ "text/plain": { "schema": { "type": "string" } } }),
+    }
+}
+
+fn schema_ref(name: &str) -> String {
+    format!("#/components/schemas/{}", name)
+}
+
+fn operation(route: &Route) -> Value {
+    let params: Vec<Value> = route
+        .params
+        .iter()
+        .map(|p| {
+            json!({
+                "name": p.name,
+                "in": match p.location {
+                    ParamIn::Path => "path",
+                    ParamIn::Query => "query",
+                },
+                "required": p.required,
+                "description": p.description,
+                "schema": { "type": "string" },
+            })
+        })
+        .collect();
+
+    let mut op = json!({
+        "operationId": route.operation_id,
+        "summary": route.summary,
+        "parameters": params,
+        "responses": {
+            route.status.to_string(): { "description": "Success", "content": body_content(route.response) },
+            "default": { "description": "Error", "content": body_content(Body::Json("ErrorResponse")) },
+        },
+    });
+    if let Some(request) = route.request {
+        op["requestBody"] = json!({ "required": true, "content": body_content(Body::Json(request)) });
+    }
+    op
+}
+
+/// The full OpenAPI 3.0 document served at /openapi.json
+pub fn document() -> Value {
+    let mut paths = Map::new();
+    for route in ROUTES {
+        let entry = paths.entry(route.path).or_insert_with(|| json!({}));
+        entry[route.method] = operation(route);
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Divine REST Gateway",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        // Every path is also served unprefixed, as an alias of v1
+        "servers": [{ "url": "/v1" }],
+        "paths": paths,
+        "components": { "schemas": component_schemas() },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_referenced_schema_exists() {
+        let schemas = component_schemas();
+        for route in ROUTES {
+            if let Body::Json(name) | Body::Jsonl(name) = route.response {
+                assert!(schemas.contains_key(name), "missing schema {}", name);
+            }
+            if let Some(name) = route.request {
+                assert!(schemas.contains_key(name), "missing schema {}", name);
+            }
+        }
+        assert!(schemas.contains_key("ErrorResponse"));
+    }
+
+    #[test]
+    fn test_operation_ids_unique() {
+        let mut ids: Vec<_> = ROUTES.iter().map(|r| r.operation_id).collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), ROUTES.len());
+    }
+
+    #[test]
+    fn test_document_shape() {
+        let doc = document();
+        assert_eq!(doc["paths"]["/query"]["get"]["operationId"], "query");
+        assert_eq!(
+            doc["paths"]["/publish"]["post"]["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/PublishRequest"
+        );
+        assert_eq!(doc["components"]["schemas"]["QuerySource"]["enum"], json!(["cache", "relay", "archive"]));
+    }
+}
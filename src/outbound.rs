@@ -0,0 +1,256 @@
+// ABOUTME: Guarded outbound HTTP for URLs that come from users or event content
+// ABOUTME: Public hosts only, allowed schemes only, bounded redirects, response size and time
+
+use futures_util::future::{select, Either};
+use futures_util::StreamExt;
+use std::time::Duration;
+use worker::*;
+
+/// Cloud metadata services reachable by name. IP literals (169.254.169.254 and
+/// friends) are refused outright, so only hostnames need listing.
+const METADATA_HOSTS: [&str; 4] = [
+    "metadata.google.internal",
+    "metadata.goog",
+    "metadata.tencentyun.com",
+    "instance-data.ec2.internal",
+];
+
+/// Why a host can't be the target of an outbound request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostError {
+    IpAddress,
+    Private,
+}
+
+/// Outbound requests only go to public hostnames, never to IP literals, local
+/// names or metadata services. Refusing every IP literal covers the private and
+/// link-local ranges without having to enumerate them.
+pub fn check_public_host(host: &str) -> std::result::Result<(), HostError> {
+    let host = host.to_ascii_lowercase();
+    let host = host.trim_end_matches('.');
+    let bare = host.trim_start_matches('[').trim_end_matches(']');
+    if bare.parse::<std::net::IpAddr>().is_ok() {
+        return Err(HostError::IpAddress);
+    }
+    let local = host == "localhost"
+        || !host.contains('.')
+        || [".localhost", ".internal", ".local", ".lan", ".home.arpa"]
+            .iter()
+            .any(|suffix| host.ends_with(suffix));
+    if local || METADATA_HOSTS.contains(&host) {
+        return Err(HostError::Private);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutboundError {
+    Scheme,
+    Host(HostError),
+    TooManyRedirects,
+    TooLarge,
+    Timeout,
+    Fetch(String),
+}
+
+impl std::fmt::Display for OutboundError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Scheme => write!(f, "url scheme not allowed"),
+            Self::Host(HostError::IpAddress) => write!(f, "url must use a hostname, not an IP address"),
+            Self::Host(HostError::Private) => write!(f, "url must be publicly reachable"),
+            Self::TooManyRedirects => write!(f, "too many redirects"),
+            Self::TooLarge => write!(f, "response too large"),
+            Self::Timeout => write!(f, "request timed out"),
+            Self::Fetch(e) => write!(f, "fetch failed: {}", e),
+        }
+    }
+}
+
+impl From<Error> for OutboundError {
+    fn from(e: Error) -> Self {
+        Self::Fetch(e.to_string())
+    }
+}
+
+impl From<OutboundError> for Error {
+    fn from(e: OutboundError) -> Self {
+        Error::RustError(e.to_string())
+    }
+}
+
+/// Limits for one outbound request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Policy {
+    pub schemes: &'static [&'static str],
+    /// Redirects followed by `get`; other methods never follow them
+    pub max_redirects: u8,
+    /// Largest response body `get` reads
+    pub max_bytes: usize,
+    /// For the whole exchange, redirects and body included
+    pub timeout: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            schemes: &["https"],
+            max_redirects: 3,
+            max_bytes: 1024 * 1024,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Policy {
+    pub fn check_url(&self, url: &Url) -> std::result::Result<(), OutboundError> {
+        if !self.schemes.contains(&url.scheme()) {
+            return Err(OutboundError::Scheme);
+        }
+        let host = url.host_str().ok_or(OutboundError::Host(HostError::Private))?;
+        check_public_host(host).map_err(OutboundError::Host)
+    }
+
+    /// Where a redirect points, checked like the original URL
+    fn redirect_target(&self, from: &Url, location: &str) -> std::result::Result<Url, OutboundError> {
+        let next = from.join(location).map_err(|e| OutboundError::Fetch(e.to_string()))?;
+        self.check_url(&next)?;
+        Ok(next)
+    }
+}
+
+/// A response read in full
+#[derive(Debug)]
+pub struct Fetched {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl Fetched {
+    pub fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+async fn with_timeout<T>(
+    timeout: Duration,
+    work: impl std::future::Future<Output = std::result::Result<T, OutboundError>>,
+) -> std::result::Result<T, OutboundError> {
+    match select(Box::pin(work), Delay::from(timeout)).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => Err(OutboundError::Timeout),
+    }
+}
+
+/// GET a URL, following up to `max_redirects` redirects, each re-checked
+pub async fn get(url: Url, headers: &Headers, policy: &Policy) -> std::result::Result<Fetched, OutboundError> {
+    with_timeout(policy.timeout, async {
+        let mut url = url;
+        let mut redirects = 0;
+        loop {
+            policy.check_url(&url)?;
+            let mut init = RequestInit::new();
+            init.with_headers(headers.clone()).with_redirect(RequestRedirect::Manual);
+            let mut resp = Fetch::Request(Request::new_with_init(url.as_str(), &init)?).send().await?;
+            let status = resp.status_code();
+            if (300..400).contains(&status) {
+                let Some(location) = resp.headers().get("Location")? else {
+                    return Err(OutboundError::Fetch(format!("redirect {} without a location", status)));
+                };
+                redirects += 1;
+                if redirects > policy.max_redirects {
+                    return Err(OutboundError::TooManyRedirects);
+                }
+                url = policy.redirect_target(&url, &location)?;
+                continue;
+            }
+            let body = read_capped(&mut resp, policy.max_bytes).await?;
+            return Ok(Fetched { status, body });
+        }
+    })
+    .await
+}
+
+/// Send a request built for `url` without following redirects; a 3xx comes back as is
+pub async fn send(url: &Url, init: &mut RequestInit, policy: &Policy) -> std::result::Result<Response, OutboundError> {
+    policy.check_url(url)?;
+    init.with_redirect(RequestRedirect::Manual);
+    let req = Request::new_with_init(url.as_str(), init)?;
+    with_timeout(policy.timeout, async { Ok(Fetch::Request(req).send().await?) }).await
+}
+
+async fn read_capped(resp: &mut Response, max_bytes: usize) -> std::result::Result<Vec<u8>, OutboundError> {
+    let declared = resp.headers().get("Content-Length")?.and_then(|l| l.parse::<usize>().ok());
+    if declared.is_some_and(|l| l > max_bytes) {
+        return Err(OutboundError::TooLarge);
+    }
+    let mut body = Vec::new();
+    let mut stream = resp.stream()?;
+    while let Some(chunk) = stream.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > max_bytes {
+            return Err(OutboundError::TooLarge);
+        }
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(raw: &str) -> Url {
+        Url::parse(raw).unwrap()
+    }
+
+    #[test]
+    fn test_check_public_host() {
+        assert_eq!(check_public_host("relay.example.com"), Ok(()));
+        assert_eq!(check_public_host("127.0.0.1"), Err(HostError::IpAddress));
+        assert_eq!(check_public_host("[::1]"), Err(HostError::IpAddress));
+        assert_eq!(check_public_host("169.254.169.254"), Err(HostError::IpAddress));
+        assert_eq!(check_public_host("localhost"), Err(HostError::Private));
+        assert_eq!(check_public_host("metadata"), Err(HostError::Private));
+        assert_eq!(check_public_host("db.internal"), Err(HostError::Private));
+        assert_eq!(check_public_host("Metadata.Google.Internal."), Err(HostError::Private));
+        assert_eq!(check_public_host("metadata.goog"), Err(HostError::Private));
+        assert_eq!(check_public_host("nas.home.arpa"), Err(HostError::Private));
+    }
+
+    #[test]
+    fn test_check_url() {
+        let policy = Policy::default();
+        assert_eq!(policy.check_url(&url("https://cdn.example.com/a.jpg")), Ok(()));
+        assert_eq!(policy.check_url(&url("http://cdn.example.com/a.jpg")), Err(OutboundError::Scheme));
+        assert_eq!(policy.check_url(&url("file:///etc/passwd")), Err(OutboundError::Scheme));
+        // Numeric hosts are normalized to dotted IPv4 by the URL parser
+        assert_eq!(
+            policy.check_url(&url("https://2130706433/")),
+            Err(OutboundError::Host(HostError::IpAddress))
+        );
+        let http = Policy {
+            schemes: &["https", "http"],
+            ..Policy::default()
+        };
+        assert_eq!(http.check_url(&url("http://relay.example.com/")), Ok(()));
+    }
+
+    #[test]
+    fn test_redirect_target() {
+        let policy = Policy::default();
+        let from = url("https://cdn.example.com/a/b.jpg");
+        assert_eq!(policy.redirect_target(&from, "/c.jpg").unwrap().as_str(), "https://cdn.example.com/c.jpg");
+        assert_eq!(
+            policy.redirect_target(&from, "https://other.example.net/x").unwrap().as_str(),
+            "https://other.example.net/x"
+        );
+        assert_eq!(
+            policy.redirect_target(&from, "http://169.254.169.254/latest/meta-data"),
+            Err(OutboundError::Scheme)
+        );
+        assert_eq!(
+            policy.redirect_target(&from, "https://metadata.google.internal/"),
+            Err(OutboundError::Host(HostError::Private))
+        );
+    }
+}
@@ -0,0 +1,239 @@
+// ABOUTME: WebSocket passthrough between clients and the upstream relay
+// ABOUTME: Client subscriptions ride the shared relay connection under their own ids; publishes stay on the REST path
+
+use crate::hooks::Hooks;
+use crate::relay_message::RelayMessage;
+use serde::{Deserialize, Serialize};
+
+/// Most open subscriptions a session remembers for replay; past this the oldest is forgotten
+const MAX_TRACKED_SUBSCRIPTIONS: usize = 64;
+
+/// What to do with a frame sent by the client
+#[derive(Debug, PartialEq)]
+pub enum ClientFrame {
+    Forward,
+    /// Don't forward; send this reply to the client instead
+    Reject(String),
+}
+
+/// Reads (REQ, CLOSE, COUNT) pass through. EVENT is refused because publishes
+/// must go through POST /publish for NIP-98 auth and quarantine.
+pub fn classify_client_frame(text: &str) -> ClientFrame {
+    let parsed: Vec<serde_json::Value> = match serde_json::from_str(text) {
+        Ok(p) => p,
+        Err(_) => return ClientFrame::Reject(notice("invalid: expected a JSON array")),
+    };
+    match parsed.first().and_then(|v| v.as_str()) {
+        Some("REQ") | Some("CLOSE") | Some("COUNT") => ClientFrame::Forward,
+        Some("EVENT") => {
+            let id = parsed
+                .get(1)
+                .and_then(|e| e.get("id"))
+                .and_then(|v| v.as_str())
+                .unwrap_or_default();
+            let reply = serde_json::json!(["OK", id, false, "blocked: publish via POST /publish"]);
+            ClientFrame::Reject(reply.to_string())
+        }
+        Some(other) => ClientFrame::Reject(notice(&format!("unsupported: {}", other))),
+        None => ClientFrame::Reject(notice("invalid: missing message type")),
+    }
+}
+
+pub fn notice(message: &str) -> String {
+    serde_json::json!(["NOTICE", message]).to_string()
+}
+
+/// What a forwarded client frame asks of the relay
+#[derive(Debug, PartialEq)]
+pub enum ClientRequest {
+    /// REQ or COUNT: the verb, the client's subscription id, and its filters as
+    /// the raw JSON that follows the id in the frame
+    Open { verb: String, id: String, filters: String },
+    Close { id: String },
+}
+
+/// Split a forwarded frame, so it can be sent under a subscription id of the
+/// shared connection's own. `None` for a frame without a subscription id.
+pub fn client_request(text: &str) -> Option<ClientRequest> {
+    let parsed: Vec<serde_json::Value> = serde_json::from_str(text).ok()?;
+    let verb = parsed.first()?.as_str()?;
+    let id = parsed.get(1)?.as_str()?.to_string();
+    match verb {
+        "REQ" | "COUNT" => {
+            let filters: Vec<String> = parsed[2..].iter().map(|f| f.to_string()).collect();
+            if filters.is_empty() {
+                return None;
+            }
+            Some(ClientRequest::Open {
+                verb: verb.to_string(),
+                id,
+                filters: filters.join(","),
+            })
+        }
+        "CLOSE" => Some(ClientRequest::Close { id }),
+        _ => None,
+    }
+}
+
+/// A relay frame for one of the client's subscriptions, as the client should
+/// see it: under the client's own subscription id, and with events the hooks
+/// withhold left out (`None`)
+pub fn client_frame(message: &RelayMessage, client_id: &str, hooks: &Hooks) -> Option<String> {
+    let frame = match message {
+        RelayMessage::Event { event, .. } if hooks.allow_event(event) => serde_json::json!(["EVENT", client_id, event]),
+        RelayMessage::Eose { .. } => serde_json::json!(["EOSE", client_id]),
+        RelayMessage::Closed { message, .. } => serde_json::json!(["CLOSED", client_id, message]),
+        RelayMessage::Count { result, .. } => serde_json::json!(["COUNT", client_id, result]),
+        _ => return None,
+    };
+    Some(frame.to_string())
+}
+
+/// A client socket's open subscriptions, as the REQ frames that opened them. It is
+/// kept in Durable Object storage, since a hibernated socket outlives the relay
+/// connection its subscriptions were on, and its REQs are replayed on a new one.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    subscriptions: Vec<(String, String)>,
+    /// Country the client connected from, for the hooks that filter its events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    country: Option<String>,
+}
+
+impl Session {
+    pub fn new(country: Option<String>) -> Self {
+        Self {
+            subscriptions: Vec::new(),
+            country,
+        }
+    }
+
+    pub fn country(&self) -> Option<&str> {
+        self.country.as_deref()
+    }
+
+    /// Note a forwarded frame: a REQ opens (or replaces) a subscription, a CLOSE ends one
+    pub fn track(&mut self, text: &str) {
+        let Ok(parsed) = serde_json::from_str::<Vec<serde_json::Value>>(text) else {
+            return;
+        };
+        let (Some(kind), Some(id)) = (parsed.first().and_then(|v| v.as_str()), parsed.get(1).and_then(|v| v.as_str())) else {
+            return;
+        };
+        match kind {
+            "REQ" => {
+                self.subscriptions.retain(|(open, _)| open != id);
+                if self.subscriptions.len() >= MAX_TRACKED_SUBSCRIPTIONS {
+                    self.subscriptions.remove(0);
+                }
+                self.subscriptions.push((id.to_string(), text.to_string()));
+            }
+            "CLOSE" => self.subscriptions.retain(|(open, _)| open != id),
+            _ => {}
+        }
+    }
+
+    /// REQ frames that reopen every subscription on a new upstream connection
+    pub fn replay(&self) -> impl Iterator<Item = &str> {
+        self.subscriptions.iter().map(|(_, req)| req.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reads_forwarded() {
+        assert_eq!(classify_client_frame(r#"["REQ","s1",{"kinds":[1]}]"#), ClientFrame::Forward);
+        assert_eq!(classify_client_frame(r#"["CLOSE","s1"]"#), ClientFrame::Forward);
+        assert_eq!(classify_client_frame(r#"["COUNT","c1",{"kinds":[1]}]"#), ClientFrame::Forward);
+    }
+
+    #[test]
+    fn test_event_rejected_with_ok_false() {
+        let frame = classify_client_frame(r#"["EVENT",{"id":"abc","kind":1}]"#);
+        assert_eq!(
+            frame,
+            ClientFrame::Reject(r#"["OK","abc",false,"blocked: publish via POST /publish"]"#.to_string())
+        );
+    }
+
+    #[test]
+    fn test_session_tracking() {
+        let mut session = Session::default();
+        session.track(r#"["REQ","a",{"kinds":[1]}]"#);
+        session.track(r#"["REQ","b",{"kinds":[0]}]"#);
+        session.track(r#"["REQ","a",{"kinds":[7]}]"#);
+        session.track(r#"["COUNT","c",{"kinds":[1]}]"#);
+        session.track(r#"["CLOSE","b"]"#);
+        session.track("garbage");
+        assert_eq!(session.replay().collect::<Vec<_>>(), vec![r#"["REQ","a",{"kinds":[7]}]"#]);
+
+        for i in 0..MAX_TRACKED_SUBSCRIPTIONS + 1 {
+            session.track(&format!(r#"["REQ","s{}",{{}}]"#, i));
+        }
+        assert_eq!(session.replay().count(), MAX_TRACKED_SUBSCRIPTIONS);
+        assert!(!session.replay().any(|req| req.contains(r#""a""#)));
+    }
+
+    #[test]
+    fn test_client_request() {
+        assert_eq!(
+            client_request(r#"["REQ","s1",{"kinds":[1]},{"kinds":[6]}]"#),
+            Some(ClientRequest::Open {
+                verb: "REQ".to_string(),
+                id: "s1".to_string(),
+                filters: r#"{"kinds":[1]},{"kinds":[6]}"#.to_string()
+            })
+        );
+        assert_eq!(client_request(r#"["CLOSE","s1"]"#), Some(ClientRequest::Close { id: "s1".to_string() }));
+        assert_eq!(client_request(r#"["REQ","s1"]"#), None);
+        assert_eq!(client_request(r#"["REQ",{"kinds":[1]}]"#), None);
+    }
+
+    #[test]
+    fn test_client_frames() {
+        use crate::hooks::{Hook, RequestContext};
+        use worker::Method;
+        struct NoKind4;
+        impl Hook for NoKind4 {
+            fn name(&self) -> &'static str {
+                "no_kind_4"
+            }
+            fn allow_event(&self, _ctx: &RequestContext, event: &serde_json::Value) -> bool {
+                event["kind"] != 4
+            }
+        }
+        let ctx = RequestContext {
+            method: Method::Get,
+            path: "/ws".to_string(),
+            country: None,
+        };
+        let hooks = Hooks::new(ctx, vec![Box::new(NoKind4)]);
+        let event = |id: &str, kind: u16| RelayMessage::Event {
+            subscription: "q7".to_string(),
+            event: serde_json::json!({"id": id, "kind": kind}),
+        };
+        assert_eq!(client_frame(&event("a", 4), "s1", &hooks), None);
+        let allowed = client_frame(&event("b", 1), "s1", &hooks);
+        assert_eq!(allowed.as_deref(), Some(r#"["EVENT","s1",{"id":"b","kind":1}]"#));
+        let eose = RelayMessage::Eose {
+            subscription: "q7".to_string(),
+        };
+        assert_eq!(client_frame(&eose, "s1", &hooks).as_deref(), Some(r#"["EOSE","s1"]"#));
+        assert_eq!(client_frame(&RelayMessage::Notice("hi".to_string()), "s1", &hooks), None);
+    }
+
+    #[test]
+    fn test_garbage_gets_notice() {
+        assert_eq!(
+            classify_client_frame("hello"),
+            ClientFrame::Reject(r#"["NOTICE","invalid: expected a JSON array"]"#.to_string())
+        );
+        assert_eq!(
+            classify_client_frame(r#"["AUTH",{}]"#),
+            ClientFrame::Reject(r#"["NOTICE","unsupported: AUTH"]"#.to_string())
+        );
+    }
+}
@@ -0,0 +1,48 @@
+// ABOUTME: Operator-named filters stored in KV and run with GET /query?preset={name}
+// ABOUTME: Heavy, popular queries stay byte-identical across clients and can be retuned without a release
+
+use crate::filter::{Filter, FilterError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use worker::kv::KvStore;
+use worker::*;
+
+/// KV prefix of stored presets
+pub const KEY_PREFIX: &str = "preset:";
+
+/// Presets are read on every `?preset=` query, so reads are edge-cached;
+/// changes take up to this long to reach every colo
+const CACHE_SECONDS: u64 = 60;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Preset {
+    pub name: String,
+    pub filter: Value,
+    pub updated_at: u64,
+}
+
+impl Preset {
+    pub fn to_filter(&self) -> std::result::Result<Filter, FilterError> {
+        Filter::from_json(&self.filter.to_string())
+    }
+}
+
+pub async fn get(kv: &KvStore, name: &str) -> Result<Option<Preset>> {
+    Ok(kv
+        .get(&format!("{}{}", KEY_PREFIX, name))
+        .cache_ttl(CACHE_SECONDS)
+        .json::<Preset>()
+        .await?)
+}
+
+pub async fn put(kv: &KvStore, preset: &Preset) -> Result<()> {
+    kv.put(&format!("{}{}", KEY_PREFIX, preset.name), serde_json::to_string(preset)?)?
+        .execute()
+        .await?;
+    Ok(())
+}
+
+pub async fn delete(kv: &KvStore, name: &str) -> Result<()> {
+    kv.delete(&format!("{}{}", KEY_PREFIX, name)).await?;
+    Ok(())
+}
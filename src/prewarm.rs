@@ -0,0 +1,235 @@
+// ABOUTME: Background cache prewarming for known traffic spikes
+// ABOUTME: Jobs run through the publish queue a batch of filters per message, tracking progress in KV
+
+use crate::cache::{now_seconds, Cache};
+use crate::filter::Filter;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+/// Filters accepted per job
+pub const MAX_PREWARM_FILTERS: usize = 50;
+
+/// Filters run per queue message; the rest go back on the queue as a new message
+const FILTERS_PER_MESSAGE: usize = 10;
+
+/// Progress is kept for a day after the job starts
+const JOB_TTL_SECONDS: u64 = 86400;
+
+/// A running job whose progress hasn't moved for this long has died with its
+/// consumer (or been dead-lettered) and is reported as failed
+const STALE_AFTER_SECONDS: u64 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    Done,
+    Failed,
+}
+
+/// Progress of a prewarm job, readable via `GET /admin/prewarm/{id}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrewarmJob {
+    pub id: String,
+    pub status: JobStatus,
+    pub total: usize,
+    pub completed: usize,
+    pub failed: usize,
+    /// Events cached across all filters
+    pub events: usize,
+    pub started_at: u64,
+    /// Last time a consumer saved progress
+    #[serde(default)]
+    pub heartbeat_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+    /// Filters still to run, as JSON
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pending: Vec<String>,
+}
+
+/// Queue message carrying a job along with its remaining filters, so the
+/// consumer never depends on reading its own progress back from KV
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PrewarmMessage {
+    pub prewarm: PrewarmJob,
+}
+
+impl PrewarmJob {
+    pub fn new(id: String, filters: Vec<String>, now: u64) -> Self {
+        Self {
+            id,
+            status: JobStatus::Running,
+            total: filters.len(),
+            completed: 0,
+            failed: 0,
+            events: 0,
+            started_at: now,
+            heartbeat_at: now,
+            finished_at: None,
+            pending: filters,
+        }
+    }
+
+    /// Record one filter's outcome: the number of events cached, or None if it failed
+    pub fn record(&mut self, events: Option<usize>, now: u64) {
+        match events {
+            Some(events) => {
+                self.completed += 1;
+                self.events += events;
+            }
+            None => self.failed += 1,
+        }
+        self.heartbeat_at = now;
+        if self.completed + self.failed >= self.total {
+            self.status = JobStatus::Done;
+            self.finished_at = Some(now);
+        }
+    }
+
+    /// As reported: a running job nobody has touched in a while is failed
+    pub fn observed(mut self, now: u64) -> Self {
+        if self.status == JobStatus::Running && now.saturating_sub(self.heartbeat_at) > STALE_AFTER_SECONDS {
+            self.status = JobStatus::Failed;
+            self.finished_at = Some(self.heartbeat_at);
+        }
+        self
+    }
+}
+
+pub fn new_job_id() -> String {
+    let mut bytes = [0u8; 8];
+    // Falls back to a timestamp id if the RNG is unavailable
+    match getrandom::getrandom(&mut bytes) {
+        Ok(()) => hex::encode(bytes),
+        Err(_) => format!("{:x}", js_sys::Date::now() as u64),
+    }
+}
+
+fn job_key(id: &str) -> String {
+    format!("prewarm:{}", id)
+}
+
+pub async fn get_job(cache: &Cache, id: &str) -> Result<Option<PrewarmJob>> {
+    Ok(cache
+        .get_raw(&job_key(id))
+        .await?
+        .and_then(|raw| serde_json::from_str::<PrewarmJob>(&raw).ok())
+        .map(|job| job.observed(now_seconds())))
+}
+
+/// Progress as stored: the filters still to run travel in the queue message instead
+async fn save_job(env: &Env, job: &PrewarmJob) -> Result<()> {
+    let progress = PrewarmJob {
+        pending: Vec::new(),
+        ..job.clone()
+    };
+    env.kv("REST_GATEWAY_CACHE")?
+        .put(&job_key(&job.id), serde_json::to_string(&progress)?)?
+        .expiration_ttl(JOB_TTL_SECONDS)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Store the initial job record so its id is immediately queryable, and queue it
+pub async fn start_job(env: &Env, filters: &[Filter]) -> Result<PrewarmJob> {
+    let filters = filters.iter().map(|f| f.as_json().to_string()).collect();
+    let job = PrewarmJob::new(new_job_id(), filters, now_seconds());
+    save_job(env, &job).await?;
+    env.queue("PUBLISH_QUEUE")?
+        .send(PrewarmMessage { prewarm: job.clone() })
+        .await?;
+    Ok(job)
+}
+
+/// Query the next batch of a job's filters live and cache the results, then
+/// save progress and queue the rest. A consumer that dies mid-batch has its
+/// message redelivered, and the batch runs again from the same snapshot.
+pub async fn run_batch(env: &Env, mut job: PrewarmJob) -> Result<()> {
+    let cache = Cache::from_env(env)?;
+    let rest = job.pending.split_off(job.pending.len().min(FILTERS_PER_MESSAGE));
+    for filter_json in std::mem::replace(&mut job.pending, rest) {
+        let result = match Filter::from_json(&filter_json) {
+            Ok(filter) => warm(env, &cache, &job.id, &filter).await,
+            Err(e) => {
+                console_log!("Prewarm {} skipped a bad filter: {}", job.id, e);
+                None
+            }
+        };
+        job.record(result, now_seconds());
+    }
+
+    save_job(env, &job).await?;
+    if job.status == JobStatus::Running && !job.pending.is_empty() {
+        env.queue("PUBLISH_QUEUE")?.send(PrewarmMessage { prewarm: job }).await?;
+    }
+    Ok(())
+}
+
+/// One filter queried and cached: the number of events, or None if it failed
+async fn warm(env: &Env, cache: &Cache, job_id: &str, filter: &Filter) -> Option<usize> {
+    // The queue consumer holds jobs back while degraded, so this always asks the relay
+    let events = match crate::router::query_relay(env, filter, None).await {
+        Ok(events) => events,
+        Err(e) => {
+            console_log!("Prewarm {} query failed: {}", job_id, e);
+            return None;
+        }
+    };
+    let count = events.len();
+    let ttl = crate::router::cache_ttl(env, filter);
+    match cache.put_query(filter, events, true, ttl).await {
+        Ok(()) => Some(count),
+        Err(e) => {
+            console_log!("Prewarm {} cache write failed: {}", job_id, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!(r#"{{"kinds":[{}]}}"#, i)).collect()
+    }
+
+    #[test]
+    fn test_job_progress() {
+        let mut job = PrewarmJob::new("j".to_string(), filters(3), 100);
+        job.record(Some(5), 101);
+        job.record(None, 102);
+        assert_eq!(job.status, JobStatus::Running);
+        assert!(job.finished_at.is_none());
+        job.record(Some(2), 103);
+        assert_eq!(job.status, JobStatus::Done);
+        assert_eq!((job.completed, job.failed, job.events), (2, 1, 7));
+        assert_eq!(job.finished_at, Some(103));
+    }
+
+    #[test]
+    fn test_stale_job_reported_failed() {
+        let mut job = PrewarmJob::new("j".to_string(), filters(2), 100);
+        job.record(Some(1), 110);
+        assert_eq!(job.clone().observed(110 + STALE_AFTER_SECONDS).status, JobStatus::Running);
+        let stale = job.clone().observed(111 + STALE_AFTER_SECONDS);
+        assert_eq!(stale.status, JobStatus::Failed);
+        assert_eq!(stale.finished_at, Some(110));
+        job.record(Some(1), 120);
+        assert_eq!(job.observed(10_000).status, JobStatus::Done);
+    }
+
+    #[test]
+    fn test_job_serialization() {
+        let job = PrewarmJob::new("abc".to_string(), filters(2), 100);
+        let json = serde_json::to_string(&PrewarmMessage { prewarm: job }).unwrap();
+        assert!(json.contains(r#""status":"running""#));
+        assert!(json.contains(r#""pending":["#));
+        assert!(!json.contains("finished_at"));
+        // Publish queue messages are events, which never parse as a prewarm message
+        let event = serde_json::json!({"id": "abc", "kind": 1});
+        assert!(serde_json::from_value::<PrewarmMessage>(event).is_err());
+    }
+}
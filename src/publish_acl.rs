@@ -0,0 +1,268 @@
+// ABOUTME: Who may publish through POST /publish: pubkey allow/deny lists and the NIP-98 signer rule
+// ABOUTME: An allowlist makes the gateway closed; the denylist blocks abusive keys on an open one
+
+use k256::schnorr::{Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use worker::kv::KvStore;
+use worker::*;
+
+const KV_KEY: &str = "publish_acl";
+
+/// Edge-cached reads of the KV lists; edits take up to this long to reach every colo
+const CACHE_SECONDS: u64 = 60;
+
+/// Pubkey lists as stored in KV and set through the admin API
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AccessLists {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+/// Why a publish was turned away
+#[derive(Debug, Clone, PartialEq)]
+pub enum Denial {
+    Blocked(String),
+    NotAllowed(String),
+}
+
+impl std::fmt::Display for Denial {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Blocked(pubkey) => write!(f, "pubkey {} is blocked from publishing", pubkey),
+            Self::NotAllowed(pubkey) => write!(f, "pubkey {} is not allowed to publish here", pubkey),
+        }
+    }
+}
+
+impl AccessLists {
+    /// The env lists (`PUBLISH_ALLOWED_PUBKEYS`, `PUBLISH_BLOCKED_PUBKEYS`) merged
+    /// with the KV record. A failed KV read leaves just the env lists.
+    pub async fn load(env: &Env) -> Self {
+        let list = |name: &str| -> Vec<String> {
+            env.var(name)
+                .map(|v| v.to_string())
+                .unwrap_or_default()
+                .split(',')
+                .map(|p| p.trim().to_string())
+                .filter(|p| !p.is_empty())
+                .collect()
+        };
+        let mut lists = Self {
+            allow: list("PUBLISH_ALLOWED_PUBKEYS"),
+            deny: list("PUBLISH_BLOCKED_PUBKEYS"),
+        };
+        let stored = match env.kv("REST_GATEWAY_CACHE") {
+            Ok(kv) => get(&kv, CACHE_SECONDS).await,
+            Err(e) => Err(e),
+        };
+        match stored {
+            Ok(Some(stored)) => {
+                lists.allow.extend(stored.allow);
+                lists.deny.extend(stored.deny);
+            }
+            Ok(None) => {}
+            Err(e) => console_log!("Publish access list lookup failed: {}", e),
+        }
+        lists
+    }
+
+    /// Check every pubkey a publish involves (the author, and the NIP-98 signer when
+    /// there is one). The denylist wins; a non-empty allowlist must hold them all.
+    pub fn check(&self, pubkeys: &[&str]) -> std::result::Result<(), Denial> {
+        let listed = |list: &[String], pubkey: &str| list.iter().any(|p| p.eq_ignore_ascii_case(pubkey));
+        if let Some(pubkey) = pubkeys.iter().find(|p| listed(&self.deny, p)) {
+            return Err(Denial::Blocked(pubkey.to_string()));
+        }
+        if self.allow.is_empty() {
+            return Ok(());
+        }
+        match pubkeys.iter().find(|p| !listed(&self.allow, p)) {
+            Some(pubkey) => Err(Denial::NotAllowed(pubkey.to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Whether a NIP-98 signer must be the event's author (`PUBLISH_REQUIRE_AUTHOR_MATCH`,
+/// on unless set to `false`)
+pub fn require_author_match(env: &Env) -> bool {
+    env.var("PUBLISH_REQUIRE_AUTHOR_MATCH")
+        .map(|v| v.to_string().trim() != "false")
+        .unwrap_or(true)
+}
+
+/// Whether `signer` may submit `event`: it is the author, or it delegated to the
+/// author with a NIP-26 `delegation` tag. That tag names the delegator and carries
+/// its signature over `nostr:delegation:<author>:<conditions>`, so only the
+/// delegator can make one, and the event has to meet its conditions.
+pub fn signer_may_publish(signer: &str, event: &Value) -> bool {
+    let author = event.get("pubkey").and_then(Value::as_str).unwrap_or_default();
+    if author.eq_ignore_ascii_case(signer) {
+        return true;
+    }
+    let kind = event.get("kind").and_then(Value::as_u64);
+    let created_at = event.get("created_at").and_then(Value::as_u64);
+    event
+        .get("tags")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_array)
+        .any(|tag| {
+            let tag: Vec<&str> = tag.iter().filter_map(Value::as_str).collect();
+            matches!(tag.as_slice(), ["delegation", delegator, conditions, sig, ..]
+                if delegator.eq_ignore_ascii_case(signer)
+                    && conditions_met(conditions, kind, created_at)
+                    && delegation_signed(delegator, author, conditions, sig))
+        })
+}
+
+/// Whether an event meets NIP-26 conditions such as `kind=1&created_at>1700000000`.
+/// Any of the listed kinds will do; every `created_at` bound must hold. Conditions
+/// the gateway doesn't know are not met.
+fn conditions_met(conditions: &str, kind: Option<u64>, created_at: Option<u64>) -> bool {
+    let mut kinds = Vec::new();
+    for clause in conditions.split('&').filter(|c| !c.is_empty()) {
+        let met = if let Some(value) = clause.strip_prefix("kind=") {
+            value.parse::<u64>().map(|k| kinds.push(k)).is_ok()
+        } else if let Some(value) = clause.strip_prefix("created_at>") {
+            value.parse::<u64>().is_ok_and(|after| created_at.is_some_and(|t| t > after))
+        } else if let Some(value) = clause.strip_prefix("created_at<") {
+            value.parse::<u64>().is_ok_and(|before| created_at.is_some_and(|t| t < before))
+        } else {
+            false
+        };
+        if !met {
+            return false;
+        }
+    }
+    kinds.is_empty() || kind.is_some_and(|k| kinds.contains(&k))
+}
+
+/// Whether `sig` is the delegator's Schnorr signature over the SHA-256 of the
+/// delegation token for `delegatee` and `conditions`
+fn delegation_signed(delegator: &str, delegatee: &str, conditions: &str, sig: &str) -> bool {
+    let token = format!("nostr:delegation:{}:{}", delegatee.to_ascii_lowercase(), conditions);
+    let key = hex::decode(delegator).ok().and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok());
+    let sig = hex::decode(sig).ok().and_then(|bytes| Signature::try_from(bytes.as_slice()).ok());
+    match (key, sig) {
+        (Some(key), Some(sig)) => key.verify_raw(&Sha256::digest(token.as_bytes()), &sig).is_ok(),
+        _ => false,
+    }
+}
+
+/// The KV record alone, edge-cached for `cache_ttl` seconds (0 reads it fresh)
+pub async fn get(kv: &KvStore, cache_ttl: u64) -> Result<Option<AccessLists>> {
+    let read = kv.get(KV_KEY);
+    let read = if cache_ttl > 0 { read.cache_ttl(cache_ttl) } else { read };
+    Ok(read.json::<AccessLists>().await?)
+}
+
+pub async fn put(kv: &KvStore, lists: &AccessLists) -> Result<()> {
+    kv.put(KV_KEY, serde_json::to_string(lists)?)?.execute().await?;
+    Ok(())
+}
+
+pub async fn clear(kv: &KvStore) -> Result<()> {
+    kv.delete(KV_KEY).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lists(allow: &[&str], deny: &[&str]) -> AccessLists {
+        AccessLists {
+            allow: allow.iter().map(|p| p.to_string()).collect(),
+            deny: deny.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_open_gateway() {
+        let acl = lists(&[], &["bad"]);
+        assert!(acl.check(&["good"]).is_ok());
+        assert_eq!(acl.check(&["good", "BAD"]), Err(Denial::Blocked("BAD".to_string())));
+        assert!(AccessLists::default().check(&["anyone"]).is_ok());
+    }
+
+    /// A delegation tag signed by the key with secret `secret`
+    fn delegation(secret: u8, delegatee: &str, conditions: &str) -> (String, Value) {
+        let key = k256::schnorr::SigningKey::from_bytes(&[[0u8; 31].as_slice(), &[secret]].concat()).unwrap();
+        let delegator = hex::encode(key.verifying_key().to_bytes());
+        let token = format!("nostr:delegation:{}:{}", delegatee, conditions);
+        let sig = key.sign_raw(&Sha256::digest(token.as_bytes()), &[0u8; 32]).unwrap();
+        let tag = serde_json::json!(["delegation", delegator, conditions, hex::encode(sig.to_bytes())]);
+        (delegator, tag)
+    }
+
+    #[test]
+    fn test_signer_may_publish() {
+        let event = serde_json::json!({"pubkey": "author", "tags": [["p", "signer"]]});
+        assert!(signer_may_publish("author", &event));
+        assert!(!signer_may_publish("signer", &event));
+        assert!(!signer_may_publish("signer", &serde_json::json!({})));
+    }
+
+    #[test]
+    fn test_delegated_publish() {
+        let author = "a".repeat(64);
+        let conditions = "kind=1&created_at>1700000000&created_at<1800000000";
+        let (delegator, tag) = delegation(3, &author, conditions);
+        let event = |kind: u64, created_at: u64| {
+            serde_json::json!({"pubkey": author, "kind": kind, "created_at": created_at, "tags": [tag]})
+        };
+        assert!(signer_may_publish(&delegator, &event(1, 1750000000)));
+        assert!(!signer_may_publish(&"b".repeat(64), &event(1, 1750000000)));
+        // Outside the conditions
+        assert!(!signer_may_publish(&delegator, &event(7, 1750000000)));
+        assert!(!signer_may_publish(&delegator, &event(1, 1800000000)));
+    }
+
+    #[test]
+    fn test_forged_delegation() {
+        let author = "a".repeat(64);
+        let (delegator, _) = delegation(3, &author, "kind=1");
+        // Signed by another key but naming the delegator
+        let (_, mut forged) = delegation(4, &author, "kind=1");
+        forged[1] = delegator.clone().into();
+        let event = serde_json::json!({"pubkey": author, "kind": 1, "created_at": 1750000000, "tags": [forged]});
+        assert!(!signer_may_publish(&delegator, &event));
+
+        // A real token, but for another delegatee
+        let (_, theirs) = delegation(3, &"c".repeat(64), "kind=1");
+        let event = serde_json::json!({"pubkey": author, "kind": 1, "created_at": 1750000000, "tags": [theirs]});
+        assert!(!signer_may_publish(&delegator, &event));
+
+        // Unsigned, the way any author could write it
+        let bare = serde_json::json!(["delegation", delegator, "kind=1", "sig"]);
+        let event = serde_json::json!({"pubkey": author, "kind": 1, "created_at": 1750000000, "tags": [bare]});
+        assert!(!signer_may_publish(&delegator, &event));
+    }
+
+    #[test]
+    fn test_delegation_conditions() {
+        assert!(conditions_met("kind=1&kind=7", Some(7), None));
+        assert!(conditions_met("", Some(1), Some(1)));
+        assert!(!conditions_met("kind=1&created_at>100", Some(1), None));
+        assert!(!conditions_met("kind=1&unknown=2", Some(1), Some(1)));
+    }
+
+    #[test]
+    fn test_closed_gateway() {
+        let acl = lists(&["member", "signer"], &["signer"]);
+        assert!(acl.check(&["member"]).is_ok());
+        // Both the author and the signer must be allowed, and the denylist wins
+        assert_eq!(acl.check(&["member", "outsider"]), Err(Denial::NotAllowed("outsider".to_string())));
+        assert_eq!(acl.check(&["member", "signer"]), Err(Denial::Blocked("signer".to_string())));
+        assert_eq!(
+            Denial::NotAllowed("abc".to_string()).to_string(),
+            "pubkey abc is not allowed to publish here"
+        );
+    }
+}
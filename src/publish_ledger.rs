@@ -0,0 +1,127 @@
+// ABOUTME: Durable Object holding the authoritative state of one publish, keyed by event id
+// ABOUTME: Transitions are checked and stored in one step, so a cancel can't race the queue consumer
+
+use crate::publish_state::PublishState;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use worker::*;
+
+const STATE_KEY: &str = "state";
+
+/// How long a ledger outlives its last transition, matching the KV status copy
+const RETENTION: Duration = Duration::from_secs(86400);
+
+#[derive(Serialize, Deserialize)]
+struct TransitionRequest {
+    /// The caller's view of the current state, used while the ledger has none
+    from: PublishState,
+    to: PublishState,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LedgerState {
+    state: Option<PublishState>,
+}
+
+/// Outcome of a transition
+pub enum Transition {
+    Moved(PublishState),
+    /// Not allowed from the ledger's state, which is returned
+    Refused(PublishState),
+}
+
+/// The state the transition starts from: the ledger's own once it has one
+fn next_state(stored: Option<PublishState>, assumed: PublishState, to: PublishState) -> std::result::Result<PublishState, PublishState> {
+    let current = stored.unwrap_or(assumed);
+    current.transition(to).map_err(|_| current)
+}
+
+#[durable_object]
+pub struct PublishLedger {
+    state: State,
+}
+
+impl DurableObject for PublishLedger {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        let storage = self.state.storage();
+        let stored = storage.get::<PublishState>(STATE_KEY).await.ok().flatten();
+        match (req.method(), req.path().as_str()) {
+            (Method::Get, "/state") => Response::from_json(&LedgerState { state: stored }),
+            (Method::Post, "/transition") => {
+                let body: TransitionRequest = req.json().await?;
+                match next_state(stored, body.from, body.to) {
+                    Ok(state) => {
+                        storage.put(STATE_KEY, &state).await?;
+                        storage.set_alarm(RETENTION).await?;
+                        Response::from_json(&LedgerState { state: Some(state) })
+                    }
+                    Err(current) => Ok(Response::from_json(&LedgerState { state: Some(current) })?.with_status(409)),
+                }
+            }
+            _ => Response::error("not found", 404),
+        }
+    }
+
+    async fn alarm(&self) -> Result<Response> {
+        self.state.storage().delete_all().await?;
+        Response::empty()
+    }
+}
+
+fn stub(env: &Env, event_id: &str) -> Result<Stub> {
+    env.durable_object("PUBLISH_LEDGER")?.id_from_name(event_id)?.get_stub()
+}
+
+/// The recorded state of a publish, `None` before its first transition
+pub async fn state(env: &Env, event_id: &str) -> Result<Option<PublishState>> {
+    let body: LedgerState = stub(env, event_id)?.fetch_with_str("http://do/state").await?.json().await?;
+    Ok(body.state)
+}
+
+/// Move a publish to `to` if its current state allows it. `from` is what the
+/// caller last saw, and only counts for a publish the ledger hasn't recorded yet.
+pub async fn transition(env: &Env, event_id: &str, from: &PublishState, to: PublishState) -> Result<Transition> {
+    let body = serde_json::to_string(&TransitionRequest { from: from.clone(), to })?;
+    let req = Request::new_with_init(
+        "http://do/transition",
+        RequestInit::new().with_method(Method::Post).with_body(Some(body.into())),
+    )?;
+    let mut resp = stub(env, event_id)?.fetch_with_request(req).await?;
+    let refused = resp.status_code() == 409;
+    let body: LedgerState = resp.json().await?;
+    let state = body.state.ok_or_else(|| Error::from("publish ledger answered without a state"))?;
+    Ok(if refused {
+        Transition::Refused(state)
+    } else {
+        Transition::Moved(state)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_ledger_starts_from_the_callers_view() {
+        let next = next_state(None, PublishState::Queued, PublishState::Cancelled);
+        assert_eq!(next, Ok(PublishState::Cancelled));
+    }
+
+    #[test]
+    fn test_recorded_state_wins_over_a_stale_view() {
+        // The consumer has started publishing while the canceller still read `queued`
+        let stored = Some(PublishState::Publishing { attempt: 1 });
+        let next = next_state(stored, PublishState::Queued, PublishState::Cancelled);
+        assert_eq!(next, Err(PublishState::Publishing { attempt: 1 }));
+    }
+
+    #[test]
+    fn test_cancelled_publish_is_not_picked_up() {
+        let next = next_state(Some(PublishState::Cancelled), PublishState::Queued, PublishState::Publishing { attempt: 1 });
+        assert_eq!(next, Err(PublishState::Cancelled));
+    }
+}
@@ -0,0 +1,354 @@
+// ABOUTME: Typed lifecycle of a published event, from acceptance to published or failed
+// ABOUTME: Validates transitions and keeps a flat, stable wire form for the status endpoint
+
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Ephemeral events (kinds 20000-29999) are relayed to subscribers but never stored
+pub fn is_ephemeral(event: &serde_json::Value) -> bool {
+    event
+        .get("kind")
+        .and_then(|k| k.as_u64())
+        .is_some_and(|kind| (20000..30000).contains(&kind))
+}
+
+/// Where a publish is in its lifecycle
+///
+/// ```text
+/// Queued ──────────────┐          ┌──> Retrying ──> Publishing (next attempt)
+/// Quarantined ─────────┼──> Publishing ──> AwaitingVerification ──> Published
+///   └──> Rejected      │          ├──────────────┴──> Failed
+///   └──> Queued ───────┘          └──> Broadcast (ephemeral events)
+/// ```
+///
+/// Queued, quarantined and retrying publishes can also be `Cancelled` by their author.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "StateFields", try_from = "StateFields")]
+pub enum PublishState {
+    /// Accepted and waiting in the publish queue
+    Queued,
+    /// Held for review; released to the queue after a delay unless rejected
+    Quarantined,
+    /// Dropped during quarantine review
+    Rejected,
+    /// Being sent to the relay
+    Publishing { attempt: u32 },
+    /// Relay accepted it; checking that it can be read back
+    AwaitingVerification { attempt: u32 },
+    /// Read back from the relay
+    Published,
+    /// Ephemeral event the relay accepted. It can't be read back, so this is final.
+    Broadcast,
+    /// An attempt failed and the queue will try again
+    Retrying { attempt: u32, reason: String },
+    /// Out of attempts
+    Failed { reason: String },
+    /// Withdrawn by the author before it reached the relay
+    Cancelled,
+}
+
+/// Names of the states as they appear in `status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PublishStateName {
+    Queued,
+    Quarantined,
+    Rejected,
+    Publishing,
+    AwaitingVerification,
+    Published,
+    Broadcast,
+    Retrying,
+    Failed,
+    Cancelled,
+}
+
+impl PublishStateName {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Quarantined => "quarantined",
+            Self::Rejected => "rejected",
+            Self::Publishing => "publishing",
+            Self::AwaitingVerification => "awaiting_verification",
+            Self::Published => "published",
+            Self::Broadcast => "broadcast",
+            Self::Retrying => "retrying",
+            Self::Failed => "failed",
+            Self::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidTransition {
+    pub from: PublishStateName,
+    pub to: PublishStateName,
+}
+
+impl std::fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid publish transition {} -> {}", self.from.as_str(), self.to.as_str())
+    }
+}
+
+impl PublishState {
+    pub fn name(&self) -> PublishStateName {
+        match self {
+            Self::Queued => PublishStateName::Queued,
+            Self::Quarantined => PublishStateName::Quarantined,
+            Self::Rejected => PublishStateName::Rejected,
+            Self::Publishing { .. } => PublishStateName::Publishing,
+            Self::AwaitingVerification { .. } => PublishStateName::AwaitingVerification,
+            Self::Published => PublishStateName::Published,
+            Self::Broadcast => PublishStateName::Broadcast,
+            Self::Retrying { .. } => PublishStateName::Retrying,
+            Self::Failed { .. } => PublishStateName::Failed,
+            Self::Cancelled => PublishStateName::Cancelled,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        self.name().as_str()
+    }
+
+    /// Nothing happens to the event after these
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            Self::Rejected | Self::Published | Self::Broadcast | Self::Failed { .. } | Self::Cancelled
+        )
+    }
+
+    /// Attempt number of an in-flight state, 0 before the first attempt
+    pub fn attempt(&self) -> u32 {
+        match self {
+            Self::Publishing { attempt } | Self::AwaitingVerification { attempt } | Self::Retrying { attempt, .. } => {
+                *attempt
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn can_transition_to(&self, next: &PublishState) -> bool {
+        use PublishState::*;
+        match (self, next) {
+            // Approval re-queues a held event; an unreviewed one is picked up directly
+            (Quarantined, Queued | Rejected) => true,
+            (Queued | Quarantined, Publishing { attempt }) => *attempt == 1,
+            (Publishing { attempt: a }, AwaitingVerification { attempt: b } | Retrying { attempt: b, .. }) => a == b,
+            (AwaitingVerification { attempt: a }, Retrying { attempt: b, .. }) => a == b,
+            (AwaitingVerification { .. }, Published) => true,
+            (Publishing { .. }, Broadcast) => true,
+            (Publishing { .. } | AwaitingVerification { .. }, Failed { .. }) => true,
+            // Nothing has been sent to the relay that the queue will resend
+            (Queued | Quarantined | Retrying { .. }, Cancelled) => true,
+            // The queue redelivers after a retry, or after an attempt was cut short
+            (Retrying { .. } | Publishing { .. } | AwaitingVerification { .. }, Publishing { attempt }) => {
+                *attempt == self.attempt() + 1
+            }
+            _ => false,
+        }
+    }
+
+    pub fn transition(&self, next: PublishState) -> Result<PublishState, InvalidTransition> {
+        if self.can_transition_to(&next) {
+            Ok(next)
+        } else {
+            Err(InvalidTransition {
+                from: self.name(),
+                to: next.name(),
+            })
+        }
+    }
+}
+
+/// Wire form: the state name in `status`, plus the fields of that state
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct StateFields {
+    #[schemars(with = "PublishStateName")]
+    status: String,
+    /// Attempt number, for `publishing`, `awaiting_verification` and `retrying`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attempt: Option<u32>,
+    /// Why the last attempt failed, for `retrying` and `failed`
+    #[serde(default, alias = "error", skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+impl From<PublishState> for StateFields {
+    fn from(state: PublishState) -> Self {
+        let status = state.as_str().to_string();
+        let (attempt, reason) = match state {
+            PublishState::Publishing { attempt } | PublishState::AwaitingVerification { attempt } => {
+                (Some(attempt), None)
+            }
+            PublishState::Retrying { attempt, reason } => (Some(attempt), Some(reason)),
+            PublishState::Failed { reason } => (None, Some(reason)),
+            _ => (None, None),
+        };
+        Self { status, attempt, reason }
+    }
+}
+
+impl TryFrom<StateFields> for PublishState {
+    type Error = String;
+
+    fn try_from(fields: StateFields) -> Result<Self, Self::Error> {
+        let attempt = fields.attempt.unwrap_or(1);
+        let reason = fields.reason.unwrap_or_else(|| "unknown".to_string());
+        // Statuses written before the state machine existed are read as their
+        // nearest state until they expire from KV
+        let legacy_attempt = |prefix: &str| fields.status.strip_prefix(prefix).and_then(|n| n.parse::<u32>().ok());
+        Ok(match fields.status.as_str() {
+            "queued" | "processing" => Self::Queued,
+            "quarantined" => Self::Quarantined,
+            "rejected" => Self::Rejected,
+            "publishing" => Self::Publishing { attempt },
+            "awaiting_verification" => Self::AwaitingVerification { attempt },
+            "published" | "verified" => Self::Published,
+            "broadcast" => Self::Broadcast,
+            "retrying" => Self::Retrying { attempt, reason },
+            "failed" => Self::Failed { reason },
+            "cancelled" => Self::Cancelled,
+            other => match (legacy_attempt("attempt_"), legacy_attempt("retry_")) {
+                (Some(attempt), _) => Self::Publishing { attempt },
+                (_, Some(attempt)) => Self::Retrying { attempt, reason },
+                _ => return Err(format!("unknown publish status {}", other)),
+            },
+        })
+    }
+}
+
+impl JsonSchema for PublishState {
+    fn schema_name() -> String {
+        "PublishState".to_string()
+    }
+
+    /// Inlined, so a flattened state adds its fields to the containing object
+    fn is_referenceable() -> bool {
+        false
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        StateFields::json_schema(gen)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn retrying(attempt: u32) -> PublishState {
+        PublishState::Retrying {
+            attempt,
+            reason: "relay rejected".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_happy_path_transitions() {
+        let state = PublishState::Queued;
+        let state = state.transition(PublishState::Publishing { attempt: 1 }).unwrap();
+        let state = state.transition(PublishState::AwaitingVerification { attempt: 1 }).unwrap();
+        let state = state.transition(PublishState::Published).unwrap();
+        assert!(state.is_terminal());
+    }
+
+    #[test]
+    fn test_retry_transitions() {
+        let state = PublishState::Publishing { attempt: 1 };
+        let state = state.transition(retrying(1)).unwrap();
+        assert!(!state.can_transition_to(&PublishState::Publishing { attempt: 3 }));
+        let state = state.transition(PublishState::Publishing { attempt: 2 }).unwrap();
+        let state = state
+            .transition(PublishState::Failed {
+                reason: "relay rejected".to_string(),
+            })
+            .unwrap();
+        assert!(state.is_terminal());
+    }
+
+    #[test]
+    fn test_broadcast_transitions() {
+        let state = PublishState::Queued.transition(PublishState::Publishing { attempt: 1 }).unwrap();
+        let state = state.transition(PublishState::Broadcast).unwrap();
+        assert!(state.is_terminal());
+        assert!(!PublishState::AwaitingVerification { attempt: 1 }.can_transition_to(&PublishState::Broadcast));
+        assert!(!PublishState::Broadcast.can_transition_to(&PublishState::Cancelled));
+        assert_eq!(serde_json::to_value(PublishState::Broadcast).unwrap(), json!({"status": "broadcast"}));
+    }
+
+    #[test]
+    fn test_is_ephemeral() {
+        assert!(is_ephemeral(&json!({"kind": 20000})));
+        assert!(is_ephemeral(&json!({"kind": 29999})));
+        assert!(!is_ephemeral(&json!({"kind": 30000})));
+        assert!(!is_ephemeral(&json!({"kind": 1})));
+        assert!(!is_ephemeral(&json!({})));
+    }
+
+    #[test]
+    fn test_cancel_transitions() {
+        assert!(PublishState::Queued.can_transition_to(&PublishState::Cancelled));
+        assert!(PublishState::Quarantined.can_transition_to(&PublishState::Cancelled));
+        assert!(retrying(2).can_transition_to(&PublishState::Cancelled));
+        assert!(!PublishState::Publishing { attempt: 1 }.can_transition_to(&PublishState::Cancelled));
+        assert!(!PublishState::Published.can_transition_to(&PublishState::Cancelled));
+        assert!(PublishState::Cancelled.is_terminal());
+        assert_eq!(serde_json::to_value(PublishState::Cancelled).unwrap(), json!({"status": "cancelled"}));
+    }
+
+    #[test]
+    fn test_invalid_transitions() {
+        assert_eq!(
+            PublishState::Queued.transition(PublishState::Published),
+            Err(InvalidTransition {
+                from: PublishStateName::Queued,
+                to: PublishStateName::Published,
+            })
+        );
+        assert!(!PublishState::Published.can_transition_to(&PublishState::Publishing { attempt: 1 }));
+        assert!(!PublishState::Rejected.can_transition_to(&PublishState::Queued));
+        assert!(!PublishState::Queued.can_transition_to(&PublishState::Rejected));
+        assert!(!PublishState::Publishing { attempt: 1 }.can_transition_to(&retrying(2)));
+        assert!(PublishState::Quarantined.can_transition_to(&PublishState::Publishing { attempt: 1 }));
+        // An attempt interrupted mid-flight is redelivered as the next one
+        let interrupted = PublishState::AwaitingVerification { attempt: 2 };
+        assert!(interrupted.can_transition_to(&PublishState::Publishing { attempt: 3 }));
+    }
+
+    #[test]
+    fn test_wire_format() {
+        assert_eq!(serde_json::to_value(PublishState::Queued).unwrap(), json!({"status": "queued"}));
+        assert_eq!(
+            serde_json::to_value(retrying(2)).unwrap(),
+            json!({"status": "retrying", "attempt": 2, "reason": "relay rejected"})
+        );
+        assert_eq!(
+            serde_json::to_value(PublishState::AwaitingVerification { attempt: 1 }).unwrap(),
+            json!({"status": "awaiting_verification", "attempt": 1})
+        );
+        let parsed: PublishState = serde_json::from_value(json!({"status": "failed", "reason": "x"})).unwrap();
+        assert_eq!(parsed, PublishState::Failed { reason: "x".to_string() });
+        assert!(serde_json::from_value::<PublishState>(json!({"status": "bogus"})).is_err());
+    }
+
+    #[test]
+    fn test_legacy_statuses() {
+        let parse = |status: &str| serde_json::from_value::<PublishState>(json!({ "status": status })).unwrap();
+        assert_eq!(parse("attempt_3"), PublishState::Publishing { attempt: 3 });
+        assert_eq!(
+            parse("retry_2"),
+            PublishState::Retrying {
+                attempt: 2,
+                reason: "unknown".to_string()
+            }
+        );
+        assert_eq!(parse("processing"), PublishState::Queued);
+        assert_eq!(parse("verified"), PublishState::Published);
+    }
+}
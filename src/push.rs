@@ -0,0 +1,335 @@
+// ABOUTME: Web Push notifications for filters: registrations in KV, VAPID auth, RFC 8291 encryption
+// ABOUTME: Matching events arrive from the WebhookHub's live relay subscription
+
+use crate::outbound::{self, Policy};
+use crate::types::{PushKeys, PushRegistrationResponse, PushSubscription};
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes128Gcm, Nonce};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hkdf::Hkdf;
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{PublicKey, SecretKey};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use worker::*;
+
+/// Record size advertised in the aes128gcm header; payloads are always one record
+const RECORD_SIZE: u32 = 4096;
+
+/// Registrations expire unless the device registers again
+pub const REGISTRATION_TTL_SECONDS: u64 = 90 * 86400;
+
+/// How long the push service holds a message for an offline device
+const PUSH_TTL_SECONDS: u64 = 86400;
+
+/// VAPID tokens are valid for up to 24h; stay well inside that
+const VAPID_TOKEN_SECONDS: u64 = 12 * 3600;
+
+/// A registered subscription, stored in KV under `push:{id}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushRegistration {
+    pub id: String,
+    /// NIP-98 pubkey that registered it; only the owner can remove it
+    pub owner: String,
+    pub subscription: PushSubscription,
+    pub filter: Value,
+    pub created_at: u64,
+}
+
+impl PushRegistration {
+    /// Notifications are only for new events, so every (re)subscribe starts from now
+    pub fn subscription_filter(&self, now: u64) -> Value {
+        let mut filter = self.filter.clone();
+        if let Some(obj) = filter.as_object_mut() {
+            obj.remove("limit");
+            obj.insert("since".to_string(), now.into());
+        }
+        filter
+    }
+
+    pub fn view(&self) -> PushRegistrationResponse {
+        PushRegistrationResponse {
+            id: self.id.clone(),
+            filter: self.filter.clone(),
+            created_at: self.created_at,
+        }
+    }
+}
+
+/// Check the browser keys decode to a P-256 point and a 16-byte secret
+pub fn validate_keys(keys: &PushKeys) -> std::result::Result<(), &'static str> {
+    let p256dh = URL_SAFE_NO_PAD
+        .decode(keys.p256dh.trim_end_matches('='))
+        .map_err(|_| "p256dh is not base64url")?;
+    PublicKey::from_sec1_bytes(&p256dh).map_err(|_| "p256dh is not a P-256 public key")?;
+    match URL_SAFE_NO_PAD.decode(keys.auth.trim_end_matches('=')) {
+        Ok(auth) if auth.len() == 16 => Ok(()),
+        _ => Err("auth must be 16 bytes of base64url"),
+    }
+}
+
+/// Registrations are keyed by endpoint, so a device re-registering replaces its old filter
+pub fn registration_id(endpoint: &str) -> String {
+    hex::encode(&Sha256::digest(endpoint.as_bytes())[..8])
+}
+
+pub fn kv_key(id: &str) -> String {
+    format!("push:{}", id)
+}
+
+/// VAPID keypair and contact from `VAPID_PRIVATE_KEY` (base64url 32-byte scalar) and `VAPID_SUBJECT`
+pub struct Vapid {
+    key: SigningKey,
+    subject: String,
+}
+
+impl Vapid {
+    pub fn from_env(env: &Env) -> Option<Self> {
+        let private = env.secret("VAPID_PRIVATE_KEY").ok()?.to_string();
+        let subject = env
+            .var("VAPID_SUBJECT")
+            .map(|v| v.to_string())
+            .unwrap_or_else(|_| "mailto:admin@divine.video".to_string());
+        Self::new(&private, subject)
+    }
+
+    pub fn new(private_b64: &str, subject: String) -> Option<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(private_b64.trim_end_matches('=')).ok()?;
+        let key = SigningKey::from_slice(&bytes).ok()?;
+        Some(Self { key, subject })
+    }
+
+    /// Uncompressed public key, base64url - the browser's `applicationServerKey`
+    pub fn public_key(&self) -> String {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        URL_SAFE_NO_PAD.encode(point.as_bytes())
+    }
+
+    /// `Authorization` header value for a push endpoint (RFC 8292)
+    pub fn authorization(&self, endpoint: &str, now: u64) -> Option<String> {
+        let url = Url::parse(endpoint).ok()?;
+        let audience = format!("{}://{}", url.scheme(), url.host_str()?);
+        let header = URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT","alg":"ES256"}"#);
+        let claims = serde_json::json!({
+            "aud": audience,
+            "exp": now + VAPID_TOKEN_SECONDS,
+            "sub": self.subject,
+        });
+        let claims = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let signing_input = format!("{}.{}", header, claims);
+        let signature: Signature = self.key.sign(signing_input.as_bytes());
+        Some(format!(
+            "vapid t={}.{}, k={}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+            self.public_key()
+        ))
+    }
+}
+
+fn hkdf_expand(salt: &[u8], ikm: &[u8], info: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = vec![0u8; len];
+    Hkdf::<Sha256>::new(Some(salt), ikm).expand(info, &mut out).ok()?;
+    Some(out)
+}
+
+/// RFC 8291 `aes128gcm` body, with the ephemeral key and salt supplied by the caller
+pub fn encrypt_with(
+    payload: &[u8],
+    keys: &PushKeys,
+    ephemeral: &SecretKey,
+    salt: &[u8; 16],
+) -> Option<Vec<u8>> {
+    let ua_public_bytes = URL_SAFE_NO_PAD.decode(keys.p256dh.trim_end_matches('=')).ok()?;
+    let auth_secret = URL_SAFE_NO_PAD.decode(keys.auth.trim_end_matches('=')).ok()?;
+    let ua_public = PublicKey::from_sec1_bytes(&ua_public_bytes).ok()?;
+    let as_public = ephemeral.public_key().to_encoded_point(false);
+
+    let shared = p256::ecdh::diffie_hellman(ephemeral.to_nonzero_scalar(), ua_public.as_affine());
+
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(&ua_public_bytes);
+    key_info.extend_from_slice(as_public.as_bytes());
+    let ikm = hkdf_expand(&auth_secret, shared.raw_secret_bytes(), &key_info, 32)?;
+
+    let cek = hkdf_expand(salt, &ikm, b"Content-Encoding: aes128gcm\0", 16)?;
+    let nonce = hkdf_expand(salt, &ikm, b"Content-Encoding: nonce\0", 12)?;
+
+    // Single record, so the padding delimiter is 0x02
+    let mut plaintext = payload.to_vec();
+    plaintext.push(0x02);
+    let cipher = Aes128Gcm::new_from_slice(&cek).ok()?;
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), plaintext.as_slice()).ok()?;
+
+    let mut body = salt.to_vec();
+    body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+    body.push(as_public.as_bytes().len() as u8);
+    body.extend_from_slice(as_public.as_bytes());
+    body.extend_from_slice(&ciphertext);
+    Some(body)
+}
+
+/// Encrypt with a fresh ephemeral key and salt
+pub fn encrypt(payload: &[u8], keys: &PushKeys) -> Option<Vec<u8>> {
+    let mut seed = [0u8; 32];
+    let mut salt = [0u8; 16];
+    getrandom::getrandom(&mut seed).ok()?;
+    getrandom::getrandom(&mut salt).ok()?;
+    let ephemeral = SecretKey::from_slice(&seed).ok()?;
+    encrypt_with(payload, keys, &ephemeral, &salt)
+}
+
+/// Notification body handed to the service worker
+pub fn notification_payload(registration_id: &str, event: &Value) -> String {
+    serde_json::json!({
+        "registration_id": registration_id,
+        "event": crate::references::event_preview(event),
+    })
+    .to_string()
+}
+
+pub async fn get_registration(env: &Env, id: &str) -> Result<Option<PushRegistration>> {
+    Ok(env.kv("REST_GATEWAY_CACHE")?.get(&kv_key(id)).json().await?)
+}
+
+pub async fn delete_registration(env: &Env, id: &str) -> Result<()> {
+    env.kv("REST_GATEWAY_CACHE")?.delete(&kv_key(id)).await?;
+    Ok(())
+}
+
+/// What happened to one push
+#[derive(Debug, PartialEq)]
+pub enum PushOutcome {
+    Sent,
+    /// The push service no longer knows the subscription (404/410); drop it
+    Gone,
+    Failed,
+}
+
+/// Send one notification. Pushes are best-effort and not retried.
+pub async fn send(vapid: &Vapid, registration: &PushRegistration, event: &Value, now: u64) -> PushOutcome {
+    let endpoint = &registration.subscription.endpoint;
+    let payload = notification_payload(&registration.id, event);
+    let (Some(body), Some(authorization)) = (
+        encrypt(payload.as_bytes(), &registration.subscription.keys),
+        vapid.authorization(endpoint, now),
+    ) else {
+        console_log!("Push {} has unusable keys or endpoint", registration.id);
+        return PushOutcome::Gone;
+    };
+
+    let result = async {
+        let mut headers = Headers::new();
+        headers.set("Content-Encoding", "aes128gcm")?;
+        headers.set("Content-Type", "application/octet-stream")?;
+        headers.set("TTL", &PUSH_TTL_SECONDS.to_string())?;
+        headers.set("Authorization", &authorization)?;
+        let url = Url::parse(endpoint).map_err(|e| Error::from(e.to_string()))?;
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post)
+            .with_headers(headers)
+            .with_body(Some(js_sys::Uint8Array::from(body.as_slice()).into()));
+        Ok::<_, Error>(outbound::send(&url, &mut init, &Policy::default()).await?)
+    }
+    .await;
+
+    match result {
+        Ok(resp) if (200..300).contains(&resp.status_code()) => PushOutcome::Sent,
+        Ok(resp) if matches!(resp.status_code(), 404 | 410) => PushOutcome::Gone,
+        Ok(resp) => {
+            console_log!("Push {} rejected with {}", registration.id, resp.status_code());
+            PushOutcome::Failed
+        }
+        Err(e) => {
+            console_log!("Push {} failed: {}", registration.id, e);
+            PushOutcome::Failed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> PushKeys {
+        PushKeys {
+            p256dh: UA_PUBLIC.to_string(),
+            auth: UA_AUTH.to_string(),
+        }
+    }
+
+    // Vector produced with Python's `cryptography` package following RFC 8291
+    // section 3, and checked by decrypting it with the receiver's private key
+    const UA_PUBLIC: &str = "BFFcPW6545a5BNP-yn9U_c0MwemXvzddylFa0KbDtANfRTa-OlDzGPv5pUdZAqIhUCvvDVfgjFOyzApW8X2fk1Q";
+    const UA_AUTH: &str = "ZGVmZ2hpamtsbW5vcHFycw";
+    const AS_PRIVATE: &str = "ISIjJCUmJygpKissLS4vMDEyMzQ1Njc4OTo7PD0-P0A";
+    const SALT: &str = "yMnKy8zNzs_Q0dLT1NXW1w";
+    const EXPECTED: &str = "yMnKy8zNzs_Q0dLT1NXW1wAAEABBBB8UAUa_sbJR-E9N2-DUzc_Xev2YSpUg41eUAh-DErue7JlaCLH6dwTfPcwLUKlmUmP7dxH5X5-KRJxQluR8iSuTbxIS_76FMoPa_G3eyXuomlGtzj5Bzzjn5hvIeKjGXU6wIT5s0hVDwpiJ-rzzJoB2yXPrPb4WfXFk";
+
+    #[test]
+    fn test_encrypt_matches_vector() {
+        let ephemeral = SecretKey::from_slice(&URL_SAFE_NO_PAD.decode(AS_PRIVATE).unwrap()).unwrap();
+        let salt: [u8; 16] = URL_SAFE_NO_PAD.decode(SALT).unwrap().try_into().unwrap();
+        let body = encrypt_with(b"When I grow up, I want to be a watermelon", &keys(), &ephemeral, &salt).unwrap();
+        assert_eq!(URL_SAFE_NO_PAD.encode(&body), EXPECTED);
+        // Header: salt, record size, key id length, 65-byte key id
+        assert_eq!(&body[16..20], &RECORD_SIZE.to_be_bytes());
+        assert_eq!(body[20], 65);
+    }
+
+    #[test]
+    fn test_vapid_authorization() {
+        let vapid = Vapid::new(AS_PRIVATE, "mailto:ops@example.com".to_string()).unwrap();
+        let header = vapid.authorization("https://fcm.googleapis.com/fcm/send/abc", 1_700_000_000).unwrap();
+        let (token, key) = header.strip_prefix("vapid t=").unwrap().split_once(", k=").unwrap();
+        assert_eq!(key, vapid.public_key());
+        let claims = token.split('.').nth(1).unwrap();
+        let claims: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).unwrap()).unwrap();
+        assert_eq!(claims["aud"], "https://fcm.googleapis.com");
+        assert_eq!(claims["exp"], 1_700_000_000 + VAPID_TOKEN_SECONDS);
+        assert_eq!(token.split('.').count(), 3);
+    }
+
+    #[test]
+    fn test_validate_keys() {
+        assert!(validate_keys(&keys()).is_ok());
+        let short_auth = PushKeys {
+            auth: "AAAA".to_string(),
+            ..keys()
+        };
+        assert!(validate_keys(&short_auth).is_err());
+        let bad_point = PushKeys {
+            p256dh: URL_SAFE_NO_PAD.encode([4u8; 65]),
+            ..keys()
+        };
+        assert!(validate_keys(&bad_point).is_err());
+    }
+
+    #[test]
+    fn test_registration_id_is_stable_per_endpoint() {
+        let a = registration_id("https://push.example/1");
+        assert_eq!(a.len(), 16);
+        assert_eq!(a, registration_id("https://push.example/1"));
+        assert_ne!(a, registration_id("https://push.example/2"));
+    }
+
+    #[test]
+    fn test_subscription_filter_starts_now() {
+        let registration = PushRegistration {
+            id: "r".to_string(),
+            owner: "pk".to_string(),
+            subscription: PushSubscription {
+                endpoint: "https://push.example/1".to_string(),
+                keys: keys(),
+            },
+            filter: serde_json::json!({"#p": ["pk"], "limit": 10, "since": 1}),
+            created_at: 1,
+        };
+        assert_eq!(
+            registration.subscription_filter(500),
+            serde_json::json!({"#p": ["pk"], "since": 500})
+        );
+    }
+}
@@ -0,0 +1,182 @@
+// ABOUTME: Soft-quarantine policy for borderline publishes
+// ABOUTME: Decides whether an event is held for review instead of forwarded immediately
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use worker::Env;
+
+/// Cloudflare Queues cap message delays at 12 hours
+const MAX_DELAY_SECONDS: u32 = 43_200;
+
+/// Operator-configured quarantine thresholds
+#[derive(Debug, Clone)]
+pub struct QuarantinePolicy {
+    pub enabled: bool,
+    /// Hold events from pubkeys the gateway has never published for
+    pub hold_new_pubkeys: bool,
+    /// Hold events from pubkeys whose web-of-trust score is below this
+    pub min_wot_score: Option<f64>,
+    /// Hold events once a pubkey exceeds this many publishes per hour
+    pub max_hourly_publishes: u32,
+    /// Held events are released automatically after this delay
+    pub delay_seconds: u32,
+}
+
+impl Default for QuarantinePolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            hold_new_pubkeys: true,
+            min_wot_score: None,
+            max_hourly_publishes: 20,
+            delay_seconds: 3600,
+        }
+    }
+}
+
+impl QuarantinePolicy {
+    /// Load from `QUARANTINE_*` vars; disabled unless `QUARANTINE_ENABLED=true`
+    pub fn from_env(env: &Env) -> Self {
+        let var = |name: &str| env.var(name).ok().map(|v| v.to_string());
+        let defaults = Self::default();
+        Self {
+            enabled: var("QUARANTINE_ENABLED").map(|v| v == "true" || v == "1").unwrap_or(false),
+            hold_new_pubkeys: var("QUARANTINE_NEW_PUBKEYS").map(|v| v != "false" && v != "0").unwrap_or(true),
+            min_wot_score: var("QUARANTINE_MIN_WOT").and_then(|v| v.parse().ok()),
+            max_hourly_publishes: var("QUARANTINE_MAX_HOURLY")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.max_hourly_publishes),
+            delay_seconds: var("QUARANTINE_DELAY_SECONDS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.delay_seconds)
+                .min(MAX_DELAY_SECONDS),
+        }
+    }
+
+    /// Reasons to hold the event, or empty if it can be forwarded immediately
+    pub fn evaluate(&self, signals: &PublishSignals) -> Vec<QuarantineReason> {
+        if !self.enabled {
+            return Vec::new();
+        }
+
+        let mut reasons = Vec::new();
+        if self.hold_new_pubkeys && signals.first_publish {
+            reasons.push(QuarantineReason::NewPubkey);
+        }
+        if let (Some(min), Some(score)) = (self.min_wot_score, signals.wot_score) {
+            if score < min {
+                reasons.push(QuarantineReason::LowWot);
+            }
+        }
+        if signals.hourly_publishes > self.max_hourly_publishes {
+            reasons.push(QuarantineReason::HighRate);
+        }
+        reasons
+    }
+}
+
+/// Signals gathered about the publishing pubkey
+#[derive(Debug, Clone, Default)]
+pub struct PublishSignals {
+    /// No event from this pubkey has been published through the gateway before
+    pub first_publish: bool,
+    /// Operator-supplied web-of-trust score, if known
+    pub wot_score: Option<f64>,
+    /// Publishes by this pubkey in the current hour, including this one
+    pub hourly_publishes: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum QuarantineReason {
+    NewPubkey,
+    LowWot,
+    HighRate,
+}
+
+/// A held event awaiting review or automatic release, stored in KV
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuarantineEntry {
+    pub event: serde_json::Value,
+    pub reasons: Vec<QuarantineReason>,
+    pub held_at: u64,
+    pub release_at: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled() -> QuarantinePolicy {
+        QuarantinePolicy {
+            enabled: true,
+            min_wot_score: Some(0.5),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_disabled_policy_never_holds() {
+        let policy = QuarantinePolicy::default();
+        let signals = PublishSignals {
+            first_publish: true,
+            wot_score: Some(0.0),
+            hourly_publishes: 1000,
+        };
+        assert!(policy.evaluate(&signals).is_empty());
+    }
+
+    #[test]
+    fn test_established_pubkey_passes() {
+        let signals = PublishSignals {
+            first_publish: false,
+            wot_score: Some(0.9),
+            hourly_publishes: 3,
+        };
+        assert!(enabled().evaluate(&signals).is_empty());
+    }
+
+    #[test]
+    fn test_new_pubkey_held() {
+        let signals = PublishSignals {
+            first_publish: true,
+            ..Default::default()
+        };
+        assert_eq!(enabled().evaluate(&signals), vec![QuarantineReason::NewPubkey]);
+
+        let lenient = QuarantinePolicy {
+            hold_new_pubkeys: false,
+            ..enabled()
+        };
+        assert!(lenient.evaluate(&signals).is_empty());
+    }
+
+    #[test]
+    fn test_low_wot_and_high_rate_held() {
+        let signals = PublishSignals {
+            first_publish: false,
+            wot_score: Some(0.1),
+            hourly_publishes: 21,
+        };
+        assert_eq!(
+            enabled().evaluate(&signals),
+            vec![QuarantineReason::LowWot, QuarantineReason::HighRate]
+        );
+    }
+
+    #[test]
+    fn test_unknown_wot_is_not_a_signal() {
+        let signals = PublishSignals {
+            first_publish: false,
+            wot_score: None,
+            hourly_publishes: 1,
+        };
+        assert!(enabled().evaluate(&signals).is_empty());
+    }
+
+    #[test]
+    fn test_reason_serialization() {
+        let json = serde_json::to_string(&vec![QuarantineReason::NewPubkey, QuarantineReason::HighRate]).unwrap();
+        assert_eq!(json, r#"["new_pubkey","high_rate"]"#);
+    }
+}
@@ -1,116 +1,254 @@
-// ABOUTME: Cloudflare Queue consumer for processing event publishes
-// ABOUTME: Handles publishing to relay with verification and retry logic
+// ABOUTME: Cloudflare Queue consumer for processing event publishes (and prewarm jobs)
+// ABOUTME: Handles publishing to relay with verification and retry logic (ephemeral events skip both)
 
+use crate::archive::Archive;
 use crate::cache::Cache;
+use crate::degraded;
+use crate::invalidation::{self, SavedFilters};
+use crate::kind;
+use crate::metrics::{record, Observation};
+use crate::prewarm::{self, PrewarmMessage};
+use crate::publish_ledger::{self, Transition};
+use crate::publish_state::{is_ephemeral, PublishState};
 use crate::types::PublishStatus;
 use worker::*;
 
+/// Deliveries before the queue dead-letters a message: the first plus
+/// `max_retries` in wrangler.toml
+const MAX_DELIVERIES: u32 = 7;
+
+/// What to tell the queue about a message once it has been handled
+enum Delivery {
+    Ack,
+    Retry,
+}
+
 pub async fn handle_queue(message_batch: MessageBatch<serde_json::Value>, env: Env) -> Result<()> {
     let relay_pool = env.durable_object("RELAY_POOL")?;
     let stub = relay_pool.id_from_name("default")?.get_stub()?;
-    let kv = env.kv("REST_GATEWAY_CACHE")?;
-    let cache = Cache::new(kv);
+    let cache = Cache::from_env(&env)?;
 
+    // Relay maintenance: put everything back with a delay, as fresh messages so
+    // the wait doesn't use up their retries
+    if let Some(mode) = degraded::get(&env).await {
+        let queue = env.queue("PUBLISH_QUEUE")?;
+        for message in message_batch.messages()? {
+            let postponed = MessageBuilder::new(message.body().clone())
+                .delay_seconds(mode.retry_after)
+                .build();
+            match queue.send(postponed).await {
+                Ok(()) => message.ack(),
+                Err(e) => {
+                    console_log!("Postponing a queue message failed: {}", e);
+                    message.retry();
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // Presets and feeds are read once for the batch, not once per publish
+    let saved = match SavedFilters::load(&env, &cache).await {
+        Ok(saved) => saved,
+        Err(e) => {
+            console_log!("Listing presets and feeds for publish invalidation failed: {}", e);
+            SavedFilters::default()
+        }
+    };
+
+    // Each message is settled on its own, so one failure doesn't send the
+    // whole batch back to the queue
     for message in message_batch.messages()? {
-        let event = message.body();
-        let event_id = event
-            .get("id")
-            .and_then(|v| v.as_str())
-            .unwrap_or("unknown")
-            .to_string();
-
-        // Get current attempt count
-        let current_status = cache.get_publish_status(&event_id).await?.unwrap_or(PublishStatus {
-            status: "processing".to_string(),
-            attempts: Some(0),
-            verified_at: None,
-            error: None,
-        });
-        let attempts = current_status.attempts.unwrap_or(0) + 1;
-
-        // Update status to processing
-        cache
-            .set_publish_status(
-                &event_id,
-                &PublishStatus {
-                    status: format!("attempt_{}", attempts),
-                    attempts: Some(attempts),
-                    verified_at: None,
-                    error: None,
-                },
-            )
-            .await?;
-
-        // Publish to relay
-        let publish_req = Request::new_with_init(
-            "http://do/publish",
-            RequestInit::new()
-                .with_method(Method::Post)
-                .with_body(Some(serde_json::to_string(&event)?.into())),
-        )?;
-        let mut publish_resp = stub.fetch_with_request(publish_req).await?;
-        let publish_result: serde_json::Value = publish_resp.json().await?;
-        let relay_ok = publish_result.get("ok").and_then(|v| v.as_bool()).unwrap_or(false);
-
-        if !relay_ok {
-            // Relay rejected - retry
-            cache
-                .set_publish_status(
-                    &event_id,
-                    &PublishStatus {
-                        status: format!("retry_{}", attempts),
-                        attempts: Some(attempts),
-                        verified_at: None,
-                        error: Some("relay rejected".to_string()),
-                    },
-                )
-                .await?;
-            message.retry();
-            continue;
+        match handle_message(&env, &stub, &cache, &saved, &message).await {
+            Ok(Delivery::Ack) => message.ack(),
+            Ok(Delivery::Retry) => message.retry(),
+            Err(e) => {
+                console_log!("Queue message failed: {}", e);
+                message.retry();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_message(
+    env: &Env,
+    stub: &Stub,
+    cache: &Cache,
+    saved: &SavedFilters,
+    message: &Message<serde_json::Value>,
+) -> Result<Delivery> {
+    // Prewarm jobs share the queue with publishes
+    if let Ok(PrewarmMessage { prewarm: job }) = serde_json::from_value(message.body().clone()) {
+        prewarm::run_batch(env, job).await?;
+        return Ok(Delivery::Ack);
+    }
+
+    let event = message.body();
+    let event_id = event
+        .get("id")
+        .and_then(|v| v.as_str())
+        .unwrap_or("unknown")
+        .to_string();
+    let author = event.get("pubkey").and_then(|v| v.as_str()).map(String::from);
+
+    // The ledger has the last word; the KV copy only covers a publish it hasn't seen yet
+    let current = match publish_ledger::state(env, &event_id).await? {
+        Some(state) => state,
+        None => cache
+            .get_publish_status(&event_id)
+            .await?
+            .map(|status| status.state)
+            .unwrap_or(PublishState::Queued),
+    };
+
+    match current {
+        // Rejected during quarantine review, cancelled by the author, already
+        // delivered by an earlier message (approval re-sends before the delayed
+        // copy arrives), or given up on
+        ref state if state.is_terminal() => {
+            return Ok(Delivery::Ack);
         }
+        // Delay elapsed without review - release automatically
+        PublishState::Quarantined => cache.delete_quarantine(&event_id).await?,
+        _ => {}
+    }
+
+    let attempt = current.attempt() + 1;
+    let publishing = PublishStatus::new(PublishState::Publishing { attempt }, attempt);
+    let publishing = advance(env, cache, &event_id, &author, &current, publishing).await?;
+    let last_delivery = message.attempts() >= MAX_DELIVERIES;
+
+    // Publish to relay
+    let publish_req = Request::new_with_init(
+        "http://do/publish",
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_body(Some(serde_json::to_string(&event)?.into())),
+    )?;
+    let mut publish_resp = stub.fetch_with_request(publish_req).await?;
+    let publish_result: serde_json::Value = publish_resp.json().await?;
+    let relay_ok = publish_result.get("ok").and_then(|v| v.as_bool()).unwrap_or(false);
 
-        // Verify event exists on relay
-        let verify_req = Request::new_with_init(
-            "http://do/verify",
-            RequestInit::new()
-                .with_method(Method::Post)
-                .with_body(Some(serde_json::json!({ "event_id": event_id }).to_string().into())),
-        )?;
-        let mut verify_resp = stub.fetch_with_request(verify_req).await?;
-        let verify_result: serde_json::Value = verify_resp.json().await?;
-        let found = verify_result.get("found").and_then(|v| v.as_bool()).unwrap_or(false);
-
-        if found {
-            // Success - mark as published
-            let now = js_sys::Date::new_0().to_iso_string().as_string().unwrap_or_default();
-            cache
-                .set_publish_status(
-                    &event_id,
-                    &PublishStatus {
-                        status: "published".to_string(),
-                        attempts: Some(attempts),
-                        verified_at: Some(now),
-                        error: None,
-                    },
-                )
-                .await?;
-            message.ack();
+    // Relays don't store ephemeral events, so their OK is all there is to confirm,
+    // and a late retry would deliver them after they stopped mattering
+    if is_ephemeral(&event) {
+        let next = if relay_ok {
+            PublishState::Broadcast
         } else {
-            // Not found - retry
-            cache
-                .set_publish_status(
-                    &event_id,
-                    &PublishStatus {
-                        status: format!("retry_{}", attempts),
-                        attempts: Some(attempts),
-                        verified_at: None,
-                        error: Some("event not found on relay".to_string()),
-                    },
-                )
-                .await?;
-            message.retry();
+            PublishState::Failed {
+                reason: "relay rejected".to_string(),
+            }
+        };
+        advance(env, cache, &event_id, &author, &publishing, PublishStatus::new(next, attempt)).await?;
+        record(env, &publish_outcome(if relay_ok { "broadcast" } else { "relay_rejected" })).await;
+        return Ok(Delivery::Ack);
+    }
+
+    if !relay_ok {
+        let next = failure_state(attempt, "relay rejected", last_delivery);
+        advance(env, cache, &event_id, &author, &publishing, PublishStatus::new(next, attempt)).await?;
+        record(env, &publish_outcome("relay_rejected")).await;
+        return Ok(Delivery::Retry);
+    }
+    let verifying = advance(
+        env,
+        cache,
+        &event_id,
+        &author,
+        &publishing,
+        PublishStatus::new(PublishState::AwaitingVerification { attempt }, attempt),
+    )
+    .await?;
+
+    // Verify event exists on relay
+    let verify_req = Request::new_with_init(
+        "http://do/verify",
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_body(Some(serde_json::json!({ "event_id": event_id }).to_string().into())),
+    )?;
+    let mut verify_resp = stub.fetch_with_request(verify_req).await?;
+    let verify_result: serde_json::Value = verify_resp.json().await?;
+    let found = verify_result.get("found").and_then(|v| v.as_bool()).unwrap_or(false);
+
+    if found {
+        // Success - mark as published
+        let now = js_sys::Date::new_0().to_iso_string().as_string().unwrap_or_default();
+        let status = PublishStatus {
+            verified_at: Some(now),
+            ..PublishStatus::new(PublishState::Published, attempt)
+        };
+        advance(env, cache, &event_id, &author, &verifying, status).await?;
+        // Published is terminal, so nothing past this point may fail the message
+        if let Some(pubkey) = &author {
+            if let Err(e) = cache.mark_known_publisher(pubkey).await {
+                console_log!("Marking {} as a known publisher failed: {}", pubkey, e);
+            }
+        }
+        // The relay has it now, so the archive can answer for it too
+        let published: &serde_json::Value = &event;
+        if let Some(archive) = Archive::from_env(env).filter(|_| !kind::is_private_event(published)) {
+            if let Err(e) = archive.store_events(std::slice::from_ref(published)).await {
+                console_log!("Archive write of {} failed: {}", event_id, e);
+            }
         }
+        // So the author reads back what they just published
+        invalidation::on_publish(env, cache, saved, published).await;
+        record(env, &publish_outcome("published")).await;
+        Ok(Delivery::Ack)
+    } else {
+        let next = failure_state(attempt, "event not found on relay", last_delivery);
+        advance(env, cache, &event_id, &author, &verifying, PublishStatus::new(next, attempt)).await?;
+        record(env, &publish_outcome("not_found")).await;
+        Ok(Delivery::Retry)
     }
+}
 
-    Ok(())
+fn publish_outcome(outcome: &str) -> Observation {
+    Observation::Publish {
+        outcome: outcome.to_string(),
+    }
+}
+
+/// Retried attempts stay `retrying`; on the last delivery the message still
+/// goes back to the queue so it lands in the dead letter queue
+fn failure_state(attempt: u32, reason: &str, last_delivery: bool) -> PublishState {
+    let reason = reason.to_string();
+    if last_delivery {
+        PublishState::Failed { reason }
+    } else {
+        PublishState::Retrying { attempt, reason }
+    }
+}
+
+/// Record the next status in the publish ledger and copy it to KV. A transition
+/// the ledger refuses (say the author cancelled meanwhile) is an error, and the
+/// redelivery finds the publish in its new state.
+async fn advance(
+    env: &Env,
+    cache: &Cache,
+    event_id: &str,
+    author: &Option<String>,
+    from: &PublishState,
+    next: PublishStatus,
+) -> Result<PublishState> {
+    let state = match publish_ledger::transition(env, event_id, from, next.state.clone()).await? {
+        Transition::Moved(state) => state,
+        Transition::Refused(current) => {
+            return Err(Error::from(format!(
+                "{}: publish is {}, not moving it to {}",
+                event_id,
+                current.as_str(),
+                next.state.as_str()
+            )));
+        }
+    };
+    let next = PublishStatus {
+        pubkey: author.clone(),
+        ..next
+    };
+    cache.set_publish_status(event_id, &next).await?;
+    Ok(state)
 }
@@ -0,0 +1,301 @@
+// ABOUTME: Daily read and publish quotas per authenticated pubkey, counted by UTC day in a QuotaCounter per pubkey
+// ABOUTME: Limits come from QUOTA_READS_PER_DAY and QUOTA_PUBLISHES_PER_DAY; unset means unlimited
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use worker::*;
+
+/// Storage key of a counter's usage
+const USAGE_KEY: &str = "usage";
+
+/// How long a counter outlives its last counted action; its day is over by then
+const RETENTION: Duration = Duration::from_secs(2 * 86400);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Read,
+    Publish,
+}
+
+impl Action {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Publish => "publish",
+        }
+    }
+}
+
+/// Per-day limits; `None` is unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaLimits {
+    pub reads: Option<u64>,
+    pub publishes: Option<u64>,
+}
+
+impl QuotaLimits {
+    pub fn from_env(env: &Env) -> Self {
+        let limit = |name: &str| {
+            env.var(name)
+                .ok()
+                .and_then(|v| v.to_string().trim().parse::<u64>().ok())
+                .filter(|n| *n > 0)
+        };
+        Self {
+            reads: limit("QUOTA_READS_PER_DAY"),
+            publishes: limit("QUOTA_PUBLISHES_PER_DAY"),
+        }
+    }
+
+    fn limit(&self, action: Action) -> Option<u64> {
+        match action {
+            Action::Read => self.reads,
+            Action::Publish => self.publishes,
+        }
+    }
+}
+
+/// What one pubkey has used on one day
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    #[serde(default)]
+    pub reads: u64,
+    #[serde(default)]
+    pub publishes: u64,
+}
+
+impl Usage {
+    fn used(&self, action: Action) -> u64 {
+        match action {
+            Action::Read => self.reads,
+            Action::Publish => self.publishes,
+        }
+    }
+
+    /// Count one action, unless that would take it past `limit`
+    fn try_add(&mut self, action: Action, limit: u64) -> bool {
+        if self.used(action) >= limit {
+            return false;
+        }
+        match action {
+            Action::Read => self.reads += 1,
+            Action::Publish => self.publishes += 1,
+        }
+        true
+    }
+
+    pub fn allowance(&self, limits: &QuotaLimits, action: Action) -> Allowance {
+        let used = self.used(action);
+        let limit = limits.limit(action);
+        Allowance {
+            limit,
+            used,
+            remaining: limit.map(|l| l.saturating_sub(used)),
+        }
+    }
+}
+
+/// One action's standing for the day, as `GET /quota` reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct Allowance {
+    /// Daily limit; absent when unlimited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+    pub used: u64,
+    /// Absent when unlimited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining: Option<u64>,
+}
+
+/// A request turned away because its pubkey used up the day's quota
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exceeded {
+    pub action: Action,
+    pub limit: u64,
+    /// Seconds until the quota resets
+    pub retry_after: u64,
+}
+
+impl std::fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "daily {} quota of {} used up", self.action.as_str(), self.limit)
+    }
+}
+
+/// Days since the epoch, which usage is counted under
+pub fn day(now: u64) -> u64 {
+    now / 86400
+}
+
+/// Unix time of the next UTC midnight, when every count starts over
+pub fn resets_at(now: u64) -> u64 {
+    (day(now) + 1) * 86400
+}
+
+/// A counter's usage along with the day it counts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct DayUsage {
+    day: u64,
+    usage: Usage,
+}
+
+/// Usage on `day`; a stored count from an earlier day is over
+fn usage_on(stored: Option<DayUsage>, day: u64) -> Usage {
+    stored.filter(|stored| stored.day == day).map(|stored| stored.usage).unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize)]
+struct UsageRequest {
+    day: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ConsumeRequest {
+    day: u64,
+    action: Action,
+    limit: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ConsumeResponse {
+    allowed: bool,
+}
+
+/// Counts for one pubkey. Each check and increment happens in one step, so
+/// concurrent requests can't both take the last unit of a quota.
+#[durable_object]
+pub struct QuotaCounter {
+    state: State,
+}
+
+impl DurableObject for QuotaCounter {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        let storage = self.state.storage();
+        let stored = storage.get::<DayUsage>(USAGE_KEY).await.ok().flatten();
+        match (req.method(), req.path().as_str()) {
+            (Method::Post, "/usage") => {
+                let body: UsageRequest = req.json().await?;
+                Response::from_json(&usage_on(stored, body.day))
+            }
+            (Method::Post, "/consume") => {
+                let body: ConsumeRequest = req.json().await?;
+                let mut usage = usage_on(stored, body.day);
+                let allowed = usage.try_add(body.action, body.limit);
+                // A refusal changes nothing, so only a counted action is written
+                if allowed {
+                    storage.put(USAGE_KEY, &DayUsage { day: body.day, usage }).await?;
+                    storage.set_alarm(RETENTION).await?;
+                }
+                Response::from_json(&ConsumeResponse { allowed })
+            }
+            _ => Response::error("not found", 404),
+        }
+    }
+
+    async fn alarm(&self) -> Result<Response> {
+        self.state.storage().delete_all().await?;
+        Response::empty()
+    }
+}
+
+async fn call<T, R>(env: &Env, pubkey: &str, path: &str, body: &T) -> Result<R>
+where
+    T: Serialize,
+    R: serde::de::DeserializeOwned,
+{
+    let stub = env.durable_object("QUOTA")?.id_from_name(&pubkey.to_ascii_lowercase())?.get_stub()?;
+    let req = Request::new_with_init(
+        &format!("http://do{}", path),
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_body(Some(serde_json::to_string(body)?.into())),
+    )?;
+    stub.fetch_with_request(req).await?.json().await
+}
+
+pub async fn get(env: &Env, pubkey: &str, day: u64) -> Result<Usage> {
+    call(env, pubkey, "/usage", &UsageRequest { day }).await
+}
+
+/// Count one action against `pubkey`, or refuse it when the limit is reached.
+/// Unlimited actions aren't counted at all.
+pub async fn consume(
+    env: &Env,
+    pubkey: &str,
+    action: Action,
+    now: u64,
+) -> Result<std::result::Result<(), Exceeded>> {
+    let Some(limit) = QuotaLimits::from_env(env).limit(action) else {
+        return Ok(Ok(()));
+    };
+    let request = ConsumeRequest {
+        day: day(now),
+        action,
+        limit,
+    };
+    let response: ConsumeResponse = call(env, pubkey, "/consume", &request).await?;
+    if response.allowed {
+        return Ok(Ok(()));
+    }
+    Ok(Err(Exceeded {
+        action,
+        limit,
+        retry_after: resets_at(now) - now,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowance() {
+        let limits = QuotaLimits {
+            reads: Some(100),
+            publishes: None,
+        };
+        let usage = Usage { reads: 120, publishes: 7 };
+        let reads = usage.allowance(&limits, Action::Read);
+        assert_eq!(reads.remaining, Some(0));
+        assert_eq!(reads.used, 120);
+        let publishes = usage.allowance(&limits, Action::Publish);
+        assert_eq!(publishes.limit, None);
+        assert_eq!(publishes.remaining, None);
+        assert_eq!(serde_json::to_value(publishes).unwrap(), serde_json::json!({"used": 7}));
+    }
+
+    #[test]
+    fn test_try_add_stops_at_the_limit() {
+        let mut usage = Usage::default();
+        assert!(usage.try_add(Action::Read, 2));
+        assert!(usage.try_add(Action::Read, 2));
+        assert!(!usage.try_add(Action::Read, 2));
+        assert_eq!(usage, Usage { reads: 2, publishes: 0 });
+        assert!(usage.try_add(Action::Publish, 1));
+    }
+
+    #[test]
+    fn test_reset() {
+        let now = 19_000 * 86400 + 3600;
+        assert_eq!(day(now), 19_000);
+        assert_eq!(resets_at(now) - now, 23 * 3600);
+        let yesterday = DayUsage {
+            day: 18_999,
+            usage: Usage { reads: 5, publishes: 1 },
+        };
+        assert_eq!(usage_on(Some(yesterday), 19_000), Usage::default());
+        assert_eq!(usage_on(Some(yesterday), 18_999).reads, 5);
+        let exceeded = Exceeded {
+            action: Action::Publish,
+            limit: 50,
+            retry_after: 60,
+        };
+        assert_eq!(exceeded.to_string(), "daily publish quota of 50 used up");
+    }
+}
@@ -0,0 +1,263 @@
+// ABOUTME: Per-IP and per-pubkey request rate limits, counted by one RateLimiter Durable Object per client
+// ABOUTME: Sliding-window counters; limited responses carry X-RateLimit-* headers and 429s a Retry-After
+
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use worker::*;
+
+/// Limits from `RATE_LIMIT_IP` and `RATE_LIMIT_PUBKEY` (requests per window);
+/// either one unset leaves that dimension unlimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    pub ip_limit: Option<u64>,
+    pub pubkey_limit: Option<u64>,
+    pub window_seconds: u64,
+}
+
+impl RateLimitConfig {
+    pub fn from_env(env: &Env) -> Self {
+        let number = |name: &str| {
+            env.var(name)
+                .ok()
+                .and_then(|v| v.to_string().trim().parse::<u64>().ok())
+                .filter(|n| *n > 0)
+        };
+        Self {
+            ip_limit: number("RATE_LIMIT_IP"),
+            pubkey_limit: number("RATE_LIMIT_PUBKEY"),
+            window_seconds: number("RATE_LIMIT_WINDOW_SECONDS").unwrap_or(60),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.ip_limit.is_some() || self.pubkey_limit.is_some()
+    }
+}
+
+/// Where a client stands after a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    /// Seconds until the current window ends
+    pub reset: u64,
+    /// Seconds until a request would be let through again; 0 when allowed
+    pub retry_after: u64,
+}
+
+impl Decision {
+    /// Set the `X-RateLimit-*` headers (and `Retry-After` on a refusal)
+    pub fn set_headers(&self, headers: &mut Headers) -> Result<()> {
+        headers.set("X-RateLimit-Limit", &self.limit.to_string())?;
+        headers.set("X-RateLimit-Remaining", &self.remaining.to_string())?;
+        headers.set("X-RateLimit-Reset", &self.reset.to_string())?;
+        if !self.allowed {
+            headers.set("Retry-After", &self.retry_after.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// The one to report when several limits apply: a refusal, else the least headroom
+    fn tighter(self, other: Self) -> Self {
+        match (self.allowed, other.allowed) {
+            (false, true) => self,
+            (true, false) => other,
+            (false, false) if self.retry_after >= other.retry_after => self,
+            (true, true) if self.remaining <= other.remaining => self,
+            _ => other,
+        }
+    }
+}
+
+/// Sliding-window counter: the previous fixed window's count, weighted by how much
+/// of it still overlaps the trailing window, plus the current window's count
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SlidingWindow {
+    /// Start of the current window, ms since the epoch
+    window_start: u64,
+    current: u64,
+    previous: u64,
+}
+
+impl SlidingWindow {
+    /// Count a request costing `cost` at `now_ms`, unless it would take the trailing
+    /// window past `limit`
+    pub fn hit(&mut self, now_ms: u64, limit: u64, window_seconds: u64, cost: u64) -> Decision {
+        let cost = cost.clamp(1, limit.max(1));
+        let window_ms = window_seconds.max(1) * 1000;
+        // Never step back a window if the clock does
+        let start = (now_ms - now_ms % window_ms).max(self.window_start);
+        if start != self.window_start {
+            // Only the window just before this one still overlaps it
+            self.previous = if start - self.window_start == window_ms { self.current } else { 0 };
+            self.current = 0;
+            self.window_start = start;
+        }
+        let elapsed = now_ms.saturating_sub(start);
+        let overlap = (window_ms - elapsed) as f64 / window_ms as f64;
+        let estimate = (self.previous as f64 * overlap) as u64 + self.current;
+        let reset = (window_ms - elapsed).div_ceil(1000);
+
+        if estimate + cost > limit {
+            return Decision {
+                allowed: false,
+                limit,
+                remaining: 0,
+                reset,
+                // Room for the whole cost has to free up
+                retry_after: self.retry_after_ms(elapsed, window_ms, (limit + 1).saturating_sub(cost)).div_ceil(1000).max(1),
+            };
+        }
+        self.current += cost;
+        Decision {
+            allowed: true,
+            limit,
+            remaining: limit - estimate - cost,
+            reset,
+            retry_after: 0,
+        }
+    }
+
+    /// How long until the estimate drops below `limit` if nothing else arrives
+    fn retry_after_ms(&self, elapsed: u64, window_ms: u64, limit: u64) -> u64 {
+        let (previous, current, limit) = (self.previous as f64, self.current as f64, limit as f64);
+        let (elapsed, window) = (elapsed as f64, window_ms as f64);
+        let wait = if current < limit {
+            // The previous window's share has to slide out far enough
+            window - elapsed - (limit - current) * window / previous
+        } else {
+            // This window's count becomes the previous one, which then has to slide out
+            window - elapsed + window * (1.0 - limit / current)
+        };
+        wait.max(0.0).ceil() as u64
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct HitRequest {
+    limit: u64,
+    window_seconds: u64,
+    #[serde(default = "one")]
+    cost: u64,
+}
+
+fn one() -> u64 {
+    1
+}
+
+/// Counts for one client. Kept in memory only: a limiter is evicted after being idle,
+/// by which time its windows would have expired anyway.
+#[durable_object]
+pub struct RateLimiter {
+    window: RefCell<SlidingWindow>,
+}
+
+impl DurableObject for RateLimiter {
+    fn new(_state: State, _env: Env) -> Self {
+        Self {
+            window: RefCell::new(SlidingWindow::default()),
+        }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        let hit: HitRequest = req.json().await?;
+        let now_ms = js_sys::Date::now() as u64;
+        let decision = self.window.borrow_mut().hit(now_ms, hit.limit, hit.window_seconds, hit.cost);
+        Response::from_json(&decision)
+    }
+}
+
+async fn hit(env: &Env, key: &str, limit: u64, window_seconds: u64, cost: u64) -> Result<Decision> {
+    let stub = env.durable_object("RATE_LIMITER")?.id_from_name(key)?.get_stub()?;
+    let body = serde_json::to_string(&HitRequest { limit, window_seconds, cost })?;
+    let req = Request::new_with_init(
+        "http://do/hit",
+        RequestInit::new().with_method(Method::Post).with_body(Some(body.into())),
+    )?;
+    stub.fetch_with_request(req).await?.json().await
+}
+
+/// Count a request against its client address and, when it is NIP-98 authenticated,
+/// its pubkey. `cost` is how many requests it counts as. Returns the decision to
+/// report, or `None` when no limit applies. A limiter that can't be reached lets
+/// the request through.
+pub async fn check(
+    env: &Env,
+    config: &RateLimitConfig,
+    ip: Option<&str>,
+    pubkey: Option<&str>,
+    cost: u64,
+) -> Option<Decision> {
+    let by_ip = ip.zip(config.ip_limit).map(|(ip, limit)| (format!("ip:{}", ip), limit));
+    let by_pubkey = pubkey.zip(config.pubkey_limit).map(|(pk, limit)| (format!("pubkey:{}", pk), limit));
+    let hits = by_ip.into_iter().chain(by_pubkey).map(|(key, limit)| async move {
+        match hit(env, &key, limit, config.window_seconds, cost).await {
+            Ok(decision) => Some(decision),
+            Err(e) => {
+                console_log!("Rate limiter for {} failed: {}", key, e);
+                None
+            }
+        }
+    });
+    futures_util::future::join_all(hits)
+        .await
+        .into_iter()
+        .flatten()
+        .reduce(Decision::tighter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_window_fills() {
+        let mut window = SlidingWindow::default();
+        let start = 600_000;
+        for i in 0..3 {
+            let decision = window.hit(start + i, 3, 60, 1);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, 2 - i);
+        }
+        let refused = window.hit(start + 10_000, 3, 60, 1);
+        assert!(!refused.allowed);
+        assert_eq!(refused.reset, 50);
+        // A full window stops counting against the limit as soon as the next one begins
+        assert_eq!(refused.retry_after, 50);
+    }
+
+    #[test]
+    fn test_previous_window_slides_out() {
+        let mut window = SlidingWindow::default();
+        for _ in 0..10 {
+            window.hit(60_000, 10, 60, 1);
+        }
+        // Halfway through the next window, half of the previous ten still count
+        let decision = window.hit(150_000, 10, 60, 1);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 4);
+        // Two windows on, nothing carries over
+        assert_eq!(window.hit(300_000, 10, 60, 1).remaining, 9);
+    }
+
+    #[test]
+    fn test_costly_requests() {
+        let mut window = SlidingWindow::default();
+        assert_eq!(window.hit(60_000, 10, 60, 4).remaining, 6);
+        assert_eq!(window.hit(60_000, 10, 60, 4).remaining, 2);
+        // Too costly for what's left, though a cheaper request still fits
+        assert!(!window.hit(60_000, 10, 60, 4).allowed);
+        assert!(window.hit(60_000, 10, 60, 1).allowed);
+        // A cost above the limit is charged as the whole limit, not refused forever
+        assert!(SlidingWindow::default().hit(60_000, 10, 60, 50).allowed);
+    }
+
+    #[test]
+    fn test_tighter() {
+        let allowed = |remaining| Decision { allowed: true, limit: 10, remaining, reset: 30, retry_after: 0 };
+        let refused = Decision { allowed: false, limit: 5, remaining: 0, reset: 30, retry_after: 12 };
+        assert_eq!(allowed(3).tighter(allowed(7)), allowed(3));
+        assert_eq!(allowed(0).tighter(refused), refused);
+    }
+}
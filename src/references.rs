@@ -0,0 +1,144 @@
+// ABOUTME: Parses an event's reference tags (e/q/p/a/r) into typed references
+// ABOUTME: Builds the short previews attached to resolved references
+
+use crate::types::{ProfileMetadata, Reference, ReferenceType};
+use serde_json::{json, Value};
+
+/// Preview text is cut to this many characters
+const PREVIEW_CHARS: usize = 280;
+
+/// Reference tags in tag order, skipping malformed and duplicate ones
+pub fn parse_references(event: &Value) -> Vec<Reference> {
+    let mut refs: Vec<Reference> = Vec::new();
+    let tags = event.get("tags").and_then(|t| t.as_array()).cloned().unwrap_or_default();
+    for tag in tags {
+        let Some(tag) = tag.as_array() else { continue };
+        let field = |i: usize| tag.get(i).and_then(|v| v.as_str()).filter(|s| !s.is_empty()).map(String::from);
+        let ref_type = match field(0).as_deref() {
+            Some("e") => ReferenceType::Event,
+            Some("q") => ReferenceType::Quote,
+            Some("p") => ReferenceType::Pubkey,
+            Some("a") => ReferenceType::Address,
+            Some("r") => ReferenceType::Url,
+            _ => continue,
+        };
+        let Some(value) = field(1) else { continue };
+        if refs.iter().any(|r| r.ref_type == ref_type && r.value == value) {
+            continue;
+        }
+        refs.push(Reference {
+            ref_type,
+            value,
+            relay_hint: if ref_type == ReferenceType::Url { None } else { field(2) },
+            marker: if ref_type == ReferenceType::Event { field(3) } else { None },
+            preview: None,
+        });
+    }
+    refs
+}
+
+/// Parse `kind:pubkey:d-tag`
+pub fn parse_address(address: &str) -> Option<(u64, String, String)> {
+    let mut parts = address.splitn(3, ':');
+    let kind = parts.next()?.parse().ok()?;
+    let pubkey = parts.next()?.to_string();
+    let d_tag = parts.next().unwrap_or_default().to_string();
+    Some((kind, pubkey, d_tag))
+}
+
+/// Filter for the newest version of an addressable event
+pub fn address_filter(address: &str) -> Option<Value> {
+    let (kind, pubkey, d_tag) = parse_address(address)?;
+    Some(json!({ "kinds": [kind], "authors": [pubkey], "#d": [d_tag], "limit": 1 }))
+}
+
+fn truncate(text: &str) -> String {
+    match text.char_indices().nth(PREVIEW_CHARS) {
+        Some((cut, _)) => format!("{}…", &text[..cut]),
+        None => text.to_string(),
+    }
+}
+
+/// Preview of a referenced event: who, when, what kind, and the start of its content
+pub fn event_preview(event: &Value) -> Value {
+    json!({
+        "id": event.get("id"),
+        "kind": event.get("kind"),
+        "pubkey": event.get("pubkey"),
+        "created_at": event.get("created_at"),
+        "content": truncate(event.get("content").and_then(|c| c.as_str()).unwrap_or_default()),
+    })
+}
+
+/// Preview of a referenced pubkey from its kind-0 event
+pub fn profile_preview(profile_event: &Value) -> Option<Value> {
+    let profile = ProfileMetadata::from_event(profile_event)?;
+    Some(json!({
+        "name": profile.best_name(),
+        "picture": profile.picture,
+        "nip05": profile.nip05,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_references() {
+        let event = json!({
+            "tags": [
+                ["e", "root", "wss://r.example", "root"],
+                ["e", "reply", "", "reply"],
+                ["e", "root"],
+                ["p", "pk", "wss://p.example"],
+                ["q", "quoted"],
+                ["a", "30023:pk:article"],
+                ["r", "https://example.com", "read"],
+                ["t", "nostr"],
+                ["e"]
+            ]
+        });
+        let refs = parse_references(&event);
+        let summary: Vec<_> = refs.iter().map(|r| (r.ref_type, r.value.as_str())).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (ReferenceType::Event, "root"),
+                (ReferenceType::Event, "reply"),
+                (ReferenceType::Pubkey, "pk"),
+                (ReferenceType::Quote, "quoted"),
+                (ReferenceType::Address, "30023:pk:article"),
+                (ReferenceType::Url, "https://example.com"),
+            ]
+        );
+        assert_eq!(refs[0].relay_hint.as_deref(), Some("wss://r.example"));
+        assert_eq!(refs[0].marker.as_deref(), Some("root"));
+        assert_eq!(refs[1].relay_hint, None);
+        assert_eq!(refs[2].marker, None);
+        assert_eq!(refs[5].relay_hint, None);
+    }
+
+    #[test]
+    fn test_address_filter() {
+        assert_eq!(
+            address_filter("34236:pk:my-video"),
+            Some(json!({"kinds": [34236], "authors": ["pk"], "#d": ["my-video"], "limit": 1}))
+        );
+        assert_eq!(parse_address("0:pk"), Some((0, "pk".to_string(), String::new())));
+        assert!(address_filter("video:pk:d").is_none());
+    }
+
+    #[test]
+    fn test_previews() {
+        let long = "x".repeat(300);
+        let preview = event_preview(&json!({"id": "a", "kind": 1, "pubkey": "pk", "created_at": 5, "content": long}));
+        assert_eq!(preview["content"].as_str().unwrap().chars().count(), PREVIEW_CHARS + 1);
+
+        let profile = json!({"kind": 0, "content": r#"{"name":"alice","picture":"https://p/a.png"}"#});
+        assert_eq!(
+            profile_preview(&profile),
+            Some(json!({"name": "alice", "picture": "https://p/a.png", "nip05": null}))
+        );
+    }
+}
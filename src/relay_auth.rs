@@ -0,0 +1,200 @@
+// ABOUTME: NIP-42 authentication of the gateway itself to upstream relays that require it
+// ABOUTME: Answers AUTH challenges with kind 22242 events signed by the GATEWAY_SECRET_KEY keypair
+
+use crate::auth::event_id;
+use k256::schnorr::SigningKey;
+use serde_json::Value;
+use worker::Env;
+
+pub const AUTH_KIND: u32 = 22242;
+
+/// Machine-readable prefix of CLOSED and OK messages refused until the client authenticates
+const AUTH_REQUIRED_PREFIX: &str = "auth-required:";
+
+/// Whether a relay's refusal asks us to authenticate first
+pub fn is_auth_required(message: &str) -> bool {
+    message.starts_with(AUTH_REQUIRED_PREFIX)
+}
+
+/// The keypair the gateway authenticates with, from the hex `GATEWAY_SECRET_KEY`
+/// secret. Operators grant its pubkey access on the relay.
+#[derive(Clone)]
+pub struct GatewayKey {
+    signing: SigningKey,
+}
+
+impl GatewayKey {
+    pub fn from_env(env: &Env) -> Option<Self> {
+        let secret = env.secret("GATEWAY_SECRET_KEY").ok()?.to_string();
+        let key = Self::from_hex(secret.trim());
+        if key.is_none() {
+            worker::console_log!("GATEWAY_SECRET_KEY is not a 32-byte hex secret key; relay auth is off");
+        }
+        key
+    }
+
+    pub fn from_hex(secret: &str) -> Option<Self> {
+        let bytes = hex::decode(secret).ok().filter(|b| b.len() == 32)?;
+        let signing = SigningKey::from_bytes(&bytes).ok()?;
+        Some(Self { signing })
+    }
+
+    pub fn pubkey(&self) -> String {
+        hex::encode(self.signing.verifying_key().to_bytes())
+    }
+
+    /// A signed kind 22242 event answering `challenge` from the relay at `relay_url`
+    pub fn auth_event(&self, relay_url: &str, challenge: &str, created_at: u64) -> Value {
+        let pubkey = self.pubkey();
+        let tags = vec![
+            vec!["relay".to_string(), relay_url.to_string()],
+            vec!["challenge".to_string(), challenge.to_string()],
+        ];
+        let id = event_id(&pubkey, created_at, AUTH_KIND, &tags, "");
+        let mut aux = [0u8; 32];
+        // Auxiliary randomness only hardens against side channels; zeros still sign correctly
+        let _ = getrandom::getrandom(&mut aux);
+        let id_bytes = hex::decode(&id).expect("event id is hex");
+        let sig = self
+            .signing
+            .sign_raw(&id_bytes, &aux)
+            .expect("a 32-byte message always signs");
+        serde_json::json!({
+            "id": id,
+            "pubkey": pubkey,
+            "created_at": created_at,
+            "kind": AUTH_KIND,
+            "tags": tags,
+            "content": "",
+            "sig": hex::encode(sig.to_bytes()),
+        })
+    }
+}
+
+/// What to do with the request a relay refused for lack of auth
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Keep reading; the handshake isn't finished
+    Continue,
+    /// Authenticated now: send the refused request again
+    Resend,
+    /// Authentication isn't possible or didn't help
+    GiveUp,
+}
+
+/// The NIP-42 handshake on one relay connection. The challenge and the refusal
+/// can arrive in either order, and a request is resent at most once.
+pub struct Handshake<'a> {
+    key: Option<&'a GatewayKey>,
+    auth_id: Option<String>,
+    authenticated: bool,
+    /// A request was refused and is waiting on the handshake
+    waiting: bool,
+}
+
+impl<'a> Handshake<'a> {
+    pub fn new(key: Option<&'a GatewayKey>) -> Self {
+        Self {
+            key,
+            auth_id: None,
+            authenticated: false,
+            waiting: false,
+        }
+    }
+
+    /// The `["AUTH", event]` reply to a challenge, when we have a key to sign it
+    pub fn challenge(&mut self, relay_url: &str, challenge: &str, now: u64) -> Option<String> {
+        let event = self.key?.auth_event(relay_url, challenge, now);
+        self.auth_id = event.get("id").and_then(Value::as_str).map(String::from);
+        Some(serde_json::json!(["AUTH", event]).to_string())
+    }
+
+    /// Whether an OK message is the relay's verdict on our auth event
+    pub fn is_auth_event(&self, event_id: &str) -> bool {
+        self.auth_id.as_deref() == Some(event_id)
+    }
+
+    /// The relay's verdict on our auth event
+    pub fn auth_ok(&mut self, accepted: bool) -> Step {
+        if !accepted {
+            // Signing again won't change its mind
+            self.key = None;
+            return if self.waiting { Step::GiveUp } else { Step::Continue };
+        }
+        self.authenticated = true;
+        if std::mem::take(&mut self.waiting) {
+            Step::Resend
+        } else {
+            Step::Continue
+        }
+    }
+
+    /// A request was refused with `auth-required:`
+    pub fn auth_required(&mut self) -> Step {
+        if self.authenticated || self.key.is_none() {
+            return Step::GiveUp;
+        }
+        self.waiting = true;
+        Step::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::schnorr::{Signature, VerifyingKey};
+
+    const SECRET: &str = "0000000000000000000000000000000000000000000000000000000000000003";
+
+    #[test]
+    fn test_auth_event() {
+        let key = GatewayKey::from_hex(SECRET).unwrap();
+        let event = key.auth_event("wss://relay.example", "abc", 1700000000);
+        assert_eq!(event["kind"], 22242);
+        assert_eq!(event["tags"], serde_json::json!([["relay", "wss://relay.example"], ["challenge", "abc"]]));
+
+        let tags: Vec<Vec<String>> = serde_json::from_value(event["tags"].clone()).unwrap();
+        let id = event_id(&key.pubkey(), 1700000000, AUTH_KIND, &tags, "");
+        assert_eq!(event["id"], id.as_str());
+
+        let pubkey = VerifyingKey::from_bytes(&hex::decode(key.pubkey()).unwrap()).unwrap();
+        let sig = Signature::try_from(hex::decode(event["sig"].as_str().unwrap()).unwrap().as_slice()).unwrap();
+        assert!(pubkey.verify_raw(&hex::decode(&id).unwrap(), &sig).is_ok());
+
+        assert!(GatewayKey::from_hex("nsec1notsupported").is_none());
+        assert!(GatewayKey::from_hex(&"00".repeat(32)).is_none());
+    }
+
+    #[test]
+    fn test_handshake() {
+        let key = GatewayKey::from_hex(SECRET).unwrap();
+
+        // Refused first, then challenged
+        let mut handshake = Handshake::new(Some(&key));
+        assert_eq!(handshake.auth_required(), Step::Continue);
+        let reply = handshake.challenge("wss://relay.example", "abc", 1700000000).unwrap();
+        let sent: Value = serde_json::from_str(&reply).unwrap();
+        assert_eq!(sent[0], "AUTH");
+        assert!(handshake.is_auth_event(sent[1]["id"].as_str().unwrap()));
+        assert_eq!(handshake.auth_ok(true), Step::Resend);
+        // Refused again while authenticated: we just aren't allowed
+        assert_eq!(handshake.auth_required(), Step::GiveUp);
+
+        // Challenged on connect, authenticated before any refusal
+        let mut handshake = Handshake::new(Some(&key));
+        handshake.challenge("wss://relay.example", "abc", 1700000000);
+        assert_eq!(handshake.auth_ok(true), Step::Continue);
+
+        let mut handshake = Handshake::new(Some(&key));
+        handshake.challenge("wss://relay.example", "abc", 1700000000);
+        assert_eq!(handshake.auth_ok(false), Step::Continue);
+        assert_eq!(handshake.auth_required(), Step::GiveUp);
+
+        let mut keyless = Handshake::new(None);
+        assert_eq!(keyless.challenge("wss://relay.example", "abc", 1700000000), None);
+        assert_eq!(keyless.auth_required(), Step::GiveUp);
+
+        assert!(is_auth_required("auth-required: we only serve members"));
+        assert!(!is_auth_required("restricted: not a member"));
+    }
+}
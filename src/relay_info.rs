@@ -0,0 +1,154 @@
+// ABOUTME: NIP-11 relay information document fetching and caching
+// ABOUTME: Exposes relay capabilities (supported NIPs, limits) to clients and the relay pool
+
+use crate::outbound::{self, Policy};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+/// Relay info changes rarely; refetch hourly
+const INFO_TTL_SECONDS: u64 = 3600;
+
+/// NIP-11 documents are small; anything bigger isn't one
+const MAX_DOCUMENT_BYTES: usize = 256 * 1024;
+
+/// The parts of a NIP-11 document the gateway understands
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RelayInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub software: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub supported_nips: Vec<u32>,
+    #[serde(default)]
+    pub limitation: RelayLimitation,
+}
+
+/// NIP-11 `limitation` object
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RelayLimitation {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_message_length: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_subscriptions: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_filters: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_limit: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_event_tags: Option<u64>,
+    #[serde(default)]
+    pub auth_required: bool,
+    #[serde(default)]
+    pub payment_required: bool,
+    #[serde(default)]
+    pub restricted_writes: bool,
+}
+
+impl RelayInfo {
+    /// Parse a raw NIP-11 document, ignoring malformed or unknown fields
+    pub fn from_document(doc: &serde_json::Value) -> Self {
+        let supported_nips = doc
+            .get("supported_nips")
+            .and_then(|n| n.as_array())
+            .map(|nips| nips.iter().filter_map(|n| n.as_u64().map(|n| n as u32)).collect())
+            .unwrap_or_default();
+        let limitation = doc
+            .get("limitation")
+            .and_then(|l| serde_json::from_value(l.clone()).ok())
+            .unwrap_or_default();
+        let field = |name: &str| doc.get(name).and_then(|v| v.as_str()).map(String::from);
+        Self {
+            name: field("name"),
+            software: field("software"),
+            version: field("version"),
+            supported_nips,
+            limitation,
+        }
+    }
+
+    #[cfg(test)]
+    fn supports_nip(&self, nip: u32) -> bool {
+        self.supported_nips.contains(&nip)
+    }
+}
+
+/// NIP-11 documents are served over HTTP(S) at the relay's websocket URL
+pub fn http_url(relay_url: &str) -> Option<String> {
+    if let Some(rest) = relay_url.strip_prefix("wss://") {
+        Some(format!("https://{}", rest))
+    } else {
+        relay_url.strip_prefix("ws://").map(|rest| format!("http://{}", rest))
+    }
+}
+
+/// Fetch a relay's NIP-11 document through the KV cache.
+/// Returns the raw document and whether it came from cache.
+pub async fn fetch_document(env: &Env, relay_url: &str) -> Result<(serde_json::Value, bool)> {
+    let kv = env.kv("REST_GATEWAY_CACHE")?;
+    let key = format!("relayinfo:{}", relay_url);
+    if let Some(doc) = kv.get(&key).json::<serde_json::Value>().await? {
+        return Ok((doc, true));
+    }
+
+    let http = http_url(relay_url).ok_or("relay url must use ws:// or wss://")?;
+    let http = Url::parse(&http).map_err(|e| Error::from(e.to_string()))?;
+    let mut headers = Headers::new();
+    headers.set("Accept", "application/nostr+json")?;
+    // `?url=` makes this user-controlled; ws:// relays serve their document over http
+    let policy = Policy {
+        schemes: &["https", "http"],
+        max_bytes: MAX_DOCUMENT_BYTES,
+        ..Policy::default()
+    };
+    let resp = outbound::get(http, &headers, &policy).await?;
+    if resp.status != 200 {
+        return Err(format!("relay info fetch failed with status {}", resp.status).into());
+    }
+    let doc: serde_json::Value = resp.json()?;
+
+    kv.put(&key, doc.to_string())?
+        .expiration_ttl(crate::ttl::TtlBounds::from_env(env).apply(INFO_TTL_SECONDS))
+        .execute()
+        .await?;
+    Ok((doc, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_url() {
+        assert_eq!(http_url("wss://relay.divine.video"), Some("https://relay.divine.video".to_string()));
+        assert_eq!(http_url("ws://localhost:7777/"), Some("http://localhost:7777/".to_string()));
+        assert_eq!(http_url("https://relay.divine.video"), None);
+    }
+
+    #[test]
+    fn test_from_document() {
+        let doc = serde_json::json!({
+            "name": "Divine Relay",
+            "software": "strfry",
+            "supported_nips": [1, 11, 42, 50, "bogus"],
+            "limitation": {"max_limit": 1000, "auth_required": true, "unknown": 5}
+        });
+        let info = RelayInfo::from_document(&doc);
+        assert_eq!(info.name.as_deref(), Some("Divine Relay"));
+        assert_eq!(info.supported_nips, vec![1, 11, 42, 50]);
+        assert!(info.supports_nip(50));
+        assert!(!info.supports_nip(45));
+        assert_eq!(info.limitation.max_limit, Some(1000));
+        assert!(info.limitation.auth_required);
+        assert!(!info.limitation.payment_required);
+    }
+
+    #[test]
+    fn test_from_document_tolerates_garbage() {
+        let info = RelayInfo::from_document(&serde_json::json!({"limitation": "nope", "supported_nips": 3}));
+        assert_eq!(info, RelayInfo::default());
+    }
+}
@@ -0,0 +1,137 @@
+// ABOUTME: Parsing of NIP-01 messages received from an upstream relay
+// ABOUTME: Kept free of Workers types so it can be unit tested and fuzzed natively
+
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RelayMessage {
+    /// `["EVENT", <subscription id>, <event>]`
+    Event { subscription: String, event: Value },
+    /// `["EOSE", <subscription id>]`
+    Eose { subscription: String },
+    /// `["OK", <event id>, <accepted>, <message>]`
+    Ok { event_id: String, accepted: bool, message: String },
+    /// `["NOTICE", <message>]`
+    Notice(String),
+    /// `["CLOSED", <subscription id>, <message>]`
+    Closed { subscription: String, message: String },
+    /// `["AUTH", <challenge>]` (NIP-42)
+    Auth { challenge: String },
+    /// `["COUNT", <subscription id>, <result>]` (NIP-45)
+    Count { subscription: String, result: Value },
+    /// Any other well-formed message
+    Other,
+}
+
+/// Parse one relay frame. `None` for anything that isn't a JSON array starting
+/// with a message type and carrying the fields that type requires.
+pub fn parse(text: &str) -> Option<RelayMessage> {
+    let parts: Vec<Value> = serde_json::from_str(text).ok()?;
+    let string = |i: usize| parts.get(i).and_then(|v| v.as_str()).map(String::from);
+    let message = match parts.first()?.as_str()? {
+        "EVENT" => RelayMessage::Event {
+            subscription: string(1)?,
+            event: parts.get(2).filter(|e| e.is_object())?.clone(),
+        },
+        "EOSE" => RelayMessage::Eose { subscription: string(1)? },
+        "OK" => RelayMessage::Ok {
+            event_id: string(1)?,
+            // A missing or malformed flag counts as a rejection
+            accepted: parts.get(2).and_then(|v| v.as_bool()).unwrap_or(false),
+            message: string(3).unwrap_or_default(),
+        },
+        "NOTICE" => RelayMessage::Notice(string(1).unwrap_or_default()),
+        "CLOSED" => RelayMessage::Closed {
+            subscription: string(1)?,
+            message: string(2).unwrap_or_default(),
+        },
+        "AUTH" => RelayMessage::Auth { challenge: string(1)? },
+        "COUNT" => RelayMessage::Count {
+            subscription: string(1)?,
+            result: parts.get(2).filter(|r| r.is_object())?.clone(),
+        },
+        _ => RelayMessage::Other,
+    };
+    Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_event_and_eose() {
+        assert_eq!(
+            parse(r#"["EVENT","sub",{"id":"a"}]"#),
+            Some(RelayMessage::Event {
+                subscription: "sub".to_string(),
+                event: json!({"id": "a"})
+            })
+        );
+        assert_eq!(parse(r#"["EVENT","sub"]"#), None);
+        assert_eq!(parse(r#"["EVENT","sub","not an event"]"#), None);
+        assert_eq!(
+            parse(r#"["EOSE","sub"]"#),
+            Some(RelayMessage::Eose {
+                subscription: "sub".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_ok() {
+        assert_eq!(
+            parse(r#"["OK","id",false,"blocked: spam"]"#),
+            Some(RelayMessage::Ok {
+                event_id: "id".to_string(),
+                accepted: false,
+                message: "blocked: spam".to_string()
+            })
+        );
+        assert_eq!(
+            parse(r#"["OK","id",true]"#),
+            Some(RelayMessage::Ok {
+                event_id: "id".to_string(),
+                accepted: true,
+                message: String::new()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_auth() {
+        assert_eq!(
+            parse(r#"["AUTH","challenge"]"#),
+            Some(RelayMessage::Auth {
+                challenge: "challenge".to_string()
+            })
+        );
+        assert_eq!(
+            parse(r#"["CLOSED","sub","auth-required: members only"]"#),
+            Some(RelayMessage::Closed {
+                subscription: "sub".to_string(),
+                message: "auth-required: members only".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_garbage() {
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("{}"), None);
+        assert_eq!(parse("[]"), None);
+        assert_eq!(parse("[1, 2]"), None);
+        assert_eq!(parse(r#"["AUTH"]"#), None);
+        assert_eq!(
+            parse(r#"["COUNT","sub",{"count":1}]"#),
+            Some(RelayMessage::Count {
+                subscription: "sub".to_string(),
+                result: json!({"count": 1})
+            })
+        );
+        assert_eq!(parse(r#"["COUNT","sub"]"#), None);
+        assert_eq!(parse(r#"["PONG"]"#), Some(RelayMessage::Other));
+        assert_eq!(parse(r#"["NOTICE"]"#), Some(RelayMessage::Notice(String::new())));
+    }
+}
@@ -0,0 +1,230 @@
+// ABOUTME: Routing of relay frames to the concurrent queries sharing one relay connection
+// ABOUTME: Each query gets its own subscription id and inbox; kept free of Workers types so it can be unit tested natively
+
+use crate::relay_message::RelayMessage;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::future::poll_fn;
+use std::rc::Rc;
+use std::task::{Poll, Waker};
+
+/// Frames for one subscription, in the order the relay sent them
+#[derive(Default)]
+pub struct Inbox {
+    queue: RefCell<VecDeque<RelayMessage>>,
+    waker: RefCell<Option<Waker>>,
+    closed: Cell<bool>,
+}
+
+impl Inbox {
+    fn push(&self, message: RelayMessage) {
+        self.queue.borrow_mut().push_back(message);
+        self.wake();
+    }
+
+    /// End the inbox once what is queued has been read
+    pub fn close(&self) {
+        self.closed.set(true);
+        self.wake();
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = self.waker.borrow_mut().take() {
+            waker.wake();
+        }
+    }
+
+    /// The next frame, or `None` once the connection is gone and every frame has been read
+    pub async fn next(&self) -> Option<RelayMessage> {
+        poll_fn(|cx| {
+            if let Some(message) = self.queue.borrow_mut().pop_front() {
+                return Poll::Ready(Some(message));
+            }
+            if self.closed.get() {
+                return Poll::Ready(None);
+            }
+            *self.waker.borrow_mut() = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+struct Subscription {
+    /// The REQ frame, kept for resending once NIP-42 auth is done
+    req: String,
+    inbox: Rc<Inbox>,
+    /// Refused with `auth-required:` and waiting on the handshake
+    held: bool,
+}
+
+/// The open subscriptions on one relay connection
+#[derive(Default)]
+pub struct Mux {
+    subscriptions: RefCell<HashMap<String, Subscription>>,
+    next_id: Cell<u64>,
+    closed: Cell<bool>,
+}
+
+impl Mux {
+    /// Open a subscription for a raw filter: its id, the REQ frame to send, and
+    /// the inbox its frames arrive in. Ids are unique for the connection's life.
+    pub fn open(&self, filter_json: &str) -> (String, String, Rc<Inbox>) {
+        self.open_with("REQ", filter_json)
+    }
+
+    /// Open a subscription with another verb (`COUNT`) or several filters, given
+    /// as the raw JSON that follows the subscription id in the frame
+    pub fn open_with(&self, verb: &str, filters_json: &str) -> (String, String, Rc<Inbox>) {
+        let id = format!("q{}", self.next_id.get());
+        self.next_id.set(self.next_id.get() + 1);
+        // The raw filter string goes to the relay as is
+        let req = format!(r#"["{}","{}",{}]"#, verb, id, filters_json);
+        let inbox = Rc::new(Inbox::default());
+        if self.closed.get() {
+            inbox.close();
+        }
+        let subscription = Subscription {
+            req: req.clone(),
+            inbox: inbox.clone(),
+            held: false,
+        };
+        self.subscriptions.borrow_mut().insert(id.clone(), subscription);
+        (id, req, inbox)
+    }
+
+    /// Forget a subscription, returning the CLOSE frame to send if it was open.
+    /// Frames the relay still sends for it are dropped.
+    pub fn close(&self, id: &str) -> Option<String> {
+        self.subscriptions.borrow_mut().remove(id)?;
+        Some(serde_json::json!(["CLOSE", id]).to_string())
+    }
+
+    /// Open subscriptions
+    pub fn active(&self) -> usize {
+        self.subscriptions.borrow().len()
+    }
+
+    /// Hand a frame to the subscription it names. Frames that name none (NOTICE,
+    /// AUTH, OK) come back for the connection to handle; frames for a
+    /// subscription that is no longer open are dropped.
+    pub fn route(&self, message: RelayMessage) -> Option<RelayMessage> {
+        let id = match &message {
+            RelayMessage::Event { subscription, .. }
+            | RelayMessage::Eose { subscription }
+            | RelayMessage::Closed { subscription, .. }
+            | RelayMessage::Count { subscription, .. } => subscription,
+            _ => return Some(message),
+        };
+        if let Some(subscription) = self.subscriptions.borrow().get(id) {
+            subscription.inbox.push(message);
+        }
+        None
+    }
+
+    /// Keep a subscription the relay refused for lack of auth until the handshake is done
+    pub fn hold(&self, id: &str) {
+        if let Some(subscription) = self.subscriptions.borrow_mut().get_mut(id) {
+            subscription.held = true;
+        }
+    }
+
+    /// REQ frames of the held subscriptions, to send again now that auth is done
+    pub fn release_held(&self) -> Vec<String> {
+        let mut subscriptions = self.subscriptions.borrow_mut();
+        subscriptions
+            .values_mut()
+            .filter_map(|s| std::mem::take(&mut s.held).then(|| s.req.clone()))
+            .collect()
+    }
+
+    /// Auth didn't work out: each held subscription is closed with the relay's refusal
+    pub fn refuse_held(&self, message: &str) {
+        for (id, subscription) in self.subscriptions.borrow_mut().iter_mut().filter(|(_, s)| s.held) {
+            subscription.held = false;
+            subscription.inbox.push(RelayMessage::Closed {
+                subscription: id.clone(),
+                message: message.to_string(),
+            });
+        }
+    }
+
+    /// The connection is gone: every inbox ends once drained, now and for later subscriptions
+    pub fn shutdown(&self) {
+        self.closed.set(true);
+        for subscription in self.subscriptions.borrow().values() {
+            subscription.inbox.close();
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+    use serde_json::json;
+
+    fn event(subscription: &str, id: &str) -> RelayMessage {
+        RelayMessage::Event {
+            subscription: subscription.to_string(),
+            event: json!({"id": id}),
+        }
+    }
+
+    #[test]
+    fn test_route() {
+        let mux = Mux::default();
+        let (a, req, inbox_a) = mux.open(r#"{"kinds":[1]}"#);
+        let (b, _, inbox_b) = mux.open(r#"{"kinds":[0]}"#);
+        assert_ne!(a, b);
+        assert_eq!(req, format!(r#"["REQ","{}",{{"kinds":[1]}}]"#, a));
+        assert_eq!(mux.active(), 2);
+        let (c, count, _) = mux.open_with("COUNT", r#"{"kinds":[1]},{"kinds":[6]}"#);
+        assert_eq!(count, format!(r#"["COUNT","{}",{{"kinds":[1]}},{{"kinds":[6]}}]"#, c));
+        mux.close(&c);
+
+        assert_eq!(mux.route(event(&b, "x")), None);
+        assert_eq!(mux.route(event(&a, "y")), None);
+        assert_eq!(mux.route(RelayMessage::Notice("hi".to_string())), Some(RelayMessage::Notice("hi".to_string())));
+        assert_eq!(inbox_a.next().now_or_never(), Some(Some(event(&a, "y"))));
+        assert_eq!(inbox_b.next().now_or_never(), Some(Some(event(&b, "x"))));
+        // Nothing queued yet: still waiting
+        assert_eq!(inbox_a.next().now_or_never(), None);
+
+        assert_eq!(mux.close(&a), Some(format!(r#"["CLOSE","{}"]"#, a)));
+        assert_eq!(mux.close(&a), None);
+        assert_eq!(mux.route(event(&a, "late")), None);
+        assert_eq!(inbox_a.next().now_or_never(), None);
+    }
+
+    #[test]
+    fn test_held_subscriptions() {
+        let mux = Mux::default();
+        let (a, req_a, _) = mux.open("{}");
+        let (b, _, inbox_b) = mux.open("{}");
+        mux.hold(&a);
+        assert_eq!(mux.release_held(), vec![req_a]);
+        assert!(mux.release_held().is_empty());
+
+        mux.hold(&b);
+        mux.refuse_held("auth-required: no");
+        assert!(matches!(inbox_b.next().now_or_never(), Some(Some(RelayMessage::Closed { subscription, .. })) if subscription == b));
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let mux = Mux::default();
+        let (a, _, inbox) = mux.open("{}");
+        mux.route(event(&a, "x"));
+        mux.shutdown();
+        assert!(mux.is_closed());
+        assert_eq!(inbox.next().now_or_never(), Some(Some(event(&a, "x"))));
+        assert_eq!(inbox.next().now_or_never(), Some(None));
+        let (_, _, later) = mux.open("{}");
+        assert_eq!(later.next().now_or_never(), Some(None));
+    }
+}
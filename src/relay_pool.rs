@@ -1,17 +1,133 @@
 // ABOUTME: Durable Object that maintains persistent websocket connections to Nostr relay
-// ABOUTME: Handles query execution, request coalescing, and connection management
+// ABOUTME: Handles query execution over one multiplexed relay connection, request coalescing, and connection management
 
+use crate::cache::now_seconds;
+use crate::circuit::CircuitBreaker;
+use crate::fanout::{self, Contribution, RelayAnswer};
+use crate::hooks::{Hooks, RequestContext};
+use crate::latency::{LatencyTracker, TimeoutBounds, Timeouts};
+use crate::metrics::Observation;
+use crate::passthrough::{self, ClientFrame, ClientRequest, Session};
+use crate::relay_auth::{self, GatewayKey, Handshake, Step};
+use crate::relay_info::{fetch_document, RelayInfo};
+use crate::relay_message::{self, RelayMessage};
+use crate::relay_mux::{Inbox, Mux};
 use futures_util::StreamExt;
 use serde::Deserialize;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use std::time::Duration;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
 use worker::*;
 
+/// An idle relay connection is only reused while it has heard from the relay this
+/// recently; relays may drop idle connections without a close frame reaching us
+const IDLE_MAX_AGE_MS: f64 = 45_000.0;
+
+/// Default seconds between keep-warm alarms; `RELAY_WARM_INTERVAL_SECONDS=0` disables them
+const DEFAULT_WARM_INTERVAL_SECONDS: u64 = 30;
+
+/// Filter of the keepalive probe: one id no event has, which any relay answers
+/// straight away with EOSE
+const PROBE_FILTER: &str = r#"{"ids":["0000000000000000000000000000000000000000000000000000000000000000"],"limit":1}"#;
+
+/// How long the keepalive probe waits for the relay's answer
+const PROBE_TIMEOUT_MS: u32 = 5000;
+
+/// How long fanned-out relays still have once the primary relay has answered;
+/// whatever they haven't sent by then is left out
+const FANOUT_GRACE_MS: f64 = 300.0;
+
+/// Storage key of the relay override, so alarms after an eviction warm the right relay
+const RELAY_URL_KEY: &str = "relay_url";
+
+/// Who a `/ws` upgrade is for, set by the router: `key:{id}` or `ip:{address}`
+pub const STREAM_CLIENT_HEADER: &str = "X-Stream-Client";
+
+/// How many `/ws` sockets that client may hold open, from its tier's `sse_concurrency`
+pub const STREAM_LIMIT_HEADER: &str = "X-Stream-Limit";
+
+/// Country the `/ws` client connects from, for the hooks filtering what it is sent
+pub const STREAM_COUNTRY_HEADER: &str = "X-Stream-Country";
+
+/// Storage prefix of `/ws` client sessions, followed by the id in the socket's attachment
+const SESSION_PREFIX: &str = "ws:";
+
+/// RelayPool instances and the relay each one talks to (`None` is RELAY_URL)
+pub fn shards(env: &Env) -> Vec<(&'static str, Option<String>)> {
+    let mut shards = vec![("default", None)];
+    if let Some(config) = crate::write_behind::WriteBehindConfig::from_env(env) {
+        shards.push(("home", Some(config.home_relay)));
+    }
+    shards
+}
+
+/// Touch every shard so it is awake, has its relay info loaded and holds an
+/// open relay connection. Called from the cron trigger; each shard then keeps
+/// itself warm with an alarm.
+pub async fn warm_shards(env: &Env) {
+    for (name, relay) in shards(env) {
+        if let Err(e) = warm_shard(env, name, relay.as_deref()).await {
+            console_log!("Warmup of RelayPool {} failed: {}", name, e);
+        }
+    }
+}
+
+async fn warm_shard(env: &Env, name: &str, relay: Option<&str>) -> Result<()> {
+    let stub = env.durable_object("RELAY_POOL")?.id_from_name(name)?.get_stub()?;
+    let mut headers = Headers::new();
+    if let Some(relay) = relay {
+        headers.set("X-Relay-Url", relay)?;
+    }
+    let req = Request::new_with_init(
+        "http://do/warm",
+        RequestInit::new().with_method(Method::Post).with_headers(headers),
+    )?;
+    stub.fetch_with_request(req).await?;
+    Ok(())
+}
+
 #[durable_object]
 pub struct RelayPool {
     state: State,
     env: Env,
-    relay_url: Option<String>,
+    /// Relay this instance talks to, when set by the caller (see `X-Relay-Url`)
+    relay_url: RefCell<Option<String>>,
+    /// NIP-11 info per relay, loaded once per DO instance
+    relay_info: RefCell<HashMap<String, RelayInfo>>,
+    /// Per-relay EOSE latency samples used to autotune query timeouts
+    latency: RefCell<HashMap<String, LatencyTracker>>,
+    /// True until the first call after the instance was created
+    cold: Cell<bool>,
+    /// The connection queries to each relay share, opened on first use or by warmup
+    connections: RefCell<HashMap<String, Rc<Connection>>>,
+    /// What each relay has added to fanned-out queries
+    contributions: RefCell<BTreeMap<String, Contribution>>,
+    /// Trips after repeated failed queries so callers fail fast during relay outages
+    breaker: RefCell<CircuitBreaker>,
+    /// Keepalive alarms in a row that couldn't get an answering relay connection
+    reconnect_failures: Cell<u32>,
+    /// Keypair for answering NIP-42 challenges from auth-required relays
+    gateway_key: Option<GatewayKey>,
+    /// Live state of `/ws` sessions, by session id. Lost with an eviction, unlike
+    /// the hibernated client sockets and their stored sessions.
+    sessions: RefCell<HashMap<String, Rc<LiveSession>>>,
+}
+
+/// A `/ws` client's subscriptions while the instance is in memory, each carried on
+/// the shared relay connection under an id of the connection's own
+struct LiveSession {
+    hooks: Rc<Hooks>,
+    /// By the client's subscription id
+    subscriptions: RefCell<HashMap<String, ClientSubscription>>,
+}
+
+struct ClientSubscription {
+    connection: Rc<Connection>,
+    mux_id: String,
+    inbox: Rc<Inbox>,
 }
 
 impl DurableObject for RelayPool {
@@ -19,7 +135,16 @@ impl DurableObject for RelayPool {
         Self {
             state,
             env,
-            relay_url: None,
+            relay_url: RefCell::new(None),
+            relay_info: RefCell::new(HashMap::new()),
+            latency: RefCell::new(HashMap::new()),
+            cold: Cell::new(true),
+            connections: RefCell::new(HashMap::new()),
+            contributions: RefCell::new(BTreeMap::new()),
+            breaker: RefCell::new(CircuitBreaker::default()),
+            reconnect_failures: Cell::new(0),
+            gateway_key: GatewayKey::from_env(&env),
+            sessions: RefCell::new(HashMap::new()),
         }
     }
 
@@ -27,27 +152,580 @@ impl DurableObject for RelayPool {
         let url = req.url()?;
         let path = url.path();
 
+        if self.cold.replace(false) {
+            // Reported in the background so it doesn't add to the cold request
+            let env = self.env.clone();
+            let trigger = if path == "/warm" { "warmup" } else { "request" };
+            wasm_bindgen_futures::spawn_local(async move {
+                let observation = Observation::ColdStart {
+                    trigger: trigger.to_string(),
+                };
+                crate::metrics::record(&env, &observation).await;
+            });
+        }
+
+        // Named instances can be pointed at a relay other than RELAY_URL
+        if let Some(relay) = req.headers().get("X-Relay-Url")? {
+            *self.relay_url.borrow_mut() = Some(relay);
+        }
+
         match path {
             "/query" => self.handle_query(req).await,
             "/publish" => self.handle_publish(req).await,
             "/verify" => self.handle_verify(req).await,
+            "/latency" => self.handle_latency().await,
+            "/reset" => self.handle_reset().await,
+            "/ws" => self.handle_ws(req).await,
+            "/warm" => self.handle_warm().await,
             _ => Response::error("not found", 404),
         }
     }
+
+    async fn alarm(&self) -> Result<Response> {
+        self.restore_relay_url().await;
+        let next = match self.keep_alive().await {
+            Ok(()) => {
+                self.reconnect_failures.set(0);
+                self.warm_interval()
+            }
+            Err(e) => {
+                let failures = self.reconnect_failures.get() + 1;
+                self.reconnect_failures.set(failures);
+                console_log!("Relay keepalive failed ({} in a row): {}", failures, e);
+                // Try again on the backoff schedule rather than the regular interval
+                self.warm_interval().map(|_| crate::circuit::reconnect_delay(failures))
+            }
+        };
+        self.resume_sessions().await;
+        if let Some(delay) = next {
+            self.state.storage().set_alarm(delay).await?;
+        }
+        Response::empty()
+    }
+
+    async fn websocket_message(&self, ws: WebSocket, message: WebSocketIncomingMessage) -> Result<()> {
+        let WebSocketIncomingMessage::String(text) = message else {
+            return Ok(());
+        };
+        let Some(id) = ws.deserialize_attachment::<String>()? else {
+            return Ok(());
+        };
+        match passthrough::classify_client_frame(&text) {
+            ClientFrame::Forward => self.forward_client_frame(&id, &ws, &text).await,
+            ClientFrame::Reject(reply) => ws.send_with_str(&reply),
+        }
+    }
+
+    async fn websocket_close(&self, ws: WebSocket, _code: usize, _reason: String, _was_clean: bool) -> Result<()> {
+        self.end_session(&ws).await
+    }
+
+    async fn websocket_error(&self, ws: WebSocket, _error: Error) -> Result<()> {
+        self.end_session(&ws).await
+    }
 }
 
 impl RelayPool {
+    /// Accept a client WebSocket through the Hibernation API, so the client stays
+    /// connected while the DO is out of memory. Its frames arrive in
+    /// `websocket_message` and its subscriptions go out on the shared relay connection.
+    async fn handle_ws(&self, req: Request) -> Result<Response> {
+        if !req.headers().get("Upgrade")?.is_some_and(|v| v.eq_ignore_ascii_case("websocket")) {
+            return Response::error("expected websocket upgrade", 426);
+        }
+
+        // Each client's sockets carry its tag, so the hibernated ones count too
+        let client = req.headers().get(STREAM_CLIENT_HEADER)?.unwrap_or_default();
+        let limit = req.headers().get(STREAM_LIMIT_HEADER)?.and_then(|v| v.parse::<usize>().ok());
+        if let Some(limit) = limit.filter(|_| !client.is_empty()) {
+            if self.state.get_websockets_with_tag(&client).len() >= limit {
+                return Response::error("too many streams", 429);
+            }
+        }
+
+        let mut bytes = [0u8; 16];
+        getrandom::getrandom(&mut bytes).expect("RNG available in Workers");
+        let pair = WebSocketPair::new()?;
+        if client.is_empty() {
+            self.state.accept_web_socket(&pair.server);
+        } else {
+            self.state.accept_websocket_with_tags(&pair.server, &[client.as_str()]);
+        }
+        let id = hex::encode(bytes);
+        // Kept with the session, as the hooks filtering a resumed session need it too
+        if let Some(country) = req.headers().get(STREAM_COUNTRY_HEADER)? {
+            let key = format!("{}{}", SESSION_PREFIX, id);
+            self.state.storage().put(&key, &Session::new(Some(country))).await?;
+        }
+        pair.server.serialize_attachment(id)?;
+        Response::from_websocket(pair.client)
+    }
+
+    /// Carry a client frame to the relay and note what it opens or closes. A relay
+    /// that can't be reached gets the client a NOTICE, not a dropped socket.
+    async fn forward_client_frame(&self, id: &str, client: &WebSocket, text: &str) -> Result<()> {
+        let Some(request) = passthrough::client_request(text) else {
+            return client.send_with_str(&passthrough::notice("invalid: missing subscription id or filter"));
+        };
+        let forwarded = async {
+            let live = self.live_session(id, client).await?;
+            match request {
+                ClientRequest::Open { verb, id: sub_id, filters } => {
+                    self.open_client_subscription(&live, client, &sub_id, &verb, &filters, false).await
+                }
+                ClientRequest::Close { id: sub_id } => {
+                    self.close_client_subscription(&live, &sub_id);
+                    Ok(())
+                }
+            }
+        };
+        if let Err(e) = forwarded.await {
+            console_log!("WebSocket passthrough couldn't reach the relay: {}", e);
+            return client.send_with_str(&passthrough::notice("error: relay unavailable"));
+        }
+        // Read only now: the client's other frames may have been handled while this
+        // one waited on the relay, and reading after keeps what they noted
+        let key = format!("{}{}", SESSION_PREFIX, id);
+        let mut session = self.state.storage().get::<Session>(&key).await.ok().flatten().unwrap_or_default();
+        session.track(text);
+        self.state.storage().put(&key, &session).await
+    }
+
+    /// A session's live state. After an eviction there is none: it is set up again
+    /// from storage and the session's subscriptions are reopened.
+    async fn live_session(&self, id: &str, client: &WebSocket) -> Result<Rc<LiveSession>> {
+        if let Some(live) = self.sessions.borrow().get(id) {
+            return Ok(live.clone());
+        }
+        let key = format!("{}{}", SESSION_PREFIX, id);
+        let session = self.state.storage().get::<Session>(&key).await.ok().flatten().unwrap_or_default();
+        // Another frame from the same client may have set it up meanwhile
+        if let Some(live) = self.sessions.borrow().get(id) {
+            return Ok(live.clone());
+        }
+        let ctx = RequestContext {
+            method: Method::Get,
+            path: "/ws".to_string(),
+            country: session.country().map(String::from),
+        };
+        let live = Rc::new(LiveSession {
+            hooks: Rc::new(Hooks::from_env(&self.env, ctx)),
+            subscriptions: RefCell::new(HashMap::new()),
+        });
+        self.sessions.borrow_mut().insert(id.to_string(), live.clone());
+        for req in session.replay() {
+            let Some(ClientRequest::Open { verb, id: sub_id, filters }) = passthrough::client_request(req) else {
+                continue;
+            };
+            if let Err(e) = self.open_client_subscription(&live, client, &sub_id, &verb, &filters, true).await {
+                // Left for the next frame or alarm to try again from the start
+                self.drop_live_session(id);
+                return Err(e);
+            }
+        }
+        Ok(live)
+    }
+
+    /// Open a client subscription on the shared relay connection and forward its
+    /// frames under the client's id. A `replayed` subscription was open before an
+    /// eviction: its stored events already reached the client, so everything up to
+    /// its EOSE is dropped. When the relay connection goes, the client is hung up on.
+    async fn open_client_subscription(
+        &self,
+        live: &LiveSession,
+        client: &WebSocket,
+        sub_id: &str,
+        verb: &str,
+        filters: &str,
+        replayed: bool,
+    ) -> Result<()> {
+        self.restore_relay_url().await;
+        let (connection, mux_id, inbox) = self.subscribe_with(&self.get_relay_url(), verb, filters).await?;
+        // A REQ reusing an open subscription's id replaces it
+        self.close_client_subscription(live, sub_id);
+        let subscription = ClientSubscription {
+            connection: connection.clone(),
+            mux_id: mux_id.clone(),
+            inbox: inbox.clone(),
+        };
+        live.subscriptions.borrow_mut().insert(sub_id.to_string(), subscription);
+
+        let (client, sub_id, hooks) = (client.clone(), sub_id.to_string(), live.hooks.clone());
+        wasm_bindgen_futures::spawn_local(async move {
+            let mut skipping = replayed;
+            while let Some(message) = inbox.next().await {
+                if skipping {
+                    match message {
+                        RelayMessage::Event { .. } => continue,
+                        RelayMessage::Eose { .. } => {
+                            skipping = false;
+                            continue;
+                        }
+                        _ => {}
+                    }
+                }
+                let Some(frame) = passthrough::client_frame(&message, &sub_id, &hooks) else {
+                    continue;
+                };
+                if client.send_with_str(&frame).is_err() {
+                    break;
+                }
+                // A COUNT is answered once
+                if matches!(message, RelayMessage::Count { .. }) {
+                    connection.mux.close(&mux_id);
+                    break;
+                }
+            }
+            if connection.mux.is_closed() {
+                let _ = client.close(Some(1000), Some("relay connection lost"));
+            }
+        });
+        Ok(())
+    }
+
+    fn close_client_subscription(&self, live: &LiveSession, sub_id: &str) {
+        let removed = live.subscriptions.borrow_mut().remove(sub_id);
+        if let Some(subscription) = removed {
+            self.unsubscribe(&subscription.connection, &subscription.mux_id);
+            subscription.inbox.close();
+        }
+    }
+
+    /// Forget a session's live state and close its subscriptions
+    fn drop_live_session(&self, id: &str) {
+        let removed = self.sessions.borrow_mut().remove(id);
+        if let Some(live) = removed {
+            let sub_ids: Vec<String> = live.subscriptions.borrow().keys().cloned().collect();
+            for sub_id in sub_ids {
+                self.close_client_subscription(&live, &sub_id);
+            }
+        }
+    }
+
+    /// Reopen the subscriptions hibernated clients lost to an eviction, so they
+    /// resume without waiting for the client to send something
+    async fn resume_sessions(&self) {
+        for client in self.state.get_websockets() {
+            let Ok(Some(id)) = client.deserialize_attachment::<String>() else {
+                continue;
+            };
+            if self.sessions.borrow().contains_key(&id) {
+                continue;
+            }
+            if let Err(e) = self.live_session(&id, &client).await {
+                console_log!("Resuming WebSocket session {} failed: {}", id, e);
+            }
+        }
+    }
+
+    /// The client went away: close its subscriptions and forget them
+    async fn end_session(&self, client: &WebSocket) -> Result<()> {
+        let Some(id) = client.deserialize_attachment::<String>()? else {
+            return Ok(());
+        };
+        self.drop_live_session(&id);
+        self.state.storage().delete(&format!("{}{}", SESSION_PREFIX, id)).await?;
+        Ok(())
+    }
+
+    /// Put back a relay override set before an eviction, so alarms and resumed
+    /// sessions use the relay this instance was pointed at
+    async fn restore_relay_url(&self) {
+        if self.relay_url.borrow().is_none() {
+            let stored = self.state.storage().get::<String>(RELAY_URL_KEY).await.ok().flatten();
+            *self.relay_url.borrow_mut() = stored;
+        }
+    }
+
+    async fn handle_warm(&self) -> Result<Response> {
+        if let Some(relay) = self.relay_url.borrow().clone() {
+            self.state.storage().put(RELAY_URL_KEY, relay).await?;
+        }
+        self.warm().await?;
+        if let Some(interval) = self.warm_interval() {
+            if self.state.storage().get_alarm().await?.is_none() {
+                self.state.storage().set_alarm(interval).await?;
+            }
+        }
+        let relay = self.get_relay_url();
+        let connected = self.connections.borrow().contains_key(&relay);
+        Response::from_json(&serde_json::json!({ "relay": relay, "connected": connected }))
+    }
+
+    fn warm_interval(&self) -> Option<Duration> {
+        let seconds = self
+            .env
+            .var("RELAY_WARM_INTERVAL_SECONDS")
+            .ok()
+            .and_then(|v| v.to_string().parse().ok())
+            .unwrap_or(DEFAULT_WARM_INTERVAL_SECONDS);
+        (seconds > 0).then(|| Duration::from_secs(seconds))
+    }
+
+    /// Load relay info and latency samples, and make sure the shared relay
+    /// connection is open so the next query skips the WebSocket connect and TLS handshake
+    async fn warm(&self) -> Result<()> {
+        let relay_url = self.get_relay_url();
+        self.relay_info().await;
+        self.load_tracker(&relay_url).await;
+        self.shared_connection(&relay_url).await?;
+        Ok(())
+    }
+
+    /// Warm up, then check the shared connection still answers: a probe
+    /// subscription has to get its EOSE (or CLOSED) back in time. A connection that
+    /// stays silent is replaced, so a dead socket is found here and not by a query.
+    async fn keep_alive(&self) -> Result<()> {
+        self.warm().await?;
+        let relay_url = self.get_relay_url();
+        if self.probe(&relay_url).await {
+            return Ok(());
+        }
+        let current = self.connections.borrow().get(&relay_url).cloned();
+        if let Some(connection) = current {
+            self.forget_connection(&connection);
+        }
+        self.shared_connection(&relay_url).await?;
+        if self.probe(&relay_url).await {
+            Ok(())
+        } else {
+            Err(Error::RustError("relay connection doesn't answer".to_string()))
+        }
+    }
+
+    /// Whether the relay answers a throwaway subscription in time
+    async fn probe(&self, relay_url: &str) -> bool {
+        let Ok((connection, sub_id, inbox)) = self.subscribe(relay_url, PROBE_FILTER).await else {
+            return false;
+        };
+        let answer = futures_util::future::select(Box::pin(inbox.next()), Box::pin(Self::sleep_ms(PROBE_TIMEOUT_MS))).await;
+        self.unsubscribe(&connection, &sub_id);
+        matches!(
+            answer,
+            futures_util::future::Either::Left((Some(RelayMessage::Eose { .. } | RelayMessage::Closed { .. }), _))
+        )
+    }
+
+    /// End a subscription, and hang up on a connection that is no longer shared
+    /// once its last subscription is gone
+    fn unsubscribe(&self, connection: &Rc<Connection>, sub_id: &str) {
+        if let Some(close_msg) = connection.mux.close(sub_id) {
+            let _ = connection.ws.send_with_str(&close_msg);
+        }
+        if !self.is_shared(connection) && connection.mux.active() == 0 {
+            connection.close("done");
+        }
+    }
+
+    /// The shared relay connection, opened afresh when there is none, it died, or
+    /// it sat idle too long to trust. Two queries arriving together with no
+    /// connection may each open one; the one not kept closes when its query ends.
+    async fn shared_connection(&self, relay_url: &str) -> Result<Rc<Connection>> {
+        let current = self.connections.borrow().get(relay_url).cloned();
+        if let Some(connection) = current {
+            if connection.is_usable(js_sys::Date::now()) {
+                return Ok(connection);
+            }
+            self.forget_connection(&connection);
+        }
+        let connection = Connection::open(relay_url, self.gateway_key.clone()).await?;
+        self.connections.borrow_mut().insert(relay_url.to_string(), connection.clone());
+        Ok(connection)
+    }
+
+    /// Stop handing out a connection, closing it once no query is using it
+    fn forget_connection(&self, connection: &Rc<Connection>) {
+        if self.is_shared(connection) {
+            self.connections.borrow_mut().remove(&connection.relay_url);
+        }
+        if connection.mux.active() == 0 {
+            connection.close("replaced");
+        }
+    }
+
+    fn is_shared(&self, connection: &Rc<Connection>) -> bool {
+        self.connections.borrow().get(&connection.relay_url).is_some_and(|c| Rc::ptr_eq(c, connection))
+    }
+
+    /// Open a subscription for a query: on the shared connection, or on one of its
+    /// own when the shared one is at the relay's NIP-11 `max_subscriptions`. A
+    /// shared connection that fails the send is dropped and the query reconnects once.
+    async fn subscribe(&self, relay_url: &str, filter_json: &str) -> Result<(Rc<Connection>, String, Rc<Inbox>)> {
+        self.subscribe_with(relay_url, "REQ", filter_json).await
+    }
+
+    /// `subscribe` with another verb or several filters, as `Mux::open_with` takes them
+    async fn subscribe_with(
+        &self,
+        relay_url: &str,
+        verb: &str,
+        filters_json: &str,
+    ) -> Result<(Rc<Connection>, String, Rc<Inbox>)> {
+        let max_subscriptions = self.relay_info_for(relay_url).await.limitation.max_subscriptions;
+        let shared = self.shared_connection(relay_url).await?;
+        let connection = if max_subscriptions.is_some_and(|max| shared.mux.active() as u64 >= max) {
+            Connection::open(relay_url, self.gateway_key.clone()).await?
+        } else {
+            shared
+        };
+        let (sub_id, req_msg, inbox) = connection.mux.open_with(verb, filters_json);
+        if connection.ws.send_with_str(&req_msg).is_ok() {
+            return Ok((connection, sub_id, inbox));
+        }
+        connection.mux.close(&sub_id);
+        self.forget_connection(&connection);
+        let connection = self.shared_connection(relay_url).await?;
+        let (sub_id, req_msg, inbox) = connection.mux.open_with(verb, filters_json);
+        connection.ws.send_with_str(&req_msg)?;
+        Ok((connection, sub_id, inbox))
+    }
+
     fn get_relay_url(&self) -> String {
         self.relay_url
+            .borrow()
             .clone()
             .or_else(|| self.env.var("RELAY_URL").ok().map(|v| v.to_string()))
             .unwrap_or_else(|| "wss://relay.damus.io".to_string())
     }
 
+    /// Capabilities of this instance's relay
+    async fn relay_info(&self) -> RelayInfo {
+        self.relay_info_for(&self.get_relay_url()).await
+    }
+
+    /// Relay capabilities from its NIP-11 document. Failures are not fatal -
+    /// the relay simply gets treated as having no advertised limits.
+    async fn relay_info_for(&self, relay_url: &str) -> RelayInfo {
+        if let Some(info) = self.relay_info.borrow().get(relay_url) {
+            return info.clone();
+        }
+        let info = match fetch_document(&self.env, relay_url).await {
+            Ok((doc, _)) => RelayInfo::from_document(&doc),
+            Err(e) => {
+                console_log!("Relay info for {} unavailable: {}", relay_url, e);
+                RelayInfo::default()
+            }
+        };
+        self.relay_info.borrow_mut().insert(relay_url.to_string(), info.clone());
+        info
+    }
+
+    /// Extra relays queries fan out to. Only the instance serving RELAY_URL fans
+    /// out; one pointed at a relay of its own (the home relay) asks just that one.
+    fn fanout_relays(&self, primary: &str) -> Vec<String> {
+        if self.relay_url.borrow().is_some() {
+            return Vec::new();
+        }
+        self.env
+            .var("FANOUT_RELAY_URLS")
+            .map(|v| fanout::parse_relays(&v.to_string(), primary))
+            .unwrap_or_default()
+    }
+
+    /// Load a relay's latency tracker from storage on first use
+    async fn load_tracker(&self, relay_url: &str) {
+        if self.latency.borrow().contains_key(relay_url) {
+            return;
+        }
+        let stored = self
+            .state
+            .storage()
+            .get::<LatencyTracker>(&format!("latency:{}", relay_url))
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        self.latency
+            .borrow_mut()
+            .entry(relay_url.to_string())
+            .or_insert(stored);
+    }
+
+    /// Idle/empty timeouts tuned to this relay's observed latency
+    async fn tuned_timeouts(&self, relay_url: &str) -> Timeouts {
+        self.load_tracker(relay_url).await;
+        let bounds = TimeoutBounds::from_env(&self.env);
+        self.latency
+            .borrow()
+            .get(relay_url)
+            .map(|t| t.tuned(&bounds))
+            .unwrap_or_default()
+    }
+
+    /// Record a query's latency, or `None` for one that timed out empty, persisting
+    /// every 10th query so tuning survives eviction
+    async fn record_latency(&self, relay_url: &str, eose_ms: Option<f64>, max_gap_ms: Option<f64>) {
+        let snapshot = {
+            let mut trackers = self.latency.borrow_mut();
+            let tracker = trackers.entry(relay_url.to_string()).or_default();
+            match eose_ms {
+                Some(ms) => tracker.record(ms, max_gap_ms),
+                None => tracker.record_timeout(),
+            }
+            (tracker.total() % 10 == 0).then(|| tracker.clone())
+        };
+        if let Some(tracker) = snapshot {
+            let key = format!("latency:{}", relay_url);
+            if let Err(e) = self.state.storage().put(&key, tracker).await {
+                console_log!("Failed to persist latency samples: {}", e);
+            }
+        }
+    }
+
+    async fn handle_latency(&self) -> Result<Response> {
+        let relay_url = self.get_relay_url();
+        let timeouts = self.tuned_timeouts(&relay_url).await;
+        let trackers = self.latency.borrow();
+        let tracker = trackers.get(&relay_url).cloned().unwrap_or_default();
+        Response::from_json(&serde_json::json!({
+            "relay": relay_url,
+            "samples": tracker.samples(),
+            "eose_p50_ms": tracker.eose_percentile(50.0),
+            "eose_p95_ms": tracker.eose_percentile(95.0),
+            "gap_p95_ms": tracker.gap_percentile(95.0),
+            "timed_out_queries": tracker.timeouts(),
+            "timeouts": timeouts,
+            "circuit": self.breaker.borrow().state(js_sys::Date::now()),
+            "consecutive_failures": self.breaker.borrow().failures(),
+            "fanout": &*self.contributions.borrow(),
+        }))
+    }
+
+    /// Forget cached relay info and latency samples so both are relearned
+    async fn handle_reset(&self) -> Result<Response> {
+        let relay_url = self.get_relay_url();
+        self.relay_info.borrow_mut().clear();
+        self.contributions.borrow_mut().clear();
+        self.latency.borrow_mut().remove(&relay_url);
+        self.state
+            .storage()
+            .delete(&format!("latency:{}", relay_url))
+            .await?;
+        Response::from_json(&serde_json::json!({ "reset": relay_url }))
+    }
+
     async fn handle_query(&self, mut req: Request) -> Result<Response> {
         // Get raw filter string - pass directly to relay without parsing
         let filter_str = req.text().await?;
-        let events = self.query_relay_raw(&filter_str).await?;
+        let start = js_sys::Date::now();
+        if !self.breaker.borrow_mut().allow(start) {
+            let mut resp = Response::error(crate::circuit::CIRCUIT_OPEN, 503)?;
+            resp.headers_mut().set(crate::circuit::HEADER, "open")?;
+            return Ok(resp);
+        }
+        let events = match self.query_relay_raw(&filter_str).await {
+            Ok(events) => events,
+            Err(e) => {
+                self.breaker.borrow_mut().record_failure(js_sys::Date::now());
+                return Err(e);
+            }
+        };
+        let seconds = (js_sys::Date::now() - start) / 1000.0;
+        // Reported in the background so the query doesn't wait on the collector
+        let env = self.env.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            crate::metrics::record(&env, &Observation::RelayQuery { seconds }).await;
+        });
         Response::from_json(&events)
     }
 
@@ -63,34 +741,104 @@ impl RelayPool {
         Response::from_json(&serde_json::json!({ "found": found }))
     }
 
-    /// Query relay with raw filter string - NO PARSING, preserves ALL fields
+    /// Query relay with raw filter string - NO PARSING, preserves ALL fields. With
+    /// `FANOUT_RELAY_URLS` set, the extra relays are asked at the same time and
+    /// the answers merged; the query only fails if every relay does.
     async fn query_relay_raw(&self, filter_json: &str) -> Result<Vec<serde_json::Value>> {
-        let relay_url = self.get_relay_url();
-
-        // Parse URL for WebSocket connection
-        let url = relay_url.parse().map_err(|_| "Invalid relay URL")?;
+        let primary = self.get_relay_url();
+        let extra = self.fanout_relays(&primary);
+        if extra.is_empty() {
+            let (events, answered) = self.query_one(&primary, filter_json, &Cell::new(None)).await?;
+            self.record_outcome(answered);
+            return Ok(events);
+        }
 
-        // Create websocket connection
-        let ws = WebSocket::connect(url).await?;
-        ws.accept()?;
+        // The primary relay sets the pace: the others get a short grace period
+        // after its answer rather than all their own timeouts
+        let cutoff = Cell::new(None);
+        let primary_query = async {
+            let result = self.query_one(&primary, filter_json, &Cell::new(None)).await;
+            cutoff.set(Some(js_sys::Date::now() + FANOUT_GRACE_MS));
+            result
+        };
+        let extra_queries = extra.iter().map(|relay| self.query_one(relay, filter_json, &cutoff));
+        let extra_queries = futures_util::future::join_all(extra_queries);
+        let (primary_result, extra_results) = futures_util::future::join(primary_query, extra_queries).await;
 
-        // Create event stream
-        let mut event_stream = ws.events()?;
+        let relays: Vec<String> = std::iter::once(primary).chain(extra).collect();
+        let results = std::iter::once(primary_result).chain(extra_results);
+        let mut first_error = None;
+        let mut answers = Vec::with_capacity(relays.len());
+        for (n, (relay, result)) in relays.into_iter().zip(results).enumerate() {
+            let (mut events, answered) = result.unwrap_or_else(|e| {
+                console_log!("Fan-out query to {} failed: {}", relay, e);
+                first_error.get_or_insert(e);
+                (Vec::new(), false)
+            });
+            // Only the primary relay is trusted as is; an event from any other
+            // must carry its author's signature over its own id
+            if n > 0 {
+                let before = events.len();
+                events.retain(crate::auth::verify_event);
+                if events.len() < before {
+                    console_log!("Dropped {} unverifiable events from {}", before - events.len(), relay);
+                }
+            }
+            answers.push(RelayAnswer { relay, events, answered });
+        }
+        if answers.iter().all(|a| !a.answered) {
+            if let Some(e) = first_error {
+                return Err(e);
+            }
+        }
+        self.record_outcome(answers.iter().any(|a| a.answered));
+        Ok(fanout::merge(answers, &mut self.contributions.borrow_mut()))
+    }
 
-        // Generate subscription ID
-        let sub_id = format!("q{}", js_sys::Date::now() as u64);
+    /// The breaker's view of a query: neither an EOSE nor a single event from any
+    /// relay means the relay didn't answer at all
+    fn record_outcome(&self, answered: bool) {
+        if answered {
+            self.breaker.borrow_mut().record_success();
+        } else {
+            self.breaker.borrow_mut().record_failure(js_sys::Date::now());
+        }
+    }
 
-        // Send REQ message - embed raw filter string directly into JSON array
-        let req_msg = format!(r#"["REQ","{}",{}]"#, sub_id, filter_json);
-        ws.send_with_str(&req_msg)?;
+    /// One relay's answer to a raw filter, and whether it answered at all. A `cutoff`
+    /// time, once set, ends the query early with what it has, as a fanned-out relay's
+    /// does once the primary has answered.
+    async fn query_one(
+        &self,
+        relay_url: &str,
+        filter_json: &str,
+        cutoff: &Cell<Option<f64>>,
+    ) -> Result<(Vec<serde_json::Value>, bool)> {
+        // Concurrent queries share one connection, each on its own subscription;
+        // the connection answers NIP-42 challenges for all of them
+        let (connection, sub_id, inbox) = self.subscribe(relay_url, filter_json).await?;
 
         let mut events = Vec::new();
-        let limit = 500; // Max events to collect before giving up
+        // Max events to collect before giving up: the gateway's cap, or the relay's if lower
+        let cap = crate::api_keys::max_query_limit(&self.env);
+        let limit = self
+            .relay_info_for(relay_url)
+            .await
+            .limitation
+            .max_limit
+            .map(|max| (max as usize).min(cap))
+            .unwrap_or(cap);
+        // Idle/empty timeouts are autotuned per relay within operator bounds
+        let Timeouts {
+            idle_ms: idle_timeout_ms,
+            empty_ms: empty_timeout_ms,
+        } = self.tuned_timeouts(relay_url).await;
         let start = js_sys::Date::now();
         let max_timeout_ms = 5000.0; // 5 second max
-        let idle_timeout_ms = 300.0; // 300ms idle timeout
-        let empty_timeout_ms = 1000.0; // 1s timeout for empty results
         let mut last_event_time = start;
+        let mut eose_ms: Option<f64> = None;
+        let mut max_gap_ms: Option<f64> = None;
+        let mut cut_short = false;
 
         // Collect events until done
         loop {
@@ -101,11 +849,15 @@ impl RelayPool {
             if elapsed > max_timeout_ms {
                 break; // Max timeout
             }
+            if cutoff.get().is_some_and(|at| now >= at) {
+                cut_short = true;
+                break;
+            }
             if !events.is_empty() && (now - last_event_time) > idle_timeout_ms {
                 break; // Idle timeout after first event
             }
             if events.is_empty() && elapsed > empty_timeout_ms {
-                break; // 1s timeout for empty results
+                break; // Timeout for empty results
             }
             if events.len() >= limit {
                 break; // Limit reached
@@ -117,13 +869,14 @@ impl RelayPool {
             } else {
                 idle_timeout_ms.min(max_timeout_ms - elapsed)
             };
+            let remaining = cutoff.get().map_or(remaining, |at| remaining.min(at - now));
 
             if remaining <= 0.0 {
                 break;
             }
 
             // Race between next message and timeout
-            let next_msg = event_stream.next();
+            let next_msg = inbox.next();
             let timeout = Self::sleep_ms(remaining.min(500.0) as u32); // Check every 500ms max
 
             // Use select to race timeout vs message
@@ -134,35 +887,33 @@ impl RelayPool {
             .await;
 
             match result {
-                futures_util::future::Either::Left((msg_result, _)) => {
-                    // Got a message
-                    match msg_result {
-                        Some(Ok(WebsocketEvent::Message(msg))) => {
-                            if let Some(text) = msg.text() {
-                                if let Ok(parsed) =
-                                    serde_json::from_str::<Vec<serde_json::Value>>(&text)
-                                {
-                                    if parsed.len() >= 2 {
-                                        match parsed[0].as_str() {
-                                            Some("EVENT") if parsed.len() >= 3 => {
-                                                events.push(parsed[2].clone());
-                                                last_event_time = js_sys::Date::now();
-                                            }
-                                            Some("EOSE") => break,
-                                            Some("NOTICE") => {
-                                                console_log!("Relay notice: {:?}", parsed.get(1));
-                                            }
-                                            _ => {}
-                                        }
-                                    }
-                                }
-                            }
+                futures_util::future::Either::Left((message, _)) => match message {
+                    Some(RelayMessage::Event { event, .. }) => {
+                        let now = js_sys::Date::now();
+                        if !events.is_empty() {
+                            let gap = now - last_event_time;
+                            max_gap_ms = Some(max_gap_ms.map_or(gap, |m: f64| m.max(gap)));
                         }
-                        Some(Ok(WebsocketEvent::Close(_))) => break,
-                        Some(Err(_)) => break,
-                        None => break,
+                        events.push(event);
+                        last_event_time = now;
                     }
-                }
+                    Some(RelayMessage::Eose { .. }) => {
+                        eose_ms = Some(js_sys::Date::now() - start);
+                        break;
+                    }
+                    // Only refusals the handshake couldn't get past reach the query
+                    Some(RelayMessage::Closed { message, .. }) => {
+                        if relay_auth::is_auth_required(&message) {
+                            self.log_auth_refusal(relay_url, &message);
+                        } else {
+                            console_log!("Relay closed query: {}", message);
+                        }
+                        break;
+                    }
+                    Some(_) => {}
+                    // The connection went away
+                    None => break,
+                },
                 futures_util::future::Either::Right((_, _)) => {
                     // Timeout - continue loop to re-check timeouts
                     continue;
@@ -170,11 +921,21 @@ impl RelayPool {
             }
         }
 
-        // Send CLOSE
-        let close_msg = serde_json::json!(["CLOSE", sub_id]);
-        let _ = ws.send_with_str(&close_msg.to_string());
+        self.unsubscribe(&connection, &sub_id);
+
+        // Silence on the whole connection, not just this query: reconnect next time
+        let answered = eose_ms.is_some() || !events.is_empty();
+        if !answered && !cut_short && connection.last_frame_at.get() < start {
+            self.forget_connection(&connection);
+        }
+
+        // Feed the tuner: EOSE latency when seen, and empty timeouts as such. A
+        // query cut short says nothing about how fast the relay is.
+        if !cut_short && (eose_ms.is_some() || events.is_empty()) {
+            self.record_latency(relay_url, eose_ms, max_gap_ms).await;
+        }
 
-        Ok(events)
+        Ok((events, answered))
     }
 
     /// Sleep for specified milliseconds using JS setTimeout
@@ -190,6 +951,14 @@ impl RelayPool {
         let _ = JsFuture::from(promise).await;
     }
 
+    /// A relay kept refusing us for lack of NIP-42 auth
+    fn log_auth_refusal(&self, relay_url: &str, message: &str) {
+        match &self.gateway_key {
+            Some(key) => console_log!("Relay {} refused gateway pubkey {}: {}", relay_url, key.pubkey(), message),
+            None => console_log!("Relay {} requires NIP-42 auth; set GATEWAY_SECRET_KEY: {}", relay_url, message),
+        }
+    }
+
     async fn publish_to_relay(&self, event: &serde_json::Value) -> Result<bool> {
         let relay_url = self.get_relay_url();
 
@@ -202,8 +971,9 @@ impl RelayPool {
         let mut event_stream = ws.events()?;
 
         // Send EVENT message
-        let event_msg = serde_json::json!(["EVENT", event]);
-        ws.send_with_str(&event_msg.to_string())?;
+        let event_msg = serde_json::json!(["EVENT", event]).to_string();
+        ws.send_with_str(&event_msg)?;
+        let mut handshake = Handshake::new(self.gateway_key.as_ref());
 
         // Wait for OK response
         let start = js_sys::Date::now();
@@ -216,13 +986,29 @@ impl RelayPool {
 
             match event_stream.next().await {
                 Some(Ok(WebsocketEvent::Message(msg))) => {
-                    if let Some(text) = msg.text() {
-                        if let Ok(parsed) = serde_json::from_str::<Vec<serde_json::Value>>(&text) {
-                            if parsed.get(0).and_then(|v| v.as_str()) == Some("OK") {
-                                let accepted = parsed.get(2).and_then(|v| v.as_bool()).unwrap_or(false);
-                                return Ok(accepted);
+                    match msg.text().as_deref().and_then(relay_message::parse) {
+                        Some(RelayMessage::Auth { challenge }) => {
+                            if let Some(reply) = handshake.challenge(&relay_url, &challenge, now_seconds()) {
+                                ws.send_with_str(&reply)?;
+                            }
+                        }
+                        Some(RelayMessage::Ok { event_id, accepted, .. }) if handshake.is_auth_event(&event_id) => {
+                            match handshake.auth_ok(accepted) {
+                                Step::Resend => ws.send_with_str(&event_msg)?,
+                                Step::GiveUp => return Ok(false),
+                                Step::Continue => {}
+                            }
+                        }
+                        Some(RelayMessage::Ok { accepted: false, message, .. })
+                            if relay_auth::is_auth_required(&message) =>
+                        {
+                            if handshake.auth_required() == Step::GiveUp {
+                                self.log_auth_refusal(&relay_url, &message);
+                                return Ok(false);
                             }
                         }
+                        Some(RelayMessage::Ok { accepted, .. }) => return Ok(accepted),
+                        _ => {}
                     }
                 }
                 Some(Ok(WebsocketEvent::Close(_))) | Some(Err(_)) | None => return Ok(false),
@@ -232,11 +1018,100 @@ impl RelayPool {
 
     async fn verify_event(&self, event_id: &str) -> Result<bool> {
         let filter = format!(r#"{{"ids":["{}"],"limit":1}}"#, event_id);
-        let events = self.query_relay_raw(&filter).await?;
+        // Publishes go to this instance's relay, so that's the one to read back from
+        let (events, answered) = self.query_one(&self.get_relay_url(), &filter, &Cell::new(None)).await?;
+        self.record_outcome(answered);
         Ok(!events.is_empty())
     }
 }
 
+/// A relay socket and the subscriptions multiplexed over it. A reader task hands
+/// each frame to its subscription's inbox and answers NIP-42 challenges, holding
+/// refused subscriptions until the handshake is done.
+struct Connection {
+    relay_url: String,
+    ws: WebSocket,
+    mux: Rc<Mux>,
+    /// When the relay last sent anything
+    last_frame_at: Rc<Cell<f64>>,
+}
+
+impl Connection {
+    async fn open(relay_url: &str, gateway_key: Option<GatewayKey>) -> Result<Rc<Self>> {
+        let url = relay_url.parse().map_err(|_| "Invalid relay URL")?;
+        let ws = WebSocket::connect(url).await?;
+        ws.accept()?;
+        let connection = Rc::new(Self {
+            relay_url: relay_url.to_string(),
+            ws: ws.clone(),
+            mux: Rc::new(Mux::default()),
+            last_frame_at: Rc::new(Cell::new(js_sys::Date::now())),
+        });
+
+        // Spawned tasks run before the next incoming frame is dispatched, so the
+        // reader is listening before the relay's first message (a NIP-42 challenge)
+        let mux = connection.mux.clone();
+        let last_frame_at = connection.last_frame_at.clone();
+        let relay_url = relay_url.to_string();
+        wasm_bindgen_futures::spawn_local(async move {
+            match ws.events() {
+                Ok(mut frames) => {
+                    let mut handshake = Handshake::new(gateway_key.as_ref());
+                    while let Some(Ok(WebsocketEvent::Message(msg))) = frames.next().await {
+                        last_frame_at.set(js_sys::Date::now());
+                        let Some(message) = msg.text().as_deref().and_then(relay_message::parse) else {
+                            continue;
+                        };
+                        match message {
+                            RelayMessage::Auth { challenge } => {
+                                if let Some(reply) = handshake.challenge(&relay_url, &challenge, now_seconds()) {
+                                    let _ = ws.send_with_str(&reply);
+                                }
+                            }
+                            RelayMessage::Ok { event_id, accepted, .. } if handshake.is_auth_event(&event_id) => {
+                                match handshake.auth_ok(accepted) {
+                                    Step::Resend => {
+                                        for req in mux.release_held() {
+                                            let _ = ws.send_with_str(&req);
+                                        }
+                                    }
+                                    Step::GiveUp => {
+                                        console_log!("Relay {} rejected the gateway's NIP-42 auth", relay_url);
+                                        mux.refuse_held("auth-required: the relay rejected the gateway's auth");
+                                    }
+                                    Step::Continue => {}
+                                }
+                            }
+                            RelayMessage::Closed { subscription, message }
+                                if relay_auth::is_auth_required(&message) && handshake.auth_required() == Step::Continue =>
+                            {
+                                mux.hold(&subscription);
+                            }
+                            RelayMessage::Notice(notice) => console_log!("Relay notice: {}", notice),
+                            message => {
+                                mux.route(message);
+                            }
+                        }
+                    }
+                }
+                Err(e) => console_log!("Relay connection to {} unreadable: {}", relay_url, e),
+            }
+            mux.shutdown();
+        });
+        Ok(connection)
+    }
+
+    /// Still open, and either in use or heard from recently enough to trust
+    fn is_usable(&self, now: f64) -> bool {
+        !self.mux.is_closed() && (self.mux.active() > 0 || now - self.last_frame_at.get() <= IDLE_MAX_AGE_MS)
+    }
+
+    fn close(&self, reason: &str) {
+        let _ = self.ws.close(Some(1000), Some(reason));
+        self.mux.shutdown();
+    }
+}
+
 #[derive(Deserialize)]
 struct VerifyRequest {
     event_id: String,
@@ -1,174 +1,2509 @@
 // ABOUTME: HTTP request routing for the REST gateway
 // ABOUTME: Routes requests to appropriate handlers based on path and method
 
-use crate::cache::Cache;
-use crate::filter::Filter;
-use crate::types::{ErrorResponse, QueryResponse};
+use crate::aggregate::{self, AggregateKind};
+use crate::api_keys::{self, Caller, Scope, TierLimits};
+use crate::archive::{self, Archive, ReadFirst, MAX_FOLLOWERS_PAGE};
+use crate::auth::{AnyOf, ApiKeyAuth, AuthError, AuthProvider, AuthRequest, Jwt, Nip98, Principal};
+use crate::batch;
+use crate::bot::{BotAction, BotPolicies, BotSignals};
+use crate::branding::{self, Branding};
+use crate::cache::{now_seconds, Cache};
+use crate::circuit;
+use crate::custom_routes;
+use crate::decision_log::{self, Decision, Layer, Outcome};
+use crate::deletions::{self, Deletions, RequestDeletions};
+use crate::degraded::{self, DegradedMode};
+use crate::embed::{escape_html, render_embed, EmbedCard};
+use crate::etag;
+use crate::expiration;
+use crate::filter::{check_fields, event_involves, is_hex64, Filter, FilterError, FilterSet};
+use crate::hooks::{Hooks, RequestContext};
+use crate::html_cache::{self, HtmlSurface};
+use crate::http_date;
+use crate::jwt::JwtConfig;
+use crate::kind;
+use crate::media::{picture_from_event, video_from_event, PICTURE_KIND, VIDEO_KINDS};
+use crate::metrics::Observation;
+use crate::mirror::{self, MirrorConfig};
+use crate::nwc;
+use crate::publish_acl;
+use crate::publish_ledger::{self, Transition};
+use crate::publish_state::PublishState;
+use crate::presets;
+use crate::push::{self, PushRegistration, Vapid};
+use crate::quarantine::{PublishSignals, QuarantineEntry, QuarantinePolicy};
+use crate::quota;
+use crate::rate_limit::{self, RateLimitConfig};
+use crate::references;
+use crate::relay_info::RelayInfo;
+use crate::session::{self, Session, SessionAuth};
+use crate::signed_url;
+use crate::ttl::{KindTtls, TtlBounds, TtlOverride};
+use crate::turnstile::Turnstile;
+use crate::viewer::MuteList;
+use crate::webhooks::{self, Webhook};
+use crate::write_behind::{self, WriteBehindConfig};
+use crate::types::{
+    AggregateResponse, ErrorResponse, EventsRequest, EventsResponse, FeedResponse, FilterValidateRequest,
+    FilterValidateResponse, FollowerExportLine, InfoResponse,
+    MuteListResponse, NwcRequest, NwcResponse, PicturesResponse, ProfileMetadata, ProfilesRequest, ProfilesResponse, PushRegistrationRequest, QueryExplainResponse, QueryResponse,
+    QuerySource, QuotaResponse, ReferenceType, ReferencedByResponse, ReferencesResponse, RelayInfoResponse, SessionRequest,
+    SessionResponse, StatsResponse, StatusResponse, VapidKeyResponse, VideosResponse, WebhookRequest, WebhookResponse, WebhooksResponse,
+};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
 use worker::*;
 
-pub async fn handle_request(req: Request, env: Env) -> Result<Response> {
+/// Deletions from one relay answer applied behind the response
+const MAX_APPLIED_DELETIONS: usize = 20;
+
+/// Reference previews resolved per /event/{id}/references response
+const MAX_RESOLVED_REFERENCES: usize = 50;
+
+/// `a` references each cost a query, so fewer of them are resolved
+const MAX_RESOLVED_ADDRESSES: usize = 5;
+
+/// Requests the bot policy throttles count this many times against rate limits
+const THROTTLED_REQUEST_COST: u64 = 4;
+
+/// A cache hit this far through its TTL (in percent) is refreshed behind the
+/// response, so entries that keep being read never expire in front of a client
+const REFRESH_AHEAD_PERCENT: u64 = 80;
+
+/// How long browsers and CDNs keep a found event before rechecking it. Deletions
+/// are the one change an event sees, and they should reach clients within minutes.
+const FOUND_EVENT_MAX_AGE_SECONDS: u64 = 300;
+
+/// Current API version; its routes are also served without the `/v1` prefix
+const API_VERSION: u32 = 1;
+
+/// A request path with the version prefix removed, as routes are matched
+pub(crate) fn unversioned(path: &str) -> &str {
+    match path.strip_prefix("/v1") {
+        Some("") => "/",
+        Some(rest) if rest.starts_with('/') => rest,
+        _ => path,
+    }
+}
+
+pub async fn handle_request(req: Request, env: Env, ctx: &Context) -> Result<Response> {
     let url = req.url()?;
-    let path = url.path();
+    let path = unversioned(url.path());
     let method = req.method();
+    let if_none_match = req.headers().get("If-None-Match").ok().flatten();
+    let if_modified_since = req.headers().get("If-Modified-Since").ok().flatten();
 
     // Handle CORS preflight
     if method == Method::Options {
         return cors_preflight();
     }
 
+    // HEAD on cacheable reads is answered as the GET, minus the body
+    let head = method == Method::Head && supports_head(path);
+    let method = if head { Method::Get } else { method };
+
+    // A sample of public reads is replayed against the deployment under test
+    let mirror_target = MirrorConfig::from_env(&env)
+        .filter(|config| js_sys::Math::random() < config.sample_rate)
+        .filter(|_| !head && req.headers().get(mirror::HEADER).ok().flatten().is_none())
+        .filter(|_| mirror::is_eligible(&method, path, req.headers().has("Authorization").unwrap_or(false)))
+        .map(|config| config.mirror_url(&url));
+
+    // Landing page, health and status checks, metrics scrapes, operator calls and
+    // mirror syncs are never gated
+    let ungated = matches!(path, "/" | "/health" | "/status" | "/metrics")
+        || path.starts_with("/admin/")
+        || path.starts_with("/sync/");
+    // Operator-signed deep links get past bot gating, cache bypass included
+    let signed = match signed_url::verify(signed_url::secret(&env).as_deref(), &url, now_seconds()) {
+        Ok(signed) => signed,
+        Err(e) => {
+            let err = ErrorResponse::new("invalid_signature").with_detail(&e.to_string());
+            return add_cors_headers(json_response(&err, 403));
+        }
+    };
+    let bot_action = if ungated || signed {
+        BotAction::Allow
+    } else {
+        BotPolicies::from_env(&env)
+            .for_path(path)
+            .decide(&BotSignals::from_request(&req))
+    };
+    let bot_action = match bot_action {
+        BotAction::Block => {
+            let err = ErrorResponse::new("forbidden").with_detail("automated traffic is not allowed on this route");
+            return add_cors_headers(json_response(&err, 403));
+        }
+        // A solved Turnstile widget lets the request through as a human's
+        BotAction::Challenge => match Turnstile::from_request(&env, &req) {
+            Some(turnstile) => match turnstile.verify().await {
+                Ok(()) => BotAction::Allow,
+                Err(e) => {
+                    let err = ErrorResponse::new("challenge_required").with_detail(&e.to_string());
+                    return add_cors_headers(json_response(&err, 403));
+                }
+            },
+            None => {
+                let err = ErrorResponse::new("challenge_required").with_detail("request looks automated");
+                return add_cors_headers(json_response(&err, 403));
+            }
+        },
+        action => action,
+    };
+
+    // Deployment-specific policies compiled in and enabled through GATEWAY_HOOKS
+    let hooks = Rc::new(Hooks::from_env(&env, RequestContext::from_request(&req, path)));
+    if let Some(rejection) = hooks.check_request().filter(|_| !ungated) {
+        console_log!("Hook {} rejected {}", rejection.hook, hooks.context());
+        let err = ErrorResponse::new(rejection.error).with_detail(&rejection.detail);
+        return add_cors_headers(json_response(&err, rejection.status));
+    }
+
+    // API key tier; requests without a key are served as the free tier
+    let caller = if ungated {
+        Caller::default()
+    } else {
+        match api_keys::resolve(&req, &env).await? {
+            Some(caller) => caller,
+            None => {
+                let err = ErrorResponse::new("invalid_api_key").with_detail("unknown API key");
+                return add_cors_headers(json_response(&err, 401));
+            }
+        }
+    };
+    // A key scoped only to publishing doesn't unlock its tier for reads
+    let publishing = path == "/publish" || (method == Method::Delete && path.starts_with("/publish/"));
+    if caller.key.is_some() && !publishing && !caller.allows(Scope::Read) {
+        let err = ErrorResponse::new("insufficient_scope").with_detail("API key lacks the read scope");
+        return add_cors_headers(json_response(&err, 403));
+    }
+    let limits = caller.limits().capped(api_keys::max_query_limit(&env));
+
+    // Per-client request rates, counted by address and by NIP-98 signer
+    let rate_config = RateLimitConfig::from_env(&env);
+    let rate = if ungated || !rate_config.enabled() {
+        None
+    } else {
+        let ip = req.headers().get("CF-Connecting-IP").ok().flatten();
+        let pubkey = signer_pubkey(&req, &method);
+        let cost = if method == Method::Get && path == "/query" { query_cost(&url, limits) } else { 1 };
+        // Likely scrapers use up their budget faster than the clients sharing their address
+        let cost = if bot_action == BotAction::Throttle { cost * THROTTLED_REQUEST_COST } else { cost };
+        rate_limit::check(&env, &rate_config, ip.as_deref(), pubkey.as_deref(), cost).await
+    };
+    if let Some(decision) = rate.filter(|d| !d.allowed) {
+        let err = ErrorResponse::new("rate_limited")
+            .with_detail("too many requests; slow down")
+            .with_retry_after(decision.retry_after as u32);
+        let mut resp = json_response(&err, 429)?;
+        decision.set_headers(resp.headers_mut())?;
+        return add_cors_headers(Ok(resp));
+    }
+
+    // During planned relay maintenance only cached and archived data is served.
+    // Read once here, so every lookup this request makes agrees on the mode.
+    let degraded = degraded::get(&env).await;
+    let scope = ReadScope {
+        degraded: degraded.clone(),
+        hooks: hooks.clone(),
+        deletions: Rc::default(),
+        signed,
+    };
+    if let Some(mode) = &degraded {
+        if matches!((&method, path), (Method::Get, "/ws") | (Method::Post, "/publish")) {
+            return add_cors_headers(degraded_response(mode));
+        }
+    }
+
+    // Upgrade responses go back untouched; CORS headers don't apply to WebSockets
+    if method == Method::Get && path == "/ws" {
+        if bot_action == BotAction::Throttle {
+            // A live socket is uncached relay access, which throttled traffic doesn't get
+            let err = ErrorResponse::new("forbidden").with_detail("live subscriptions are not available to automated traffic");
+            return add_cors_headers(json_response(&err, 403));
+        }
+        return handle_ws(req, env, &caller, limits, hooks.context()).await;
+    }
+
     let response = match (method, path) {
-        (Method::Get, "/") => landing_page(),
+        (Method::Get, "/") => landing_page(&branding::get(&env, url.host_str().unwrap_or_default()).await),
+
+        (Method::Get, "/health") => Response::ok(if degraded.is_some() { "degraded" } else { "ok" }),
+
+        (Method::Get, "/status") => handle_status(degraded.as_ref()),
+
+        (Method::Get, "/metrics") => handle_metrics(req, env).await,
+
+        (Method::Get, "/query") => handle_query(req, env, ctx, &scope, bot_action, &caller, limits).await,
+
+        (Method::Get, "/query/explain") => handle_query_explain(req, env, &scope, limits).await,
+
+        (Method::Post, "/filter/validate") => handle_filter_validate(req, env, limits).await,
+
+        (Method::Get, "/info") => handle_info(&caller, limits),
+
+        (Method::Get, "/quota") => handle_quota(req, env).await,
+
+        (Method::Post, "/auth/session") => handle_session_create(req, env).await,
+
+        (Method::Delete, "/auth/session") => handle_session_revoke(req, env).await,
+
+        (Method::Get, "/relay-info") => handle_relay_info(req, env).await,
+
+        (Method::Get, "/stats") => handle_stats(env).await,
+
+        (Method::Get, "/count") => handle_count(req, env, &scope).await,
 
-        (Method::Get, "/health") => Response::ok("ok"),
+        (Method::Get, path) if path.starts_with("/reactions/") => {
+            handle_event_aggregate(env, &scope, AggregateKind::Reactions, &path[11..]).await
+        }
+
+        (Method::Get, path) if path.starts_with("/zaps/") => {
+            handle_event_aggregate(env, &scope, AggregateKind::Zaps, &path[6..]).await
+        }
+
+        (Method::Get, "/openapi.json") => {
+            json_response_with_cache(&crate::openapi::document(), 200, TtlBounds::from_env(&env).apply(3600))
+        }
 
-        (Method::Get, "/query") => handle_query(req, env).await,
+        (Method::Get, path) if path.starts_with("/profile/") && path.ends_with("/followers/export") => {
+            let pubkey = path.trim_start_matches("/profile/").trim_end_matches("/followers/export");
+            handle_follower_export(req, env, &caller, limits, pubkey).await
+        }
 
         (Method::Get, path) if path.starts_with("/profile/") => {
-            handle_profile(req, env, &path[9..]).await
+            handle_profile(env, ctx, &scope, &path[9..], bot_action).await
+        }
+
+        (Method::Get, path) if path.starts_with("/event/") && path.ends_with("/references") => {
+            let id = path.trim_start_matches("/event/").trim_end_matches("/references");
+            handle_references(env, ctx, &scope, id).await
+        }
+
+        (Method::Get, path) if path.starts_with("/event/") && path.ends_with("/referenced-by") => {
+            let id = path.trim_start_matches("/event/").trim_end_matches("/referenced-by");
+            handle_referenced_by(req, env, id).await
         }
 
         (Method::Get, path) if path.starts_with("/event/") => {
-            handle_event(req, env, &path[7..]).await
+            handle_event(env, ctx, &scope, &path[7..], bot_action).await
+        }
+
+        (Method::Get, path) if path.starts_with("/video/") => handle_video(env, ctx, &scope, &path[7..]).await,
+
+        (Method::Get, "/pictures") => handle_pictures(req, env, ctx, &scope).await,
+
+        (Method::Get, path) if path.starts_with("/videos/") => {
+            handle_videos(req, env, ctx, &scope, &path[8..]).await
+        }
+
+        (Method::Get, path) if path.starts_with("/feeds/") => {
+            handle_feed(req, env, ctx, &scope, &path[7..], limits).await
+        }
+
+        (Method::Get, path) if path.starts_with("/mutes/") => handle_mutes(env, ctx, &scope, &path[7..]).await,
+
+        (Method::Get, path) if path.starts_with("/embed/") => {
+            handle_embed(req, env, ctx, &scope, &path[7..]).await
+        }
+
+        (Method::Get, path) if path.starts_with("/publish/status/") => {
+            handle_publish_status(env, &path[16..]).await
+        }
+
+        (Method::Post, "/publish") => handle_publish(req, env, ctx, &caller).await,
+
+        (Method::Delete, path) if path.starts_with("/publish/") => handle_publish_cancel(req, env).await,
+
+        (Method::Post, "/profiles") => handle_profiles(req, env, ctx, &scope, bot_action).await,
+
+        (Method::Get, "/events") | (Method::Post, "/events") => handle_events(req, env, ctx, &scope, bot_action).await,
+
+        (Method::Get, "/webhooks") | (Method::Post, "/webhooks") => handle_webhooks(req, env).await,
+
+        (Method::Post, "/nwc") => handle_nwc(req, bot_action).await,
+
+        (Method::Get, "/push/vapid-public-key") => handle_vapid_key(env),
+
+        (Method::Post, "/push/subscriptions") => handle_push_register(req, env).await,
+
+        (Method::Delete, path) if path.starts_with("/push/subscriptions/") => {
+            handle_push_unregister(req, env).await
+        }
+
+        (Method::Delete, path) if path.starts_with("/webhooks/") => handle_webhooks(req, env).await,
+
+        (_, path) if path.starts_with("/admin/") => crate::admin::handle_admin(req, env).await,
+
+        (_, path) if path.starts_with("/sync/") => crate::cache_sync::handle(req, env).await,
+
+        _ => {
+            let err = ErrorResponse::new("not_found").with_detail("endpoint not found");
+            json_response(&err, 404)
+        }
+    };
+
+    // Reads that needed the relay while it was switched off
+    let response = match (response, &degraded) {
+        (Err(e), Some(mode)) if degraded::is_relay_unavailable(&e) => degraded_response(mode),
+        (Err(e), _) if circuit::is_circuit_open(&e) => circuit_open_response(),
+        (response, _) => response,
+    };
+
+    let response = response.and_then(|mut resp| {
+        hooks.finish_response(&mut resp)?;
+        if let Some(decision) = &rate {
+            decision.set_headers(resp.headers_mut())?;
+        }
+        Ok(resp)
+    });
+    let response = response.and_then(|resp| not_modified(resp, if_none_match.as_deref(), if_modified_since.as_deref()));
+    let mut response = if head { response.and_then(without_body) } else { response };
+
+    if let (Some(target), Ok(resp)) = (mirror_target, response.as_mut()) {
+        ctx.wait_until(mirror::run(target, path.to_string(), resp.cloned()?));
+    }
+
+    // Add CORS headers to all responses
+    add_cors_headers(response)
+}
+
+fn supports_head(path: &str) -> bool {
+    let export = path.ends_with("/export");
+    path == "/query" || (path.starts_with("/profile/") && !export) || path.starts_with("/event/")
+}
+
+/// `304 Not Modified` with the same headers and no body, when the client already
+/// holds what a 200 carries. As RFC 9110 has it, `If-Modified-Since` is only
+/// looked at when there's no `If-None-Match`.
+fn not_modified(response: Response, if_none_match: Option<&str>, if_modified_since: Option<&str>) -> Result<Response> {
+    if response.status_code() != 200 {
+        return Ok(response);
+    }
+    let unchanged = match (if_none_match, if_modified_since) {
+        (Some(tags), _) => response.headers().get("ETag")?.is_some_and(|etag| etag::matches(tags, &etag)),
+        (None, Some(since)) => {
+            let last_modified = response.headers().get("Last-Modified")?.and_then(|date| http_date::parse(&date));
+            matches!((last_modified, http_date::parse(since)), (Some(modified), Some(since)) if modified <= since)
+        }
+        (None, None) => false,
+    };
+    if !unchanged {
+        return Ok(response);
+    }
+    let headers = response.headers().clone();
+    Ok(Response::empty()?.with_status(304).with_headers(headers))
+}
+
+/// Same status and headers, empty body
+fn without_body(response: Response) -> Result<Response> {
+    let status = response.status_code();
+    let headers = response.headers().clone();
+    Ok(Response::empty()?.with_status(status).with_headers(headers))
+}
+
+/// 503 telling clients when to come back
+fn degraded_response(mode: &DegradedMode) -> Result<Response> {
+    let err = ErrorResponse::new("degraded")
+        .with_detail(&mode.reason)
+        .with_retry_after(mode.retry_after);
+    let mut resp = json_response(&err, 503)?;
+    resp.headers_mut().set("Retry-After", &mode.retry_after.to_string())?;
+    Ok(resp)
+}
+
+/// The relay keeps failing and the request had no archive to fall back on
+fn circuit_open_response() -> Result<Response> {
+    let retry_after = (circuit::COOLDOWN_MS / 1000.0) as u32;
+    let err = ErrorResponse::new("relay_unavailable")
+        .with_detail("the upstream relay is failing; try again shortly")
+        .with_retry_after(retry_after);
+    let mut resp = json_response(&err, 503)?;
+    resp.headers_mut().set("Retry-After", &retry_after.to_string())?;
+    Ok(resp)
+}
+
+fn cors_preflight() -> Result<Response> {
+    let mut headers = Headers::new();
+    headers.set("Access-Control-Allow-Origin", "*")?;
+    headers.set("Access-Control-Allow-Methods", "GET, HEAD, POST, DELETE, OPTIONS")?;
+    headers.set("Access-Control-Allow-Headers", "Content-Type, Authorization, X-Api-Key, CF-Turnstile-Response, If-None-Match, If-Modified-Since")?;
+    headers.set("Access-Control-Max-Age", "86400")?;
+    Ok(Response::empty()?.with_status(204).with_headers(headers))
+}
+
+/// Hand the upgrade to the RelayPool DO, which owns the upstream relay socket,
+/// holds each client to its tier's `sse_concurrency` open sockets and runs the
+/// deployment's hooks on the events it forwards
+async fn handle_ws(req: Request, env: Env, caller: &Caller, limits: TierLimits, hook_ctx: &RequestContext) -> Result<Response> {
+    if !req.headers().get("Upgrade")?.is_some_and(|v| v.eq_ignore_ascii_case("websocket")) {
+        let err = ErrorResponse::new("upgrade_required").with_detail("connect with a WebSocket client");
+        return add_cors_headers(json_response(&err, 426));
+    }
+    // Keyed clients are counted by key, anonymous ones by address
+    let client = match &caller.key {
+        Some(key) => format!("key:{}", key.id),
+        None => format!("ip:{}", req.headers().get("CF-Connecting-IP")?.unwrap_or_default()),
+    };
+    let mut headers = Headers::new();
+    for (name, value) in req.headers().entries() {
+        headers.set(&name, &value)?;
+    }
+    headers.set(crate::relay_pool::STREAM_CLIENT_HEADER, &client)?;
+    headers.set(crate::relay_pool::STREAM_LIMIT_HEADER, &limits.sse_concurrency.to_string())?;
+    if let Some(country) = &hook_ctx.country {
+        headers.set(crate::relay_pool::STREAM_COUNTRY_HEADER, country)?;
+    }
+    let upgrade = Request::new_with_init(req.url()?.as_str(), RequestInit::new().with_headers(headers))?;
+
+    let stub = env.durable_object("RELAY_POOL")?.id_from_name("default")?.get_stub()?;
+    let resp = stub.fetch_with_request(upgrade).await?;
+    if resp.status_code() == 429 {
+        let detail = format!("at most {} live streams at once on this tier", limits.sse_concurrency);
+        let err = ErrorResponse::new("too_many_streams").with_detail(&detail);
+        return add_cors_headers(json_response(&err, 429));
+    }
+    Ok(resp)
+}
+
+/// Response headers browser clients may read
+const EXPOSED_HEADERS: &str = "Age, ETag, Last-Modified, Retry-After, X-Cache, X-RateLimit-Limit, X-RateLimit-Remaining, X-RateLimit-Reset";
+
+fn add_cors_headers(response: Result<Response>) -> Result<Response> {
+    let mut resp = response?;
+    let headers = resp.headers_mut();
+    headers.set("Access-Control-Allow-Origin", "*")?;
+    headers.set("Access-Control-Allow-Methods", "GET, HEAD, POST, DELETE, OPTIONS")?;
+    headers.set("Access-Control-Allow-Headers", "Content-Type, Authorization, X-Api-Key, CF-Turnstile-Response, If-None-Match, If-Modified-Since")?;
+    headers.set("Access-Control-Expose-Headers", EXPOSED_HEADERS)?;
+    headers.set("API-Version", &API_VERSION.to_string())?;
+    Ok(resp)
+}
+
+async fn handle_query(
+    req: Request,
+    env: Env,
+    ctx: &Context,
+    scope: &ReadScope,
+    bot_action: BotAction,
+    caller: &Caller,
+    limits: TierLimits,
+) -> Result<Response> {
+    let url = req.url()?;
+    let params: std::collections::HashMap<_, _> = url.query_pairs().collect();
+
+    let (filter, preset) = match request_filter(&env, &url).await? {
+        Ok(parsed) => parsed,
+        Err(resp) => return Ok(resp),
+    };
+    let (filter, rejection) = prepare_filter(&env, filter, preset, limits);
+    if let Some((error, e)) = rejection {
+        let err = ErrorResponse::new(error).with_detail(&e.to_string());
+        return json_response(&err, 400);
+    }
+
+    // Optional NIP-98 or session auth identifies the viewer, whose mute list is applied
+    let viewer = match request_viewer(&req, &env).await {
+        Ok(viewer) => viewer,
+        Err(e) => {
+            let err = ErrorResponse::new("auth_failed").with_detail(&e.to_string());
+            return json_response(&err, 401);
+        }
+    };
+    if let Some(viewer) = &viewer {
+        if let Some(resp) = charge_quota(&env, viewer, quota::Action::Read).await {
+            return resp;
+        }
+    }
+    let mutes = match &viewer {
+        Some(viewer) => Some(viewer_mutes(&env, ctx, scope, viewer).await),
+        None => None,
+    };
+    let mutes = match with_applied_mutes(&env, ctx, scope, mutes, params.get("apply_mutes").map(|p| p.as_ref())).await {
+        Ok(m) => m,
+        Err(detail) => return json_response(&ErrorResponse::new("invalid_pubkey").with_detail(detail), 400),
+    };
+
+    if filter.requests_private_kinds() {
+        let options = QueryOptions { mutes, limits, ..QueryOptions::new(scope, bot_action) };
+        return private_query(&env, &filter, viewer.as_deref(), &options).await;
+    }
+
+    // ?source=archive answers only from the archive, ?source=relay forces a live query
+    let source = match params.get("source").map(|s| s.as_ref()) {
+        None => None,
+        Some("archive") => Some(QuerySource::Archive),
+        Some("relay") => Some(QuerySource::Relay),
+        Some(_) => {
+            let err = ErrorResponse::new("invalid_source").with_detail("source must be 'archive' or 'relay'");
+            return json_response(&err, 400);
+        }
+    };
+
+    let authenticated = viewer.is_some() || caller.key.is_some();
+
+    // ?ttl= asks for fresher (or longer-lived) results than the filter's own TTL
+    let ttl = match params.get("ttl").map(|t| t.parse::<u64>()) {
+        None => None,
+        Some(Ok(requested)) => {
+            let ttl = TtlOverride::from_env(&env).apply(requested, cache_ttl(&env, &filter), authenticated);
+            Some(TtlBounds::from_env(&env).apply(ttl))
+        }
+        Some(Err(_)) => {
+            let err = ErrorResponse::new("invalid_ttl").with_detail("ttl must be a number of seconds");
+            return json_response(&err, 400);
+        }
+    };
+
+    // Cache bypass (?cache=bypass, ?nocache=1, or a Cache-Control: no-cache header)
+    // costs a relay query, so it's kept to callers we can identify. Operator-signed
+    // links already vouch for it.
+    let bypass_param = params.get("cache").is_some_and(|v| v == "bypass")
+        || params.get("nocache").is_some_and(|v| v == "1" || v == "true");
+    let bypass_header = req
+        .headers()
+        .get("Cache-Control")
+        .ok()
+        .flatten()
+        .is_some_and(|v| v.contains("no-cache"));
+    let trusted = authenticated || scope.signed;
+    if !trusted && (bypass_param || source == Some(QuerySource::Relay)) {
+        let err = ErrorResponse::new("auth_required")
+            .with_detail("cache bypass needs an API key, NIP-98 or session auth, or a signed URL");
+        return json_response(&err, 401);
+    }
+
+    let options = QueryOptions {
+        source,
+        // Browsers send no-cache on every reload, so from anonymous callers it's ignored
+        nocache: trusted && (bypass_param || bypass_header),
+        bot_action,
+        mutes,
+        limits,
+        archive_fallback: false,
+        ttl,
+        hooks: scope.hooks.clone(),
+        degraded: scope.degraded.clone(),
+        deletions: scope.deletions.clone(),
+    };
+    run_query(&env, ctx, &filter, &options).await
+}
+
+/// How `/query` would handle a filter, worked out without reading the cache or
+/// asking the relay
+async fn handle_query_explain(req: Request, env: Env, scope: &ReadScope, limits: TierLimits) -> Result<Response> {
+    let url = req.url()?;
+    let (filter, preset) = match request_filter(&env, &url).await? {
+        Ok(parsed) => parsed,
+        Err(resp) => return Ok(resp),
+    };
+    let (filter, rejection) = prepare_filter(&env, filter, preset, limits);
+    let rejected = rejection.map(|(_, e)| e.to_string());
+    let relay = match scope.degraded {
+        Some(_) => None,
+        None => Some(relay_url(&env)),
+    };
+    let json = |raw: &str| serde_json::from_str(raw).unwrap_or_default();
+    let response = QueryExplainResponse {
+        filter: json(filter.as_json()),
+        canonical: json(&filter.canonical_json()),
+        cache_key: filter.cache_key(),
+        ttl_class: filter.ttl_class(now_seconds(), &KindTtls::from_env(&env)).label(),
+        ttl_seconds: cache_ttl(&env, &filter),
+        source: if relay.is_some() { QuerySource::Relay } else { QuerySource::Archive },
+        shard: relay.as_ref().map(|_| "default".to_string()),
+        relay,
+        cost: filter.cost(),
+        rejected,
+    };
+    json_response(&response, 200)
+}
+
+/// Decode a filter and report how the gateway reads it: which fields it
+/// understands, what is wrong with them, and how the result would be cached
+async fn handle_filter_validate(mut req: Request, env: Env, limits: TierLimits) -> Result<Response> {
+    let body: FilterValidateRequest = match req.json().await {
+        Ok(b) => b,
+        Err(_) => {
+            let err = ErrorResponse::new("invalid_body").with_detail("expected {\"filter\": {...}}");
+            return json_response(&err, 400);
+        }
+    };
+    let decoded = match &body.filter {
+        serde_json::Value::String(encoded) => Filter::from_base64(encoded),
+        other => Filter::from_json(&other.to_string()),
+    };
+    let value = decoded
+        .map_err(|e| e.to_string())
+        .and_then(|filter| match serde_json::from_str::<serde_json::Value>(filter.as_json()) {
+            Ok(serde_json::Value::Object(object)) => Ok((filter, object)),
+            _ => Err("filter must be a JSON object".to_string()),
+        });
+    let (filter, object) = match value {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            let response = FilterValidateResponse {
+                valid: false,
+                filter: None,
+                recognized: Vec::new(),
+                ignored: Vec::new(),
+                cache_key: None,
+                ttl_class: None,
+                ttl_seconds: None,
+                cost: None,
+                errors: vec![e],
+            };
+            return json_response(&response, 200);
+        }
+    };
+
+    // Checked and capped exactly as /query would, so the key and cost match its own
+    let report = check_fields(&object);
+    let mut errors = report.errors;
+    let (filter, rejection) = prepare_filter(&env, filter, false, limits);
+    errors.extend(rejection.map(|(_, e)| e.to_string()));
+    let response = FilterValidateResponse {
+        valid: errors.is_empty(),
+        filter: serde_json::from_str(filter.as_json()).ok(),
+        recognized: report.recognized,
+        ignored: report.ignored,
+        cache_key: Some(filter.cache_key()),
+        ttl_class: Some(filter.ttl_class(now_seconds(), &KindTtls::from_env(&env)).label()),
+        ttl_seconds: Some(cache_ttl(&env, &filter)),
+        cost: Some(filter.cost()),
+        errors,
+    };
+    json_response(&response, 200)
+}
+
+/// Cap a `/query` filter to the tier's `limit` and check it the way `/query` does.
+/// An unconstrained query would hold the relay connection for its full timeout,
+/// and an expensive one holds it for long. Presets are the operator's call, so
+/// they skip the checks. Returns the capped filter, and the error code and reason
+/// of the first check it fails.
+fn prepare_filter(
+    env: &Env,
+    filter: Filter,
+    preset: bool,
+    limits: TierLimits,
+) -> (Filter, Option<(&'static str, FilterError)>) {
+    let breadth = filter.check_breadth();
+    let filter = filter.with_max_limit(limits.max_limit);
+    if preset {
+        return (filter, None);
+    }
+    let rejection = match breadth {
+        Err(e @ FilterError::PrefixTooShort { .. }) => Some(("prefix_too_short", e)),
+        Err(e) => Some(("filter_too_broad", e)),
+        Ok(()) => filter.check_cost(api_keys::max_query_cost(env)).err().map(|e| ("filter_too_expensive", e)),
+    };
+    (filter, rejection)
+}
+
+/// How many requests a `/query` counts as against the rate limits: its filter's
+/// `cost()` once capped to the tier, so a heavy filter uses up the budget faster
+/// than a lookup. Presets and filters that won't parse count once.
+fn query_cost(url: &Url, limits: TierLimits) -> u64 {
+    let params: std::collections::HashMap<_, _> = url.query_pairs().collect();
+    if params.contains_key("preset") {
+        return 1;
+    }
+    let filter = match params.get("filter") {
+        Some(encoded) => Filter::from_base64(encoded).map(Some),
+        None => {
+            let pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+            Filter::from_query_params(pairs.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+        }
+    };
+    match filter {
+        Ok(Some(filter)) => filter.with_max_limit(limits.max_limit).cost(),
+        _ => 1,
+    }
+}
+
+/// The filter of a `/query` URL, and whether it came from a preset. A filter that
+/// can't be used comes back as the error response to send instead.
+async fn request_filter(env: &Env, url: &Url) -> Result<std::result::Result<(Filter, bool), Response>> {
+    let params: std::collections::HashMap<_, _> = url.query_pairs().collect();
+
+    // A base64url `filter`, or the same fields as plain parameters
+    let filter = match params.get("filter") {
+        Some(encoded) => Filter::from_base64(encoded).map(Some),
+        None => {
+            let pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+            Filter::from_query_params(pairs.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+        }
+    };
+    // ...or an operator preset, which stands alone so every caller shares its cache entry
+    let preset = params.get("preset");
+    let filter = match (preset, filter) {
+        (None, filter) => filter,
+        (Some(name), Ok(None)) => match presets::get(&env.kv("REST_GATEWAY_CACHE")?, name).await? {
+            Some(preset) => preset.to_filter().map(Some),
+            None => {
+                let err = ErrorResponse::new("unknown_preset").with_detail("no preset with that name");
+                return Ok(Err(json_response(&err, 404)?));
+            }
+        },
+        (Some(_), _) => {
+            let err =
+                ErrorResponse::new("invalid_filter").with_detail("preset can't be combined with filter parameters");
+            return Ok(Err(json_response(&err, 400)?));
+        }
+    };
+    let filter = match filter {
+        Ok(Some(f)) => f,
+        Ok(None) => {
+            let err = ErrorResponse::new("invalid_filter").with_detail("missing filter parameter");
+            return Ok(Err(json_response(&err, 400)?));
+        }
+        Err(e) => {
+            let err = ErrorResponse::new("invalid_filter").with_detail(&e.to_string());
+            return Ok(Err(json_response(&err, 400)?));
+        }
+    };
+    Ok(Ok((filter, preset.is_some())))
+}
+
+/// What every read in one request shares, fixed when the request arrives
+struct ReadScope {
+    /// Degraded mode as read at the start of the request
+    degraded: Option<DegradedMode>,
+    /// Deployment hooks that may withhold events
+    hooks: Rc<Hooks>,
+    /// Recent deletions, read once however many lookups the request makes
+    deletions: Rc<RequestDeletions>,
+    /// Whether the URL carries a valid operator signature
+    signed: bool,
+}
+
+/// How a query is answered, decided by the caller rather than read back from a request
+#[derive(Debug, Clone)]
+struct QueryOptions {
+    /// `Archive` answers only from the archive, `Relay` forces a live query
+    source: Option<QuerySource>,
+    /// Skip the cache lookups (the result is still cached)
+    nocache: bool,
+    bot_action: BotAction,
+    /// The authenticated viewer's mute list
+    mutes: Option<MuteList>,
+    /// Caps of the caller's API key tier
+    limits: TierLimits,
+    /// Answer from the archive when the relay circuit is open
+    archive_fallback: bool,
+    /// The caller's bounded `?ttl=`, which is also the oldest cache entry they'll accept
+    ttl: Option<u64>,
+    /// Deployment hooks that may withhold events
+    hooks: Rc<Hooks>,
+    /// Degraded mode as the request found it
+    degraded: Option<DegradedMode>,
+    /// The request's recent deletions
+    deletions: Rc<RequestDeletions>,
+}
+
+impl QueryOptions {
+    /// Options for single-event lookups, which no tier's caps ever bind
+    fn new(scope: &ReadScope, bot_action: BotAction) -> Self {
+        Self {
+            source: None,
+            nocache: false,
+            bot_action,
+            mutes: None,
+            limits: api_keys::Tier::default().limits(),
+            archive_fallback: false,
+            ttl: None,
+            hooks: scope.hooks.clone(),
+            degraded: scope.degraded.clone(),
+            deletions: scope.deletions.clone(),
+        }
+    }
+
+    /// Whether the caller asked for a live answer rather than a cached one
+    fn bypasses_cache(&self) -> bool {
+        self.nocache || self.source == Some(QuerySource::Relay)
+    }
+
+    /// Options for the point lookups that stay up through relay outages
+    fn with_archive_fallback(scope: &ReadScope, bot_action: BotAction) -> Self {
+        Self {
+            archive_fallback: true,
+            ..Self::new(scope, bot_action)
+        }
+    }
+}
+
+/// Answer a filter from the archive, the cache or the relay, as a /query response
+async fn run_query(env: &Env, ctx: &Context, filter: &Filter, options: &QueryOptions) -> Result<Response> {
+    let ttl = options.ttl.unwrap_or_else(|| cache_ttl(env, filter));
+
+    if options.source == Some(QuerySource::Archive) {
+        if filter.search().is_some() {
+            let err = ErrorResponse::new("invalid_filter").with_detail("the archive can't answer NIP-50 searches");
+            return json_response(&err, 400);
+        }
+        let archive = match Archive::from_env(env) {
+            Some(a) => a,
+            None => {
+                let err = ErrorResponse::new("archive_unavailable").with_detail("no archive configured");
+                return json_response(&err, 503);
+            }
+        };
+        return archive_query(env, &archive, filter, ttl, options).await;
+    }
+
+    // Throttled (likely automated) clients can't force relay queries
+    let throttled = options.bot_action == BotAction::Throttle;
+    let skip_cache = !throttled && options.bypasses_cache();
+    // Entries older than a requested TTL are refreshed, unless the caller is throttled
+    let max_age = options.ttl.filter(|_| !throttled);
+
+    let cache = Cache::from_env(env)?;
+    let deleted = options.deletions.get(env).await;
+    let cache_key = filter.cache_key();
+    let lookup = |result| CacheLookup {
+        key: cache_key.clone(),
+        class: filter.ttl_class(now_seconds(), &KindTtls::from_env(env)).label(),
+        result,
+    };
+
+    // Check cache first (unless bypass requested)
+    if skip_cache {
+        decision_log::record(env, &Decision::new(Layer::Kv, cache_key.as_str(), Outcome::Bypass).with_ttl(ttl));
+    } else if let Some((cached, age)) = cache
+        .get_query(&cache_key)
+        .await?
+        .filter(|(_, age)| max_age.map_or(true, |max| *age <= max))
+    {
+        let entry_ttl = cached.ttl(ttl);
+        decision_log::record(env, &Decision::lookup_hit(Layer::Kv, cache_key.as_str(), age, entry_ttl));
+        // Past its TTL, or close to it: answer with it anyway and fetch a fresh copy
+        // behind the response
+        let stale = age > entry_ttl;
+        let expiring = age * 100 >= entry_ttl * REFRESH_AHEAD_PERCENT;
+        if expiring && !throttled {
+            refresh_in_background(env, ctx, filter, ttl, options.degraded.clone());
+        }
+        let response = QueryResponse {
+            events: cached.events,
+            eose: cached.eose,
+            complete: cached.eose,
+            cached: true,
+            stale,
+            cache_age_seconds: Some(age),
+            source: QuerySource::Cache,
+            muted: None,
+            version: API_VERSION,
+        };
+        // A stale answer shouldn't be cached downstream for another full TTL
+        let max_age = if stale { 0 } else { entry_ttl };
+        let result = if stale { "STALE" } else { "HIT" };
+        return query_response(response, filter, max_age, options, &deleted, Some(lookup(result)));
+    } else {
+        decision_log::record(env, &Decision::new(Layer::Kv, cache_key.as_str(), Outcome::Miss).with_ttl(ttl));
+    }
+
+    // Cache miss while degraded: the archive is all that's left, and it can't search
+    if let Some(mode) = &options.degraded {
+        return match Archive::from_env(env).filter(|_| filter.search().is_none()) {
+            Some(archive) => archive_query(env, &archive, filter, ttl, options).await,
+            None => degraded_response(mode),
+        };
+    }
+
+    // Lookups the archive can answer skip the relay round trip
+    let first = Archive::from_env(env).filter(|_| !skip_cache && archive::is_lookup(filter) && archive::reads_first(env));
+    if let Some(store) = first {
+        let events = store.query(filter).await.unwrap_or_else(|e| {
+            console_log!("Archive read failed: {}", e);
+            Vec::new()
+        });
+        let read = archive::read_first(filter, &events);
+        if read != ReadFirst::Relay {
+            decision_log::record(env, &Decision::new(Layer::Archive, cache_key.as_str(), Outcome::Hit).with_ttl(ttl));
+            let complete = read == ReadFirst::Complete;
+            let max_age = if complete {
+                cache_in_background(env, ctx, filter, &events, ttl);
+                ttl
+            } else {
+                // The relay may know newer events; its answer replaces this one in KV
+                refresh_in_background(env, ctx, filter, ttl, options.degraded.clone());
+                archive::PROVISIONAL_TTL_SECONDS.min(ttl)
+            };
+            let response = QueryResponse {
+                events,
+                eose: true,
+                complete,
+                cached: false,
+                stale: false,
+                cache_age_seconds: None,
+                source: QuerySource::Archive,
+                muted: None,
+                version: API_VERSION,
+            };
+            return query_response(response, filter, max_age, options, &deleted, Some(lookup("MISS")));
         }
+    }
+
+    // Cache miss - query relay via Durable Object
+    let events = match query_relay(env, filter, options.degraded.as_ref()).await {
+        Err(e) if circuit::is_circuit_open(&e) && options.archive_fallback => {
+            return match Archive::from_env(env) {
+                Some(archive) => archive_fallback(env, &archive, filter, ttl, options).await,
+                None => Err(e),
+            };
+        }
+        Err(e) => {
+            // Whatever KV still holds beats a 500, however old or bypassed
+            let Some((cached, age)) = cache.get_query(&cache_key).await.ok().flatten() else {
+                return Err(e);
+            };
+            console_log!("Relay query for {} failed, serving stale: {}", cache_key, e);
+            decision_log::record(env, &Decision::new(Layer::Kv, cache_key.as_str(), Outcome::Stale).with_age(age).with_trigger("relay_error"));
+            let response = QueryResponse {
+                events: cached.events,
+                eose: cached.eose,
+                complete: false,
+                cached: true,
+                stale: true,
+                cache_age_seconds: Some(age),
+                source: QuerySource::Cache,
+                muted: None,
+                version: API_VERSION,
+            };
+            return query_response(response, filter, 0, options, &deleted, Some(lookup("STALE")));
+        }
+        Ok(events) => events,
+    };
+
+    // Optionally copy what users read here to the deployment's home relay
+    if let Some(config) = WriteBehindConfig::from_env(env) {
+        let sampled = write_behind::sample(&config, &events, js_sys::Math::random());
+        if !sampled.is_empty() {
+            ctx.wait_until(write_behind::run(env.clone(), config, sampled));
+        }
+    }
+
+    cache_in_background(env, ctx, filter, &events, ttl);
+    apply_deletions_in_background(env, ctx, &events);
+
+    // Empty results are only trusted briefly, downstream too. A bypass answer isn't
+    // kept downstream at all, or the next bypass could be served from the CDN.
+    let max_age = if skip_cache {
+        0
+    } else if events.is_empty() {
+        crate::cache::negative_ttl(ttl)
+    } else {
+        ttl
+    };
+    let response = QueryResponse {
+        events,
+        eose: true,
+        complete: true,
+        cached: false,
+        stale: false,
+        cache_age_seconds: None,
+        source: QuerySource::Relay,
+        muted: None,
+        version: API_VERSION,
+    };
+    let result = if skip_cache { "BYPASS" } else { "MISS" };
+    query_response(response, filter, max_age, options, &deleted, Some(lookup(result)))
+}
+
+/// DMs and gift wraps, served only to a NIP-98 viewer the filter names in `authors`
+/// or `#p`. They come straight from the relay and are never cached or archived.
+async fn private_query(env: &Env, filter: &Filter, viewer: Option<&str>, options: &QueryOptions) -> Result<Response> {
+    let Some(viewer) = viewer else {
+        let err = ErrorResponse::new("auth_required").with_detail("direct messages and gift wraps need NIP-98 auth");
+        return json_response(&err, 401);
+    };
+    if !filter.names_only_pubkey(viewer) {
+        let err = ErrorResponse::new("forbidden").with_detail("authors and #p must name only the authed pubkey");
+        return json_response(&err, 403);
+    }
+    if let Some(mode) = &options.degraded {
+        return degraded_response(mode);
+    }
+
+    decision_log::record(env, &Decision::new(Layer::Kv, filter.cache_key(), Outcome::Bypass));
+    let mut events = relay_pool_query(env, filter).await?;
+    // Whatever the relay answers, only the viewer's own conversations go back
+    events.retain(|event| event_involves(event, viewer));
+    filter.apply_limit(&mut events);
+    options.hooks.filter_events(&mut events);
+    let mut response = QueryResponse {
+        events,
+        eose: true,
+        complete: true,
+        cached: false,
+        stale: false,
+        cache_age_seconds: None,
+        source: QuerySource::Relay,
+        muted: None,
+        version: API_VERSION,
+    };
+    if let Some(mutes) = &options.mutes {
+        response.muted = Some(mutes.apply(&mut response.events));
+    }
+    if api_keys::fit_to_budget(&mut response.events, options.limits.max_response_bytes) {
+        response.complete = false;
+    }
+    let mut resp = json_response_private(&response, 200, 0)?;
+    resp.headers_mut().set("Cache-Control", "private, no-store")?;
+    with_query_headers(Ok(resp), None, response.events.len())
+}
+
+async fn archive_query(
+    env: &Env,
+    archive: &Archive,
+    filter: &Filter,
+    ttl: u64,
+    options: &QueryOptions,
+) -> Result<Response> {
+    let events = archive.query(filter).await?;
+    let outcome = if events.is_empty() { Outcome::Miss } else { Outcome::Hit };
+    decision_log::record(env, &Decision::new(Layer::Archive, filter.cache_key(), outcome));
+    let response = QueryResponse {
+        events,
+        eose: true,
+        complete: true,
+        cached: false,
+        stale: false,
+        cache_age_seconds: None,
+        source: QuerySource::Archive,
+        muted: None,
+        version: API_VERSION,
+    };
+    query_response(response, filter, ttl, options, &options.deletions.get(env).await, None)
+}
+
+/// Point lookup answered from the archive while the relay circuit is open. It may
+/// be behind the relay, so it's cached briefly and flagged with a `Warning` header.
+async fn archive_fallback(
+    env: &Env,
+    archive: &Archive,
+    filter: &Filter,
+    ttl: u64,
+    options: &QueryOptions,
+) -> Result<Response> {
+    let ttl = ttl.min((circuit::COOLDOWN_MS / 1000.0) as u64);
+    let mut resp = archive_query(env, archive, filter, ttl, options).await?;
+    resp.headers_mut().set("Warning", "110 - \"relay unavailable, answered from archive\"")?;
+    Ok(resp)
+}
+
+/// Pubkey from an optional NIP-98 `Authorization: Nostr ...` header on a GET, or a
+/// read-scoped session token. No credential means an anonymous viewer; one that
+/// fails validation is an error.
+async fn request_viewer(req: &Request, env: &Env) -> std::result::Result<Option<String>, AuthError> {
+    let header = req.headers().get("Authorization").ok().flatten();
+    let url = req.url().map_err(|_| AuthError::InvalidUrl)?.to_string();
+    let auth_req = AuthRequest {
+        method: "GET",
+        url: &url,
+        authorization: header.as_deref(),
+        api_key: None,
+        now: now_seconds(),
+    };
+    let mut providers: Vec<Box<dyn AuthProvider>> = Vec::new();
+    if let Ok(kv) = env.kv("REST_GATEWAY_CACHE") {
+        providers.push(Box::new(SessionAuth { kv, scope: Scope::Read }));
+    }
+    providers.push(Box::new(Nip98));
+    let viewer_auth = AnyOf(providers);
+    if !viewer_auth.presented(&auth_req) {
+        return Ok(None);
+    }
+    match viewer_auth.authenticate(&auth_req).await? {
+        Principal::Nostr(pubkey) => Ok(Some(pubkey)),
+        _ => Ok(None),
+    }
+}
+
+/// Pubkey of a valid NIP-98 header signed for this request, if any. Rejecting a bad
+/// header is left to the handler; here it just doesn't count as the signer's request.
+fn signer_pubkey(req: &Request, method: &Method) -> Option<String> {
+    let header = req.headers().get("Authorization").ok().flatten()?;
+    let method_name = match method {
+        Method::Post => "POST",
+        Method::Delete => "DELETE",
+        _ => "GET",
+    };
+    let url = req.url().ok()?;
+    crate::auth::validate_nip98(Some(&header), method_name, url.as_str())
+        .ok()
+        .map(|auth| auth.pubkey)
+}
+
+/// Count a read or publish against an authed pubkey's daily quota, returning the
+/// `429` to send once it's used up. A counter that can't be reached lets the request through.
+async fn charge_quota(env: &Env, pubkey: &str, action: quota::Action) -> Option<Result<Response>> {
+    let exceeded = match quota::consume(env, pubkey, action, now_seconds()).await {
+        Ok(Ok(())) => return None,
+        Ok(Err(exceeded)) => exceeded,
+        Err(e) => {
+            console_log!("Quota check failed: {}", e);
+            return None;
+        }
+    };
+    let retry_after = exceeded.retry_after as u32;
+    let err = ErrorResponse::new("quota_exceeded")
+        .with_detail(&exceeded.to_string())
+        .with_retry_after(retry_after);
+    Some(json_response(&err, 429).and_then(|mut resp| {
+        resp.headers_mut().set("Retry-After", &retry_after.to_string())?;
+        Ok(resp)
+    }))
+}
+
+/// A pubkey's mute list, through the query cache. A failed lookup mutes nothing.
+async fn viewer_mutes(env: &Env, ctx: &Context, scope: &ReadScope, viewer: &str) -> MuteList {
+    match fetch_events(env, ctx, scope, &Filter::mute_list(viewer)).await {
+        Ok(events) => events.first().map(MuteList::from_event).unwrap_or_default(),
+        Err(e) => {
+            console_log!("Mute list lookup failed: {}", e);
+            MuteList::default()
+        }
+    }
+}
+
+/// Fold the list named by `?apply_mutes=<pubkey>` into the viewer's own, if any
+async fn with_applied_mutes(
+    env: &Env,
+    ctx: &Context,
+    scope: &ReadScope,
+    mutes: Option<MuteList>,
+    apply_mutes: Option<&str>,
+) -> std::result::Result<Option<MuteList>, &'static str> {
+    let Some(pubkey) = apply_mutes else {
+        return Ok(mutes);
+    };
+    if !is_hex64(pubkey) {
+        return Err("apply_mutes must be a 64 character hex pubkey");
+    }
+    let mut list = mutes.unwrap_or_default();
+    list.extend(viewer_mutes(env, ctx, scope, pubkey).await);
+    Ok(Some(list))
+}
+
+/// Public entries of a pubkey's kind 10000 mute list
+async fn handle_mutes(env: Env, ctx: &Context, scope: &ReadScope, pubkey: &str) -> Result<Response> {
+    if !is_hex64(pubkey) {
+        let err = ErrorResponse::new("invalid_pubkey").with_detail("pubkey must be 64 hex characters");
+        return json_response(&err, 400);
+    }
+    let filter = Filter::mute_list(pubkey);
+    let Some(event) = fetch_events(&env, ctx, scope, &filter).await?.into_iter().next() else {
+        let err = ErrorResponse::new("not_found").with_detail("mute list not found");
+        return json_response(&err, 404);
+    };
+    let list = MuteList::from_event(&event);
+    let response = MuteListResponse {
+        pubkey: pubkey.to_string(),
+        pubkeys: list.pubkeys,
+        hashtags: list.hashtags,
+        words: list.words,
+        threads: list.event_ids,
+        created_at: event.get("created_at").and_then(|v| v.as_u64()),
+    };
+    json_response_with_cache(&response, 200, cache_ttl(&env, &filter))
+}
+
+/// Send a query response, newest first and no longer than the filter's `limit`.
+/// With a viewer it is filtered through their mute list and
+/// marked `private`, so shared caches never hand one viewer's results to another;
+/// the KV cache itself only ever holds the unfiltered events. Cache bypasses are
+/// `private` too.
+fn query_response(
+    mut response: QueryResponse,
+    filter: &Filter,
+    ttl: u64,
+    options: &QueryOptions,
+    deleted: &Deletions,
+    lookup: Option<CacheLookup>,
+) -> Result<Response> {
+    // Archive rows and cache entries written before private kinds were withheld
+    response.events.retain(|event| !kind::is_private_event(event));
+    deleted.strip(&mut response.events);
+    let now = now_seconds();
+    expiration::strip(&mut response.events, now);
+    // The archive keeps every version, and older cache entries weren't deduplicated
+    kind::dedupe_replaceable(&mut response.events);
+    filter.apply_limit(&mut response.events);
+    options.hooks.filter_events(&mut response.events);
+    if let Some(mutes) = &options.mutes {
+        response.muted = Some(mutes.apply(&mut response.events));
+    }
+    if api_keys::fit_to_budget(&mut response.events, options.limits.max_response_bytes) {
+        response.complete = false;
+    }
+    // Nobody downstream should hold an event past its expiry either
+    let ttl = expiration::cap_ttl(&response.events, now, ttl);
+    let resp = if options.mutes.is_some() || options.bypasses_cache() {
+        json_response_private(&response, 200, ttl)
+    } else if filter.is_immutable_lookup()
+        && !response.events.is_empty()
+        && !response.stale
+        && response.events.iter().all(|e| expiration::expires_at(e).is_none())
+    {
+        // Found by its full id, the event can only be deleted: downstream caches recheck
+        // it every few minutes, answering from their copy while they do
+        let max_age = FOUND_EVENT_MAX_AGE_SECONDS.min(ttl);
+        let resp = json_response_with_cache(&response, 200, max_age).and_then(|resp| stale_while_revalidate(resp, ttl));
+        vary_on_authorization(resp)
+    } else {
+        vary_on_authorization(json_response_with_cache(&response, 200, ttl))
+    };
+    // Cached answers are as old as their entry, which downstream caches count against max-age
+    let resp = resp.and_then(|resp| with_age(resp, response.cache_age_seconds.unwrap_or(0)));
+    match lookup {
+        Some(lookup) => with_query_headers(resp, Some(&lookup), response.events.len()),
+        None => resp,
+    }
+}
+
+/// How a query's cache lookup went
+struct CacheLookup {
+    key: String,
+    /// Label of the filter's TTL class
+    class: String,
+    /// `HIT`, `STALE`, `MISS` or `BYPASS`
+    result: &'static str,
+}
+
+thread_local! {
+    /// Cache keys this isolate is refreshing, so a burst of stale hits sends one relay query
+    static REFRESHING: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+}
+
+/// Re-run a filter against the relay after the response has gone out, and cache the result
+fn refresh_in_background(env: &Env, ctx: &Context, filter: &Filter, ttl: u64, degraded: Option<DegradedMode>) {
+    let cache_key = filter.cache_key();
+    if !REFRESHING.with(|r| r.borrow_mut().insert(cache_key.clone())) {
+        return;
+    }
+    let env = env.clone();
+    let filter = filter.clone();
+    ctx.wait_until(async move {
+        let refreshed = async {
+            let events = query_relay(&env, &filter, degraded.as_ref()).await?;
+            Cache::from_env(&env)?
+                .put_query(&filter, events, true, ttl)
+                .await
+        };
+        if let Err(e) = refreshed.await {
+            console_log!("Background refresh of {} failed: {}", cache_key, e);
+        }
+        REFRESHING.with(|r| r.borrow_mut().remove(&cache_key));
+    });
+}
+
+/// Mark how a query's cache lookup went and how many events it returned (all
+/// read by the metrics counter). Without a lookup it was never cacheable.
+fn with_query_headers(response: Result<Response>, lookup: Option<&CacheLookup>, events: usize) -> Result<Response> {
+    let mut resp = response?;
+    match lookup {
+        Some(lookup) => {
+            resp.headers_mut().set("X-Cache", lookup.result)?;
+            resp.headers_mut().set("X-Cache-Key", &lookup.key)?;
+            resp.headers_mut().set("X-Cache-Class", &lookup.class)?;
+        }
+        None => resp.headers_mut().set("X-Cache", "MISS")?,
+    }
+    resp.headers_mut().set("X-Event-Count", &events.to_string())?;
+    Ok(resp)
+}
+
+/// Run a filter against the relay via the RelayPool Durable Object.
+/// Results are also written to the archive when one is configured. DMs and gift
+/// wraps are dropped, so nothing downstream (KV, archive, feeds) ever holds them.
+/// While `degraded` the archive answers instead.
+pub(crate) async fn query_relay(
+    env: &Env,
+    filter: &Filter,
+    degraded: Option<&DegradedMode>,
+) -> Result<Vec<serde_json::Value>> {
+    if degraded.is_some() {
+        return match Archive::from_env(env).filter(|_| filter.search().is_none()) {
+            Some(archive) => archive.query(filter).await.map(|mut events| {
+                events.retain(|event| !kind::is_private_event(event));
+                kind::dedupe_replaceable(&mut events);
+                events
+            }),
+            None => Err(Error::RustError(degraded::RELAY_UNAVAILABLE.to_string())),
+        };
+    }
+
+    let mut events = relay_pool_query(env, filter).await?;
+    events.retain(|event| !kind::is_private_event(event));
+
+    // Keep the archive filling from live results (best-effort)
+    if let Some(archive) = Archive::from_env(env) {
+        if let Err(e) = archive.store_events(&events).await {
+            console_log!("Archive write failed: {}", e);
+        }
+    }
+
+    // Every version was worth archiving, but only the newest is worth serving
+    kind::dedupe_replaceable(&mut events);
+    Ok(events)
+}
+
+/// The RelayPool Durable Object's answer to a filter, untouched by any cache
+async fn relay_pool_query(env: &Env, filter: &Filter) -> Result<Vec<serde_json::Value>> {
+    let relay_pool = env.durable_object("RELAY_POOL")?;
+    let stub = relay_pool.id_from_name("default")?.get_stub()?;
+
+    // Pass the raw filter JSON directly to preserve ALL fields (tags, etc.)
+    let do_req = Request::new_with_init(
+        "http://do/query",
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_body(Some(filter.raw_json.clone().into())),
+    )?;
+
+    let mut do_resp = stub.fetch_with_request(do_req).await?;
+    if do_resp.headers().get(circuit::HEADER)?.is_some() {
+        return Err(Error::RustError(circuit::CIRCUIT_OPEN.to_string()));
+    }
+    let mut events: Vec<serde_json::Value> = do_resp.json().await?;
+
+    // Relays without NIP-01 prefix support may answer a prefix with unrelated events
+    if filter.has_prefixes() {
+        events.retain(|event| filter.matches_ids_and_authors(event));
+    }
+
+    Ok(events)
+}
+
+/// Kind-based TTL for a filter, clamped to the operator's global bounds
+pub(crate) fn cache_ttl(env: &Env, filter: &Filter) -> u64 {
+    TtlBounds::from_env(env).apply(filter.ttl_seconds(now_seconds(), &KindTtls::from_env(env)))
+}
+
+/// Fetch events through the KV cache, falling back to the relay.
+/// Used by endpoints that reshape events rather than returning a QueryResponse.
+async fn fetch_events(env: &Env, ctx: &Context, scope: &ReadScope, filter: &Filter) -> Result<Vec<serde_json::Value>> {
+    fetch_events_with_ttl(env, ctx, scope, filter, cache_ttl(env, filter)).await
+}
+
+async fn fetch_events_with_ttl(
+    env: &Env,
+    ctx: &Context,
+    scope: &ReadScope,
+    filter: &Filter,
+    ttl: u64,
+) -> Result<Vec<serde_json::Value>> {
+    let cache = Cache::from_env(env)?;
+    let cache_key = filter.cache_key();
+    // These callers have no way to refresh behind a response, so stale entries are misses
+    let fresh = cache.get_query(&cache_key).await?.filter(|(cached, age)| *age <= cached.ttl(ttl));
+    let mut events = if let Some((cached, age)) = fresh {
+        decision_log::record(env, &Decision::lookup_hit(Layer::Kv, cache_key, age, cached.ttl(ttl)));
+        cached.events
+    } else {
+        decision_log::record(env, &Decision::new(Layer::Kv, cache_key.as_str(), Outcome::Miss).with_ttl(ttl));
+        match query_relay(env, filter, scope.degraded.as_ref()).await {
+            Ok(events) => {
+                cache_in_background(env, ctx, filter, &events, ttl);
+                events
+            }
+            // A stale entry is better than failing the whole endpoint
+            Err(e) => match cache.get_query(&cache_key).await.ok().flatten() {
+                Some((cached, _)) => {
+                    console_log!("Relay query for {} failed, serving stale: {}", cache_key, e);
+                    cached.events
+                }
+                None => return Err(e),
+            },
+        }
+    };
+    scope.deletions.get(env).await.strip(&mut events);
+    expiration::strip(&mut events, now_seconds());
+    // Every route reshaping events reads through here, so none can skip the hooks
+    scope.hooks.filter_events(&mut events);
+    // Newest first, whatever order the relay or the cache had them in
+    filter.apply_limit(&mut events);
+    Ok(events)
+}
+
+/// Deletions a relay answer carries apply to what the gateway has cached and
+/// archived too. Only the first few per answer, as each one costs KV writes.
+fn apply_deletions_in_background(env: &Env, ctx: &Context, events: &[serde_json::Value]) {
+    let seen: Vec<serde_json::Value> = events
+        .iter()
+        .filter(|e| e.get("kind").and_then(|k| k.as_u64()) == Some(kind::DELETION as u64))
+        // Checked here too, so forged deletions can't use up the budget
+        .filter(|e| crate::auth::verify_event(e))
+        .take(MAX_APPLIED_DELETIONS)
+        .cloned()
+        .collect();
+    if seen.is_empty() {
+        return;
+    }
+    let env = env.clone();
+    ctx.wait_until(async move {
+        let applied = async { deletions::apply(&env, &Cache::from_env(&env)?, &seen).await };
+        if let Err(e) = applied.await {
+            console_log!("Applying relay deletions failed: {}", e);
+        }
+    });
+}
+
+/// Cache a filter's result once the response has gone out, so a miss doesn't wait
+/// on the KV write. A failed write is only logged: the caller had the events either way.
+fn cache_in_background(env: &Env, ctx: &Context, filter: &Filter, events: &[serde_json::Value], ttl: u64) {
+    let env = env.clone();
+    let filter = filter.clone();
+    let events = events.to_vec();
+    ctx.wait_until(async move {
+        let written = async { Cache::from_env(&env)?.put_query(&filter, events, true, ttl).await };
+        if let Err(e) = written.await {
+            console_log!("Failed to cache {}: {}", filter.cache_key(), e);
+        }
+    });
+}
+
+async fn handle_video(env: Env, ctx: &Context, scope: &ReadScope, naddr: &str) -> Result<Response> {
+    let pointer = match crate::nip19::decode_naddr(naddr) {
+        Ok(p) => p,
+        Err(e) => {
+            let err = ErrorResponse::new("invalid_naddr").with_detail(&e.to_string());
+            return json_response(&err, 400);
+        }
+    };
+    if !VIDEO_KINDS.iter().any(|k| *k as u32 == pointer.kind) {
+        let err = ErrorResponse::new("invalid_naddr").with_detail("naddr does not point at a video event");
+        return json_response(&err, 400);
+    }
+
+    let filter_json = serde_json::json!({
+        "authors": [pointer.pubkey],
+        "kinds": [pointer.kind],
+        "#d": [pointer.identifier],
+        "limit": 1
+    })
+    .to_string();
+    let filter = Filter::from_json(&filter_json).map_err(|e| worker::Error::from(e.to_string()))?;
+
+    let events = fetch_events(&env, ctx, scope, &filter).await?;
+    // Addressable events: newest version wins if the relay returned several
+    let newest = events
+        .iter()
+        .max_by_key(|e| e.get("created_at").and_then(|v| v.as_u64()).unwrap_or(0));
+
+    match newest.and_then(video_from_event) {
+        Some(video) => json_response_with_cache(&video, 200, cache_ttl(&env, &filter)),
+        None => {
+            let err = ErrorResponse::new("not_found").with_detail("video not found");
+            json_response(&err, 404)
+        }
+    }
+}
+
+async fn handle_videos(req: Request, env: Env, ctx: &Context, scope: &ReadScope, pubkey: &str) -> Result<Response> {
+    if !is_hex64(pubkey) {
+        let err = ErrorResponse::new("invalid_pubkey").with_detail("pubkey must be 64 hex characters");
+        return json_response(&err, 400);
+    }
+    let pubkey = pubkey.to_lowercase();
+    let url = req.url()?;
+    let params: std::collections::HashMap<_, _> = url.query_pairs().collect();
+    let limit = params
+        .get("limit")
+        .and_then(|l| l.parse::<usize>().ok())
+        .unwrap_or(20)
+        .min(100);
+
+    let mut filter_json = serde_json::json!({
+        "authors": [pubkey],
+        "kinds": VIDEO_KINDS,
+        "limit": limit
+    });
+    if let Some(until) = params.get("until").and_then(|u| u.parse::<u64>().ok()) {
+        filter_json["until"] = until.into();
+    }
+    let filter = Filter::from_json(&filter_json.to_string()).map_err(|e| worker::Error::from(e.to_string()))?;
+
+    let events = fetch_events(&env, ctx, scope, &filter).await?;
+    let response = VideosResponse {
+        videos: events.iter().filter_map(video_from_event).collect(),
+    };
+    json_response_with_cache(&response, 200, cache_ttl(&env, &filter))
+}
+
+/// Kind 20 picture posts, newest first, optionally from one author
+async fn handle_pictures(req: Request, env: Env, ctx: &Context, scope: &ReadScope) -> Result<Response> {
+    let url = req.url()?;
+    let params: std::collections::HashMap<_, _> = url.query_pairs().collect();
+    let limit = params
+        .get("limit")
+        .and_then(|l| l.parse::<usize>().ok())
+        .unwrap_or(20)
+        .min(100);
+
+    let mut filter_json = serde_json::json!({ "kinds": [PICTURE_KIND], "limit": limit });
+    if let Some(author) = params.get("author") {
+        if !is_hex64(author) {
+            let err = ErrorResponse::new("invalid_pubkey").with_detail("author must be 64 hex characters");
+            return json_response(&err, 400);
+        }
+        filter_json["authors"] = serde_json::json!([author.to_lowercase()]);
+    }
+    if let Some(until) = params.get("until").and_then(|u| u.parse::<u64>().ok()) {
+        filter_json["until"] = until.into();
+    }
+    let filter = Filter::from_json(&filter_json.to_string()).map_err(|e| worker::Error::from(e.to_string()))?;
+
+    let events = fetch_events(&env, ctx, scope, &filter).await?;
+    let response = PicturesResponse {
+        pictures: events.iter().filter_map(picture_from_event).collect(),
+    };
+    json_response_with_cache(&response, 200, cache_ttl(&env, &filter))
+}
+
+async fn handle_relay_info(req: Request, env: Env) -> Result<Response> {
+    let url = req.url()?;
+    let params: std::collections::HashMap<_, _> = url.query_pairs().collect();
+    let relay_url = match params.get("url") {
+        Some(u) => u.to_string(),
+        None => relay_url(&env),
+    };
+    if crate::relay_info::http_url(&relay_url).is_none() {
+        let err = ErrorResponse::new("invalid_relay_url").with_detail("relay url must use ws:// or wss://");
+        return json_response(&err, 400);
+    }
+
+    let (document, cached) = match crate::relay_info::fetch_document(&env, &relay_url).await {
+        Ok(result) => result,
+        Err(e) => {
+            let err = ErrorResponse::new("relay_info_unavailable").with_detail(&e.to_string());
+            return json_response(&err, 502);
+        }
+    };
+    let info = RelayInfo::from_document(&document);
+
+    let response = RelayInfoResponse {
+        relay: relay_url,
+        supported_nips: info.supported_nips,
+        limitation: info.limitation,
+        document,
+        cached,
+    };
+    json_response_with_cache(&response, 200, TtlBounds::from_env(&env).apply(3600))
+}
+
+/// Operator-defined feed: a filter saved in KV under `customroute:{name}`
+async fn handle_feed(
+    req: Request,
+    env: Env,
+    ctx: &Context,
+    scope: &ReadScope,
+    name: &str,
+    limits: TierLimits,
+) -> Result<Response> {
+    let route = match custom_routes::get(&env.kv("REST_GATEWAY_CACHE")?, name).await? {
+        Some(r) => r,
+        None => {
+            let err = ErrorResponse::new("not_found").with_detail("feed not found");
+            return json_response(&err, 404);
+        }
+    };
+    let url = req.url()?;
+    let params: std::collections::HashMap<String, String> = url.query_pairs().into_owned().collect();
+    let filter = match route.request_filter(&params) {
+        Ok(f) => f.with_max_limit(limits.max_limit),
+        Err(e) => {
+            // Saved filters are validated on write, so this is a bad `limit`/`until`
+            let err = ErrorResponse::new("invalid_filter").with_detail(&e.to_string());
+            return json_response(&err, 400);
+        }
+    };
+    let viewer = match request_viewer(&req, &env).await {
+        Ok(viewer) => viewer,
+        Err(e) => {
+            let err = ErrorResponse::new("auth_failed").with_detail(&e.to_string());
+            return json_response(&err, 401);
+        }
+    };
+    if let Some(viewer) = &viewer {
+        if let Some(resp) = charge_quota(&env, viewer, quota::Action::Read).await {
+            return resp;
+        }
+    }
+    let mutes = match &viewer {
+        Some(viewer) => Some(viewer_mutes(&env, ctx, scope, viewer).await),
+        None => None,
+    };
+    let mutes = match with_applied_mutes(&env, ctx, scope, mutes, params.get("apply_mutes").map(|p| p.as_str())).await {
+        Ok(m) => m,
+        Err(detail) => return json_response(&ErrorResponse::new("invalid_pubkey").with_detail(detail), 400),
+    };
+    let ttl = TtlBounds::from_env(&env).apply(route.ttl.unwrap_or_else(|| filter.ttl_seconds(now_seconds(), &KindTtls::from_env(&env))));
+    let mut events = fetch_events_with_ttl(&env, ctx, scope, &filter, ttl).await?;
+    let muted = mutes.as_ref().map(|m| m.apply(&mut events));
+
+    // Hydration is a paid-tier feature; free callers get the bare feed
+    let profiles = if route.hydrate.profiles && limits.hydration {
+        let mut authors: Vec<String> = Vec::new();
+        for author in events.iter().filter_map(|e| e.get("pubkey").and_then(|v| v.as_str())) {
+            if !authors.iter().any(|a| a == author) {
+                authors.push(author.to_string());
+            }
+        }
+        Some(lookup_profiles(&env, ctx, scope, &authors, true).await?.0)
+    } else {
+        None
+    };
+
+    let ttl = expiration::cap_ttl(&events, now_seconds(), ttl);
+    let response = FeedResponse {
+        name: route.name,
+        events,
+        profiles,
+        muted,
+    };
+    match mutes {
+        Some(_) => json_response_private(&response, 200, ttl),
+        None => vary_on_authorization(json_response_with_cache(&response, 200, ttl)),
+    }
+}
+
+/// Number of events matching a filter, maintained incrementally
+async fn handle_count(req: Request, env: Env, scope: &ReadScope) -> Result<Response> {
+    let url = req.url()?;
+    let params: std::collections::HashMap<_, _> = url.query_pairs().collect();
+    let filter = match params.get("filter").map(|f| Filter::from_base64(f)) {
+        Some(Ok(f)) => f,
+        Some(Err(e)) => {
+            let err = ErrorResponse::new("invalid_filter").with_detail(&e.to_string());
+            return json_response(&err, 400);
+        }
+        None => {
+            let err = ErrorResponse::new("invalid_filter").with_detail("missing filter parameter");
+            return json_response(&err, 400);
+        }
+    };
+    let base: serde_json::Value = serde_json::from_str(filter.as_json())?;
+    aggregate_response(&env, scope, AggregateKind::Count, &base, cache_ttl(&env, &filter)).await
+}
+
+/// Reaction summary or zap total for one event
+async fn handle_event_aggregate(env: Env, scope: &ReadScope, kind: AggregateKind, event_id: &str) -> Result<Response> {
+    if !is_hex64(event_id) {
+        let err = ErrorResponse::new("invalid_event_id").with_detail("event id must be 64 hex characters");
+        return json_response(&err, 400);
+    }
+    let base = aggregate::target_filter(kind, &event_id.to_ascii_lowercase());
+    let filter = Filter::from_json(&base.to_string()).map_err(|e| worker::Error::from(e.to_string()))?;
+    aggregate_response(&env, scope, kind, &base, cache_ttl(&env, &filter)).await
+}
+
+async fn aggregate_response(
+    env: &Env,
+    scope: &ReadScope,
+    kind: AggregateKind,
+    base: &serde_json::Value,
+    ttl: u64,
+) -> Result<Response> {
+    let (state, cached) = aggregate::load(env, kind, base, ttl, now_seconds(), scope.degraded.as_ref()).await?;
+    let response = AggregateResponse {
+        count: state.count,
+        reactions: (kind == AggregateKind::Reactions).then_some(state.reactions),
+        zap_msats: (kind == AggregateKind::Zaps).then_some(state.zap_msats),
+        since: state.watermark,
+        cached,
+        refreshed_at: state.refreshed_at,
+    };
+    json_response_with_cache(&response, 200, ttl)
+}
+
+async fn handle_stats(env: Env) -> Result<Response> {
+    let kv = env.kv("REST_GATEWAY_CACHE")?;
+    match kv.get(crate::stats::CURRENT_KEY).json::<StatsResponse>().await? {
+        Some(stats) => json_response_with_cache(&stats, 200, TtlBounds::from_env(&env).apply(60)),
+        None => {
+            let err = ErrorResponse::new("stats_unavailable").with_detail("stats have not been aggregated yet");
+            json_response(&err, 503)
+        }
+    }
+}
+
+/// Configured upstream relay, with the same fallback as the RelayPool
+pub(crate) fn relay_url(env: &Env) -> String {
+    env.var("RELAY_URL")
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| "wss://relay.damus.io".to_string())
+}
+
+async fn handle_embed(req: Request, env: Env, ctx: &Context, scope: &ReadScope, event_id: &str) -> Result<Response> {
+    if !is_hex64(event_id) {
+        let err = ErrorResponse::new("invalid_event_id").with_detail("event id must be 64 hex characters");
+        return json_response(&err, 400);
+    }
+
+    // Rendered once per event (and template version), then served from the edge cache
+    let url = req.url()?;
+    let host = url.host_str().unwrap_or_default().to_string();
+    let branding = branding::get(&env, &host).await;
+    let revision = branding.revision();
+    let edge_key = html_cache::cache_key(HtmlSurface::Embed, &host, &revision, event_id);
+    if let Some(cached) = html_cache::get(HtmlSurface::Embed, &host, &revision, event_id).await {
+        decision_log::record(&env, &Decision::new(Layer::Edge, edge_key, Outcome::Hit));
+        return Ok(cached);
+    }
+    decision_log::record(&env, &Decision::new(Layer::Edge, edge_key, Outcome::Miss));
+
+    let event = match fetch_events(&env, ctx, scope, &Filter::note(event_id)).await?.into_iter().next() {
+        Some(e) => e,
+        None => {
+            let err = ErrorResponse::new("not_found").with_detail("event not found");
+            return json_response(&err, 404);
+        }
+    };
+
+    // Author name/picture for the card; a missing profile just falls back to the pubkey
+    let pubkey = event.get("pubkey").and_then(|v| v.as_str()).unwrap_or_default();
+    let profile = fetch_events(&env, ctx, scope, &Filter::profile(pubkey))
+        .await?
+        .first()
+        .and_then(ProfileMetadata::from_event);
+
+    let card = EmbedCard::from_event(&event, profile.as_ref());
+    let html = render_embed(&card, url.as_str(), &branding);
+    let bounds = TtlBounds::from_env(&env);
+    html_cache::put(HtmlSurface::Embed, &host, &revision, event_id, html, 3600, &bounds).await
+}
+
+async fn handle_profile(
+    env: Env,
+    ctx: &Context,
+    scope: &ReadScope,
+    pubkey: &str,
+    bot_action: BotAction,
+) -> Result<Response> {
+    let filter = Filter::profile(pubkey);
+    run_query(&env, ctx, &filter, &QueryOptions::with_archive_fallback(scope, bot_action)).await
+}
+
+/// Profiles for many pubkeys at once. Hits come from the per-pubkey profile
+/// cache; all misses are fetched in one relay REQ and cached in the background.
+async fn handle_profiles(mut req: Request, env: Env, ctx: &Context, scope: &ReadScope, bot_action: BotAction) -> Result<Response> {
+    let body: ProfilesRequest = match req.json().await {
+        Ok(b) => b,
+        Err(_) => {
+            let err = ErrorResponse::new("invalid_body").with_detail("expected {\"pubkeys\": [...]}");
+            return json_response(&err, 400);
+        }
+    };
+    let pubkeys = match batch::normalize_hex_ids(&body.pubkeys, "pubkey") {
+        Ok(p) => p,
+        Err(e) => {
+            let err = ErrorResponse::new("invalid_pubkeys").with_detail(&e);
+            return json_response(&err, 400);
+        }
+    };
+
+    // Throttled clients only get what is already cached
+    let (profiles, cached) = lookup_profiles(&env, ctx, scope, &pubkeys, bot_action != BotAction::Throttle).await?;
+    json_response(&ProfilesResponse { profiles, cached }, 200)
+}
+
+/// Events by id. Each id shares its cache entry with /event/{id}, so only the
+/// ids missing from cache go to the relay, together in one REQ.
+async fn handle_events(mut req: Request, env: Env, ctx: &Context, scope: &ReadScope, bot_action: BotAction) -> Result<Response> {
+    let requested: Vec<String> = if req.method() == Method::Post {
+        match req.json::<EventsRequest>().await {
+            Ok(b) => b.ids,
+            Err(_) => {
+                let err = ErrorResponse::new("invalid_body").with_detail("expected {\"ids\": [...]}");
+                return json_response(&err, 400);
+            }
+        }
+    } else {
+        let url = req.url()?;
+        let params: std::collections::HashMap<_, _> = url.query_pairs().collect();
+        params
+            .get("ids")
+            .map(|ids| ids.split(',').filter(|id| !id.is_empty()).map(String::from).collect())
+            .unwrap_or_default()
+    };
+    let ids = match batch::normalize_hex_ids(&requested, "id") {
+        Ok(i) => i,
+        Err(e) => {
+            let err = ErrorResponse::new("invalid_ids").with_detail(&e);
+            return json_response(&err, 400);
+        }
+    };
+
+    let cache = Cache::from_env(&env)?;
+    let keys: Vec<String> = ids.iter().map(|id| Filter::note(id).cache_key()).collect();
+    let lookups = futures_util::future::join_all(keys.iter().map(|key| cache.get_query(key))).await;
+    let mut found: std::collections::HashMap<String, serde_json::Value> = std::collections::HashMap::new();
+    let mut misses = Vec::new();
+    for (id, lookup) in ids.iter().zip(lookups) {
+        match lookup.ok().flatten().and_then(|(cached, _)| cached.events.into_iter().next()) {
+            Some(event) => {
+                found.insert(id.clone(), event);
+            }
+            None => misses.push(id.clone()),
+        }
+    }
+    let cached = found.len();
+
+    // Throttled clients only get what is already cached
+    if !misses.is_empty() && bot_action != BotAction::Throttle {
+        let fetched = query_relay(&env, &Filter::notes(&misses), scope.degraded.as_ref()).await?;
+        let mut writes = Vec::new();
+        for event in fetched {
+            let Some(id) = event.get("id").and_then(|v| v.as_str()).map(String::from) else { continue };
+            if misses.contains(&id) && !found.contains_key(&id) {
+                writes.push((id.clone(), event.clone()));
+                found.insert(id, event);
+            }
+        }
+        let env_for_cache = env.clone();
+        ctx.wait_until(async move {
+            let Ok(cache) = Cache::from_env(&env_for_cache) else { return };
+            for (id, event) in writes {
+                let filter = Filter::note(&id);
+                let ttl = cache_ttl(&env_for_cache, &filter);
+                if let Err(e) = cache.put_query(&filter, vec![event], true, ttl).await {
+                    console_log!("Failed to cache event {}: {}", id, e);
+                }
+            }
+        });
+    }
+
+    let deleted = scope.deletions.get(&env).await;
+    let now = now_seconds();
+    // Withheld events are reported missing, as if the gateway had never seen them
+    found.retain(|_, event| {
+        !deleted.is_deleted(event) && !expiration::is_expired(event, now) && scope.hooks.allow_event(event)
+    });
+    let mut events = Vec::with_capacity(found.len());
+    let mut missing = Vec::new();
+    for id in ids {
+        match found.remove(&id) {
+            Some(event) => events.push(event),
+            None => missing.push(id),
+        }
+    }
+    json_response(&EventsResponse { events, missing, cached }, 200)
+}
+
+/// Profiles from the per-pubkey cache, with misses fetched in one relay REQ
+/// when `fetch_misses` is set. Also returns how many came from cache. The entries
+/// are the ones `/profile/{pubkey}` reads, so each fetch warms both.
+async fn lookup_profiles(
+    env: &Env,
+    ctx: &Context,
+    scope: &ReadScope,
+    pubkeys: &[String],
+    fetch_misses: bool,
+) -> Result<(std::collections::BTreeMap<String, Option<ProfileMetadata>>, usize)> {
+    let cache = Cache::from_env(env)?;
+    let ttl = cache_ttl(env, &Filter::profiles(pubkeys));
+    let keys: Vec<String> = pubkeys.iter().map(|pk| Filter::profile(pk).cache_key()).collect();
+    let lookups = futures_util::future::join_all(keys.iter().map(|key| cache.get_query(key))).await;
+    let mut found = std::collections::BTreeMap::new();
+    let mut misses = Vec::new();
+    for (pubkey, lookup) in pubkeys.iter().zip(lookups) {
+        // Stale entries are refetched along with the misses
+        match lookup.unwrap_or(None).filter(|(cached, age)| *age <= cached.ttl(ttl)) {
+            Some((cached, _)) => {
+                let allowed = cached.events.first().filter(|event| scope.hooks.allow_event(event));
+                found.insert(pubkey.clone(), allowed.and_then(ProfileMetadata::from_event));
+            }
+            None => misses.push(pubkey.clone()),
+        }
+    }
+    let cached = found.len();
+
+    let mut fetched = std::collections::HashMap::new();
+    if !misses.is_empty() && fetch_misses {
+        let events = query_relay(env, &Filter::profiles(&misses), scope.degraded.as_ref()).await?;
+        let mut allowed = events.clone();
+        scope.hooks.filter_events(&mut allowed);
+        fetched = batch::latest_by_author(&allowed);
+        // Pubkeys without a profile are cached too, on the negative TTL
+        let newest = batch::newest_by_author(&events);
+        let writes: Vec<(String, Vec<serde_json::Value>)> = misses
+            .iter()
+            .map(|pk| (pk.clone(), newest.get(pk).map(|e| vec![(*e).clone()]).unwrap_or_default()))
+            .collect();
+        ctx.wait_until(async move {
+            for (pubkey, events) in writes {
+                if let Err(e) = cache.put_query(&Filter::profile(&pubkey), events, true, ttl).await {
+                    console_log!("Failed to cache profile {}: {}", pubkey, e);
+                }
+            }
+        });
+    }
+    for pubkey in misses {
+        let profile = fetched.get(&pubkey).cloned();
+        found.insert(pubkey, profile);
+    }
+    Ok((found, cached))
+}
+
+async fn handle_event(
+    env: Env,
+    ctx: &Context,
+    scope: &ReadScope,
+    event_id: &str,
+    bot_action: BotAction,
+) -> Result<Response> {
+    let filter = Filter::note(event_id);
+    // An unconfirmed deletion holds only if the event turns out to be its author's
+    if let Some(record) = deletions::record_for(&env.kv("REST_GATEWAY_CACHE")?, event_id).await? {
+        let deleted = record.confirmed
+            || fetch_events(&env, ctx, scope, &filter)
+                .await
+                .is_ok_and(|events| events.first().is_some_and(|event| record.covers(event)));
+        if deleted {
+            let err = ErrorResponse::new("deleted").with_detail("the author deleted this event");
+            return json_response(&err, 410);
+        }
+    }
+    run_query(&env, ctx, &filter, &QueryOptions::with_archive_fallback(scope, bot_action)).await
+}
+
+/// Everything an event points at. Previews are looked up through the query
+/// cache; a failed lookup leaves that preview out rather than failing the response.
+async fn handle_references(env: Env, ctx: &Context, scope: &ReadScope, event_id: &str) -> Result<Response> {
+    if !is_hex64(event_id) {
+        let err = ErrorResponse::new("invalid_event_id").with_detail("event id must be 64 hex characters");
+        return json_response(&err, 400);
+    }
+    let event_filter = Filter::note(event_id);
+    let event = match fetch_events(&env, ctx, scope, &event_filter).await?.into_iter().next() {
+        Some(e) => e,
+        None => {
+            let err = ErrorResponse::new("not_found").with_detail("event not found");
+            return json_response(&err, 404);
+        }
+    };
+    let mut refs = references::parse_references(&event);
+
+    // Referenced events and profiles are each resolved in a single query, and
+    // addresses need one query each, so only the first few are resolved
+    let ids: Vec<String> = refs
+        .iter()
+        .filter(|r| matches!(r.ref_type, ReferenceType::Event | ReferenceType::Quote) && is_hex64(&r.value))
+        .map(|r| r.value.clone())
+        .take(MAX_RESOLVED_REFERENCES)
+        .collect();
+    let pubkeys: Vec<String> = refs
+        .iter()
+        .filter(|r| r.ref_type == ReferenceType::Pubkey && is_hex64(&r.value))
+        .map(|r| r.value.clone())
+        .take(MAX_RESOLVED_REFERENCES)
+        .collect();
+    let addresses: Vec<Option<Filter>> = refs
+        .iter()
+        .filter(|r| r.ref_type == ReferenceType::Address)
+        .take(MAX_RESOLVED_ADDRESSES)
+        .map(|r| references::address_filter(&r.value).and_then(|f| Filter::from_json(&f.to_string()).ok()))
+        .collect();
+
+    let mut previews = FilterSet::new();
+    previews.push(Filter::notes(&ids));
+    previews.push(Filter::profiles(&pubkeys));
+    for filter in addresses.iter().flatten() {
+        previews.push(filter.clone());
+    }
+    let mut found = fetch_previews(&env, ctx, scope, &previews).await.into_iter();
+    let events = found.next().unwrap_or_default();
+    let profiles = found.next().unwrap_or_default();
+
+    for r in refs.iter_mut() {
+        r.preview = match r.ref_type {
+            ReferenceType::Event | ReferenceType::Quote => events
+                .iter()
+                .find(|e| e.get("id").and_then(|v| v.as_str()) == Some(r.value.as_str()))
+                .map(references::event_preview),
+            ReferenceType::Pubkey => profiles
+                .iter()
+                .filter(|e| e.get("pubkey").and_then(|v| v.as_str()) == Some(r.value.as_str()))
+                .max_by_key(|e| e.get("created_at").and_then(|v| v.as_u64()).unwrap_or(0))
+                .and_then(references::profile_preview),
+            _ => continue,
+        };
+    }
+    // Address results follow in order, one per address that parsed
+    for (r, filter) in refs
+        .iter_mut()
+        .filter(|r| r.ref_type == ReferenceType::Address)
+        .zip(&addresses)
+    {
+        if filter.is_some() {
+            r.preview = found
+                .next()
+                .unwrap_or_default()
+                .iter()
+                .max_by_key(|e| e.get("created_at").and_then(|v| v.as_u64()).unwrap_or(0))
+                .map(references::event_preview);
+        }
+    }
+
+    let response = ReferencesResponse {
+        event_id: event_id.to_string(),
+        references: refs,
+    };
+    json_response_with_cache(&response, 200, cache_ttl(&env, &event_filter))
+}
+
+/// Each filter's events through the query cache, looked up concurrently and in
+/// order. A filter that can't match anything isn't sent; a failed lookup is empty.
+async fn fetch_previews(env: &Env, ctx: &Context, scope: &ReadScope, filters: &FilterSet) -> Vec<Vec<serde_json::Value>> {
+    let lookups = filters.iter().map(|filter| async move {
+        if filter.matches_nothing() {
+            return Vec::new();
+        }
+        fetch_events(env, ctx, scope, filter).await.unwrap_or_else(|e| {
+            console_log!("Reference preview lookup failed: {}", e);
+            Vec::new()
+        })
+    });
+    futures_util::future::join_all(lookups).await
+}
+
+/// Events that reference this one (`e` and `q` tags), from the archive's tag index
+async fn handle_referenced_by(req: Request, env: Env, event_id: &str) -> Result<Response> {
+    if !is_hex64(event_id) {
+        let err = ErrorResponse::new("invalid_event_id").with_detail("event id must be 64 hex characters");
+        return json_response(&err, 400);
+    }
+    let archive = match Archive::from_env(&env) {
+        Some(a) => a,
+        None => {
+            let err = ErrorResponse::new("archive_unavailable").with_detail("no archive configured");
+            return json_response(&err, 503);
+        }
+    };
+    let url = req.url()?;
+    let params: std::collections::HashMap<_, _> = url.query_pairs().collect();
+    let limit = params
+        .get("limit")
+        .and_then(|l| l.parse::<usize>().ok())
+        .unwrap_or(50);
+    let until = params.get("until").and_then(|u| u.parse::<u64>().ok());
+
+    let event_id = event_id.to_ascii_lowercase();
+    let events = archive.referenced_by(&event_id, until, limit).await?;
+    let response = ReferencedByResponse {
+        event_id,
+        events,
+        source: QuerySource::Archive,
+    };
+    json_response_with_cache(&response, 200, TtlBounds::from_env(&env).apply(60))
+}
+
+/// Pubkeys following `pubkey` according to the archive, as JSONL (one
+/// `{"pubkey": ...}` per line). `X-Next-Cursor` is set while more remain.
+/// Needs an API key whose tier allows export, and counts against its daily quota.
+async fn handle_follower_export(
+    req: Request,
+    env: Env,
+    caller: &Caller,
+    limits: TierLimits,
+    pubkey: &str,
+) -> Result<Response> {
+    if !is_hex64(pubkey) {
+        let err = ErrorResponse::new("invalid_pubkey").with_detail("pubkey must be 64 hex characters");
+        return json_response(&err, 400);
+    }
+    let Some(key) = caller.key.as_ref().filter(|_| limits.export) else {
+        let err = ErrorResponse::new("export_not_allowed").with_detail("needs an API key on a tier with export");
+        return json_response(&err, 403);
+    };
+    let archive = match Archive::from_env(&env) {
+        Some(a) => a,
+        None => {
+            let err = ErrorResponse::new("archive_unavailable").with_detail("no archive configured");
+            return json_response(&err, 503);
+        }
+    };
+    let url = req.url()?;
+    let params: std::collections::HashMap<_, _> = url.query_pairs().collect();
+    let cursor = params.get("cursor").map(|c| c.to_ascii_lowercase());
+    if cursor.as_deref().is_some_and(|c| !is_hex64(c)) {
+        let err = ErrorResponse::new("invalid_cursor").with_detail("cursor must be a cursor returned by this endpoint");
+        return json_response(&err, 400);
+    }
+
+    let kv = env.kv("REST_GATEWAY_CACHE")?;
+    let now = now_seconds();
+    let day = now / 86400;
+    let used = api_keys::export_used(&kv, &key.id, day).await?;
+    let remaining = limits.export_rows_per_day.saturating_sub(used);
+    if remaining == 0 {
+        let retry_after = ((day + 1) * 86400 - now) as u32;
+        let err = ErrorResponse::new("export_quota_exceeded")
+            .with_detail("daily export quota used up")
+            .with_retry_after(retry_after);
+        let mut resp = json_response(&err, 429)?;
+        resp.headers_mut().set("Retry-After", &retry_after.to_string())?;
+        return Ok(resp);
+    }
+    let limit = params
+        .get("limit")
+        .and_then(|l| l.parse::<usize>().ok())
+        .unwrap_or(MAX_FOLLOWERS_PAGE)
+        .clamp(1, MAX_FOLLOWERS_PAGE)
+        .min(remaining as usize);
 
-        (Method::Get, path) if path.starts_with("/publish/status/") => {
-            handle_publish_status(env, &path[16..]).await
-        }
+    // One extra row tells whether another page follows
+    let mut followers = archive
+        .followers(&pubkey.to_ascii_lowercase(), cursor.as_deref(), limit + 1)
+        .await?;
+    let more = followers.len() > limit;
+    followers.truncate(limit);
+    api_keys::record_export(&kv, &key.id, day, followers.len() as u64).await?;
 
-        (Method::Post, "/publish") => handle_publish(req, env).await,
+    let mut body = String::new();
+    for follower in &followers {
+        body.push_str(&serde_json::to_string(&FollowerExportLine { pubkey: follower.clone() })?);
+        body.push('\n');
+    }
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/x-ndjson")?;
+    headers.set("Cache-Control", "private, no-store")?;
+    if let Some(last) = followers.last().filter(|_| more) {
+        headers.set("X-Next-Cursor", last)?;
+    }
+    Ok(Response::from_body(ResponseBody::Body(body.into_bytes()))?.with_headers(headers))
+}
 
-        _ => {
-            let err = ErrorResponse::new("not_found").with_detail("endpoint not found");
-            json_response(&err, 404)
+/// Webhook management. Every call is NIP-98 authenticated and scoped to the signer's pubkey.
+async fn handle_webhooks(mut req: Request, env: Env) -> Result<Response> {
+    let url = req.url()?;
+    let method = req.method();
+    let method_name = match method {
+        Method::Post => "POST",
+        Method::Delete => "DELETE",
+        _ => "GET",
+    };
+    let auth_header = req.headers().get("Authorization")?;
+    let owner = match crate::auth::validate_nip98(auth_header.as_deref(), method_name, url.as_str()) {
+        Ok(auth) => auth.pubkey,
+        Err(e) => {
+            let err = ErrorResponse::new("auth_failed").with_detail(&e.to_string());
+            return json_response(&err, 401);
         }
     };
+    let stub = env.durable_object("WEBHOOKS")?.id_from_name("global")?.get_stub()?;
 
-    // Add CORS headers to all responses
-    add_cors_headers(response)
-}
+    match method {
+        Method::Post => {
+            let body: WebhookRequest = match req.json().await {
+                Ok(b) => b,
+                Err(_) => {
+                    let err = ErrorResponse::new("invalid_body").with_detail("expected {\"filter\": {...}, \"url\": \"...\"}");
+                    return json_response(&err, 400);
+                }
+            };
+            if !body.filter.is_object() || Filter::from_json(&body.filter.to_string()).is_err() {
+                let err = ErrorResponse::new("invalid_filter").with_detail("filter must be a NIP-01 filter object");
+                return json_response(&err, 400);
+            }
+            if let Err(reason) = webhooks::validate_callback_url(&body.url) {
+                let err = ErrorResponse::new("invalid_callback").with_detail(reason);
+                return json_response(&err, 400);
+            }
 
-fn cors_preflight() -> Result<Response> {
-    let mut headers = Headers::new();
-    headers.set("Access-Control-Allow-Origin", "*")?;
-    headers.set("Access-Control-Allow-Methods", "GET, POST, OPTIONS")?;
-    headers.set("Access-Control-Allow-Headers", "Content-Type, Authorization")?;
-    headers.set("Access-Control-Max-Age", "86400")?;
-    Ok(Response::empty()?.with_status(204).with_headers(headers))
+            let webhook = Webhook {
+                id: webhooks::new_webhook_id(),
+                owner,
+                filter: body.filter,
+                url: body.url,
+                secret: webhooks::new_secret(),
+                created_at: now_seconds(),
+                consecutive_failures: 0,
+                disabled: false,
+                last_event_at: None,
+            };
+            let do_req = Request::new_with_init(
+                "http://do/create",
+                RequestInit::new()
+                    .with_method(Method::Post)
+                    .with_body(Some(serde_json::to_string(&webhook)?.into())),
+            )?;
+            let mut resp = stub.fetch_with_request(do_req).await?;
+            if resp.status_code() == 409 {
+                let err = ErrorResponse::new("webhook_limit")
+                    .with_detail(&format!("at most {} webhooks per pubkey", webhooks::MAX_WEBHOOKS_PER_OWNER));
+                return json_response(&err, 409);
+            }
+            if resp.status_code() == 503 {
+                return hub_full();
+            }
+            let created: WebhookResponse = resp.json().await?;
+            json_response(&created, 201)
+        }
+        Method::Delete => {
+            let id = &unversioned(url.path())[10..];
+            let do_url = format!("http://do/delete?id={}&owner={}", id, owner);
+            let do_req = Request::new_with_init(&do_url, RequestInit::new().with_method(Method::Delete))?;
+            let mut resp = stub.fetch_with_request(do_req).await?;
+            if resp.status_code() == 404 {
+                let err = ErrorResponse::new("not_found").with_detail("webhook not found");
+                return json_response(&err, 404);
+            }
+            let deleted: WebhookResponse = resp.json().await?;
+            json_response(&deleted, 200)
+        }
+        _ => {
+            let mut resp = stub.fetch_with_str(&format!("http://do/list?owner={}", owner)).await?;
+            let response = WebhooksResponse {
+                webhooks: resp.json().await?,
+            };
+            json_response(&response, 200)
+        }
+    }
 }
 
-fn add_cors_headers(response: Result<Response>) -> Result<Response> {
-    let mut resp = response?;
-    let headers = resp.headers_mut();
-    headers.set("Access-Control-Allow-Origin", "*")?;
-    headers.set("Access-Control-Allow-Methods", "GET, POST, OPTIONS")?;
-    headers.set("Access-Control-Allow-Headers", "Content-Type, Authorization")?;
-    Ok(resp)
+fn handle_vapid_key(env: Env) -> Result<Response> {
+    match Vapid::from_env(&env) {
+        Some(vapid) => json_response_with_cache(
+            &VapidKeyResponse {
+                public_key: vapid.public_key(),
+            },
+            200,
+            TtlBounds::from_env(&env).apply(3600),
+        ),
+        None => {
+            let err = ErrorResponse::new("push_unavailable").with_detail("Web Push is not configured");
+            json_response(&err, 503)
+        }
+    }
 }
 
-async fn handle_query(req: Request, env: Env) -> Result<Response> {
+/// Register (or replace) a device's Web Push subscription and filter. NIP-98 authenticated.
+async fn handle_push_register(mut req: Request, env: Env) -> Result<Response> {
+    if Vapid::from_env(&env).is_none() {
+        let err = ErrorResponse::new("push_unavailable").with_detail("Web Push is not configured");
+        return json_response(&err, 503);
+    }
     let url = req.url()?;
-    let params: std::collections::HashMap<_, _> = url.query_pairs().collect();
-
-    let filter_param = match params.get("filter") {
-        Some(f) => f,
-        None => {
-            let err = ErrorResponse::new("invalid_filter").with_detail("missing filter parameter");
-            return json_response(&err, 400);
+    let auth_header = req.headers().get("Authorization")?;
+    let owner = match crate::auth::validate_nip98(auth_header.as_deref(), "POST", url.as_str()) {
+        Ok(auth) => auth.pubkey,
+        Err(e) => {
+            let err = ErrorResponse::new("auth_failed").with_detail(&e.to_string());
+            return json_response(&err, 401);
         }
     };
 
-    let filter = match Filter::from_base64(filter_param) {
-        Ok(f) => f,
-        Err(e) => {
-            let err = ErrorResponse::new("invalid_filter").with_detail(&e.to_string());
+    let body: PushRegistrationRequest = match req.json().await {
+        Ok(b) => b,
+        Err(_) => {
+            let err = ErrorResponse::new("invalid_body").with_detail("expected {\"subscription\": {...}, \"filter\": {...}}");
             return json_response(&err, 400);
         }
     };
+    if !body.filter.is_object() || Filter::from_json(&body.filter.to_string()).is_err() {
+        let err = ErrorResponse::new("invalid_filter").with_detail("filter must be a NIP-01 filter object");
+        return json_response(&err, 400);
+    }
+    let checks = webhooks::validate_callback_url(&body.subscription.endpoint)
+        .and_then(|_| push::validate_keys(&body.subscription.keys));
+    if let Err(reason) = checks {
+        let err = ErrorResponse::new("invalid_subscription").with_detail(reason);
+        return json_response(&err, 400);
+    }
 
-    // Check for cache bypass: ?nocache=1 or Cache-Control: no-cache header
-    let nocache_param = params.get("nocache").map(|v| v == "1" || v == "true").unwrap_or(false);
-    let nocache_header = req
-        .headers()
-        .get("Cache-Control")
-        .ok()
-        .flatten()
-        .map(|v| v.contains("no-cache"))
-        .unwrap_or(false);
-    let skip_cache = nocache_param || nocache_header;
-
-    let kv = env.kv("REST_GATEWAY_CACHE")?;
-    let cache = Cache::new(kv);
-    let cache_key = filter.cache_key();
-
-    // Check cache first (unless bypass requested)
-    if !skip_cache {
-        if let Some((cached, age)) = cache.get_query(&cache_key).await? {
-            let response = QueryResponse {
-                events: cached.events,
-                eose: cached.eose,
-                complete: cached.eose,
-                cached: true,
-                cache_age_seconds: Some(age),
-            };
-            return json_response_with_cache(&response, 200, filter.ttl_seconds());
+    let registration = PushRegistration {
+        id: push::registration_id(&body.subscription.endpoint),
+        owner,
+        subscription: body.subscription,
+        filter: body.filter,
+        created_at: now_seconds(),
+    };
+    let stub = env.durable_object("WEBHOOKS")?.id_from_name("global")?.get_stub()?;
+    let do_req = Request::new_with_init(
+        "http://do/push/subscribe",
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_body(Some(serde_json::to_string(&registration)?.into())),
+    )?;
+    match stub.fetch_with_request(do_req).await?.status_code() {
+        409 => {
+            let err = ErrorResponse::new("push_limit")
+                .with_detail(&format!("at most {} push subscriptions per pubkey", webhooks::MAX_PUSH_PER_OWNER));
+            return json_response(&err, 409);
         }
+        503 => return hub_full(),
+        _ => {}
     }
+    env.kv("REST_GATEWAY_CACHE")?
+        .put(&push::kv_key(&registration.id), serde_json::to_string(&registration)?)?
+        .expiration_ttl(push::REGISTRATION_TTL_SECONDS)
+        .execute()
+        .await?;
 
-    // Cache miss - query relay via Durable Object
-    let relay_pool = env.durable_object("RELAY_POOL")?;
-    let stub = relay_pool.id_from_name("default")?.get_stub()?;
+    json_response(&registration.view(), 201)
+}
 
-    // Pass the raw filter JSON directly to preserve ALL fields (tags, etc.)
+/// The shared webhook and push subscription socket is at its cap
+fn hub_full() -> Result<Response> {
+    let err = ErrorResponse::new("subscriptions_full")
+        .with_detail("no room for more live subscriptions; try again later");
+    json_response(&err, 503)
+}
+
+async fn handle_push_unregister(req: Request, env: Env) -> Result<Response> {
+    let url = req.url()?;
+    let auth_header = req.headers().get("Authorization")?;
+    let owner = match crate::auth::validate_nip98(auth_header.as_deref(), "DELETE", url.as_str()) {
+        Ok(auth) => auth.pubkey,
+        Err(e) => {
+            let err = ErrorResponse::new("auth_failed").with_detail(&e.to_string());
+            return json_response(&err, 401);
+        }
+    };
+    let id = &unversioned(url.path())["/push/subscriptions/".len()..];
+    let registration = match push::get_registration(&env, id).await? {
+        Some(r) if r.owner == owner => r,
+        _ => {
+            let err = ErrorResponse::new("not_found").with_detail("push subscription not found");
+            return json_response(&err, 404);
+        }
+    };
+    push::delete_registration(&env, id).await?;
+
+    let stub = env.durable_object("WEBHOOKS")?.id_from_name("global")?.get_stub()?;
     let do_req = Request::new_with_init(
-        "http://do/query",
-        RequestInit::new()
-            .with_method(Method::Post)
-            .with_body(Some(filter.raw_json.clone().into())),
+        &format!("http://do/push/unsubscribe?id={}", id),
+        RequestInit::new().with_method(Method::Post),
     )?;
+    stub.fetch_with_request(do_req).await?;
 
-    let mut do_resp = stub.fetch_with_request(do_req).await?;
-    let events: Vec<serde_json::Value> = do_resp.json().await?;
+    json_response(&registration.view(), 200)
+}
 
-    // Cache the result
-    cache
-        .put_query(&cache_key, events.clone(), true, filter.ttl_seconds())
-        .await?;
+/// Whether the gateway is serving normally or degraded, and why
+fn handle_status(degraded: Option<&DegradedMode>) -> Result<Response> {
+    let response = StatusResponse {
+        mode: if degraded.is_some() { "degraded" } else { "normal" }.to_string(),
+        reason: degraded.map(|m| m.reason.clone()),
+        since: degraded.map(|m| m.since),
+        retry_after: degraded.map(|m| m.retry_after),
+    };
+    json_response_with_cache(&response, 200, 0)
+}
 
-    let response = QueryResponse {
-        events,
-        eose: true,
-        complete: true,
-        cached: false,
-        cache_age_seconds: None,
+/// Tier and limits of the calling API key, or of the free tier without one
+fn handle_info(caller: &Caller, limits: TierLimits) -> Result<Response> {
+    let response = InfoResponse {
+        tier: caller.tier(),
+        key_name: caller.key.as_ref().map(|k| k.name.clone()),
+        scopes: caller.key.as_ref().map(|k| k.scopes.clone()).unwrap_or_default(),
+        limits,
     };
-    json_response_with_cache(&response, 200, filter.ttl_seconds())
+    json_response_private(&response, 200, 0)
 }
 
-async fn handle_profile(_req: Request, env: Env, pubkey: &str) -> Result<Response> {
-    // Create filter JSON directly
-    let filter_json = format!(r#"{{"authors":["{}"],"kinds":[0],"limit":1}}"#, pubkey);
-    let filter = Filter::from_json(&filter_json).map_err(|e| worker::Error::from(e.to_string()))?;
+/// The NIP-98 signer's quota standing for the current UTC day
+async fn handle_quota(req: Request, env: Env) -> Result<Response> {
+    let pubkey = match request_viewer(&req, &env).await {
+        Ok(Some(pubkey)) => pubkey,
+        Ok(None) => {
+            let err = ErrorResponse::new("auth_failed").with_detail(&AuthError::MissingHeader.to_string());
+            return json_response(&err, 401);
+        }
+        Err(e) => {
+            let err = ErrorResponse::new("auth_failed").with_detail(&e.to_string());
+            return json_response(&err, 401);
+        }
+    };
+    let now = now_seconds();
+    let usage = quota::get(&env, &pubkey, quota::day(now)).await?;
+    let limits = quota::QuotaLimits::from_env(&env);
+    let response = QuotaResponse {
+        reads: usage.allowance(&limits, quota::Action::Read),
+        publishes: usage.allowance(&limits, quota::Action::Publish),
+        resets_at: quota::resets_at(now),
+        pubkey,
+    };
+    json_response_private(&response, 200, 0)
+}
+
+/// Forward a NIP-47 wallet-connect request to the wallet service's relay and return
+/// its reply, so browser clients only ever talk to the gateway origin
+async fn handle_nwc(mut req: Request, bot_action: BotAction) -> Result<Response> {
+    let url = req.url()?;
+    let auth_header = req.headers().get("Authorization")?;
+    if let Err(e) = crate::auth::validate_nip98(auth_header.as_deref(), "POST", url.as_str()) {
+        let err = ErrorResponse::new("auth_failed").with_detail(&e.to_string());
+        return json_response(&err, 401);
+    }
+    if bot_action == BotAction::Throttle {
+        let err = ErrorResponse::new("forbidden").with_detail("wallet relaying is not available to automated traffic");
+        return json_response(&err, 403);
+    }
+    let body: NwcRequest = match req.json().await {
+        Ok(b) => b,
+        Err(_) => {
+            let err = ErrorResponse::new("invalid_body").with_detail("expected {\"relay\": \"wss://...\", \"event\": {...}}");
+            return json_response(&err, 400);
+        }
+    };
+    let relay = match nwc::validate_relay_url(&body.relay) {
+        Ok(r) => r,
+        Err(detail) => return json_response(&ErrorResponse::new("invalid_relay").with_detail(detail), 400),
+    };
+    let wallet = match nwc::wallet_pubkey(&body.event) {
+        Ok(w) => w,
+        Err(detail) => return json_response(&ErrorResponse::new("invalid_event").with_detail(detail), 400),
+    };
 
-    let encoded = filter.to_base64();
-    let url = format!("http://internal/query?filter={}", encoded);
-    let req = Request::new(&url, Method::Get)?;
-    handle_query(req, env).await
+    match nwc::exchange(relay, &body.event, &wallet).await {
+        Ok(nwc::Outcome::Response(event)) => json_response(&NwcResponse { event }, 200),
+        Ok(nwc::Outcome::Rejected(reason)) => {
+            let err = ErrorResponse::new("wallet_relay_rejected").with_detail(&reason);
+            json_response(&err, 502)
+        }
+        Ok(nwc::Outcome::Timeout) => {
+            let err = ErrorResponse::new("wallet_timeout").with_detail("the wallet service did not answer in time");
+            json_response(&err, 504)
+        }
+        Err(e) => {
+            console_log!("NWC relay connection failed: {}", e);
+            let err = ErrorResponse::new("wallet_relay_unreachable").with_detail("could not connect to the wallet relay");
+            json_response(&err, 502)
+        }
+    }
 }
 
-async fn handle_event(_req: Request, env: Env, event_id: &str) -> Result<Response> {
-    // Create filter JSON directly
-    let filter_json = format!(r#"{{"ids":["{}"],"limit":1}}"#, event_id);
-    let filter = Filter::from_json(&filter_json).map_err(|e| worker::Error::from(e.to_string()))?;
+/// Prometheus scrape endpoint. Requires `Authorization: Bearer <METRICS_TOKEN>`, and
+/// isn't served at all until the token is set.
+async fn handle_metrics(req: Request, env: Env) -> Result<Response> {
+    let token = match env.secret("METRICS_TOKEN").map(|t| t.to_string()) {
+        Ok(token) if !token.is_empty() => token,
+        _ => {
+            let err = ErrorResponse::new("not_found").with_detail("metrics are not enabled");
+            return json_response(&err, 404);
+        }
+    };
+    let expected = format!("Bearer {}", token);
+    if req.headers().get("Authorization")?.as_deref() != Some(expected.as_str()) {
+        let err = ErrorResponse::new("unauthorized").with_detail("metrics token required");
+        return json_response(&err, 401);
+    }
 
-    let encoded = filter.to_base64();
-    let url = format!("http://internal/query?filter={}", encoded);
-    let req = Request::new(&url, Method::Get)?;
-    handle_query(req, env).await
+    let body = crate::metrics::render(&env).await?;
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "text/plain; version=0.0.4")?;
+    headers.set("Cache-Control", "no-store")?;
+    Ok(Response::ok(body)?.with_headers(headers))
 }
 
 async fn handle_publish_status(env: Env, event_id: &str) -> Result<Response> {
-    let kv = env.kv("REST_GATEWAY_CACHE")?;
-    let cache = Cache::new(kv);
+    let cache = Cache::from_env(&env)?;
 
     match cache.get_publish_status(event_id).await? {
         Some(status) => json_response(&status, 200),
@@ -179,17 +2514,138 @@ async fn handle_publish_status(env: Env, event_id: &str) -> Result<Response> {
     }
 }
 
-async fn handle_publish(mut req: Request, env: Env) -> Result<Response> {
-    // Get full URL for NIP-98 validation
-    let url = req.url()?.to_string();
+/// Withdraw a publish that hasn't reached the relay. Only the event author can cancel;
+/// the queue acks the message without sending it when it is delivered.
+async fn handle_publish_cancel(req: Request, env: Env) -> Result<Response> {
+    let url = req.url()?;
     let auth_header = req.headers().get("Authorization")?;
+    let author = match crate::auth::validate_nip98(auth_header.as_deref(), "DELETE", url.as_str()) {
+        Ok(auth) => auth.pubkey,
+        Err(e) => {
+            let err = ErrorResponse::new("auth_failed").with_detail(&e.to_string());
+            return json_response(&err, 401);
+        }
+    };
+    let event_id = &unversioned(url.path())["/publish/".len()..];
+
+    let cache = Cache::from_env(&env)?;
+    let mut status = match cache.get_publish_status(event_id).await? {
+        Some(s) if s.pubkey.as_deref() == Some(author.as_str()) => s,
+        _ => {
+            let err = ErrorResponse::new("not_found").with_detail("publish not found");
+            return json_response(&err, 404);
+        }
+    };
+    // The ledger checks and records the cancel in one step, so the queue can't
+    // pick the publish up between the check and the write
+    let was_quarantined = status.state == PublishState::Quarantined;
+    status.state = match publish_ledger::transition(&env, event_id, &status.state, PublishState::Cancelled).await? {
+        Transition::Moved(state) => state,
+        Transition::Refused(current) => {
+            let detail = format!("publish is already {}", current.as_str());
+            let err = ErrorResponse::new("not_cancellable").with_detail(&detail);
+            return json_response(&err, 409);
+        }
+    };
+    if was_quarantined {
+        cache.delete_quarantine(event_id).await?;
+    }
+    cache.set_publish_status(event_id, &status).await?;
+    record_publish(&env, "cancelled").await;
+    json_response(&status, 200)
+}
 
-    // Validate NIP-98 auth
-    match crate::auth::validate_nip98(auth_header.as_deref(), "POST", &url) {
-        Ok(_auth) => {
-            // Auth successful, proceed with publish
+/// Exchange one NIP-98 event, signed for this POST, for a session token that stands
+/// in for per-request signing until it expires
+async fn handle_session_create(mut req: Request, env: Env) -> Result<Response> {
+    let url = req.url()?;
+    let auth_header = req.headers().get("Authorization")?;
+    let pubkey = match crate::auth::validate_nip98(auth_header.as_deref(), "POST", url.as_str()) {
+        Ok(auth) => auth.pubkey,
+        Err(e) => {
+            let err = ErrorResponse::new("auth_failed").with_detail(&e.to_string());
+            return json_response(&err, 401);
+        }
+    };
+    let body = req.text().await?;
+    let request: SessionRequest = if body.trim().is_empty() {
+        SessionRequest::default()
+    } else {
+        match serde_json::from_str(&body) {
+            Ok(request) => request,
+            Err(_) => {
+                let err = ErrorResponse::new("invalid_body").with_detail("expected {\"scopes\": [\"read\", \"publish\"]}");
+                return json_response(&err, 400);
+            }
         }
+    };
+    let scopes = session::grant(&request.scopes);
+
+    let ttl = session::ttl(&env);
+    let record = Session {
+        pubkey,
+        scopes,
+        expires_at: now_seconds() + ttl,
+    };
+    let token = session::create(&env.kv("REST_GATEWAY_CACHE")?, &record, ttl).await?;
+    let response = SessionResponse {
+        token: Some(token),
+        pubkey: record.pubkey,
+        scopes: record.scopes,
+        expires_at: record.expires_at,
+    };
+    json_response_private(&response, 201, 0)
+}
+
+/// Revoke the session token the request is made with
+async fn handle_session_revoke(req: Request, env: Env) -> Result<Response> {
+    let header = req.headers().get("Authorization")?;
+    let token = header
+        .as_deref()
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| session::is_session_token(token));
+    let Some(token) = token else {
+        let err = ErrorResponse::new("auth_failed").with_detail("expected Authorization: Bearer <session token>");
+        return json_response(&err, 401);
+    };
+    let kv = env.kv("REST_GATEWAY_CACHE")?;
+    let Some(record) = session::get(&kv, token, now_seconds()).await? else {
+        let err = ErrorResponse::new("auth_failed").with_detail(&AuthError::UnknownSession.to_string());
+        return json_response(&err, 401);
+    };
+    session::revoke(&kv, token).await?;
+    let response = SessionResponse {
+        token: None,
+        pubkey: record.pubkey,
+        scopes: record.scopes,
+        expires_at: record.expires_at,
+    };
+    json_response_private(&response, 200, 0)
+}
+
+async fn handle_publish(mut req: Request, env: Env, ctx: &Context, caller: &Caller) -> Result<Response> {
+    // Get full URL for NIP-98 validation
+    let request_url = req.url()?;
+    let url = request_url.to_string();
+    let host = request_url.host_str().unwrap_or_default().to_string();
+    let auth_header = req.headers().get("Authorization")?;
+    let api_key_header = req.headers().get(api_keys::HEADER)?;
+
+    let auth_req = AuthRequest {
+        method: "POST",
+        url: &url,
+        authorization: auth_header.as_deref(),
+        api_key: api_key_header.as_deref(),
+        now: now_seconds(),
+    };
+    let mut signer = None;
+    match publish_auth(&env, &req, caller).authenticate(&auth_req).await {
+        Ok(Principal::Nostr(pubkey)) => signer = Some(pubkey),
+        // Keys, services and web-form visitors carry no signer, so note which one it was
+        Ok(principal) => console_log!("Publish authenticated as {}", principal),
         Err(e) => {
+            record_publish(&env, "auth_failed").await;
             let err = ErrorResponse::new("auth_failed").with_detail(&e.to_string());
             return json_response(&err, 401);
         }
@@ -204,39 +2660,154 @@ async fn handle_publish(mut req: Request, env: Env) -> Result<Response> {
         .and_then(|v| v.as_str())
         .unwrap_or("unknown")
         .to_string();
+    let pubkey = body
+        .event
+        .get("pubkey")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_string();
+
+    // A NIP-98 token only vouches for its own key's events, or those delegated to it
+    if let Some(signer) = signer.as_deref().filter(|_| publish_acl::require_author_match(&env)) {
+        if !publish_acl::signer_may_publish(signer, &body.event) {
+            record_publish(&env, "forbidden").await;
+            let err = ErrorResponse::new("forbidden").with_detail("auth pubkey does not match the event pubkey");
+            return json_response(&err, 403);
+        }
+    }
+
+    // Closed gateways and blocked keys
+    let involved: Vec<&str> = std::iter::once(pubkey.as_str()).chain(signer.as_deref()).collect();
+    if let Err(denial) = publish_acl::AccessLists::load(&env).await.check(&involved) {
+        record_publish(&env, "forbidden").await;
+        let err = ErrorResponse::new("forbidden").with_detail(&denial.to_string());
+        return json_response(&err, 403);
+    }
+
+    // NIP-98 publishes count against the signer's daily quota
+    if let Some(signer) = &signer {
+        if let Some(resp) = charge_quota(&env, signer, quota::Action::Publish).await {
+            record_publish(&env, "quota_exceeded").await;
+            return resp;
+        }
+    }
 
-    // TODO: Queue for publishing - Cloudflare Queues API not yet available in worker-rs
-    // For now, return a placeholder response
-    // let queue = env.queue("PUBLISH_QUEUE")?;
-    // queue.send(body.event).await?;
+    let deletion_targets = html_cache::deletion_targets(&body.event);
+
+    let cache = Cache::from_env(&env)?;
+
+    // Borderline publishes are accepted but held instead of rejected outright
+    let policy = QuarantinePolicy::from_env(&env);
+    let reasons = if policy.enabled {
+        let signals = PublishSignals {
+            first_publish: !cache.is_known_publisher(&pubkey).await?,
+            wot_score: cache.wot_score(&pubkey).await?,
+            hourly_publishes: cache.incr_hourly_publishes(&pubkey).await?,
+        };
+        policy.evaluate(&signals)
+    } else {
+        Vec::new()
+    };
+
+    let queue = env.queue("PUBLISH_QUEUE")?;
+    let state = if reasons.is_empty() {
+        queue.send(body.event).await?;
+        PublishState::Queued
+    } else {
+        // Held events are released by the delayed queue message unless an admin
+        // rejects them first; approval re-sends immediately
+        let now = now_seconds();
+        let entry = QuarantineEntry {
+            event: body.event.clone(),
+            reasons: reasons.clone(),
+            held_at: now,
+            release_at: now + policy.delay_seconds as u64,
+        };
+        cache.put_quarantine(&event_id, &entry).await?;
+        queue
+            .send(MessageBuilder::new(body.event).delay_seconds(policy.delay_seconds).build())
+            .await?;
+        PublishState::Quarantined
+    };
 
     // Set initial status
-    let kv = env.kv("REST_GATEWAY_CACHE")?;
-    let cache = Cache::new(kv);
-    let status = crate::types::PublishStatus {
-        status: "queued".to_string(),
-        attempts: Some(0),
-        verified_at: None,
-        error: None,
+    let outcome = state.as_str();
+    let publish_status = crate::types::PublishStatus {
+        quarantine_reasons: (!reasons.is_empty()).then_some(reasons),
+        pubkey: Some(pubkey),
+        ..crate::types::PublishStatus::new(state, 0)
     };
-    cache.set_publish_status(&event_id, &status).await?;
+    // Written behind the response. The consumer treats a missing status as queued,
+    // and this write doesn't replace one it has already advanced.
+    let status_env = env.clone();
+    let status_id = event_id.clone();
+    ctx.wait_until(async move {
+        let written = async {
+            let cache = Cache::from_env(&status_env)?;
+            if cache.get_publish_status(&status_id).await?.is_none() {
+                cache.set_publish_status(&status_id, &publish_status).await?;
+            }
+            Ok::<_, Error>(())
+        };
+        if let Err(e) = written.await {
+            console_log!("Failed to store publish status of {}: {}", status_id, e);
+        }
+    });
+    record_publish(&env, outcome).await;
+
+    // A deletion makes cached renders of its targets stale
+    for target in &deletion_targets {
+        html_cache::purge_event(&env, &host, target, "deletion").await;
+    }
 
     let response = crate::types::PublishResponse {
-        status: "queued".to_string(),
+        status: outcome.to_string(),
         event_id,
     };
     json_response(&response, 202)
 }
 
-fn landing_page() -> Result<Response> {
-    let html = r#"<!DOCTYPE html>
+/// Publishing takes a publish-scoped API key or session, a service JWT or Turnstile
+/// token where the operator has configured them, or NIP-98
+fn publish_auth(env: &Env, req: &Request, caller: &Caller) -> AnyOf {
+    let mut providers: Vec<Box<dyn AuthProvider>> = vec![Box::new(ApiKeyAuth {
+        key: caller.key.clone(),
+        scope: Scope::Publish,
+    })];
+    if let Ok(kv) = env.kv("REST_GATEWAY_CACHE") {
+        providers.push(Box::new(SessionAuth {
+            kv,
+            scope: Scope::Publish,
+        }));
+    }
+    if let Some(config) = JwtConfig::from_env(env) {
+        providers.push(Box::new(Jwt(config)));
+    }
+    if let Some(turnstile) = Turnstile::from_request(env, req) {
+        providers.push(Box::new(turnstile));
+    }
+    providers.push(Box::new(Nip98));
+    AnyOf(providers)
+}
+
+async fn record_publish(env: &Env, outcome: &str) {
+    let observation = Observation::Publish {
+        outcome: outcome.to_string(),
+    };
+    crate::metrics::record(env, &observation).await;
+}
+
+/// The landing page, in the host's branding. The template is full of literal braces
+/// (CSS, JS, JSON), so branded values go in through `%PLACEHOLDER%`s rather than `format!`.
+fn landing_page(branding: &Branding) -> Result<Response> {
+    let template = r#"<!DOCTYPE html>
 <html lang="en">
 <head>
     <meta charset="UTF-8">
     <meta name="viewport" content="width=device-width, initial-scale=1.0">
-    <title>Divine REST Gateway</title>
+    <title>%NAME%</title>
     <style>
-        :root { --bg: #0d1117; --fg: #c9d1d9; --accent: #58a6ff; --code-bg: #161b22; --border: #30363d; }
+        :root { --bg: %BACKGROUND%; --fg: %TEXT%; --accent: %ACCENT%; --code-bg: %SURFACE%; --border: %BORDER%; }
         * { box-sizing: border-box; }
         body { font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', sans-serif; background: var(--bg); color: var(--fg); line-height: 1.6; margin: 0; padding: 2rem; max-width: 900px; margin: 0 auto; }
         h1, h2, h3 { color: #fff; }
@@ -253,10 +2824,11 @@ fn landing_page() -> Result<Response> {
         .path { font-family: monospace; color: var(--accent); }
         .desc { margin-top: 0.5rem; color: #8b949e; }
         .try-it { margin-top: 0.5rem; font-size: 0.9em; }
+        .logo { height: 1.5em; vertical-align: middle; margin-right: 0.5rem; }
     </style>
 </head>
 <body>
-    <h1>Divine REST Gateway</h1>
+    <h1>%LOGO%%NAME%</h1>
     <p>REST API caching proxy for <a href="https://nostr.com">Nostr</a>, running on Cloudflare Workers.</p>
 
     <h2>How It Works</h2>
@@ -295,6 +2867,42 @@ fn landing_page() -> Result<Response> {
         <p class="desc">Get a single event by its ID.</p>
     </div>
 
+    <div class="endpoint">
+        <span class="method get">GET</span>
+        <span class="path">/relay-info?url=&lt;wss-url&gt;</span>
+        <p class="desc">NIP-11 information document for the upstream relay (or any relay via <code>url</code>), with supported NIPs and limits.</p>
+        <div class="try-it">
+            <a href="/relay-info">Try it</a>
+        </div>
+    </div>
+
+    <div class="endpoint">
+        <span class="method get">GET</span>
+        <span class="path">/stats</span>
+        <p class="desc">Gateway stats: events served, 24h cache hit rate and publishes, and the upstream relay. Refreshed every 15 minutes.</p>
+        <div class="try-it">
+            <a href="/stats">Try it</a>
+        </div>
+    </div>
+
+    <div class="endpoint">
+        <span class="method get">GET</span>
+        <span class="path">/embed/{id}</span>
+        <p class="desc">HTML preview of an event with Open Graph and Twitter Card tags, for sharing links in chat apps.</p>
+    </div>
+
+    <div class="endpoint">
+        <span class="method get">GET</span>
+        <span class="path">/video/{naddr}</span>
+        <p class="desc">Get a NIP-71 video (kind 34235/34236) by naddr, with <code>imeta</code> tags parsed into structured media entries.</p>
+    </div>
+
+    <div class="endpoint">
+        <span class="method get">GET</span>
+        <span class="path">/videos/{pubkey}?limit=20&amp;until=&lt;timestamp&gt;</span>
+        <p class="desc">List a user's videos, newest first, with parsed media entries (url, dim, duration, thumbnails).</p>
+    </div>
+
     <div class="endpoint">
         <span class="method post">POST</span>
         <span class="path">/publish</span>
@@ -326,11 +2934,14 @@ fetch(`/query?filter=${encoded}`);</code></pre>
   "eose": true,         // End of stored events reached
   "complete": true,     // Query fully satisfied
   "cached": true,       // Response served from cache
-  "cache_age_seconds": 42
+  "stale": false,       // Cached copy past its TTL, being refreshed
+  "cache_age_seconds": 42,
+  "source": "cache"     // cache, relay, or archive
 }</code></pre>
+    <p>Add <code>source=archive</code> to answer only from the event archive, or <code>source=relay</code> to force a live relay query.</p>
 
     <h2>Cache Behavior</h2>
-    <p>TTLs vary by content type:</p>
+    <p>TTLs vary by content type, taking the shortest when a filter asks for several kinds:</p>
     <ul>
         <li><strong>Profiles (kind 0)</strong>: 15 minutes</li>
         <li><strong>Contacts (kind 3)</strong>: 10 minutes</li>
@@ -338,38 +2949,117 @@ fetch(`/query?filter=${encoded}`);</code></pre>
         <li><strong>Reactions (kind 7)</strong>: 2 minutes</li>
         <li><strong>Other queries</strong>: 5 minutes</li>
     </ul>
+    <p>A lookup of one event by its full id is cached for a day, and once found is sent with a 5-minute <code>max-age</code> and a day of <code>stale-while-revalidate</code>; other lookups by <code>ids</code> are cached for an hour, and filters whose <code>until</code> is over an hour old for 6 hours.</p>
     <h3>Cache Bypass</h3>
     <p>To force a fresh fetch from the relay, use either:</p>
     <ul>
-        <li><code>?nocache=1</code> query parameter</li>
+        <li><code>?cache=bypass</code> (or <code>?nocache=1</code>) query parameter</li>
         <li><code>Cache-Control: no-cache</code> request header</li>
     </ul>
+    <p>Bypass is for callers with an API key or NIP-98 auth. The fresh result is still written back to the cache.</p>
 
     <h2>Source Code</h2>
     <p>Written in Rust, compiled to WebAssembly. <a href="https://github.com/divinevideo/divine-rest-gateway">View on GitHub</a></p>
-
+%SUPPORT%
     <footer style="margin-top: 3rem; padding-top: 1rem; border-top: 1px solid var(--border); color: #8b949e; font-size: 0.9em;">
-        Divine REST Gateway v0.1.0 &middot; Powered by Cloudflare Workers
+        %NAME% v0.1.0 &middot; Powered by Cloudflare Workers
     </footer>
 </body>
 </html>"#;
 
+    let logo = branding
+        .logo_url
+        .as_ref()
+        .map(|l| format!(r#"<img class="logo" src="{}" alt="">"#, escape_html(l)))
+        .unwrap_or_default();
+    let support: Vec<String> = [
+        branding.support_url.as_ref().map(|u| (u.clone(), u.clone())),
+        branding.support_email.as_ref().map(|e| (format!("mailto:{}", e), e.clone())),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|(href, text)| format!(r#"<a href="{}">{}</a>"#, escape_html(&href), escape_html(&text)))
+    .collect();
+    let support = if support.is_empty() {
+        String::new()
+    } else {
+        format!("\n    <h2>Support</h2>\n    <p>{}</p>\n", support.join(" &middot; "))
+    };
+    let colors = &branding.colors;
+    let html = template
+        .replace("%NAME%", &escape_html(&branding.name))
+        .replace("%LOGO%", &logo)
+        .replace("%SUPPORT%", &support)
+        .replace("%BACKGROUND%", &escape_html(&colors.background))
+        .replace("%SURFACE%", &escape_html(&colors.surface))
+        .replace("%TEXT%", &escape_html(&colors.text))
+        .replace("%ACCENT%", &escape_html(&colors.accent))
+        .replace("%BORDER%", &escape_html(&colors.border));
+
     let mut headers = Headers::new();
     headers.set("Content-Type", "text/html; charset=utf-8")?;
     Ok(Response::from_body(ResponseBody::Body(html.as_bytes().to_vec()))?.with_headers(headers))
 }
 
-fn json_response<T: serde::Serialize>(data: &T, status: u16) -> Result<Response> {
+pub(crate) fn json_response<T: serde::Serialize>(data: &T, status: u16) -> Result<Response> {
+    let body = serde_json::to_string(data)?;
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    Ok(Response::from_body(ResponseBody::Body(body.into_bytes()))?.with_status(status).with_headers(headers))
+}
+
+/// Public responses of personalizable endpoints must not answer authenticated requests,
+/// nor callers on another API key tier
+fn vary_on_authorization(response: Result<Response>) -> Result<Response> {
+    let mut resp = response?;
+    resp.headers_mut().set("Vary", "Authorization, X-Api-Key")?;
+    Ok(resp)
+}
+
+/// Let downstream caches serve a response for `window` seconds past its max-age
+/// while they revalidate it in the background
+fn stale_while_revalidate(mut resp: Response, window: u64) -> Result<Response> {
+    let cache_control = resp.headers().get("Cache-Control")?.unwrap_or_default();
+    resp.headers_mut().set("Cache-Control", &format!("{}, stale-while-revalidate={}", cache_control, window))?;
+    Ok(resp)
+}
+
+/// Set the standard `Age` header, in seconds
+fn with_age(mut resp: Response, age: u64) -> Result<Response> {
+    resp.headers_mut().set("Age", &age.to_string())?;
+    Ok(resp)
+}
+
+/// Cacheable only by the requesting client, and keyed on its credentials
+fn json_response_private<T: serde::Serialize>(data: &T, status: u16, max_age: u64) -> Result<Response> {
     let body = serde_json::to_string(data)?;
     let mut headers = Headers::new();
     headers.set("Content-Type", "application/json")?;
+    headers.set("Cache-Control", &format!("private, max-age={}", max_age))?;
+    headers.set("Vary", "Authorization, X-Api-Key")?;
     Ok(Response::from_body(ResponseBody::Body(body.into_bytes()))?.with_status(status).with_headers(headers))
 }
 
+/// Newest `created_at` among a body's events, which is when its answer last changed
+fn newest_created_at(body: &serde_json::Value) -> Option<u64> {
+    body.get("events")?.as_array()?.iter().filter_map(|e| e.get("created_at")?.as_u64()).max()
+}
+
 fn json_response_with_cache<T: serde::Serialize>(data: &T, status: u16, max_age: u64) -> Result<Response> {
     let body = serde_json::to_string(data)?;
     let mut headers = Headers::new();
     headers.set("Content-Type", "application/json")?;
-    headers.set("Cache-Control", &format!("public, max-age={}, s-maxage={}", max_age, max_age))?;
+    // Downstream caches may keep serving an answer through our errors for as long as KV would
+    let cache_control = match crate::cache::stale_window(max_age) {
+        0 => format!("public, max-age={}, s-maxage={}", max_age, max_age),
+        window => format!("public, max-age={}, s-maxage={}, stale-if-error={}", max_age, max_age, window),
+    };
+    headers.set("Cache-Control", &cache_control)?;
+    let value = serde_json::to_value(data)?;
+    headers.set("ETag", &etag::compute(&value))?;
+    if let Some(newest) = newest_created_at(&value) {
+        // An event stamped in the future doesn't make the answer newer than now
+        headers.set("Last-Modified", &http_date::format(newest.min(now_seconds())))?;
+    }
     Ok(Response::from_body(ResponseBody::Body(body.into_bytes()))?.with_status(status).with_headers(headers))
 }
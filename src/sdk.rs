@@ -0,0 +1,239 @@
+// ABOUTME: TypeScript and Swift API descriptions generated from the OpenAPI document
+// ABOUTME: Build-time only (feature "sdk"); run via `cargo run --features sdk --bin sdkgen`
+
+use serde_json::Value;
+
+const HEADER: &str = "Generated by sdkgen from the divine-rest-gateway route registry. Do not edit.";
+
+fn schemas(doc: &Value) -> Vec<(&String, &Value)> {
+    doc["components"]["schemas"]
+        .as_object()
+        .map(|s| s.iter().collect())
+        .unwrap_or_default()
+}
+
+/// (operation id, method, path, response schema name) for every JSON operation
+fn operations(doc: &Value) -> Vec<(String, String, String, Option<String>)> {
+    let mut ops = Vec::new();
+    for (path, methods) in doc["paths"].as_object().into_iter().flatten() {
+        for (method, op) in methods.as_object().into_iter().flatten() {
+            let Some(id) = op["operationId"].as_str() else { continue };
+            let response = op["responses"]
+                .as_object()
+                .and_then(|r| r.iter().find(|(status, _)| status.starts_with('2')))
+                .and_then(|(_, r)| r["content"]["application/json"]["schema"]["$ref"].as_str())
+                .map(ref_name);
+            ops.push((id.to_string(), method.to_uppercase(), path.clone(), response));
+        }
+    }
+    ops.sort();
+    ops
+}
+
+fn ref_name(reference: &str) -> String {
+    reference.rsplit('/').next().unwrap_or(reference).to_string()
+}
+
+/// Unwrap `allOf: [{$ref}]`, which schemars emits for documented references
+fn single_ref(schema: &Value) -> Option<String> {
+    if let Some(r) = schema["$ref"].as_str() {
+        return Some(ref_name(r));
+    }
+    match schema["allOf"].as_array() {
+        Some(all) if all.len() == 1 => all[0]["$ref"].as_str().map(ref_name),
+        _ => None,
+    }
+}
+
+fn string_enum(schema: &Value) -> Option<Vec<&str>> {
+    schema["enum"].as_array().map(|values| values.iter().filter_map(|v| v.as_str()).collect())
+}
+
+fn required(schema: &Value) -> Vec<&str> {
+    schema["required"]
+        .as_array()
+        .map(|r| r.iter().filter_map(|v| v.as_str()).collect())
+        .unwrap_or_default()
+}
+
+fn properties(schema: &Value) -> Vec<(&String, &Value)> {
+    schema["properties"]
+        .as_object()
+        .map(|p| p.iter().collect())
+        .unwrap_or_default()
+}
+
+fn is_optional(schema: &Value, required: &[&str], name: &str) -> bool {
+    !required.contains(&name) || schema["nullable"].as_bool().unwrap_or(false)
+}
+
+fn ts_type(schema: &Value) -> String {
+    if let Some(name) = single_ref(schema) {
+        return name;
+    }
+    if let Some(values) = string_enum(schema) {
+        return values.iter().map(|v| format!("{:?}", v)).collect::<Vec<_>>().join(" | ");
+    }
+    match schema["type"].as_str() {
+        Some("string") => "string".to_string(),
+        Some("integer") | Some("number") => "number".to_string(),
+        Some("boolean") => "boolean".to_string(),
+        Some("array") => format!("{}[]", ts_type(&schema["items"])),
+        Some("object") if schema.get("properties").is_none() => "Record<string, unknown>".to_string(),
+        _ => "unknown".to_string(),
+    }
+}
+
+fn doc_comment(out: &mut String, indent: &str, marker: &str, schema: &Value) {
+    if let Some(description) = schema["description"].as_str() {
+        for line in description.lines() {
+            out.push_str(&format!("{}{} {}\n", indent, marker, line));
+        }
+    }
+}
+
+pub fn typescript(doc: &Value) -> String {
+    let mut out = format!("// {}\n\n", HEADER);
+    for (name, schema) in schemas(doc) {
+        if let Some(values) = string_enum(schema) {
+            let members: Vec<_> = values.iter().map(|v| format!("{:?}", v)).collect();
+            out.push_str(&format!("export type {} = {};\n\n", name, members.join(" | ")));
+            continue;
+        }
+        let required = required(schema);
+        out.push_str(&format!("export interface {} {{\n", name));
+        for (field, field_schema) in properties(schema) {
+            doc_comment(&mut out, "  ", "//", field_schema);
+            let optional = if is_optional(field_schema, &required, field) { "?" } else { "" };
+            out.push_str(&format!("  {}{}: {};\n", field, optional, ts_type(field_schema)));
+        }
+        out.push_str("}\n\n");
+    }
+
+    let ops = operations(doc);
+    out.push_str("export const endpoints = {\n");
+    for (id, method, path, _) in &ops {
+        out.push_str(&format!("  {}: {{ method: {:?}, path: {:?} }},\n", id, method, path));
+    }
+    out.push_str("} as const;\n\n");
+    out.push_str("export interface Responses {\n");
+    for (id, _, _, response) in &ops {
+        let response = response.clone().unwrap_or_else(|| "string".to_string());
+        out.push_str(&format!("  {}: {};\n", id, response));
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn swift_type(schema: &Value) -> String {
+    if let Some(name) = single_ref(schema) {
+        return name;
+    }
+    match schema["type"].as_str() {
+        Some("string") => "String".to_string(),
+        Some("integer") => match schema["format"].as_str() {
+            Some("uint64") | Some("uint32") | Some("uint") => "UInt64".to_string(),
+            _ => "Int64".to_string(),
+        },
+        Some("number") => "Double".to_string(),
+        Some("boolean") => "Bool".to_string(),
+        Some("array") => format!("[{}]", swift_type(&schema["items"])),
+        _ => "JSONValue".to_string(),
+    }
+}
+
+/// Arbitrary JSON (raw Nostr events, NIP-11 documents)
+const SWIFT_JSON_VALUE: &str = r#"public enum JSONValue: Codable, Equatable {
+    case null
+    case bool(Bool)
+    case number(Double)
+    case string(String)
+    case array([JSONValue])
+    case object([String: JSONValue])
+
+    public init(from decoder: Decoder) throws {
+        let c = try decoder.singleValueContainer()
+        if c.decodeNil() { self = .null }
+        else if let v = try? c.decode(Bool.self) { self = .bool(v) }
+        else if let v = try? c.decode(Double.self) { self = .number(v) }
+        else if let v = try? c.decode(String.self) { self = .string(v) }
+        else if let v = try? c.decode([JSONValue].self) { self = .array(v) }
+        else { self = .object(try c.decode([String: JSONValue].self)) }
+    }
+
+    public func encode(to encoder: Encoder) throws {
+        var c = encoder.singleValueContainer()
+        switch self {
+        case .null: try c.encodeNil()
+        case .bool(let v): try c.encode(v)
+        case .number(let v): try c.encode(v)
+        case .string(let v): try c.encode(v)
+        case .array(let v): try c.encode(v)
+        case .object(let v): try c.encode(v)
+        }
+    }
+}
+"#;
+
+pub fn swift(doc: &Value) -> String {
+    let mut out = format!("// {}\n\nimport Foundation\n\n{}\n", HEADER, SWIFT_JSON_VALUE);
+    for (name, schema) in schemas(doc) {
+        if let Some(values) = string_enum(schema) {
+            out.push_str(&format!("public enum {}: String, Codable {{\n", name));
+            for value in values {
+                out.push_str(&format!("    case {}\n", value));
+            }
+            out.push_str("}\n\n");
+            continue;
+        }
+        let required = required(schema);
+        out.push_str(&format!("public struct {}: Codable {{\n", name));
+        for (field, field_schema) in properties(schema) {
+            doc_comment(&mut out, "    ", "///", field_schema);
+            let optional = if is_optional(field_schema, &required, field) { "?" } else { "" };
+            out.push_str(&format!("    public let {}: {}{}\n", field, swift_type(field_schema), optional));
+        }
+        out.push_str("}\n\n");
+    }
+
+    out.push_str("public struct Endpoint<Response: Decodable> {\n    public let method: String\n    public let path: String\n}\n\n");
+    out.push_str("public enum Endpoints {\n");
+    for (id, method, path, response) in operations(doc) {
+        let response = response.unwrap_or_else(|| "String".to_string());
+        out.push_str(&format!(
+            "    public static let {} = Endpoint<{}>(method: {:?}, path: {:?})\n",
+            id, response, method, path
+        ));
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_typescript_from_registry() {
+        let ts = typescript(&crate::openapi::document());
+        assert!(ts.contains("export type QuerySource = \"cache\" | \"relay\" | \"archive\";"));
+        assert!(ts.contains("export interface QueryResponse {"));
+        assert!(ts.contains("  events: unknown[];"));
+        assert!(ts.contains("  cache_age_seconds?: number;"));
+        assert!(ts.contains("  source: QuerySource;"));
+        assert!(ts.contains("  query: { method: \"GET\", path: \"/query\" },"));
+        assert!(ts.contains("  getEmbed: string;"));
+    }
+
+    #[test]
+    fn test_swift_from_registry() {
+        let swift = swift(&crate::openapi::document());
+        assert!(swift.contains("public enum QuerySource: String, Codable {"));
+        assert!(swift.contains("public struct PublishResponse: Codable {"));
+        assert!(swift.contains("    public let events: [JSONValue]\n"));
+        assert!(swift.contains("    public let cache_age_seconds: UInt64?\n"));
+        assert!(swift.contains(
+            "    public static let getPublishStatus = Endpoint<PublishStatus>(method: \"GET\", path: \"/publish/status/{id}\")"
+        ));
+    }
+}
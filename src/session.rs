@@ -0,0 +1,150 @@
+// ABOUTME: Short-lived session tokens exchanged for one NIP-98 event, for clients that can't sign every call
+// ABOUTME: Sessions are stored in KV under the token's SHA-256 hash and expire with their KV entry
+
+use crate::api_keys::Scope;
+use crate::auth::{AuthError, AuthProvider, AuthRequest, Principal};
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use worker::kv::KvStore;
+use worker::*;
+
+/// KV prefix of stored sessions, followed by the token's hash
+const KEY_PREFIX: &str = "session:";
+
+/// Prefix of every session token, which tells them apart from API keys and JWTs
+const TOKEN_PREFIX: &str = "dgs_";
+
+/// Session lifetime unless `SESSION_TTL_SECONDS` says otherwise
+const DEFAULT_TTL_SECONDS: u64 = 3600;
+
+/// Longest lifetime an operator can configure
+const MAX_TTL_SECONDS: u64 = 7 * 86400;
+
+/// What a session may be used for
+pub const GRANTABLE: [Scope; 2] = [Scope::Read, Scope::Publish];
+
+/// A stored session. The token itself is only shown once, when it is created.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub pubkey: String,
+    pub scopes: Vec<Scope>,
+    pub expires_at: u64,
+}
+
+impl Session {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+/// Configured session lifetime in seconds, at least KV's 60-second minimum
+pub fn ttl(env: &Env) -> u64 {
+    env.var("SESSION_TTL_SECONDS")
+        .ok()
+        .and_then(|v| v.to_string().trim().parse::<u64>().ok())
+        .unwrap_or(DEFAULT_TTL_SECONDS)
+        .clamp(60, MAX_TTL_SECONDS)
+}
+
+/// The scopes to grant for a request: everything grantable when none are asked
+/// for, otherwise exactly those asked for
+pub fn grant(requested: &[Scope]) -> Vec<Scope> {
+    if requested.is_empty() {
+        return GRANTABLE.to_vec();
+    }
+    let mut scopes = requested.to_vec();
+    scopes.dedup();
+    scopes
+}
+
+pub fn is_session_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).expect("RNG available in Workers");
+    format!("{}{}", TOKEN_PREFIX, hex::encode(bytes))
+}
+
+fn storage_key(token: &str) -> String {
+    format!("{}{}", KEY_PREFIX, hex::encode(Sha256::digest(token.as_bytes())))
+}
+
+/// Store a session and return its new token
+pub async fn create(kv: &KvStore, session: &Session, ttl: u64) -> Result<String> {
+    let token = new_token();
+    kv.put(&storage_key(&token), serde_json::to_string(session)?)?
+        .expiration_ttl(ttl)
+        .execute()
+        .await?;
+    Ok(token)
+}
+
+/// The session behind a token, if it exists and hasn't expired
+pub async fn get(kv: &KvStore, token: &str, now: u64) -> Result<Option<Session>> {
+    let session = kv.get(&storage_key(token)).json::<Session>().await?;
+    // KV expiry is lazy, so check the clock too
+    Ok(session.filter(|s| s.expires_at > now))
+}
+
+pub async fn revoke(kv: &KvStore, token: &str) -> Result<()> {
+    kv.delete(&storage_key(token)).await?;
+    Ok(())
+}
+
+/// A session token in `Authorization: Bearer ...` that must hold `scope`.
+/// Acts for the pubkey that signed the exchange.
+pub struct SessionAuth {
+    pub kv: KvStore,
+    pub scope: Scope,
+}
+
+impl AuthProvider for SessionAuth {
+    fn presented(&self, req: &AuthRequest) -> bool {
+        req.bearer().is_some_and(is_session_token)
+    }
+
+    fn authenticate<'a>(&'a self, req: &'a AuthRequest<'a>) -> LocalBoxFuture<'a, std::result::Result<Principal, AuthError>> {
+        async move {
+            let token = req.bearer().ok_or(AuthError::MissingHeader)?;
+            let session = match get(&self.kv, token, req.now).await {
+                Ok(Some(session)) => session,
+                Ok(None) => return Err(AuthError::UnknownSession),
+                Err(e) => {
+                    console_log!("Session lookup failed: {}", e);
+                    return Err(AuthError::UnknownSession);
+                }
+            };
+            if !session.allows(self.scope) {
+                return Err(AuthError::SessionScope(self.scope));
+            }
+            Ok(Principal::Nostr(session.pubkey))
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grant() {
+        assert_eq!(grant(&[]), vec![Scope::Read, Scope::Publish]);
+        assert_eq!(grant(&[Scope::Read, Scope::Read]), vec![Scope::Read]);
+        assert_eq!(grant(&[Scope::Publish]), vec![Scope::Publish]);
+    }
+
+    #[test]
+    fn test_tokens() {
+        assert!(is_session_token("dgs_abc"));
+        assert!(!is_session_token("dgk_abc"));
+        assert!(!crate::api_keys::is_key_token("dgs_abc"));
+        // Stored under the hash, never the token
+        let key = storage_key("dgs_abc");
+        assert!(key.starts_with("session:"));
+        assert!(!key.contains("dgs_abc"));
+    }
+}
@@ -0,0 +1,146 @@
+// ABOUTME: Public gateway stats aggregated on a schedule into KV
+// ABOUTME: Turns cumulative metrics counters into 24-hour windows for GET /stats
+
+use crate::cache::now_seconds;
+use crate::metrics::MetricsState;
+use crate::types::StatsResponse;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use worker::*;
+
+const WINDOW_SECONDS: u64 = 86400;
+const HISTORY_KEY: &str = "stats:history";
+pub const CURRENT_KEY: &str = "stats:current";
+
+/// Cumulative counters at one point in time
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct StatsSample {
+    pub at: u64,
+    pub events_served: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub publishes: u64,
+}
+
+impl StatsSample {
+    pub fn from_metrics(metrics: &MetricsState, at: u64) -> Self {
+        Self {
+            at,
+            events_served: metrics.events_served(),
+            cache_hits: metrics.cache_hits(),
+            cache_misses: metrics.cache_misses(),
+            publishes: metrics.publishes("published"),
+        }
+    }
+}
+
+/// Samples spanning the last day, plus one baseline at or before the window start
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StatsHistory {
+    samples: VecDeque<StatsSample>,
+}
+
+impl StatsHistory {
+    pub fn push(&mut self, sample: StatsSample) {
+        self.samples.push_back(sample);
+        let cutoff = sample.at.saturating_sub(WINDOW_SECONDS);
+        while self.samples.len() > 1 && self.samples[1].at <= cutoff {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Stats over the window. Counters can reset when the metrics DO loses
+    /// state, so deltas saturate at zero rather than going negative.
+    pub fn summarize(&self, relay: &str) -> Option<StatsResponse> {
+        let latest = self.samples.back()?;
+        let baseline = self.samples.front()?;
+        let hits = latest.cache_hits.saturating_sub(baseline.cache_hits);
+        let misses = latest.cache_misses.saturating_sub(baseline.cache_misses);
+        Some(StatsResponse {
+            events_served: latest.events_served,
+            events_served_24h: latest.events_served.saturating_sub(baseline.events_served),
+            cache_hit_rate_24h: (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64),
+            publishes_24h: latest.publishes.saturating_sub(baseline.publishes),
+            relay: relay.to_string(),
+            updated_at: latest.at,
+        })
+    }
+}
+
+/// Scheduled job: snapshot the metrics counters and refresh the published stats
+pub async fn aggregate(env: &Env) -> Result<()> {
+    let metrics = crate::metrics::snapshot(env).await?;
+    let kv = env.kv("REST_GATEWAY_CACHE")?;
+
+    let mut history = kv
+        .get(HISTORY_KEY)
+        .json::<StatsHistory>()
+        .await?
+        .unwrap_or_default();
+    history.push(StatsSample::from_metrics(&metrics, now_seconds()));
+    kv.put(HISTORY_KEY, serde_json::to_string(&history)?)?
+        .execute()
+        .await?;
+
+    if let Some(stats) = history.summarize(&crate::router::relay_url(env)) {
+        kv.put(CURRENT_KEY, serde_json::to_string(&stats)?)?
+            .execute()
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(at: u64, events: u64, hits: u64, misses: u64, publishes: u64) -> StatsSample {
+        StatsSample {
+            at,
+            events_served: events,
+            cache_hits: hits,
+            cache_misses: misses,
+            publishes,
+        }
+    }
+
+    #[test]
+    fn test_window_deltas() {
+        let mut history = StatsHistory::default();
+        history.push(sample(1_000, 100, 10, 10, 5));
+        history.push(sample(1_000 + WINDOW_SECONDS, 400, 40, 20, 9));
+        let stats = history.summarize("wss://relay.divine.video").unwrap();
+        assert_eq!(stats.events_served, 400);
+        assert_eq!(stats.events_served_24h, 300);
+        assert_eq!(stats.cache_hit_rate_24h, Some(0.75));
+        assert_eq!(stats.publishes_24h, 4);
+        assert_eq!(stats.updated_at, 1_000 + WINDOW_SECONDS);
+    }
+
+    #[test]
+    fn test_old_samples_pruned_to_one_baseline() {
+        let mut history = StatsHistory::default();
+        for hour in 0..30 {
+            history.push(sample(hour * 3600, hour * 10, 0, 0, 0));
+        }
+        // Baseline is the newest sample at or before now - 24h
+        assert_eq!(history.samples.front().unwrap().at, 5 * 3600);
+        assert_eq!(history.summarize("r").unwrap().events_served_24h, 240);
+    }
+
+    #[test]
+    fn test_counter_reset_saturates() {
+        let mut history = StatsHistory::default();
+        history.push(sample(0, 500, 50, 50, 10));
+        history.push(sample(600, 20, 0, 0, 1));
+        let stats = history.summarize("r").unwrap();
+        assert_eq!(stats.events_served_24h, 0);
+        assert_eq!(stats.cache_hit_rate_24h, None);
+        assert_eq!(stats.publishes_24h, 0);
+    }
+
+    #[test]
+    fn test_empty_history() {
+        assert!(StatsHistory::default().summarize("r").is_none());
+    }
+}
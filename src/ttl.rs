@@ -0,0 +1,221 @@
+// ABOUTME: Per-kind cache TTLs, and the global floor and ceiling for every cache TTL the gateway sets
+// ABOUTME: Bounds are applied after per-kind and per-route TTL logic so operator bounds always win
+
+use crate::kind;
+use std::collections::HashMap;
+use worker::Env;
+
+/// KV rejects expirations shorter than this
+pub const KV_MIN_TTL_SECONDS: u64 = 60;
+
+/// TTL of kinds with no entry of their own
+const DEFAULT_TTL_SECONDS: u64 = 300;
+
+/// Built-in TTLs of "latest" queries by kind
+fn builtin_kind_ttl(kind: u16) -> Option<u64> {
+    match kind {
+        kind::PROFILE => Some(900),  // 15 min
+        kind::CONTACTS => Some(600), // 10 min
+        kind::NOTE => Some(300),     // 5 min
+        kind::REACTION => Some(120), // 2 min
+        _ => None,
+    }
+}
+
+/// TTLs of "latest" queries by kind: the built-in table, overridden by
+/// `CACHE_KIND_TTLS`, a JSON object of kind to seconds whose `"default"` entry
+/// covers every kind listed in neither
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KindTtls {
+    overrides: HashMap<u16, u64>,
+    default: Option<u64>,
+}
+
+impl KindTtls {
+    /// Load from `CACHE_KIND_TTLS`. A malformed value is logged and ignored.
+    pub fn from_env(env: &Env) -> Self {
+        let raw = env.var("CACHE_KIND_TTLS").map(|v| v.to_string()).unwrap_or_default();
+        if raw.trim().is_empty() {
+            return Self::default();
+        }
+        Self::parse(&raw).unwrap_or_else(|e| {
+            worker::console_log!("Ignoring CACHE_KIND_TTLS: {}", e);
+            Self::default()
+        })
+    }
+
+    /// Parse `{"0": 900, "34235": 3600, "default": 600}`
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        let entries: HashMap<String, u64> = serde_json::from_str(raw).map_err(|e| e.to_string())?;
+        let mut ttls = Self::default();
+        for (key, seconds) in entries {
+            if key == "default" {
+                ttls.default = Some(seconds);
+                continue;
+            }
+            let kind = key.parse::<u16>().map_err(|_| format!("'{}' is not an event kind", key))?;
+            ttls.overrides.insert(kind, seconds);
+        }
+        Ok(ttls)
+    }
+
+    pub fn seconds(&self, kind: u16) -> u64 {
+        self.overrides
+            .get(&kind)
+            .copied()
+            .or_else(|| builtin_kind_ttl(kind))
+            .unwrap_or_else(|| self.default_seconds())
+    }
+
+    /// TTL of filters naming no kinds, and of kinds without a TTL of their own
+    pub fn default_seconds(&self) -> u64 {
+        self.default.unwrap_or(DEFAULT_TTL_SECONDS)
+    }
+}
+
+/// Operator bounds on cache lifetimes (KV entries, Cache API entries and Cache-Control)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TtlBounds {
+    /// Nothing is cached for less than this, to protect the relay
+    pub floor: u64,
+    /// Nothing is cached for longer than this, for compliance
+    pub ceiling: Option<u64>,
+}
+
+impl Default for TtlBounds {
+    fn default() -> Self {
+        Self {
+            floor: KV_MIN_TTL_SECONDS,
+            ceiling: None,
+        }
+    }
+}
+
+impl TtlBounds {
+    /// Load from `CACHE_TTL_FLOOR_SECONDS` / `CACHE_TTL_CEILING_SECONDS`
+    pub fn from_env(env: &Env) -> Self {
+        let var = |name: &str| env.var(name).ok().and_then(|v| v.to_string().parse::<u64>().ok());
+        Self::new(var("CACHE_TTL_FLOOR_SECONDS"), var("CACHE_TTL_CEILING_SECONDS"))
+    }
+
+    /// Neither bound can go below the KV minimum
+    pub fn new(floor: Option<u64>, ceiling: Option<u64>) -> Self {
+        Self {
+            floor: floor.unwrap_or(KV_MIN_TTL_SECONDS).max(KV_MIN_TTL_SECONDS),
+            ceiling: ceiling.map(|c| c.max(KV_MIN_TTL_SECONDS)),
+        }
+    }
+
+    /// Clamp a TTL. When the bounds conflict the ceiling wins, since it is a compliance limit.
+    pub fn apply(&self, ttl: u64) -> u64 {
+        let ttl = ttl.max(self.floor);
+        match self.ceiling {
+            Some(ceiling) => ttl.min(ceiling),
+            None => ttl,
+        }
+    }
+}
+
+/// Bounds on the `?ttl=` a /query caller may ask for. The kind-based TTL is
+/// always the ceiling; asking for fresher results than `auth_floor` takes an
+/// API key or NIP-98 auth, since every shortened entry costs relay queries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TtlOverride {
+    pub min: u64,
+    pub auth_floor: u64,
+}
+
+impl Default for TtlOverride {
+    fn default() -> Self {
+        Self {
+            min: KV_MIN_TTL_SECONDS,
+            auth_floor: 120,
+        }
+    }
+}
+
+impl TtlOverride {
+    /// Load from `QUERY_TTL_MIN_SECONDS` / `QUERY_TTL_AUTH_FLOOR_SECONDS`
+    pub fn from_env(env: &Env) -> Self {
+        let var = |name: &str| env.var(name).ok().and_then(|v| v.to_string().parse::<u64>().ok());
+        let defaults = Self::default();
+        Self {
+            min: var("QUERY_TTL_MIN_SECONDS").unwrap_or(defaults.min).max(KV_MIN_TTL_SECONDS),
+            auth_floor: var("QUERY_TTL_AUTH_FLOOR_SECONDS").unwrap_or(defaults.auth_floor),
+        }
+    }
+
+    /// The TTL to use for a request asking for `requested`, where `max` is what
+    /// the filter would get anyway
+    pub fn apply(&self, requested: u64, max: u64, authenticated: bool) -> u64 {
+        let lower = if authenticated { self.min } else { self.min.max(self.auth_floor) };
+        requested.max(lower).min(max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults_only_enforce_kv_minimum() {
+        let bounds = TtlBounds::default();
+        assert_eq!(bounds.apply(0), 60);
+        assert_eq!(bounds.apply(300), 300);
+        assert_eq!(bounds.apply(86400 * 30), 86400 * 30);
+    }
+
+    #[test]
+    fn test_floor_and_ceiling() {
+        let bounds = TtlBounds::new(Some(180), Some(600));
+        assert_eq!(bounds.apply(120), 180); // reactions raised to floor
+        assert_eq!(bounds.apply(300), 300);
+        assert_eq!(bounds.apply(900), 600); // profiles capped
+        assert_eq!(bounds.apply(86400), 600);
+    }
+
+    #[test]
+    fn test_ceiling_wins_over_floor() {
+        let bounds = TtlBounds::new(Some(900), Some(300));
+        assert_eq!(bounds.apply(120), 300);
+        assert_eq!(bounds.apply(3600), 300);
+    }
+
+    #[test]
+    fn test_bounds_never_below_kv_minimum() {
+        let bounds = TtlBounds::new(Some(5), Some(10));
+        assert_eq!(bounds.floor, 60);
+        assert_eq!(bounds.ceiling, Some(60));
+        assert_eq!(bounds.apply(1), 60);
+    }
+
+    #[test]
+    fn test_kind_ttls() {
+        let ttls = KindTtls::parse(r#"{"34235": 3600, "7": 60, "default": 600}"#).unwrap();
+        assert_eq!(ttls.seconds(34235), 3600);
+        assert_eq!(ttls.seconds(7), 60);
+        // Unlisted kinds keep their built-in TTL; the default only covers the rest
+        assert_eq!(ttls.seconds(0), 900);
+        assert_eq!(ttls.seconds(30023), 600);
+        assert_eq!(ttls.default_seconds(), 600);
+
+        let builtin = KindTtls::default();
+        assert_eq!(builtin.seconds(30023), 300);
+        assert_eq!(builtin.seconds(3), 600);
+
+        assert!(KindTtls::parse(r#"{"video": 3600}"#).is_err());
+        assert!(KindTtls::parse(r#"{"1": -5}"#).is_err());
+    }
+
+    #[test]
+    fn test_ttl_override() {
+        let limits = TtlOverride::default();
+        // Reactions default to 120s; anonymous callers can't go fresher
+        assert_eq!(limits.apply(30, 120, false), 120);
+        assert_eq!(limits.apply(30, 120, true), 60);
+        assert_eq!(limits.apply(90, 120, true), 90);
+        // Never longer than the kind-based TTL
+        assert_eq!(limits.apply(3600, 300, false), 300);
+        assert_eq!(limits.apply(200, 300, false), 200);
+    }
+}
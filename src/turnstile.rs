@@ -0,0 +1,137 @@
+// ABOUTME: Cloudflare Turnstile tokens as a publish credential for anonymous web-form deployments
+// ABOUTME: Tokens are checked server-side with siteverify; enabled by setting TURNSTILE_SECRET_KEY
+
+use crate::auth::{AuthError, AuthProvider, AuthRequest, Principal};
+use crate::outbound::{self, Policy};
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use serde::Deserialize;
+use worker::*;
+
+/// Request header carrying the widget's token (the value of its `cf-turnstile-response` field)
+pub const HEADER: &str = "CF-Turnstile-Response";
+
+const SITEVERIFY_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TurnstileError {
+    /// Siteverify turned the token down, with its error codes
+    Rejected(Vec<String>),
+    /// Siteverify couldn't be reached or answered nonsense
+    Unavailable(String),
+}
+
+impl std::fmt::Display for TurnstileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Rejected(codes) if codes.is_empty() => write!(f, "turnstile token rejected"),
+            Self::Rejected(codes) => write!(f, "turnstile token rejected: {}", codes.join(", ")),
+            Self::Unavailable(e) => write!(f, "turnstile verification unavailable: {}", e),
+        }
+    }
+}
+
+/// The parts of a siteverify answer we act on
+#[derive(Debug, Deserialize)]
+pub struct Siteverify {
+    pub success: bool,
+    #[serde(default, rename = "error-codes")]
+    pub error_codes: Vec<String>,
+}
+
+impl Siteverify {
+    pub fn into_result(self) -> std::result::Result<(), TurnstileError> {
+        if self.success {
+            Ok(())
+        } else {
+            Err(TurnstileError::Rejected(self.error_codes))
+        }
+    }
+}
+
+/// Verifies a request's Turnstile token. Carries the token and client IP itself,
+/// since neither belongs to the other auth schemes.
+pub struct Turnstile {
+    secret: String,
+    token: Option<String>,
+    remote_ip: Option<String>,
+}
+
+impl Turnstile {
+    /// `None` unless the operator set `TURNSTILE_SECRET_KEY`
+    pub fn from_request(env: &Env, req: &Request) -> Option<Self> {
+        let secret = env.secret("TURNSTILE_SECRET_KEY").ok()?.to_string();
+        if secret.is_empty() {
+            return None;
+        }
+        let header = |name: &str| req.headers().get(name).ok().flatten().filter(|v| !v.is_empty());
+        Some(Self {
+            secret,
+            token: header(HEADER),
+            remote_ip: header("CF-Connecting-IP"),
+        })
+    }
+
+    /// Check the request's token; a request without one is rejected as siteverify would
+    pub async fn verify(&self) -> std::result::Result<(), TurnstileError> {
+        let token = self
+            .token
+            .as_deref()
+            .ok_or_else(|| TurnstileError::Rejected(vec!["missing-input-response".to_string()]))?;
+        self.siteverify(token).await
+    }
+
+    async fn siteverify(&self, token: &str) -> std::result::Result<(), TurnstileError> {
+        let unavailable = |e: Error| TurnstileError::Unavailable(e.to_string());
+        let url = Url::parse(SITEVERIFY_URL).map_err(|e| TurnstileError::Unavailable(e.to_string()))?;
+        // Form-encode the fields by borrowing a URL's query serializer
+        let mut form = url.clone();
+        form.query_pairs_mut()
+            .append_pair("secret", &self.secret)
+            .append_pair("response", token);
+        if let Some(ip) = &self.remote_ip {
+            form.query_pairs_mut().append_pair("remoteip", ip);
+        }
+        let body = form.query().unwrap_or_default().to_string();
+
+        let mut headers = Headers::new();
+        headers.set("Content-Type", "application/x-www-form-urlencoded").map_err(unavailable)?;
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post).with_headers(headers).with_body(Some(body.into()));
+        let mut resp = outbound::send(&url, &mut init, &Policy::default())
+            .await
+            .map_err(|e| TurnstileError::Unavailable(e.to_string()))?;
+        let answer: Siteverify = resp.json().await.map_err(unavailable)?;
+        answer.into_result()
+    }
+}
+
+impl AuthProvider for Turnstile {
+    fn presented(&self, _req: &AuthRequest) -> bool {
+        self.token.is_some()
+    }
+
+    fn authenticate<'a>(&'a self, _req: &'a AuthRequest<'a>) -> LocalBoxFuture<'a, std::result::Result<Principal, AuthError>> {
+        async move {
+            let token = self.token.as_deref().ok_or(AuthError::MissingHeader)?;
+            self.siteverify(token).await.map_err(AuthError::Turnstile)?;
+            Ok(Principal::Visitor)
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_siteverify_answers() {
+        let ok: Siteverify = serde_json::from_str(r#"{"success": true, "hostname": "example.com"}"#).unwrap();
+        assert_eq!(ok.into_result(), Ok(()));
+
+        let reused: Siteverify =
+            serde_json::from_str(r#"{"success": false, "error-codes": ["timeout-or-duplicate"]}"#).unwrap();
+        let err = reused.into_result().unwrap_err();
+        assert_eq!(err.to_string(), "turnstile token rejected: timeout-or-duplicate");
+    }
+}
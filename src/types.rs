@@ -1,46 +1,87 @@
 // ABOUTME: API request/response types for the REST gateway
 // ABOUTME: Defines JSON structures for query responses and publish requests
 
+use crate::api_keys::{Scope, Tier, TierLimits};
+use crate::custom_routes::Hydration;
+use crate::publish_state::PublishState;
+use crate::quarantine::{QuarantineEntry, QuarantineReason};
+use crate::quota::Allowance;
+use crate::relay_info::RelayLimitation;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 /// Response for query endpoints
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, JsonSchema)]
 pub struct QueryResponse {
     pub events: Vec<serde_json::Value>,
     pub eose: bool,
     pub complete: bool,
     pub cached: bool,
+    /// Served from cache past its TTL while a fresh copy is fetched in the background
+    pub stale: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cache_age_seconds: Option<u64>,
+    pub source: QuerySource,
+    /// Events dropped by the authenticated viewer's mute list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub muted: Option<usize>,
+    /// API version the response shape belongs to
+    pub version: u32,
+}
+
+/// Where a query response was served from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum QuerySource {
+    Cache,
+    Relay,
+    Archive,
 }
 
 /// Request body for publish endpoint
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 pub struct PublishRequest {
     pub event: serde_json::Value,
 }
 
 /// Response for publish endpoint
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, JsonSchema)]
 pub struct PublishResponse {
     pub status: String,
     pub event_id: String,
 }
 
-/// Response for publish status endpoint
-#[derive(Debug, Serialize, Deserialize)]
+/// Response for publish status endpoint. The state contributes `status` and,
+/// depending on the state, `attempt` and `reason`.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct PublishStatus {
-    pub status: String,
+    #[serde(flatten)]
+    pub state: PublishState,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub attempts: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub verified_at: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quarantine_reasons: Option<Vec<QuarantineReason>>,
+    /// Event author, the only one allowed to cancel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<String>,
+}
+
+impl PublishStatus {
+    pub fn new(state: PublishState, attempts: u32) -> Self {
+        Self {
+            state,
+            attempts: Some(attempts),
+            verified_at: None,
+            quarantine_reasons: None,
+            pubkey: None,
+        }
+    }
 }
 
 /// Standard error response
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, JsonSchema)]
 pub struct ErrorResponse {
     pub error: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -62,6 +103,626 @@ impl ErrorResponse {
         self.detail = Some(detail.to_string());
         self
     }
+
+    pub fn with_retry_after(mut self, seconds: u32) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+}
+
+/// Parsed kind-0 profile metadata
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ProfileMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub picture: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub banner: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub about: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nip05: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lud16: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub website: Option<String>,
+}
+
+impl ProfileMetadata {
+    /// Parse the JSON content of a kind-0 event, tolerating malformed fields
+    pub fn from_event(event: &serde_json::Value) -> Option<Self> {
+        let content = event.get("content")?.as_str()?;
+        let value: serde_json::Value = serde_json::from_str(content).ok()?;
+        let field = |name: &str| value.get(name).and_then(|v| v.as_str()).map(String::from);
+        Some(Self {
+            name: field("name"),
+            display_name: field("display_name").or_else(|| field("displayName")),
+            picture: field("picture"),
+            banner: field("banner"),
+            about: field("about"),
+            nip05: field("nip05"),
+            lud16: field("lud16"),
+            website: field("website"),
+        })
+    }
+
+    /// Best human-readable name
+    pub fn best_name(&self) -> Option<&str> {
+        self.display_name
+            .as_deref()
+            .filter(|n| !n.is_empty())
+            .or(self.name.as_deref().filter(|n| !n.is_empty()))
+    }
+}
+
+/// A media attachment parsed from an `imeta` tag (NIP-92/NIP-71)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, JsonSchema)]
+pub struct MediaEntry {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dim: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub thumbnails: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fallbacks: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// Structured NIP-71 video event (kinds 34235/34236)
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct VideoEvent {
+    pub id: String,
+    pub pubkey: String,
+    pub kind: u64,
+    pub created_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identifier: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    pub media: Vec<MediaEntry>,
+    pub event: serde_json::Value,
+}
+
+/// Response for the video list endpoint
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct VideosResponse {
+    pub videos: Vec<VideoEvent>,
+}
+
+/// Structured NIP-68 picture post (kind 20)
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct PictureEvent {
+    pub id: String,
+    pub pubkey: String,
+    pub created_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Reason from a `content-warning` tag; clients should blur the media
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_warning: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hashtags: Vec<String>,
+    /// One entry per image, in display order
+    pub media: Vec<MediaEntry>,
+    pub event: serde_json::Value,
+}
+
+/// Response for GET /pictures
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct PicturesResponse {
+    pub pictures: Vec<PictureEvent>,
+}
+
+/// Response for the NIP-11 relay info proxy
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct RelayInfoResponse {
+    pub relay: String,
+    pub supported_nips: Vec<u32>,
+    pub limitation: RelayLimitation,
+    pub document: serde_json::Value,
+    pub cached: bool,
+}
+
+/// Response for the aggregate endpoints (/count, /reactions, /zaps)
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct AggregateResponse {
+    pub count: u64,
+    /// Reaction content to count; `+` includes empty reactions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reactions: Option<std::collections::BTreeMap<String, u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zap_msats: Option<u64>,
+    /// Newest `created_at` included in the aggregate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<u64>,
+    pub cached: bool,
+    pub refreshed_at: u64,
+}
+
+/// Request body for registering a webhook
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct WebhookRequest {
+    /// NIP-01 filter; `limit` is ignored and `since` is managed by the gateway
+    pub filter: serde_json::Value,
+    /// Public https callback receiving matching events
+    pub url: String,
+}
+
+/// A registered webhook. `secret` is only returned when the webhook is created.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct WebhookResponse {
+    pub id: String,
+    pub filter: serde_json::Value,
+    pub url: String,
+    pub created_at: u64,
+    pub disabled: bool,
+    pub consecutive_failures: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+/// Response for listing webhooks
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct WebhooksResponse {
+    pub webhooks: Vec<WebhookResponse>,
+}
+
+/// What a reference tag points at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ReferenceType {
+    /// `e` tag
+    Event,
+    /// `q` tag (NIP-18 quote)
+    Quote,
+    /// `p` tag
+    Pubkey,
+    /// `a` tag (`kind:pubkey:d-tag`)
+    Address,
+    /// `r` tag
+    Url,
+}
+
+/// One parsed reference tag, with a short preview of its target when it could be resolved
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct Reference {
+    #[serde(rename = "type")]
+    pub ref_type: ReferenceType,
+    pub value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relay_hint: Option<String>,
+    /// NIP-10 marker on `e` tags (root, reply, mention)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub marker: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview: Option<serde_json::Value>,
+}
+
+/// Response for /event/{id}/references
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ReferencesResponse {
+    pub event_id: String,
+    pub references: Vec<Reference>,
+}
+
+/// Response for /event/{id}/referenced-by
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ReferencedByResponse {
+    pub event_id: String,
+    pub events: Vec<serde_json::Value>,
+    pub source: QuerySource,
+}
+
+/// One line of GET /profile/{pubkey}/followers/export
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct FollowerExportLine {
+    /// Hex pubkey of a follower
+    pub pubkey: String,
+}
+
+/// Request body for POST /profiles
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ProfilesRequest {
+    /// Hex pubkeys
+    pub pubkeys: Vec<String>,
+}
+
+/// Response for POST /profiles: every requested pubkey, `null` when no profile was found
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ProfilesResponse {
+    pub profiles: std::collections::BTreeMap<String, Option<ProfileMetadata>>,
+    /// How many profiles came from the profile cache
+    pub cached: usize,
+}
+
+/// Request body for POST /events
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct EventsRequest {
+    /// Hex event ids
+    pub ids: Vec<String>,
+}
+
+/// Response for /events, with events in the order their ids were requested
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct EventsResponse {
+    pub events: Vec<serde_json::Value>,
+    /// Requested ids that weren't found
+    pub missing: Vec<String>,
+    /// How many events came from cache
+    pub cached: usize,
+}
+
+/// Response for /feeds/{name}
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct FeedResponse {
+    pub name: String,
+    pub events: Vec<serde_json::Value>,
+    /// Authors' profiles, when the feed is configured to include them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profiles: Option<std::collections::BTreeMap<String, Option<ProfileMetadata>>>,
+    /// Events dropped by the authenticated viewer's mute list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub muted: Option<usize>,
+}
+
+/// Request body for PUT /admin/routes/{name}
+#[derive(Debug, Deserialize)]
+pub struct CustomRouteRequest {
+    pub filter: serde_json::Value,
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    #[serde(default)]
+    pub hydrate: Hydration,
+}
+
+/// Response for GET /query/explain: how `/query` would handle a filter, without running it
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct QueryExplainResponse {
+    /// The filter as it would go to the relay, after the tier's limit cap
+    pub filter: serde_json::Value,
+    /// Normalized form the cache key is computed from
+    pub canonical: serde_json::Value,
+    pub cache_key: String,
+    /// `event`, `id_lookup`, `historical`, `kind:{n}` or `default`
+    pub ttl_class: String,
+    /// Cache TTL after the operator's bounds
+    pub ttl_seconds: u64,
+    /// What answers a cache miss: `relay`, or `archive` while degraded
+    pub source: QuerySource,
+    /// RelayPool shard a cache miss goes through, when the relay answers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shard: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relay: Option<String>,
+    /// `Filter::cost()` after the limit cap
+    pub cost: u64,
+    /// Why `/query` would refuse the filter, if it would
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rejected: Option<String>,
+}
+
+/// Request body for POST /filter/validate
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct FilterValidateRequest {
+    /// A filter object, or the base64url string sent as `/query?filter=`
+    pub filter: serde_json::Value,
+}
+
+/// Response for POST /filter/validate
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct FilterValidateResponse {
+    /// False when any error is reported
+    pub valid: bool,
+    /// The decoded filter
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<serde_json::Value>,
+    /// Fields the gateway reads
+    pub recognized: Vec<String>,
+    /// Fields passed to the relay that the gateway doesn't read
+    pub ignored: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_key: Option<String>,
+    /// `event`, `id_lookup`, `historical`, `kind:{n}` or `default`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl_class: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl_seconds: Option<u64>,
+    /// Estimated relay cost, as in /query/explain
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<u64>,
+    pub errors: Vec<String>,
+}
+
+/// Request body for PUT /admin/presets/{name}
+#[derive(Debug, Deserialize)]
+pub struct PresetRequest {
+    pub filter: serde_json::Value,
+}
+
+/// Request body for POST /admin/signed-urls
+#[derive(Debug, Deserialize)]
+pub struct SignedUrlRequest {
+    /// Path and query to sign, e.g. `/query?kinds=1&limit=20`
+    pub path: String,
+    /// Lifetime of the link; defaults to an hour
+    #[serde(default = "default_signed_url_ttl")]
+    pub ttl_seconds: u64,
+}
+
+fn default_signed_url_ttl() -> u64 {
+    3600
+}
+
+/// Response for GET /mutes/{pubkey}: the public entries of a kind 10000 mute list
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct MuteListResponse {
+    pub pubkey: String,
+    pub pubkeys: Vec<String>,
+    /// Lowercased, without `#`
+    pub hashtags: Vec<String>,
+    /// Lowercased
+    pub words: Vec<String>,
+    /// Muted event ids
+    pub threads: Vec<String>,
+    pub created_at: Option<u64>,
+}
+
+/// Request body for POST /admin/api-keys
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyRequest {
+    pub name: String,
+    #[serde(default)]
+    pub tier: Tier,
+    /// Defaults to `["read"]`
+    #[serde(default = "crate::api_keys::default_scopes")]
+    pub scopes: Vec<Scope>,
+}
+
+/// Response for GET /info: what the caller's API key allows
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct InfoResponse {
+    pub tier: Tier,
+    /// Name the operator gave the key; absent without a key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_name: Option<String>,
+    /// What the key may be used for; empty without a key
+    pub scopes: Vec<Scope>,
+    pub limits: TierLimits,
+}
+
+/// Response for GET /quota: the authed pubkey's usage for the current UTC day
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct QuotaResponse {
+    pub pubkey: String,
+    pub reads: Allowance,
+    pub publishes: Allowance,
+    /// Unix time the counts start over (the next UTC midnight)
+    pub resets_at: u64,
+}
+
+/// Body of POST /auth/session
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+pub struct SessionRequest {
+    /// Scopes to limit the session to; all of read and publish when empty
+    #[serde(default)]
+    pub scopes: Vec<Scope>,
+}
+
+/// Response for POST and DELETE /auth/session
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct SessionResponse {
+    /// Use as `Authorization: Bearer <token>`; only returned when the session is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    pub pubkey: String,
+    pub scopes: Vec<Scope>,
+    /// Unix time the token stops working
+    pub expires_at: u64,
+}
+
+/// Response for GET /status
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct StatusResponse {
+    /// `normal`, or `degraded` while relays are down for maintenance
+    pub mode: String,
+    /// Operator's note on the degradation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Unix timestamp degraded mode was switched on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<u64>,
+    /// Seconds clients should wait before retrying publishes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u32>,
+}
+
+/// Request body for PUT /admin/degraded
+#[derive(Debug, Default, Deserialize)]
+pub struct DegradedRequest {
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub retry_after: Option<u32>,
+}
+
+/// Request body for POST /nwc
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct NwcRequest {
+    /// The wallet service's relay from the connection URI (`wss://`)
+    pub relay: String,
+    /// Signed kind 23194 request event, content encrypted to the wallet service
+    pub event: serde_json::Value,
+}
+
+/// Response for POST /nwc
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct NwcResponse {
+    /// The wallet service's kind 23195 reply, still encrypted
+    pub event: serde_json::Value,
+}
+
+/// A browser PushSubscription, as returned by `subscription.toJSON()`
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PushSubscription {
+    pub endpoint: String,
+    pub keys: PushKeys,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PushKeys {
+    /// Base64url uncompressed P-256 public key of the browser
+    pub p256dh: String,
+    /// Base64url 16-byte auth secret
+    pub auth: String,
+}
+
+/// Request body for registering Web Push notifications
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct PushRegistrationRequest {
+    pub subscription: PushSubscription,
+    /// NIP-01 filter; matching events published after registration trigger a notification
+    pub filter: serde_json::Value,
+}
+
+/// A registered Web Push subscription
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct PushRegistrationResponse {
+    pub id: String,
+    pub filter: serde_json::Value,
+    pub created_at: u64,
+}
+
+/// The gateway's VAPID public key, used as `applicationServerKey`
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct VapidKeyResponse {
+    pub public_key: String,
+}
+
+/// Public gateway stats, aggregated by the scheduled job
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct StatsResponse {
+    /// Events returned by /query since metrics began
+    pub events_served: u64,
+    pub events_served_24h: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_hit_rate_24h: Option<f64>,
+    pub publishes_24h: u64,
+    pub relay: String,
+    pub updated_at: u64,
+}
+
+/// What a cached query entry holds, for GET /admin/cache/entry
+#[derive(Debug, Serialize)]
+pub struct CacheEntryResponse {
+    pub key: String,
+    /// The filter the key was derived from, when looked up by filter
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<serde_json::Value>,
+    pub timestamp: u64,
+    pub age_seconds: u64,
+    /// Until KV expires the entry; absent if it has no expiration
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl_remaining_seconds: Option<u64>,
+    pub eose: bool,
+    pub event_count: usize,
+    /// Stored size of the entry
+    pub size_bytes: usize,
+    /// Event count per kind
+    pub kinds: std::collections::BTreeMap<u64, usize>,
+    /// Tag filters of the filter, when known
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<(String, Vec<String>)>,
+    /// The cached events, with `?body=1`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub events: Option<Vec<serde_json::Value>>,
+}
+
+/// Query cache effectiveness, for GET /admin/cache/stats
+#[derive(Debug, Serialize)]
+pub struct CacheStatsResponse {
+    #[serde(flatten)]
+    pub total: CacheStatsRow,
+    /// Per route and TTL class, busiest first
+    pub breakdown: Vec<CacheStatsRow>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStatsRow {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    /// `kind:{n}`, `event`, `id_lookup`, `historical` or `default`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>,
+    pub hits: u64,
+    pub stale: u64,
+    pub misses: u64,
+    pub bypasses: u64,
+    /// Hits (stale included) over hits and misses; absent before any lookup
+    pub hit_ratio: Option<f64>,
+}
+
+/// One page of KV keys from the admin cache inspector
+#[derive(Debug, Serialize)]
+pub struct AdminKeysResponse {
+    pub keys: Vec<String>,
+    /// Pass back as `?cursor=` for the next page; absent on the last page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+}
+
+/// Body of `POST /admin/cache/purge`; any combination of targets may be given
+#[derive(Debug, Default, Deserialize)]
+pub struct CachePurgeRequest {
+    /// A filter as a JSON object, or base64url exactly as sent to /query
+    #[serde(default)]
+    pub filter: Option<serde_json::Value>,
+    #[serde(default)]
+    pub key: Option<String>,
+    /// Purges the pubkey's profile
+    #[serde(default)]
+    pub pubkey: Option<String>,
+    /// Purges the event lookup and its rendered embed
+    #[serde(default)]
+    pub event_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CachePurgeResponse {
+    pub deleted_keys: Vec<String>,
+    pub html_purged: bool,
+}
+
+/// Body of `POST /admin/prewarm`
+#[derive(Debug, Deserialize)]
+pub struct PrewarmRequest {
+    /// JSON objects, or base64url exactly as sent to /query
+    pub filters: Vec<serde_json::Value>,
+}
+
+/// A held event in the admin quarantine review queue
+#[derive(Debug, Serialize)]
+pub struct QuarantinedEvent {
+    pub event_id: String,
+    #[serde(flatten)]
+    pub entry: QuarantineEntry,
 }
 
 /// Cached query data stored in KV
@@ -70,6 +731,23 @@ pub struct CachedQuery {
     pub events: Vec<serde_json::Value>,
     pub eose: bool,
     pub timestamp: u64,
+    /// An empty result, which expires on the short negative TTL
+    #[serde(default)]
+    pub negative: bool,
+    /// The filter as sent to the relay, so the cache warmer can run it again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+}
+
+impl CachedQuery {
+    /// The TTL this entry is judged by, given the one its filter gets
+    pub fn ttl(&self, filter_ttl: u64) -> u64 {
+        if self.negative {
+            crate::cache::negative_ttl(filter_ttl)
+        } else {
+            filter_ttl
+        }
+    }
 }
 
 #[cfg(test)]
@@ -83,7 +761,11 @@ mod tests {
             eose: true,
             complete: true,
             cached: false,
+            stale: false,
             cache_age_seconds: None,
+            source: QuerySource::Relay,
+            muted: None,
+            version: 1,
         };
 
         let json = serde_json::to_string(&response).unwrap();
@@ -91,6 +773,7 @@ mod tests {
         assert!(json.contains("\"eose\":true"));
         assert!(json.contains("\"complete\":true"));
         assert!(json.contains("\"cached\":false"));
+        assert!(json.contains("\"version\":1"));
         // cache_age_seconds should be skipped when None
         assert!(!json.contains("cache_age_seconds"));
     }
@@ -102,12 +785,26 @@ mod tests {
             eose: true,
             complete: true,
             cached: true,
+            stale: true,
             cache_age_seconds: Some(42),
+            source: QuerySource::Cache,
+            muted: None,
+            version: 1,
         };
 
         let json = serde_json::to_string(&response).unwrap();
         assert!(json.contains("\"cached\":true"));
         assert!(json.contains("\"cache_age_seconds\":42"));
+        assert!(json.contains("\"stale\":true"));
+        assert!(json.contains("\"source\":\"cache\""));
+    }
+
+    #[test]
+    fn test_query_source_serialization() {
+        assert_eq!(serde_json::to_string(&QuerySource::Archive).unwrap(), "\"archive\"");
+        assert_eq!(serde_json::to_string(&QuerySource::Relay).unwrap(), "\"relay\"");
+        let parsed: QuerySource = serde_json::from_str("\"cache\"").unwrap();
+        assert_eq!(parsed, QuerySource::Cache);
     }
 
     #[test]
@@ -133,65 +830,87 @@ mod tests {
     #[test]
     fn test_publish_status_minimal() {
         let status = PublishStatus {
-            status: "pending".to_string(),
             attempts: None,
-            verified_at: None,
-            error: None,
+            ..PublishStatus::new(PublishState::Queued, 0)
         };
 
         let json = serde_json::to_string(&status).unwrap();
-        assert!(json.contains("\"status\":\"pending\""));
+        assert!(json.contains("\"status\":\"queued\""));
         // Optional fields should be skipped
-        assert!(!json.contains("attempts"));
+        assert!(!json.contains("attempt"));
+        assert!(!json.contains("reason"));
         assert!(!json.contains("verified_at"));
-        assert!(!json.contains("error"));
+        assert!(!json.contains("quarantine_reasons"));
+    }
+
+    #[test]
+    fn test_publish_status_quarantined() {
+        let status = PublishStatus {
+            quarantine_reasons: Some(vec![QuarantineReason::NewPubkey]),
+            ..PublishStatus::new(PublishState::Quarantined, 0)
+        };
+
+        let json = serde_json::to_string(&status).unwrap();
+        assert!(json.contains("\"status\":\"quarantined\""));
+        assert!(json.contains("\"quarantine_reasons\":[\"new_pubkey\"]"));
     }
 
     #[test]
     fn test_publish_status_full() {
         let status = PublishStatus {
-            status: "verified".to_string(),
-            attempts: Some(3),
             verified_at: Some("2024-01-01T00:00:00Z".to_string()),
-            error: None,
+            ..PublishStatus::new(PublishState::Published, 3)
         };
 
         let json = serde_json::to_string(&status).unwrap();
-        assert!(json.contains("\"status\":\"verified\""));
+        assert!(json.contains("\"status\":\"published\""));
         assert!(json.contains("\"attempts\":3"));
         assert!(json.contains("\"verified_at\""));
     }
 
     #[test]
-    fn test_publish_status_with_error() {
-        let status = PublishStatus {
-            status: "failed".to_string(),
-            attempts: Some(5),
-            verified_at: None,
-            error: Some("relay rejected".to_string()),
+    fn test_publish_status_with_reason() {
+        let state = PublishState::Retrying {
+            attempt: 5,
+            reason: "relay rejected".to_string(),
         };
+        let status = PublishStatus::new(state, 5);
 
         let json = serde_json::to_string(&status).unwrap();
-        assert!(json.contains("\"error\":\"relay rejected\""));
+        assert!(json.contains("\"status\":\"retrying\""));
+        assert!(json.contains("\"attempt\":5"));
+        assert!(json.contains("\"reason\":\"relay rejected\""));
     }
 
     #[test]
     fn test_publish_status_roundtrip() {
         let status = PublishStatus {
-            status: "verified".to_string(),
-            attempts: Some(2),
             verified_at: Some("2024-01-01T12:00:00Z".to_string()),
-            error: None,
+            ..PublishStatus::new(PublishState::Published, 2)
         };
 
         let json = serde_json::to_string(&status).unwrap();
         let deserialized: PublishStatus = serde_json::from_str(&json).unwrap();
 
-        assert_eq!(deserialized.status, status.status);
+        assert_eq!(deserialized.state, status.state);
         assert_eq!(deserialized.attempts, status.attempts);
         assert_eq!(deserialized.verified_at, status.verified_at);
     }
 
+    #[test]
+    fn test_publish_status_legacy() {
+        let json = r#"{"status":"retry_2","attempts":2,"error":"relay rejected"}"#;
+        let status: PublishStatus = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            status.state,
+            PublishState::Retrying {
+                attempt: 2,
+                reason: "relay rejected".to_string()
+            }
+        );
+        assert_eq!(status.attempts, Some(2));
+    }
+
     #[test]
     fn test_error_response_new() {
         let err = ErrorResponse::new("test_error");
@@ -219,8 +938,9 @@ mod tests {
 
     #[test]
     fn test_error_response_full_serialization() {
-        let mut err = ErrorResponse::new("rate_limited").with_detail("too many requests");
-        err.retry_after = Some(60);
+        let err = ErrorResponse::new("rate_limited")
+            .with_detail("too many requests")
+            .with_retry_after(60);
 
         let json = serde_json::to_string(&err).unwrap();
         assert!(json.contains("\"error\":\"rate_limited\""));
@@ -228,12 +948,41 @@ mod tests {
         assert!(json.contains("\"retry_after\":60"));
     }
 
+    #[test]
+    fn test_profile_metadata_from_event() {
+        let event = serde_json::json!({
+            "kind": 0,
+            "content": "{\"name\":\"alice\",\"displayName\":\"Alice\",\"picture\":\"https://x/p.jpg\",\"about\":42}"
+        });
+        let profile = ProfileMetadata::from_event(&event).unwrap();
+        assert_eq!(profile.name.as_deref(), Some("alice"));
+        assert_eq!(profile.display_name.as_deref(), Some("Alice"));
+        assert_eq!(profile.picture.as_deref(), Some("https://x/p.jpg"));
+        // Non-string fields are ignored rather than failing the whole profile
+        assert!(profile.about.is_none());
+        assert_eq!(profile.best_name(), Some("Alice"));
+    }
+
+    #[test]
+    fn test_profile_metadata_invalid_content() {
+        let event = serde_json::json!({"kind": 0, "content": "not json"});
+        assert!(ProfileMetadata::from_event(&event).is_none());
+
+        let unnamed = ProfileMetadata {
+            name: Some(String::new()),
+            ..Default::default()
+        };
+        assert_eq!(unnamed.best_name(), None);
+    }
+
     #[test]
     fn test_cached_query_serialization() {
         let cached = CachedQuery {
             events: vec![serde_json::json!({"id": "event1"})],
             eose: true,
             timestamp: 1700000000,
+            negative: false,
+            filter: None,
         };
 
         let json = serde_json::to_string(&cached).unwrap();
@@ -242,5 +991,23 @@ mod tests {
         assert_eq!(deserialized.events.len(), 1);
         assert!(deserialized.eose);
         assert_eq!(deserialized.timestamp, 1700000000);
+        assert_eq!(deserialized.ttl(3600), 3600);
+    }
+
+    #[test]
+    fn test_negative_cached_query() {
+        // Entries written before negative caching have no marker
+        let old: CachedQuery = serde_json::from_str(r#"{"events":[],"eose":true,"timestamp":1}"#).unwrap();
+        assert!(!old.negative);
+
+        let empty = CachedQuery {
+            events: vec![],
+            eose: true,
+            timestamp: 1,
+            negative: true,
+            filter: None,
+        };
+        assert_eq!(empty.ttl(3600), 60);
+        assert_eq!(empty.ttl(30), 30);
     }
 }
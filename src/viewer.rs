@@ -0,0 +1,141 @@
+// ABOUTME: Personalization for read requests carrying optional NIP-98 auth
+// ABOUTME: Parses kind 10000 mute lists and drops muted events from responses
+
+use serde_json::Value;
+
+/// Public entries of a NIP-51 mute list (kind 10000). Encrypted private
+/// entries in `content` can't be read by the gateway and are ignored.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MuteList {
+    pub pubkeys: Vec<String>,
+    pub event_ids: Vec<String>,
+    /// Lowercased, without `#`
+    pub hashtags: Vec<String>,
+    /// Lowercased
+    pub words: Vec<String>,
+}
+
+impl MuteList {
+    pub fn from_event(event: &Value) -> Self {
+        let mut list = Self::default();
+        let tags = event.get("tags").and_then(|t| t.as_array()).cloned().unwrap_or_default();
+        for tag in tags {
+            let Some(tag) = tag.as_array() else { continue };
+            let name = tag.first().and_then(|v| v.as_str());
+            let Some(value) = tag.get(1).and_then(|v| v.as_str()).filter(|v| !v.is_empty()) else { continue };
+            match name {
+                Some("p") => list.pubkeys.push(value.to_string()),
+                Some("e") => list.event_ids.push(value.to_string()),
+                Some("t") => list.hashtags.push(value.trim_start_matches('#').to_lowercase()),
+                Some("word") => list.words.push(value.to_lowercase()),
+                _ => {}
+            }
+        }
+        list
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pubkeys.is_empty() && self.event_ids.is_empty() && self.hashtags.is_empty() && self.words.is_empty()
+    }
+
+    pub fn mutes(&self, event: &Value) -> bool {
+        let field = |name: &str| event.get(name).and_then(|v| v.as_str()).unwrap_or_default();
+        if self.pubkeys.iter().any(|p| p == field("pubkey")) || self.event_ids.iter().any(|e| e == field("id")) {
+            return true;
+        }
+        if !self.hashtags.is_empty() {
+            let tags = event.get("tags").and_then(|t| t.as_array());
+            let tagged = tags.into_iter().flatten().filter_map(|t| t.as_array()).any(|t| {
+                t.first().and_then(|v| v.as_str()) == Some("t")
+                    && t.get(1)
+                        .and_then(|v| v.as_str())
+                        .is_some_and(|h| self.hashtags.contains(&h.to_lowercase()))
+            });
+            if tagged {
+                return true;
+            }
+        }
+        if !self.words.is_empty() {
+            let content = field("content").to_lowercase();
+            return self.words.iter().any(|w| content.contains(w.as_str()));
+        }
+        false
+    }
+
+    /// Add another list's entries, skipping ones already present
+    pub fn extend(&mut self, other: MuteList) {
+        let merge = |into: &mut Vec<String>, from: Vec<String>| {
+            for value in from {
+                if !into.contains(&value) {
+                    into.push(value);
+                }
+            }
+        };
+        merge(&mut self.pubkeys, other.pubkeys);
+        merge(&mut self.event_ids, other.event_ids);
+        merge(&mut self.hashtags, other.hashtags);
+        merge(&mut self.words, other.words);
+    }
+
+    /// Remove muted events in place, returning how many were removed
+    pub fn apply(&self, events: &mut Vec<Value>) -> usize {
+        let before = events.len();
+        events.retain(|e| !self.mutes(e));
+        before - events.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_from_event() {
+        let list = MuteList::from_event(&json!({
+            "kind": 10000,
+            "tags": [["p", "spammer"], ["e", "thread"], ["t", "#NSFW"], ["word", "Crypto"], ["p", ""], ["x", "y"]],
+            "content": "encrypted-private-entries"
+        }));
+        assert_eq!(list.pubkeys, vec!["spammer"]);
+        assert_eq!(list.event_ids, vec!["thread"]);
+        assert_eq!(list.hashtags, vec!["nsfw"]);
+        assert_eq!(list.words, vec!["crypto"]);
+        assert!(MuteList::from_event(&json!({"tags": []})).is_empty());
+    }
+
+    #[test]
+    fn test_apply() {
+        let list = MuteList {
+            pubkeys: vec!["spammer".to_string()],
+            event_ids: vec!["thread".to_string()],
+            hashtags: vec!["nsfw".to_string()],
+            words: vec!["crypto".to_string()],
+        };
+        let mut events = vec![
+            json!({"id": "1", "pubkey": "spammer", "content": "hi", "tags": []}),
+            json!({"id": "thread", "pubkey": "a", "content": "hi", "tags": []}),
+            json!({"id": "3", "pubkey": "a", "content": "hi", "tags": [["t", "NSFW"]]}),
+            json!({"id": "4", "pubkey": "a", "content": "Buy CRYPTO now", "tags": []}),
+            json!({"id": "5", "pubkey": "a", "content": "hello", "tags": [["t", "art"]]}),
+        ];
+        assert_eq!(list.apply(&mut events), 4);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["id"], "5");
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut list = MuteList {
+            pubkeys: vec!["a".to_string()],
+            ..Default::default()
+        };
+        list.extend(MuteList {
+            pubkeys: vec!["a".to_string(), "b".to_string()],
+            words: vec!["spam".to_string()],
+            ..Default::default()
+        });
+        assert_eq!(list.pubkeys, vec!["a", "b"]);
+        assert_eq!(list.words, vec!["spam"]);
+    }
+}
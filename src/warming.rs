@@ -0,0 +1,144 @@
+// ABOUTME: Cron-driven warming of the most-requested query cache entries
+// ABOUTME: The metrics collector counts requests per cache key; hot entries near expiry are re-queried
+
+use crate::cache::Cache;
+use crate::filter::Filter;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use worker::*;
+
+/// Keys warmed per cron run unless `CACHE_WARM_KEYS` says otherwise
+const DEFAULT_WARM_KEYS: usize = 50;
+
+/// How often the cron trigger fires (`*/15 * * * *`). An entry that would expire
+/// before the next run is refreshed on this one.
+const CRON_INTERVAL_SECONDS: u64 = 900;
+
+/// Keys the collector counts at once; the least-requested make way for new ones
+pub const MAX_TRACKED_KEYS: usize = 1000;
+
+/// Requests per cache key since the last warming run, roughly
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HotKeys {
+    counts: HashMap<String, u64>,
+}
+
+impl HotKeys {
+    pub fn hit(&mut self, key: &str) {
+        if let Some(count) = self.counts.get_mut(key) {
+            *count += 1;
+            return;
+        }
+        if self.counts.len() >= MAX_TRACKED_KEYS {
+            let coldest = self.counts.iter().min_by_key(|(_, count)| **count).map(|(k, _)| k.clone());
+            if let Some(coldest) = coldest {
+                self.counts.remove(&coldest);
+            }
+        }
+        self.counts.insert(key.to_string(), 1);
+    }
+
+    /// The `n` most-requested keys, hottest first
+    pub fn hottest(&self, n: usize) -> Vec<String> {
+        let mut ranked: Vec<(&String, &u64)> = self.counts.iter().collect();
+        ranked.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        ranked.into_iter().take(n).map(|(key, _)| key.clone()).collect()
+    }
+
+    /// The `n` most-requested keys, hottest first. Every count is then halved,
+    /// so the ranking follows recent traffic rather than all-time totals.
+    pub fn take_hottest(&mut self, n: usize) -> Vec<String> {
+        let hottest = self.hottest(n);
+        self.counts.retain(|_, count| {
+            *count /= 2;
+            *count > 0
+        });
+        hottest
+    }
+}
+
+/// Number of keys to warm per run, from `CACHE_WARM_KEYS`; 0 turns warming off
+pub fn warm_keys(env: &Env) -> usize {
+    env.var("CACHE_WARM_KEYS")
+        .ok()
+        .and_then(|v| v.to_string().trim().parse().ok())
+        .unwrap_or(DEFAULT_WARM_KEYS)
+}
+
+/// Whether an entry `age` seconds into its `ttl` expires within `ahead` seconds
+pub fn needs_warming(age: u64, ttl: u64, ahead: u64) -> bool {
+    age.saturating_add(ahead) >= ttl
+}
+
+/// Re-query the hottest cache entries that would expire before the next cron
+/// run, and return how many were refreshed. Entries already gone, or written
+/// before they recorded their filter, are left to the next request.
+pub async fn run(env: &Env) -> Result<usize> {
+    let limit = warm_keys(env);
+    if limit == 0 || crate::degraded::get(env).await.is_some() {
+        return Ok(0);
+    }
+    let keys = crate::metrics::hot_keys(env, limit).await?;
+    let cache = Cache::from_env(env)?;
+    let mut warmed = 0;
+    for key in keys {
+        let Some((cached, age)) = cache.get_query(&key).await? else {
+            continue;
+        };
+        let Some(filter) = cached.filter.as_deref().and_then(|raw| Filter::from_json(raw).ok()) else {
+            continue;
+        };
+        let ttl = crate::router::cache_ttl(env, &filter);
+        if !needs_warming(age, cached.ttl(ttl), CRON_INTERVAL_SECONDS) {
+            continue;
+        }
+        // Not degraded, or nothing would have been warmed
+        match crate::router::query_relay(env, &filter, None).await {
+            Ok(events) => match cache.put_query(&filter, events, true, ttl).await {
+                Ok(()) => warmed += 1,
+                Err(e) => console_log!("Warming {} failed to write: {}", key, e),
+            },
+            Err(e) => console_log!("Warming {} failed: {}", key, e),
+        }
+    }
+    Ok(warmed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_hottest() {
+        let mut keys = HotKeys::default();
+        for key in ["query:a", "query:b", "query:b", "query:c", "query:c", "query:c"] {
+            keys.hit(key);
+        }
+        // Looking doesn't decay the counts
+        assert_eq!(keys.hottest(2), vec!["query:c", "query:b"]);
+        assert_eq!(keys.take_hottest(2), vec!["query:c", "query:b"]);
+        // Halved: c 1, b 1, a gone
+        assert_eq!(keys.take_hottest(5), vec!["query:b", "query:c"]);
+        assert!(keys.take_hottest(5).is_empty());
+    }
+
+    #[test]
+    fn test_tracking_is_bounded() {
+        let mut keys = HotKeys::default();
+        keys.hit("query:hot");
+        keys.hit("query:hot");
+        for i in 0..MAX_TRACKED_KEYS + 10 {
+            keys.hit(&format!("query:{}", i));
+        }
+        assert_eq!(keys.counts.len(), MAX_TRACKED_KEYS);
+        assert_eq!(keys.take_hottest(1), vec!["query:hot"]);
+    }
+
+    #[test]
+    fn test_needs_warming() {
+        // Every 15 minutes: a 5-minute entry always expires before the next run
+        assert!(needs_warming(0, 300, CRON_INTERVAL_SECONDS));
+        assert!(needs_warming(3000, 3600, CRON_INTERVAL_SECONDS));
+        assert!(!needs_warming(100, 86400, CRON_INTERVAL_SECONDS));
+    }
+}
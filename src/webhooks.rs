@@ -0,0 +1,636 @@
+// ABOUTME: Webhook subscriptions: a Durable Object holding one live relay subscription per webhook
+// ABOUTME: Matching events are POSTed to the callback with an HMAC signature; also carries Web Push filters
+
+use crate::cache::now_seconds;
+use crate::outbound::{self, check_public_host, HostError, Policy};
+use crate::push::{self, PushOutcome, PushRegistration, Vapid};
+use crate::types::WebhookResponse;
+use futures_util::StreamExt;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use worker::*;
+
+pub const MAX_WEBHOOKS_PER_OWNER: usize = 10;
+
+/// Web Push registrations a pubkey can have subscribed
+pub const MAX_PUSH_PER_OWNER: usize = 10;
+
+/// Subscriptions on the hub's one relay socket, webhooks and push together
+pub const MAX_HUB_SUBSCRIPTIONS: usize = 1000;
+
+/// A callback gets this long to answer before the delivery counts as failed
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Consecutive failed deliveries before a webhook is disabled
+const MAX_CONSECUTIVE_FAILURES: u32 = 20;
+
+/// Reconnect checks and retries run on this alarm
+const ALARM_INTERVAL: Duration = Duration::from_secs(60);
+
+const WEBHOOKS_KEY: &str = "webhooks";
+const RETRIES_KEY: &str = "retries";
+const PUSH_IDS_KEY: &str = "push_ids";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: String,
+    /// NIP-98 pubkey that registered it; only the owner can list or delete it
+    pub owner: String,
+    pub filter: Value,
+    pub url: String,
+    pub secret: String,
+    pub created_at: u64,
+    #[serde(default)]
+    pub consecutive_failures: u32,
+    #[serde(default)]
+    pub disabled: bool,
+    /// Newest delivered event, used as `since` when resubscribing
+    #[serde(default)]
+    pub last_event_at: Option<u64>,
+}
+
+impl Webhook {
+    fn sub_id(&self) -> String {
+        format!("wh-{}", self.id)
+    }
+
+    /// Live subscription filter, resuming from the last delivered event
+    pub fn subscription_filter(&self) -> Value {
+        let mut filter = self.filter.clone();
+        if let Some(obj) = filter.as_object_mut() {
+            obj.remove("limit");
+            obj.insert("since".to_string(), self.last_event_at.unwrap_or(self.created_at).into());
+        }
+        filter
+    }
+
+    pub fn view(&self, include_secret: bool) -> WebhookResponse {
+        WebhookResponse {
+            id: self.id.clone(),
+            filter: self.filter.clone(),
+            url: self.url.clone(),
+            created_at: self.created_at,
+            disabled: self.disabled,
+            consecutive_failures: self.consecutive_failures,
+            secret: include_secret.then(|| self.secret.clone()),
+        }
+    }
+}
+
+/// A delivery waiting for its next attempt
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingDelivery {
+    pub webhook_id: String,
+    pub event: Value,
+    pub attempt: u32,
+    pub due_at: u64,
+}
+
+/// Delay before retry number `attempt` (1-based), or None once retries are exhausted
+pub fn retry_delay(attempt: u32) -> Option<u64> {
+    const DELAYS: [u64; 4] = [60, 300, 1800, 7200];
+    DELAYS.get(attempt.checked_sub(1)? as usize).copied()
+}
+
+/// `sha256=<hex>` HMAC of `{timestamp}.{body}`, sent as `X-Webhook-Signature`.
+/// Including the timestamp lets receivers reject replays.
+pub fn sign(secret: &str, timestamp: u64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Callbacks must be public https endpoints; the gateway should not be usable
+/// to probe internal hosts.
+pub fn validate_callback_url(raw: &str) -> std::result::Result<(), &'static str> {
+    let url = Url::parse(raw).map_err(|_| "callback url is not a valid URL")?;
+    if url.scheme() != "https" {
+        return Err("callback url must use https");
+    }
+    let host = url.host_str().ok_or("callback url has no host")?;
+    check_public_host(host).map_err(|e| match e {
+        HostError::IpAddress => "callback url must use a hostname, not an IP address",
+        HostError::Private => "callback url must be publicly reachable",
+    })
+}
+
+pub fn new_webhook_id() -> String {
+    let mut bytes = [0u8; 8];
+    getrandom::getrandom(&mut bytes).expect("RNG available in Workers");
+    hex::encode(bytes)
+}
+
+pub fn new_secret() -> String {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).expect("RNG available in Workers");
+    hex::encode(bytes)
+}
+
+/// Messages the hub sends itself from the socket read loop
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SocketMessage {
+    Event { sub_id: String, event: Value },
+    Disconnected,
+}
+
+#[durable_object]
+pub struct WebhookHub {
+    state: State,
+    env: Env,
+    /// Loaded from storage on first use
+    webhooks: RefCell<Option<BTreeMap<String, Webhook>>>,
+    /// Web Push registrations subscribed through this hub, with their owners;
+    /// the registrations themselves live in KV
+    push_ids: RefCell<BTreeMap<String, String>>,
+    socket: RefCell<Option<WebSocket>>,
+}
+
+impl DurableObject for WebhookHub {
+    fn new(state: State, env: Env) -> Self {
+        Self {
+            state,
+            env,
+            webhooks: RefCell::new(None),
+            push_ids: RefCell::new(BTreeMap::new()),
+            socket: RefCell::new(None),
+        }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        self.load().await?;
+        let url = req.url()?;
+        let params: std::collections::HashMap<_, _> = url.query_pairs().into_owned().collect();
+        let owner = params.get("owner").cloned().unwrap_or_default();
+
+        match (req.method(), url.path()) {
+            (Method::Post, "/create") => {
+                let webhook: Webhook = req.json().await?;
+                let count = self.owned(&webhook.owner).len();
+                if count >= MAX_WEBHOOKS_PER_OWNER {
+                    return Response::error("webhook limit reached", 409);
+                }
+                if self.subscriptions() >= MAX_HUB_SUBSCRIPTIONS {
+                    return Response::error("webhook hub is full", 503);
+                }
+                let view = webhook.view(true);
+                self.subscribe(&webhook);
+                self.with_webhooks(|w| {
+                    w.insert(webhook.id.clone(), webhook);
+                });
+                self.save().await?;
+                self.ensure_alarm().await?;
+                Ok(Response::from_json(&view)?.with_status(201))
+            }
+            (Method::Get, "/list") => {
+                let views: Vec<_> = self.owned(&owner).iter().map(|w| w.view(false)).collect();
+                Response::from_json(&views)
+            }
+            (Method::Delete, "/delete") => {
+                let id = params.get("id").cloned().unwrap_or_default();
+                let removed = self.with_webhooks(|w| {
+                    if w.get(&id).is_some_and(|hook| hook.owner == owner) {
+                        w.remove(&id)
+                    } else {
+                        None
+                    }
+                });
+                match removed {
+                    Some(hook) => {
+                        self.send(&serde_json::json!(["CLOSE", hook.sub_id()]));
+                        self.save().await?;
+                        Response::from_json(&hook.view(false))
+                    }
+                    None => Response::error("not found", 404),
+                }
+            }
+            (Method::Post, "/push/subscribe") => {
+                let registration: PushRegistration = req.json().await?;
+                // Registering the same endpoint again only replaces its filter
+                if !self.push_ids.borrow().contains_key(&registration.id) {
+                    let owned = self.push_ids.borrow().values().filter(|o| **o == registration.owner).count();
+                    if owned >= MAX_PUSH_PER_OWNER {
+                        return Response::error("push subscription limit reached", 409);
+                    }
+                    if self.subscriptions() >= MAX_HUB_SUBSCRIPTIONS {
+                        return Response::error("webhook hub is full", 503);
+                    }
+                }
+                self.send(&serde_json::json!([
+                    "REQ",
+                    push_sub_id(&registration.id),
+                    registration.subscription_filter(now_seconds())
+                ]));
+                self.push_ids.borrow_mut().insert(registration.id, registration.owner);
+                self.save_push_ids().await?;
+                self.ensure_alarm().await?;
+                Response::empty()
+            }
+            (Method::Post, "/push/unsubscribe") => {
+                let id = params.get("id").cloned().unwrap_or_default();
+                self.send(&serde_json::json!(["CLOSE", push_sub_id(&id)]));
+                self.push_ids.borrow_mut().remove(&id);
+                self.save_push_ids().await?;
+                Response::empty()
+            }
+            (Method::Post, "/socket") => {
+                match req.json::<SocketMessage>().await? {
+                    SocketMessage::Event { sub_id, event } => self.handle_event(&sub_id, event).await?,
+                    SocketMessage::Disconnected => {
+                        self.socket.borrow_mut().take();
+                        self.ensure_alarm().await?;
+                    }
+                }
+                Response::empty()
+            }
+            _ => Response::error("not found", 404),
+        }
+    }
+
+    async fn alarm(&self) -> Result<Response> {
+        self.load().await?;
+        let active = self.with_webhooks(|w| w.values().any(|h| !h.disabled)) || !self.push_ids.borrow().is_empty();
+        // The relay is down for maintenance; subscriptions resume from each
+        // webhook's last event once it is back
+        let degraded = crate::degraded::get(&self.env).await.is_some();
+        if active && !degraded && self.socket.borrow().is_none() {
+            if let Err(e) = self.connect().await {
+                console_log!("Webhook hub connect failed: {}", e);
+            }
+        }
+        self.process_retries().await?;
+
+        let pending = self.retries().await?;
+        if active || !pending.is_empty() {
+            self.state.storage().set_alarm(ALARM_INTERVAL).await?;
+        }
+        Response::empty()
+    }
+}
+
+impl WebhookHub {
+    async fn load(&self) -> Result<()> {
+        if self.webhooks.borrow().is_some() {
+            return Ok(());
+        }
+        let stored = self
+            .state
+            .storage()
+            .get::<BTreeMap<String, Webhook>>(WEBHOOKS_KEY)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        self.webhooks.borrow_mut().get_or_insert(stored);
+        let push_ids = self
+            .state
+            .storage()
+            .get::<BTreeMap<String, String>>(PUSH_IDS_KEY)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        *self.push_ids.borrow_mut() = push_ids;
+        Ok(())
+    }
+
+    async fn save_push_ids(&self) -> Result<()> {
+        let snapshot = self.push_ids.borrow().clone();
+        self.state.storage().put(PUSH_IDS_KEY, snapshot).await
+    }
+
+    fn with_webhooks<T>(&self, f: impl FnOnce(&mut BTreeMap<String, Webhook>) -> T) -> T {
+        let mut webhooks = self.webhooks.borrow_mut();
+        f(webhooks.get_or_insert_with(BTreeMap::new))
+    }
+
+    fn owned(&self, owner: &str) -> Vec<Webhook> {
+        self.with_webhooks(|w| w.values().filter(|h| h.owner == owner).cloned().collect())
+    }
+
+    /// Subscriptions held open on the relay socket
+    fn subscriptions(&self) -> usize {
+        self.with_webhooks(|w| w.values().filter(|h| !h.disabled).count()) + self.push_ids.borrow().len()
+    }
+
+    async fn save(&self) -> Result<()> {
+        let snapshot = self.with_webhooks(|w| w.clone());
+        self.state.storage().put(WEBHOOKS_KEY, snapshot).await
+    }
+
+    async fn retries(&self) -> Result<Vec<PendingDelivery>> {
+        Ok(self
+            .state
+            .storage()
+            .get::<Vec<PendingDelivery>>(RETRIES_KEY)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default())
+    }
+
+    async fn ensure_alarm(&self) -> Result<()> {
+        if self.state.storage().get_alarm().await?.is_none() {
+            self.state.storage().set_alarm(Duration::from_secs(1)).await?;
+        }
+        Ok(())
+    }
+
+    fn send(&self, message: &Value) {
+        if let Some(socket) = self.socket.borrow().as_ref() {
+            if let Err(e) = socket.send_with_str(message.to_string()) {
+                console_log!("Webhook hub send failed: {}", e);
+            }
+        }
+    }
+
+    fn subscribe(&self, webhook: &Webhook) {
+        if !webhook.disabled {
+            self.send(&serde_json::json!(["REQ", webhook.sub_id(), webhook.subscription_filter()]));
+        }
+    }
+
+    /// Open the relay socket and subscribe every enabled webhook. Frames are
+    /// forwarded back into this object through its own stub so they are
+    /// handled with full access to state.
+    async fn connect(&self) -> Result<()> {
+        let relay_url = crate::router::relay_url(&self.env);
+        let url = relay_url.parse().map_err(|_| "Invalid relay URL")?;
+        let socket = WebSocket::connect(url).await?;
+        socket.accept()?;
+        let mut events = socket.events()?;
+        *self.socket.borrow_mut() = Some(socket);
+
+        let webhooks = self.with_webhooks(|w| w.values().cloned().collect::<Vec<_>>());
+        for webhook in &webhooks {
+            self.subscribe(webhook);
+        }
+        let push_ids: Vec<String> = self.push_ids.borrow().keys().cloned().collect();
+        for id in push_ids {
+            match push::get_registration(&self.env, &id).await? {
+                Some(registration) => self.send(&serde_json::json!([
+                    "REQ",
+                    push_sub_id(&id),
+                    registration.subscription_filter(now_seconds())
+                ])),
+                // Expired from KV; stop tracking it
+                None => {
+                    self.push_ids.borrow_mut().remove(&id);
+                }
+            }
+        }
+        self.save_push_ids().await?;
+
+        let env = self.env.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            // Each event is handed over without waiting on its delivery, so a slow
+            // callback doesn't hold up every other owner's events behind it
+            while let Some(Ok(WebsocketEvent::Message(msg))) = events.next().await {
+                let Some(text) = msg.text() else { continue };
+                let Ok(parsed) = serde_json::from_str::<Vec<Value>>(&text) else { continue };
+                if parsed.first().and_then(|v| v.as_str()) != Some("EVENT") {
+                    continue;
+                }
+                let (Some(sub_id), Some(event)) = (parsed.get(1).and_then(|v| v.as_str()), parsed.get(2)) else {
+                    continue;
+                };
+                let message = SocketMessage::Event {
+                    sub_id: sub_id.to_string(),
+                    event: event.clone(),
+                };
+                let env = env.clone();
+                wasm_bindgen_futures::spawn_local(async move {
+                    if let Err(e) = notify_hub(&env, &message).await {
+                        console_log!("Webhook hub notify failed: {}", e);
+                    }
+                });
+            }
+            let _ = notify_hub(&env, &SocketMessage::Disconnected).await;
+        });
+        Ok(())
+    }
+
+    async fn handle_event(&self, sub_id: &str, event: Value) -> Result<()> {
+        if let Some(id) = sub_id.strip_prefix("push-") {
+            return self.handle_push(id, &event).await;
+        }
+        let Some(id) = sub_id.strip_prefix("wh-") else { return Ok(()) };
+        let Some(webhook) = self.with_webhooks(|w| w.get(id).cloned()) else { return Ok(()) };
+        if webhook.disabled {
+            return Ok(());
+        }
+        let created_at = event.get("created_at").and_then(|v| v.as_u64());
+        let delivered = self.deliver(&webhook, &event).await;
+        if !delivered {
+            self.schedule_retry(PendingDelivery {
+                webhook_id: webhook.id.clone(),
+                event,
+                attempt: 1,
+                due_at: 0,
+            })
+            .await?;
+        }
+        self.with_webhooks(|w| {
+            if let Some(hook) = w.get_mut(id) {
+                hook.last_event_at = hook.last_event_at.max(created_at);
+            }
+        });
+        self.record_outcome(id, delivered).await
+    }
+
+    /// Notify a Web Push registration. Subscriptions the push service reports
+    /// as gone are removed; other failures are dropped, since pushes aren't retried.
+    async fn handle_push(&self, id: &str, event: &Value) -> Result<()> {
+        let Some(vapid) = Vapid::from_env(&self.env) else { return Ok(()) };
+        let registration = match push::get_registration(&self.env, id).await? {
+            Some(r) => r,
+            None => {
+                self.send(&serde_json::json!(["CLOSE", push_sub_id(id)]));
+                self.push_ids.borrow_mut().remove(id);
+                return self.save_push_ids().await;
+            }
+        };
+        // Don't notify people about their own events
+        if event.get("pubkey").and_then(|v| v.as_str()) == Some(registration.owner.as_str()) {
+            return Ok(());
+        }
+        if push::send(&vapid, &registration, event, now_seconds()).await == PushOutcome::Gone {
+            push::delete_registration(&self.env, id).await?;
+            self.send(&serde_json::json!(["CLOSE", push_sub_id(id)]));
+            self.push_ids.borrow_mut().remove(id);
+            self.save_push_ids().await?;
+        }
+        Ok(())
+    }
+
+    /// POST one event to the callback. Any 2xx counts as delivered.
+    async fn deliver(&self, webhook: &Webhook, event: &Value) -> bool {
+        let body = serde_json::json!({ "webhook_id": webhook.id, "event": event }).to_string();
+        let timestamp = now_seconds();
+        let result = async {
+            let mut headers = Headers::new();
+            headers.set("Content-Type", "application/json")?;
+            headers.set("X-Webhook-Id", &webhook.id)?;
+            headers.set("X-Webhook-Timestamp", &timestamp.to_string())?;
+            headers.set("X-Webhook-Signature", &sign(&webhook.secret, timestamp, &body))?;
+            let url = Url::parse(&webhook.url).map_err(|e| Error::from(e.to_string()))?;
+            let mut init = RequestInit::new();
+            init.with_method(Method::Post)
+                .with_headers(headers)
+                .with_body(Some(body.clone().into()));
+            let policy = Policy {
+                timeout: DELIVERY_TIMEOUT,
+                ..Policy::default()
+            };
+            Ok::<_, Error>(outbound::send(&url, &mut init, &policy).await?)
+        }
+        .await;
+        match result {
+            Ok(resp) => (200..300).contains(&resp.status_code()),
+            Err(e) => {
+                console_log!("Webhook {} delivery failed: {}", webhook.id, e);
+                false
+            }
+        }
+    }
+
+    async fn record_outcome(&self, id: &str, delivered: bool) -> Result<()> {
+        let closed = self.with_webhooks(|w| {
+            let hook = w.get_mut(id)?;
+            if delivered {
+                hook.consecutive_failures = 0;
+                return None;
+            }
+            hook.consecutive_failures += 1;
+            if hook.consecutive_failures >= MAX_CONSECUTIVE_FAILURES && !hook.disabled {
+                hook.disabled = true;
+                return Some(hook.sub_id());
+            }
+            None
+        });
+        if let Some(sub_id) = closed {
+            console_log!("Webhook {} disabled after repeated failures", id);
+            self.send(&serde_json::json!(["CLOSE", sub_id]));
+        }
+        self.save().await
+    }
+
+    async fn schedule_retry(&self, mut pending: PendingDelivery) -> Result<()> {
+        let Some(delay) = retry_delay(pending.attempt) else {
+            console_log!("Webhook {} giving up on an event after {} attempts", pending.webhook_id, pending.attempt);
+            return Ok(());
+        };
+        pending.due_at = now_seconds() + delay;
+        let mut retries = self.retries().await?;
+        retries.push(pending);
+        self.state.storage().put(RETRIES_KEY, retries).await?;
+        self.ensure_alarm().await
+    }
+
+    async fn process_retries(&self) -> Result<()> {
+        let now = now_seconds();
+        let (due, later): (Vec<_>, Vec<_>) = self.retries().await?.into_iter().partition(|p| p.due_at <= now);
+        if due.is_empty() {
+            return Ok(());
+        }
+        self.state.storage().put(RETRIES_KEY, later).await?;
+
+        for mut pending in due {
+            let Some(webhook) = self.with_webhooks(|w| w.get(&pending.webhook_id).cloned()) else { continue };
+            if webhook.disabled {
+                continue;
+            }
+            let delivered = self.deliver(&webhook, &pending.event).await;
+            if !delivered {
+                pending.attempt += 1;
+                self.schedule_retry(pending).await?;
+            }
+            self.record_outcome(&webhook.id, delivered).await?;
+        }
+        Ok(())
+    }
+}
+
+fn push_sub_id(id: &str) -> String {
+    format!("push-{}", id)
+}
+
+async fn notify_hub(env: &Env, message: &SocketMessage) -> Result<()> {
+    let stub = env.durable_object("WEBHOOKS")?.id_from_name("global")?.get_stub()?;
+    let req = Request::new_with_init(
+        "http://do/socket",
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_body(Some(serde_json::to_string(message)?.into())),
+    )?;
+    stub.fetch_with_request(req).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn webhook() -> Webhook {
+        Webhook {
+            id: "abc".to_string(),
+            owner: "pk".to_string(),
+            filter: json!({"kinds": [1], "limit": 50}),
+            url: "https://example.com/hook".to_string(),
+            secret: "s".to_string(),
+            created_at: 100,
+            consecutive_failures: 0,
+            disabled: false,
+            last_event_at: None,
+        }
+    }
+
+    #[test]
+    fn test_subscription_filter_resumes_and_drops_limit() {
+        let mut hook = webhook();
+        assert_eq!(hook.subscription_filter(), json!({"kinds": [1], "since": 100}));
+        hook.last_event_at = Some(250);
+        assert_eq!(hook.subscription_filter(), json!({"kinds": [1], "since": 250}));
+    }
+
+    #[test]
+    fn test_view_hides_secret() {
+        let hook = webhook();
+        assert!(hook.view(false).secret.is_none());
+        assert_eq!(hook.view(true).secret.as_deref(), Some("s"));
+    }
+
+    #[test]
+    fn test_retry_schedule() {
+        assert_eq!(retry_delay(0), None);
+        assert_eq!(retry_delay(1), Some(60));
+        assert_eq!(retry_delay(4), Some(7200));
+        assert_eq!(retry_delay(5), None);
+    }
+
+    #[test]
+    fn test_signature() {
+        // echo -n '1700000000.{"a":1}' | openssl dgst -sha256 -hmac secret
+        assert_eq!(
+            sign("secret", 1700000000, r#"{"a":1}"#),
+            "sha256=49f24e537407743fa4a0242bb63b94b9a47ee99cbbe071ccd8a22550ae411686"
+        );
+    }
+
+    #[test]
+    fn test_callback_url_validation() {
+        assert!(validate_callback_url("https://hooks.example.com/nostr").is_ok());
+        assert!(validate_callback_url("http://hooks.example.com/nostr").is_err());
+        assert!(validate_callback_url("https://127.0.0.1/hook").is_err());
+        assert!(validate_callback_url("https://[::1]/hook").is_err());
+        assert!(validate_callback_url("https://localhost/hook").is_err());
+        assert!(validate_callback_url("https://db.internal/hook").is_err());
+        assert!(validate_callback_url("not a url").is_err());
+    }
+}
@@ -0,0 +1,159 @@
+// ABOUTME: Optional write-behind of events read from the upstream relay to a "home" relay
+// ABOUTME: Samples relay results, checks whether the home relay has them, and republishes if not
+
+use crate::publish_state::is_ephemeral;
+use serde_json::Value;
+use worker::*;
+
+/// Events are only checked once in this window
+const CHECKED_TTL_SECONDS: u64 = 7 * 86400;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteBehindConfig {
+    pub home_relay: String,
+    /// Fraction of relay fetches whose events get checked
+    pub sample_rate: f64,
+    /// Events checked per sampled fetch
+    pub max_events: usize,
+}
+
+impl WriteBehindConfig {
+    /// Enabled by `WRITE_BEHIND_RELAY`, unless it is the relay being read from
+    pub fn from_env(env: &Env) -> Option<Self> {
+        let var = |name: &str| env.var(name).ok().map(|v| v.to_string());
+        let home_relay = var("WRITE_BEHIND_RELAY").filter(|r| !r.is_empty())?;
+        if home_relay == crate::router::relay_url(env) {
+            return None;
+        }
+        Some(Self {
+            home_relay,
+            sample_rate: var("WRITE_BEHIND_SAMPLE_RATE")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0.1_f64)
+                .clamp(0.0, 1.0),
+            max_events: var("WRITE_BEHIND_MAX_EVENTS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(10),
+        })
+    }
+}
+
+/// Signed, non-ephemeral events worth copying, newest first
+pub fn candidates(events: &[Value], max: usize) -> Vec<Value> {
+    let mut picked: Vec<&Value> = events
+        .iter()
+        .filter(|e| e.get("id").and_then(|v| v.as_str()).is_some())
+        .filter(|e| e.get("sig").and_then(|v| v.as_str()).is_some())
+        // Ephemeral events are not meant to be stored
+        .filter(|e| !is_ephemeral(e))
+        .collect();
+    picked.sort_by_key(|e| std::cmp::Reverse(e.get("created_at").and_then(|v| v.as_u64()).unwrap_or(0)));
+    picked.into_iter().take(max).cloned().collect()
+}
+
+/// Decide whether this fetch is sampled and pick its candidates
+pub fn sample(config: &WriteBehindConfig, events: &[Value], roll: f64) -> Vec<Value> {
+    if roll >= config.sample_rate {
+        return Vec::new();
+    }
+    candidates(events, config.max_events)
+}
+
+/// Check each event against the home relay and republish the missing ones.
+/// Runs inside wait_until; failures are logged and the event retried on a later sample.
+pub async fn run(env: Env, config: WriteBehindConfig, events: Vec<Value>) {
+    if let Err(e) = copy_missing(&env, &config, events).await {
+        console_log!("Write-behind failed: {}", e);
+    }
+}
+
+async fn copy_missing(env: &Env, config: &WriteBehindConfig, events: Vec<Value>) -> Result<()> {
+    let kv = env.kv("REST_GATEWAY_CACHE")?;
+    // A dedicated RelayPool instance keeps the home relay's connection state and
+    // latency samples apart from the read relay's
+    let stub = env.durable_object("RELAY_POOL")?.id_from_name("home")?.get_stub()?;
+
+    for event in events {
+        let event_id = event.get("id").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+        let marker = format!("writebehind:{}", event_id);
+        if kv.get(&marker).text().await?.is_some() {
+            continue;
+        }
+
+        let verify = serde_json::json!({ "event_id": event_id }).to_string();
+        let found = home_relay_call(&stub, &config.home_relay, "/verify", verify)
+            .await?
+            .get("found")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        if !found {
+            let published = home_relay_call(&stub, &config.home_relay, "/publish", event.to_string())
+                .await?
+                .get("ok")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            if !published {
+                console_log!("Write-behind: home relay rejected {}", event_id);
+                continue;
+            }
+            console_log!("Write-behind: copied {} to {}", event_id, config.home_relay);
+        }
+
+        kv.put(&marker, "1")?
+            .expiration_ttl(CHECKED_TTL_SECONDS)
+            .execute()
+            .await?;
+    }
+    Ok(())
+}
+
+async fn home_relay_call(stub: &Stub, home_relay: &str, path: &str, body: String) -> Result<Value> {
+    let mut headers = Headers::new();
+    headers.set("X-Relay-Url", home_relay)?;
+    let req = Request::new_with_init(
+        &format!("http://do{}", path),
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_headers(headers)
+            .with_body(Some(body.into())),
+    )?;
+    stub.fetch_with_request(req).await?.json().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config() -> WriteBehindConfig {
+        WriteBehindConfig {
+            home_relay: "wss://home.example".to_string(),
+            sample_rate: 0.25,
+            max_events: 2,
+        }
+    }
+
+    #[test]
+    fn test_candidates_skip_unsigned_and_ephemeral() {
+        let events = vec![
+            json!({"id": "a", "sig": "s", "kind": 1, "created_at": 10}),
+            json!({"id": "b", "kind": 1, "created_at": 30}),
+            json!({"id": "c", "sig": "s", "kind": 20001, "created_at": 40}),
+            json!({"id": "d", "sig": "s", "kind": 1, "created_at": 20}),
+            json!({"id": "e", "sig": "s", "kind": 7, "created_at": 5}),
+        ];
+        let ids: Vec<_> = candidates(&events, 2)
+            .iter()
+            .map(|e| e["id"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(ids, vec!["d", "a"]);
+    }
+
+    #[test]
+    fn test_sampling_roll() {
+        let events = vec![json!({"id": "a", "sig": "s", "kind": 1})];
+        assert_eq!(sample(&config(), &events, 0.1).len(), 1);
+        assert!(sample(&config(), &events, 0.25).is_empty());
+        assert!(sample(&config(), &events, 0.9).is_empty());
+    }
+}